@@ -0,0 +1,553 @@
+//! Animated PNG decoding and encoding.
+//!
+//! APNG frames are decoded into the same full-canvas RGBA [`gif::Frame`]s that [`GifFrames`]
+//! produces, so trimming, timing and the frame pipeline work on them unchanged. Unlike GIF,
+//! APNG keeps full 8-bit alpha and true color, which is what makes it worth converting to.
+//!
+//! [`GifFrames`]: crate::pipeline::GifFrames
+
+use std::io::{Read, Write};
+
+use gif::{Frame, Repeat};
+use png::{chunk, BlendOp, DisposeOp, FrameControl};
+
+use crate::{
+    error::{GpuWorkerError, Result},
+    limits::{DecodeLimits, FrameBudget},
+};
+
+/// Streams decoded frames out of an APNG
+///
+/// Each frame is drawn onto the canvas according to its blend operation and the dispose
+/// operation of the frame before it, and yielded as a full-canvas RGBA frame. Delays are
+/// rounded to centiseconds, the unit the rest of the pipeline works in. The default image of
+/// files whose `IDAT` is not part of the animation is skipped. Each frame is checked against the
+/// decode limits before its pixels are decoded.
+pub struct ApngFrames<R: Read> {
+    reader: png::Reader<R>,
+    buffer: Vec<u8>,
+    canvas: Canvas,
+    remaining: u32,
+    /// Whether the reader has already read the control of the next frame.
+    at_frame: bool,
+    budget: FrameBudget,
+    pending: Option<Frame<'static>>,
+}
+
+impl<R: Read> std::fmt::Debug for ApngFrames<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApngFrames")
+            .field("width", &self.canvas.width)
+            .field("height", &self.canvas.height)
+            .field("remaining", &self.remaining)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read> ApngFrames<R> {
+    /// Size of the canvas every frame is drawn onto.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.canvas.width, self.canvas.height)
+    }
+
+    fn read_frame(&mut self) -> Result<Option<Frame<'static>>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+
+        if !self.at_frame {
+            self.reader.next_frame_info()?;
+        }
+        self.at_frame = false;
+
+        let (width, height) = self.dimensions();
+        // Plain PNGs have no frame control; their single image covers the canvas
+        let control = self.reader.info().frame_control.unwrap_or(FrameControl {
+            width,
+            height,
+            delay_num: 0,
+            ..FrameControl::default()
+        });
+        self.budget
+            .admit(delay_to_centiseconds(control.delay_num, control.delay_den))?;
+
+        let output = self.reader.next_frame(&mut self.buffer)?;
+
+        let rgba = to_rgba(&self.buffer[..output.buffer_size()], output.color_type)?;
+        let canvas = self.canvas.draw(&rgba, &control);
+
+        Ok(Some(Frame {
+            delay: delay_to_centiseconds(control.delay_num, control.delay_den),
+            width: width as u16,
+            height: height as u16,
+            buffer: canvas.into(),
+            ..Frame::default()
+        }))
+    }
+}
+
+impl<R: Read> Iterator for ApngFrames<R> {
+    type Item = Result<Frame<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.pending.take() {
+            Some(frame) => Some(Ok(frame)),
+            None => self.read_frame().transpose(),
+        }
+    }
+}
+
+/// Opens an APNG for frame-by-frame decoding
+///
+/// The first frame is decoded eagerly so that malformed or empty files, and files exceeding
+/// `limits`, are rejected before any output is produced. A PNG without animation is read as a
+/// single frame.
+pub fn decode_apng<R: Read>(data: R, limits: &DecodeLimits) -> Result<ApngFrames<R>> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+
+    let (width, height) = reader.info().size();
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(GpuWorkerError::InvalidInput(format!(
+            "APNG is {}x{}, frames can be at most {} pixels wide and high",
+            width,
+            height,
+            u16::MAX
+        )));
+    }
+    let budget = limits.frame_budget(width, height)?;
+    let animation = reader.info().animation_control;
+    let remaining = animation.map_or(1, |animation| animation.num_frames);
+    limits.check_frames(remaining as usize)?;
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let mut at_frame = true;
+    if animation.is_some() && reader.info().frame_control.is_none() {
+        // The default image is only shown by viewers that do not support APNG
+        reader.next_frame(&mut buffer)?;
+        at_frame = false;
+    }
+
+    let mut frames = ApngFrames {
+        reader,
+        buffer,
+        canvas: Canvas::new(width, height),
+        remaining,
+        at_frame,
+        budget,
+        pending: None,
+    };
+
+    frames.pending = frames.read_frame()?;
+    if frames.pending.is_none() {
+        return Err(GpuWorkerError::InvalidInput(
+            "APNG contains no frames".to_string(),
+        ));
+    }
+
+    log::info!("Decoding APNG ({}x{})", width, height);
+    Ok(frames)
+}
+
+/// Converts 8-bit decoder output to RGBA.
+fn to_rgba(data: &[u8], color_type: png::ColorType) -> Result<Vec<u8>> {
+    let rgba = match color_type {
+        png::ColorType::Rgba => data.to_vec(),
+        png::ColorType::Rgb => data
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => data
+            .chunks_exact(2)
+            .flat_map(|ga| [ga[0], ga[0], ga[0], ga[1]])
+            .collect(),
+        png::ColorType::Grayscale => data.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        png::ColorType::Indexed => {
+            return Err(GpuWorkerError::Internal(
+                "Indexed PNG data was not expanded".to_string(),
+            ))
+        }
+    };
+    Ok(rgba)
+}
+
+/// Converts an APNG delay fraction in seconds to centiseconds.
+fn delay_to_centiseconds(numerator: u16, denominator: u16) -> u16 {
+    // A zero denominator means hundredths of a second
+    let denominator = if denominator == 0 {
+        100
+    } else {
+        denominator as u32
+    };
+    let centiseconds = (numerator as u32 * 100 + denominator / 2) / denominator;
+    centiseconds.min(u16::MAX as u32) as u16
+}
+
+/// Tracks the APNG output buffer while frames are drawn onto it.
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    /// Canvas contents to restore after a frame with `DisposeOp::Previous`.
+    saved: Option<Vec<u8>>,
+    /// Dispose operation of the last drawn frame and the canvas area it covered.
+    last: Option<(DisposeOp, [u32; 4])>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (width * height * 4) as usize],
+            saved: None,
+            last: None,
+        }
+    }
+
+    /// Draws a frame of `control.width`x`control.height` RGBA pixels and returns a copy of the
+    /// resulting canvas.
+    fn draw(&mut self, rgba: &[u8], control: &FrameControl) -> Vec<u8> {
+        let first = self.last.is_none();
+        match self.last.take() {
+            Some((DisposeOp::Background, rect)) => self.clear(rect),
+            Some((DisposeOp::Previous, _)) => {
+                if let Some(saved) = self.saved.take() {
+                    self.pixels = saved;
+                }
+            }
+            _ => {}
+        }
+
+        // The spec treats "previous" on the first frame as "background"
+        let dispose = match control.dispose_op {
+            DisposeOp::Previous if first => DisposeOp::Background,
+            dispose => dispose,
+        };
+        if dispose == DisposeOp::Previous {
+            self.saved = Some(self.pixels.clone());
+        }
+
+        let rect @ [left, top, right, bottom] = self.clip(control);
+        for y in top..bottom {
+            let src_row = (y - control.y_offset) as usize * control.width as usize;
+            for x in left..right {
+                let src = (src_row + (x - control.x_offset) as usize) * 4;
+                let dst = ((y * self.width + x) * 4) as usize;
+                let (src, dst) = (&rgba[src..src + 4], &mut self.pixels[dst..dst + 4]);
+                match control.blend_op {
+                    BlendOp::Source => dst.copy_from_slice(src),
+                    BlendOp::Over => blend_over(dst, src),
+                }
+            }
+        }
+
+        self.last = Some((dispose, rect));
+        self.pixels.clone()
+    }
+
+    /// Returns the part of the canvas covered by a frame as `[left, top, right, bottom]`.
+    fn clip(&self, control: &FrameControl) -> [u32; 4] {
+        let left = control.x_offset.min(self.width);
+        let top = control.y_offset.min(self.height);
+        let right = control
+            .x_offset
+            .saturating_add(control.width)
+            .min(self.width);
+        let bottom = control
+            .y_offset
+            .saturating_add(control.height)
+            .min(self.height);
+        [left, top, right, bottom]
+    }
+
+    fn clear(&mut self, [left, top, right, bottom]: [u32; 4]) {
+        for y in top..bottom {
+            let start = ((y * self.width + left) * 4) as usize;
+            let end = ((y * self.width + right) * 4) as usize;
+            self.pixels[start..end].fill(0);
+        }
+    }
+}
+
+/// Composites a non-premultiplied RGBA pixel over another.
+fn blend_over(dst: &mut [u8], src: &[u8]) {
+    let src_alpha = src[3] as u32;
+    match src_alpha {
+        0 => return,
+        255 => {
+            dst.copy_from_slice(src);
+            return;
+        }
+        _ => {}
+    }
+
+    let dst_alpha = dst[3] as u32 * (255 - src_alpha) / 255;
+    let out_alpha = src_alpha + dst_alpha;
+    for channel in 0..3 {
+        let value = src[channel] as u32 * src_alpha + dst[channel] as u32 * dst_alpha;
+        dst[channel] = ((value + out_alpha / 2) / out_alpha) as u8;
+    }
+    dst[3] = out_alpha as u8;
+}
+
+/// A frame compressed into PNG image data, ready to be written as `IDAT` or `fdAT`.
+#[derive(Debug)]
+pub struct CompressedFrame {
+    delay: u16,
+    data: Vec<u8>,
+}
+
+/// Compresses a full-canvas RGBA frame with a delay in centiseconds
+///
+/// This is the expensive part of APNG encoding and runs on the quantization pool, the same way
+/// palette quantization does for GIF output.
+pub fn compress_frame(rgba: &[u8], width: u32, height: u32, delay: u16) -> Result<CompressedFrame> {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    writer.finish()?;
+
+    Ok(CompressedFrame {
+        delay,
+        data: image_data(&png)?,
+    })
+}
+
+/// Concatenates the `IDAT` payloads of an encoded PNG.
+fn image_data(png: &[u8]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut pos = 8;
+
+    while pos + 8 <= png.len() {
+        let length = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]);
+        let start = pos + 8;
+        let end = start + length as usize;
+        if end > png.len() {
+            break;
+        }
+        if png[pos + 4..pos + 8] == chunk::IDAT.0 {
+            data.extend_from_slice(&png[start..end]);
+        }
+        pos = end + 4;
+    }
+
+    if data.is_empty() {
+        return Err(GpuWorkerError::Internal(
+            "Encoded PNG frame has no image data".to_string(),
+        ));
+    }
+    Ok(data)
+}
+
+/// Writes compressed frames into an APNG
+///
+/// The `acTL` chunk at the start of the file holds the number of frames, which is not known
+/// while frames are still streaming in. Frames are therefore kept compressed in memory and the
+/// file is written by [`ApngEncoder::finish`].
+pub struct ApngEncoder<W: Write> {
+    output: W,
+    width: u32,
+    height: u32,
+    repeat: Repeat,
+    frames: Vec<CompressedFrame>,
+}
+
+impl<W: Write> std::fmt::Debug for ApngEncoder<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApngEncoder")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("frames", &self.frames.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> ApngEncoder<W> {
+    /// Creates an encoder for frames of `width`x`height`.
+    pub fn new(output: W, width: u32, height: u32, repeat: Repeat) -> Self {
+        Self {
+            output,
+            width,
+            height,
+            repeat,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Appends a frame to the animation.
+    pub fn write_frame(&mut self, frame: CompressedFrame) {
+        self.frames.push(frame);
+    }
+
+    /// Writes the animation to the output.
+    pub fn finish(self) -> Result<()> {
+        if self.frames.is_empty() {
+            return Err(GpuWorkerError::Internal(
+                "APNG has no frames to write".to_string(),
+            ));
+        }
+
+        let mut encoder = png::Encoder::new(self.output, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+
+        // A GIF loop count repeats the animation after the first play; APNG counts every play
+        let plays = match self.repeat {
+            Repeat::Infinite => 0,
+            Repeat::Finite(count) => count as u32 + 1,
+        };
+        let mut animation = [0; 8];
+        animation[..4].copy_from_slice(&(self.frames.len() as u32).to_be_bytes());
+        animation[4..].copy_from_slice(&plays.to_be_bytes());
+        writer.write_chunk(chunk::acTL, &animation)?;
+
+        let mut sequence = 0u32;
+        for (index, frame) in self.frames.iter().enumerate() {
+            let control = frame_control(sequence, self.width, self.height, frame.delay);
+            writer.write_chunk(chunk::fcTL, &control)?;
+            sequence += 1;
+
+            if index == 0 {
+                writer.write_chunk(chunk::IDAT, &frame.data)?;
+            } else {
+                let mut data = Vec::with_capacity(frame.data.len() + 4);
+                data.extend_from_slice(&sequence.to_be_bytes());
+                data.extend_from_slice(&frame.data);
+                writer.write_chunk(chunk::fdAT, &data)?;
+                sequence += 1;
+            }
+        }
+
+        writer.finish()?;
+        log::info!("Encoded {} APNG frames", self.frames.len());
+        Ok(())
+    }
+}
+
+/// Builds `fcTL` data for a full-canvas frame that replaces everything before it.
+fn frame_control(sequence: u32, width: u32, height: u32, delay: u16) -> [u8; 26] {
+    let mut data = [0; 26];
+    data[..4].copy_from_slice(&sequence.to_be_bytes());
+    data[4..8].copy_from_slice(&width.to_be_bytes());
+    data[8..12].copy_from_slice(&height.to_be_bytes());
+    // x and y offsets stay zero
+    data[20..22].copy_from_slice(&delay.to_be_bytes());
+    data[22..24].copy_from_slice(&100u16.to_be_bytes());
+    data[24] = DisposeOp::None as u8;
+    data[25] = BlendOp::Source as u8;
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(frames: &[(Vec<u8>, u16)], width: u32, height: u32) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = ApngEncoder::new(&mut output, width, height, Repeat::Infinite);
+        for (rgba, delay) in frames {
+            encoder.write_frame(compress_frame(rgba, width, height, *delay).unwrap());
+        }
+        encoder.finish().unwrap();
+        output
+    }
+
+    #[test]
+    fn test_round_trip_keeps_alpha_and_delays() {
+        let frames = vec![
+            (vec![255, 0, 0, 128, 0, 0, 0, 0], 5),
+            (vec![0, 255, 0, 255, 0, 0, 255, 64], 12),
+        ];
+        let apng = encode(&frames, 2, 1);
+
+        let decoded: Vec<_> = decode_apng(apng.as_slice(), &DecodeLimits::default())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(decoded.len(), 2);
+        for (frame, (rgba, delay)) in decoded.iter().zip(&frames) {
+            assert_eq!(frame.buffer.as_ref(), rgba.as_slice());
+            assert_eq!(frame.delay, *delay);
+        }
+    }
+
+    #[test]
+    fn test_encoded_animation_is_valid_apng() {
+        let apng = encode(&[(vec![0; 16], 10), (vec![255; 16], 10)], 2, 2);
+
+        let reader = png::Decoder::new(apng.as_slice()).read_info().unwrap();
+        let animation = reader.info().animation_control.unwrap();
+        assert_eq!(animation.num_frames, 2);
+        assert_eq!(animation.num_plays, 0);
+    }
+
+    #[test]
+    fn test_plain_png_is_a_single_frame() {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, 1, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[10, 20, 30]).unwrap();
+        writer.finish().unwrap();
+
+        let frames: Vec<_> = decode_apng(png.as_slice(), &DecodeLimits::default())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].buffer.as_ref(), &[10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn test_canvas_dispose_and_blend() {
+        let mut canvas = Canvas::new(2, 1);
+        let full = FrameControl {
+            width: 2,
+            height: 1,
+            ..FrameControl::default()
+        };
+
+        canvas.draw(&[255, 0, 0, 255, 255, 0, 0, 255], &full);
+
+        // Half-transparent blue over red on the right pixel, restored afterwards
+        let over = FrameControl {
+            width: 1,
+            height: 1,
+            x_offset: 1,
+            dispose_op: DisposeOp::Previous,
+            blend_op: BlendOp::Over,
+            ..FrameControl::default()
+        };
+        let blended = canvas.draw(&[0, 0, 255, 128], &over);
+        assert_eq!(&blended[..4], &[255, 0, 0, 255]);
+        assert_eq!(&blended[4..], &[127, 0, 128, 255]);
+
+        // Source replaces alpha too, then "background" clears the area for the next frame
+        let source = FrameControl {
+            width: 1,
+            height: 1,
+            dispose_op: DisposeOp::Background,
+            ..FrameControl::default()
+        };
+        let replaced = canvas.draw(&[0, 0, 0, 0], &source);
+        assert_eq!(replaced, vec![0, 0, 0, 0, 255, 0, 0, 255]);
+
+        let after = canvas.draw(&[9, 9, 9, 0], &over);
+        assert_eq!(after, vec![0, 0, 0, 0, 255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_delay_to_centiseconds() {
+        assert_eq!(delay_to_centiseconds(1, 10), 10);
+        assert_eq!(delay_to_centiseconds(1, 60), 2);
+        assert_eq!(delay_to_centiseconds(7, 0), 7);
+        assert_eq!(delay_to_centiseconds(u16::MAX, 1), u16::MAX);
+    }
+}
@@ -0,0 +1,213 @@
+//! Minimal streaming ZIP writer for frame archives.
+//!
+//! Frames are PNGs, which are already compressed, so entries are stored without compression.
+//! Each entry is written as soon as it is added and only the central directory is kept in
+//! memory, which lets frame archives stream like any other output.
+
+use std::io::Write;
+
+use crate::error::{GpuWorkerError, Result};
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+
+/// Version 2.0, the minimum for stored entries.
+const VERSION: u16 = 20;
+
+/// MS-DOS date for 1980-01-01, the earliest representable date.
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// An entry already written to the archive.
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes files into a ZIP archive as they are added
+///
+/// ZIP64 is not supported, so archives are limited to 65535 entries and 4 GiB.
+pub struct ZipWriter<W: Write> {
+    output: W,
+    entries: Vec<Entry>,
+    offset: u64,
+}
+
+impl<W: Write> std::fmt::Debug for ZipWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZipWriter")
+            .field("entries", &self.entries.len())
+            .field("offset", &self.offset)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> ZipWriter<W> {
+    /// Starts an empty archive.
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            entries: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Adds a file named `name` with the contents `data`.
+    pub fn add_file(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        if self.entries.len() == u16::MAX as usize {
+            return Err(GpuWorkerError::InvalidInput(format!(
+                "Archives can hold at most {} files",
+                u16::MAX
+            )));
+        }
+        let size = u32::try_from(data.len())
+            .map_err(|_| GpuWorkerError::InvalidInput(format!("{} is too large", name)))?;
+        let offset = self.checked_offset()?;
+        let crc = crc32fast::hash(data);
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        push_entry_fields(&mut header, crc, size, name);
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        header.extend_from_slice(name.as_bytes());
+
+        self.write(&header)?;
+        self.write(data)?;
+        self.entries.push(Entry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Writes the central directory and returns the output.
+    pub fn finish(mut self) -> Result<W> {
+        let directory_offset = self.checked_offset()?;
+        let mut directory = Vec::new();
+
+        for entry in &self.entries {
+            directory.extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            directory.extend_from_slice(&VERSION.to_le_bytes()); // version made by
+            directory.extend_from_slice(&VERSION.to_le_bytes()); // version needed
+            push_entry_fields(&mut directory, entry.crc, entry.size, &entry.name);
+            directory.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // comment length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // disk number
+            directory.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            directory.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let count = self.entries.len() as u16;
+        let directory_size = directory.len() as u32;
+        directory.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes()); // this disk
+        directory.extend_from_slice(&0u16.to_le_bytes()); // disk with the directory
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&directory_size.to_le_bytes());
+        directory.extend_from_slice(&directory_offset.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes()); // comment length
+
+        self.write(&directory)?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.output.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    fn checked_offset(&self) -> Result<u32> {
+        u32::try_from(self.offset)
+            .map_err(|_| GpuWorkerError::InvalidInput("Archive is larger than 4 GiB".to_string()))
+    }
+}
+
+/// Appends the fields shared by local and central headers, from the flags to the name length.
+fn push_entry_fields(header: &mut Vec<u8>, crc: u32, size: u32, name: &str) {
+    header.extend_from_slice(&0u16.to_le_bytes()); // flags
+    header.extend_from_slice(&0u16.to_le_bytes()); // compression: stored
+    header.extend_from_slice(&0u16.to_le_bytes()); // modification time
+    header.extend_from_slice(&DOS_DATE.to_le_bytes());
+    header.extend_from_slice(&crc.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes()); // compressed size
+    header.extend_from_slice(&size.to_le_bytes()); // uncompressed size
+    header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes([data[pos], data[pos + 1]])
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    }
+
+    /// Reads `(name, data)` for every entry through the central directory.
+    fn read_entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(archive, end), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        let count = u16_at(archive, end + 10) as usize;
+        let mut pos = u32_at(archive, end + 16) as usize;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(u32_at(archive, pos), CENTRAL_HEADER_SIGNATURE);
+            let crc = u32_at(archive, pos + 16);
+            let size = u32_at(archive, pos + 20) as usize;
+            let name_len = u16_at(archive, pos + 28) as usize;
+            let offset = u32_at(archive, pos + 42) as usize;
+            let name = String::from_utf8(archive[pos + 46..pos + 46 + name_len].to_vec()).unwrap();
+
+            assert_eq!(u32_at(archive, offset), LOCAL_HEADER_SIGNATURE);
+            let data_start = offset
+                + 30
+                + u16_at(archive, offset + 26) as usize
+                + u16_at(archive, offset + 28) as usize;
+            let data = archive[data_start..data_start + size].to_vec();
+            assert_eq!(crc32fast::hash(&data), crc);
+
+            entries.push((name, data));
+            pos += 46 + name_len;
+        }
+        entries
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let mut writer = ZipWriter::new(Vec::new());
+        writer.add_file("frame-0000.png", b"first").unwrap();
+        writer.add_file("frame-0001.png", b"").unwrap();
+        writer.add_file("frame-0002.png", b"third frame").unwrap();
+        let archive = writer.finish().unwrap();
+
+        assert_eq!(
+            read_entries(&archive),
+            vec![
+                ("frame-0000.png".to_string(), b"first".to_vec()),
+                ("frame-0001.png".to_string(), Vec::new()),
+                ("frame-0002.png".to_string(), b"third frame".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_archive() {
+        let archive = ZipWriter::new(Vec::new()).finish().unwrap();
+        assert_eq!(archive.len(), 22);
+        assert!(read_entries(&archive).is_empty());
+    }
+}
@@ -0,0 +1,547 @@
+//! Assembling animated GIFs from still images.
+//!
+//! Every uploaded image becomes one frame. Images are normalized to a common canvas with one of
+//! three [`FitMode`]s: the part of the source that is shown is cropped on the CPU and scaled to
+//! its place on the canvas by a resize callback (the GPU resize in production), so a single
+//! image is decoded at a time.
+
+use bytes::Bytes;
+use gif::{Frame, Repeat};
+use image::{imageops, RgbaImage};
+use serde::Deserialize;
+
+use crate::{
+    error::{GpuWorkerError, Result},
+    limits::{DecodeLimits, FrameBudget},
+    pipeline::EncodeOptions,
+    timing::ms_to_delay,
+};
+
+/// Largest canvas width or height an assembled GIF can have.
+pub const MAX_CANVAS_SIZE: u32 = 4096;
+
+/// Largest input image width or height, the default texture size limit of the GPU.
+pub const MAX_IMAGE_SIZE: u32 = 8192;
+
+/// Frame delay used when neither `delay` nor `delays` is given, in milliseconds.
+pub const DEFAULT_DELAY_MS: u32 = 100;
+
+/// How images whose aspect ratio differs from the canvas are normalized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    /// Scale the whole image to fit inside the canvas, leaving transparent borders.
+    #[default]
+    Fit,
+    /// Scale the image to cover the canvas, cropping whatever overflows.
+    Fill,
+    /// Keep the image at its original size, centered and cropped to the canvas.
+    Pad,
+}
+
+/// Options accepted by the assemble endpoint, as query parameters or form fields.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AssembleOptions {
+    /// Canvas width; defaults to the first image's width, or follows its aspect ratio if only
+    /// `height` is given.
+    pub width: Option<u32>,
+    /// Canvas height; defaults like `width`.
+    pub height: Option<u32>,
+    /// How images are fitted onto the canvas.
+    #[serde(default)]
+    pub fit: FitMode,
+    /// Delay of every frame in milliseconds.
+    pub delay: Option<u32>,
+    /// Comma-separated per-frame delays in milliseconds, one per image.
+    pub delays: Option<String>,
+    /// Number of times the animation repeats after playing once; `0` loops forever.
+    pub loop_count: Option<u16>,
+}
+
+impl AssembleOptions {
+    /// Checks that every option is within range.
+    pub fn validate(&self) -> Result<()> {
+        for (name, size) in [("width", self.width), ("height", self.height)] {
+            if size.is_some_and(|size| size == 0 || size > MAX_CANVAS_SIZE) {
+                return Err(GpuWorkerError::InvalidInput(format!(
+                    "{} must be between 1 and {}",
+                    name, MAX_CANVAS_SIZE
+                )));
+            }
+        }
+
+        if self.delay.is_some() && self.delays.is_some() {
+            return Err(GpuWorkerError::InvalidInput(
+                "Give either one delay or per-frame delays, not both".to_string(),
+            ));
+        }
+
+        self.parse_delays()?;
+        Ok(())
+    }
+
+    /// Returns the delay of each of `frame_count` frames in hundredths of a second.
+    pub fn frame_delays(&self, frame_count: usize) -> Result<Vec<u16>> {
+        match self.parse_delays()? {
+            Some(delays) if delays.len() != frame_count => Err(GpuWorkerError::InvalidInput(
+                format!("Got {} delays for {} images", delays.len(), frame_count),
+            )),
+            Some(delays) => Ok(delays.into_iter().map(ms_to_delay).collect()),
+            None => Ok(vec![
+                ms_to_delay(self.delay.unwrap_or(DEFAULT_DELAY_MS));
+                frame_count
+            ]),
+        }
+    }
+
+    /// Encoder settings for the assembled GIF.
+    pub fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            repeat: match self.loop_count {
+                None | Some(0) => Repeat::Infinite,
+                Some(count) => Repeat::Finite(count),
+            },
+            ..EncodeOptions::default()
+        }
+    }
+
+    /// Canvas size for a first image of `first` dimensions.
+    pub fn canvas_size(&self, (first_width, first_height): (u32, u32)) -> (u32, u32) {
+        let scaled = |size: u32, numerator: u32, denominator: u32| {
+            ((size as u64 * numerator as u64 + denominator as u64 / 2) / denominator as u64)
+                .clamp(1, MAX_CANVAS_SIZE as u64) as u32
+        };
+
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, scaled(width, first_height, first_width)),
+            (None, Some(height)) => (scaled(height, first_width, first_height), height),
+            (None, None) => (
+                first_width.min(MAX_CANVAS_SIZE),
+                first_height.min(MAX_CANVAS_SIZE),
+            ),
+        }
+    }
+
+    fn parse_delays(&self) -> Result<Option<Vec<u32>>> {
+        let Some(delays) = &self.delays else {
+            return Ok(None);
+        };
+
+        delays
+            .split(',')
+            .map(|delay| {
+                delay.trim().parse::<u32>().map_err(|_| {
+                    GpuWorkerError::InvalidInput(format!(
+                        "delays must be comma-separated milliseconds, got {:?}",
+                        delay
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+}
+
+/// Where an image ends up on the canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    /// Part of the source image that is shown, as `[x, y, width, height]`.
+    pub crop: [u32; 4],
+    /// Rectangle on the canvas the cropped part is scaled into, as `[x, y, width, height]`.
+    pub target: [u32; 4],
+}
+
+impl Placement {
+    /// Computes the placement of a `source`-sized image on a `canvas`-sized frame.
+    pub fn new(source: (u32, u32), canvas: (u32, u32), fit: FitMode) -> Self {
+        let (source_width, source_height) = source;
+        let (canvas_width, canvas_height) = canvas;
+
+        match fit {
+            FitMode::Fit => {
+                let scale = f64::min(
+                    canvas_width as f64 / source_width as f64,
+                    canvas_height as f64 / source_height as f64,
+                );
+                let width = ((source_width as f64 * scale).round() as u32).clamp(1, canvas_width);
+                let height =
+                    ((source_height as f64 * scale).round() as u32).clamp(1, canvas_height);
+                Self {
+                    crop: [0, 0, source_width, source_height],
+                    target: centered(canvas, (width, height)),
+                }
+            }
+            FitMode::Fill => {
+                // Crop the source to the canvas aspect ratio, then scale it to the whole canvas
+                let (crop_width, crop_height) = if source_width as u64 * canvas_height as u64
+                    > source_height as u64 * canvas_width as u64
+                {
+                    let width = (source_height as f64 * canvas_width as f64 / canvas_height as f64)
+                        .round() as u32;
+                    (width.clamp(1, source_width), source_height)
+                } else {
+                    let height = (source_width as f64 * canvas_height as f64 / canvas_width as f64)
+                        .round() as u32;
+                    (source_width, height.clamp(1, source_height))
+                };
+                Self {
+                    crop: centered(source, (crop_width, crop_height)),
+                    target: [0, 0, canvas_width, canvas_height],
+                }
+            }
+            FitMode::Pad => {
+                let size = (
+                    source_width.min(canvas_width),
+                    source_height.min(canvas_height),
+                );
+                Self {
+                    crop: centered(source, size),
+                    target: centered(canvas, size),
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if the cropped part has to be scaled to fit its target.
+    pub fn needs_resize(&self) -> bool {
+        self.crop[2..] != self.target[2..]
+    }
+}
+
+/// Centers a rectangle of `size` inside one of `outer` size
+fn centered((outer_width, outer_height): (u32, u32), (width, height): (u32, u32)) -> [u32; 4] {
+    [
+        (outer_width - width) / 2,
+        (outer_height - height) / 2,
+        width,
+        height,
+    ]
+}
+
+/// Frames of an animation being assembled from still images, see [`assemble_frames`].
+pub struct AssembledFrames<F> {
+    images: std::vec::IntoIter<Bytes>,
+    delays: std::vec::IntoIter<u16>,
+    fit: FitMode,
+    width: u32,
+    height: u32,
+    limits: DecodeLimits,
+    budget: FrameBudget,
+    resize: F,
+}
+
+impl<F> std::fmt::Debug for AssembledFrames<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AssembledFrames")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("fit", &self.fit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F> AssembledFrames<F> {
+    /// Size of the canvas every frame is normalized to.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+impl<F> AssembledFrames<F>
+where
+    F: Fn(&[u8], u32, u32, u32, u32) -> Result<Vec<u8>>,
+{
+    fn frame(&mut self, data: &[u8], delay: u16) -> Result<Frame<'static>> {
+        self.budget.admit(delay)?;
+        let image = decode_image(data, &self.limits)?;
+        let placement = Placement::new(image.dimensions(), self.dimensions(), self.fit);
+
+        let [crop_x, crop_y, crop_width, crop_height] = placement.crop;
+        let [x, y, width, height] = placement.target;
+        let cropped = if placement.crop == [0, 0, image.width(), image.height()] {
+            image.into_raw()
+        } else {
+            imageops::crop_imm(&image, crop_x, crop_y, crop_width, crop_height)
+                .to_image()
+                .into_raw()
+        };
+        let scaled = if placement.needs_resize() {
+            (self.resize)(&cropped, crop_width, crop_height, width, height)?
+        } else {
+            cropped
+        };
+        let scaled = RgbaImage::from_raw(width, height, scaled).ok_or_else(|| {
+            GpuWorkerError::Internal("Resized image does not match the requested size".to_string())
+        })?;
+
+        let mut canvas = RgbaImage::new(self.width, self.height);
+        imageops::replace(&mut canvas, &scaled, x as i64, y as i64);
+
+        Ok(Frame {
+            width: self.width as u16,
+            height: self.height as u16,
+            delay,
+            buffer: canvas.into_raw().into(),
+            ..Frame::default()
+        })
+    }
+}
+
+impl<F> Iterator for AssembledFrames<F>
+where
+    F: Fn(&[u8], u32, u32, u32, u32) -> Result<Vec<u8>>,
+{
+    type Item = Result<Frame<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.images.next()?;
+        let delay = self.delays.next()?;
+        Some(self.frame(&data, delay))
+    }
+}
+
+/// Prepares encoded still images for assembly into an animation
+///
+/// Images may be in any format the `image` crate detects (PNG, JPEG, the first frame of a GIF,
+/// ...). The canvas size is taken from the options or the first image, and each image is decoded
+/// and normalized as its frame is requested. `resize` scales RGBA data from the first size to
+/// the second. The image count, canvas and every image are checked against `limits`.
+pub fn assemble_frames<F>(
+    images: Vec<Bytes>,
+    options: &AssembleOptions,
+    limits: &DecodeLimits,
+    resize: F,
+) -> Result<AssembledFrames<F>>
+where
+    F: Fn(&[u8], u32, u32, u32, u32) -> Result<Vec<u8>>,
+{
+    let first = images
+        .first()
+        .ok_or_else(|| GpuWorkerError::InvalidInput("No images to assemble".to_string()))?;
+    let first_dimensions = image::io::Reader::new(std::io::Cursor::new(first))
+        .with_guessed_format()?
+        .into_dimensions()?;
+
+    limits.check_frames(images.len())?;
+    let delays = options.frame_delays(images.len())?;
+    let (width, height) = options.canvas_size(first_dimensions);
+    let budget = limits.frame_budget(width, height)?;
+    log::info!(
+        "Assembling {} images onto a {}x{} canvas",
+        images.len(),
+        width,
+        height
+    );
+
+    Ok(AssembledFrames {
+        images: images.into_iter(),
+        delays: delays.into_iter(),
+        fit: options.fit,
+        width,
+        height,
+        limits: *limits,
+        budget,
+        resize,
+    })
+}
+
+/// Decodes an image after checking its header dimensions
+fn decode_image(data: &[u8], limits: &DecodeLimits) -> Result<RgbaImage> {
+    let reader = || image::io::Reader::new(std::io::Cursor::new(data)).with_guessed_format();
+    let (width, height) = reader()?.into_dimensions()?;
+    if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+        return Err(GpuWorkerError::InvalidInput(format!(
+            "Image is {}x{}, at most {}x{} is supported",
+            width, height, MAX_IMAGE_SIZE, MAX_IMAGE_SIZE
+        )));
+    }
+    limits.check_canvas(width, height)?;
+    Ok(reader()?.decode()?.to_rgba8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgba};
+
+    fn png(width: u32, height: u32, color: [u8; 4]) -> Bytes {
+        let image = RgbaImage::from_pixel(width, height, Rgba(color));
+        let mut output = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut output, ImageOutputFormat::Png).unwrap();
+        Bytes::from(output.into_inner())
+    }
+
+    fn cpu_resize(
+        rgba: &[u8],
+        width: u32,
+        height: u32,
+        target: u32,
+        target_h: u32,
+    ) -> Result<Vec<u8>> {
+        let image = RgbaImage::from_raw(width, height, rgba.to_vec()).unwrap();
+        Ok(imageops::resize(&image, target, target_h, imageops::FilterType::Triangle).into_raw())
+    }
+
+    #[test]
+    fn test_placement_fit_letterboxes() {
+        let placement = Placement::new((200, 100), (100, 100), FitMode::Fit);
+        assert_eq!(placement.crop, [0, 0, 200, 100]);
+        assert_eq!(placement.target, [0, 25, 100, 50]);
+        assert!(placement.needs_resize());
+    }
+
+    #[test]
+    fn test_placement_fill_crops_to_aspect_ratio() {
+        let placement = Placement::new((200, 100), (100, 100), FitMode::Fill);
+        assert_eq!(placement.crop, [50, 0, 100, 100]);
+        assert_eq!(placement.target, [0, 0, 100, 100]);
+        assert!(!placement.needs_resize());
+
+        let placement = Placement::new((10, 40), (20, 10), FitMode::Fill);
+        assert_eq!(placement.crop, [0, 17, 10, 5]);
+        assert_eq!(placement.target, [0, 0, 20, 10]);
+    }
+
+    #[test]
+    fn test_placement_pad_centers_without_scaling() {
+        let placement = Placement::new((4, 20), (10, 10), FitMode::Pad);
+        assert_eq!(placement.crop, [0, 5, 4, 10]);
+        assert_eq!(placement.target, [3, 0, 4, 10]);
+        assert!(!placement.needs_resize());
+    }
+
+    #[test]
+    fn test_frame_delays() {
+        let options = AssembleOptions {
+            delays: Some("100, 250,30".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(options.frame_delays(3).unwrap(), vec![10, 25, 3]);
+        assert!(options.frame_delays(2).is_err());
+
+        let options = AssembleOptions {
+            delay: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(options.frame_delays(2).unwrap(), vec![4, 4]);
+        assert_eq!(
+            AssembleOptions::default().frame_delays(1).unwrap(),
+            vec![10]
+        );
+    }
+
+    #[test]
+    fn test_options_validation() {
+        for options in [
+            AssembleOptions {
+                width: Some(0),
+                ..Default::default()
+            },
+            AssembleOptions {
+                height: Some(MAX_CANVAS_SIZE + 1),
+                ..Default::default()
+            },
+            AssembleOptions {
+                delay: Some(100),
+                delays: Some("100".to_string()),
+                ..Default::default()
+            },
+            AssembleOptions {
+                delays: Some("100,fast".to_string()),
+                ..Default::default()
+            },
+        ] {
+            assert!(
+                options.validate().is_err(),
+                "{:?} should be rejected",
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn test_canvas_size_follows_first_image() {
+        let options = AssembleOptions::default();
+        assert_eq!(options.canvas_size((30, 20)), (30, 20));
+
+        let options = AssembleOptions {
+            width: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(options.canvas_size((30, 20)), (60, 40));
+    }
+
+    #[test]
+    fn test_loop_count() {
+        assert!(matches!(
+            AssembleOptions::default().encode_options().repeat,
+            Repeat::Infinite
+        ));
+        let options = AssembleOptions {
+            loop_count: Some(3),
+            ..Default::default()
+        };
+        assert!(matches!(options.encode_options().repeat, Repeat::Finite(3)));
+    }
+
+    #[test]
+    fn test_assemble_frames_normalizes_images() {
+        let images = vec![
+            png(8, 4, [255, 0, 0, 255]),
+            png(2, 2, [0, 255, 0, 255]),
+            png(4, 8, [0, 0, 255, 255]),
+        ];
+        let options = AssembleOptions {
+            fit: FitMode::Fit,
+            delays: Some("10,20,30".to_string()),
+            ..Default::default()
+        };
+
+        let frames =
+            assemble_frames(images, &options, &DecodeLimits::default(), cpu_resize).unwrap();
+        assert_eq!(frames.dimensions(), (8, 4));
+        let frames: Vec<_> = frames.map(|frame| frame.unwrap()).collect();
+        assert_eq!(frames.len(), 3);
+
+        for frame in &frames {
+            assert_eq!((frame.width, frame.height), (8, 4));
+            assert_eq!(frame.buffer.len(), 8 * 4 * 4);
+        }
+        assert_eq!(
+            frames.iter().map(|frame| frame.delay).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        // The square image is scaled to 4x4 and centered, leaving transparent borders
+        let pixel = |frame: &Frame, x: usize, y: usize| {
+            let offset = (y * 8 + x) * 4;
+            frame.buffer[offset..offset + 4].to_vec()
+        };
+        assert_eq!(pixel(&frames[1], 0, 0), vec![0, 0, 0, 0]);
+        assert_eq!(pixel(&frames[1], 4, 2), vec![0, 255, 0, 255]);
+        // The tall image becomes a 2x4 strip in the middle
+        assert_eq!(pixel(&frames[2], 3, 0), vec![0, 0, 255, 255]);
+        assert_eq!(pixel(&frames[2], 2, 0), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_assemble_frames_rejects_undecodable_images() {
+        let images = vec![
+            png(2, 2, [0, 0, 0, 255]),
+            Bytes::from_static(b"not an image"),
+        ];
+        let mut frames = assemble_frames(
+            images,
+            &AssembleOptions::default(),
+            &DecodeLimits::default(),
+            cpu_resize,
+        )
+        .unwrap();
+        assert!(frames.next().unwrap().is_ok());
+        assert!(matches!(
+            frames.next().unwrap(),
+            Err(GpuWorkerError::Image(_))
+        ));
+    }
+}
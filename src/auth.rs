@@ -0,0 +1,475 @@
+//! API key authentication.
+//!
+//! Keys are configured as JSON entries, inline or in a local key file, that store only the
+//! SHA-256 hash of each key:
+//!
+//! ```json
+//! [{"name": "tenant-a", "sha256": "9f86d081…", "scopes": ["transform", "jobs"], "operations": ["mirror", "blur"], "max_priority": "default"}]
+//! ```
+//!
+//! Routes wrapped in [`RequireScope`] then require a key, sent as `Authorization: Bearer <key>`
+//! or `X-Api-Key: <key>`, that has the route's [`Scope`]; `admin` keys have every scope. A key
+//! with `operations` may only run those operations, and one with `max_priority` never gets a more
+//! urgent priority than that. Missing or unknown keys get
+//! `401 Unauthorized`, keys without the scope or operation `403 Forbidden`. The key that
+//! authenticated a request is kept in its extensions, see [`authenticated`]. Without any keys
+//! configured, authentication is disabled.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    future::{ready, Ready},
+    path::PathBuf,
+    rc::Rc,
+    sync::Arc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web, HttpMessage, HttpRequest, ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::GpuWorkerError,
+    ops::{Operation, OPERATION_NAMES},
+    scheduler::Priority,
+};
+
+/// Request header carrying an API key, as an alternative to `Authorization: Bearer`.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Group of routes a key may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// The synchronous image endpoints.
+    Transform,
+    /// Submitting and following jobs.
+    Jobs,
+    /// Operational endpoints; implies every other scope.
+    Admin,
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Transform => "transform",
+            Self::Jobs => "jobs",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+/// Where the API keys come from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthConfig {
+    /// JSON array of key entries.
+    pub keys: Option<String>,
+    /// File holding a JSON array of key entries.
+    pub key_file: Option<PathBuf>,
+}
+
+/// One configured key
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyEntry {
+    /// Unique name, also used as the client id for GPU scheduling.
+    pub name: String,
+    /// Hex SHA-256 hash of the key.
+    pub sha256: String,
+    pub scopes: BTreeSet<Scope>,
+    /// Operation names the key may run; every operation when absent.
+    #[serde(default)]
+    pub operations: Option<BTreeSet<String>>,
+    /// Most urgent priority the key's requests and jobs may have; any when absent.
+    #[serde(default)]
+    pub max_priority: Option<Priority>,
+}
+
+/// A key that authenticated a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub name: String,
+    pub scopes: BTreeSet<Scope>,
+    pub operations: Option<BTreeSet<String>>,
+    pub max_priority: Option<Priority>,
+}
+
+impl ApiKey {
+    /// Whether the key may use routes of `scope`.
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    /// Fails with [`GpuWorkerError::Forbidden`] unless the key may run every operation.
+    pub fn check_operations(&self, operations: &[Operation]) -> crate::Result<()> {
+        let Some(allowed) = &self.operations else {
+            return Ok(());
+        };
+        let denied: BTreeSet<_> = operations
+            .iter()
+            .map(Operation::name)
+            .filter(|name| !allowed.contains(*name))
+            .collect();
+        if !denied.is_empty() {
+            return Err(GpuWorkerError::Forbidden(format!(
+                "API key {} may not run {}",
+                self.name,
+                denied.into_iter().collect::<Vec<_>>().join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    /// The priority the key gets when asking for `requested`, no more urgent than its maximum.
+    pub fn priority(&self, requested: Priority) -> Priority {
+        match self.max_priority {
+            // Less urgent priorities compare greater
+            Some(max_priority) => requested.max(max_priority),
+            None => requested,
+        }
+    }
+}
+
+/// The configured keys, by hash
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    by_hash: HashMap<[u8; 32], Arc<ApiKey>>,
+}
+
+impl ApiKeys {
+    /// Checks the entries and indexes them by hash.
+    pub fn new(entries: Vec<ApiKeyEntry>) -> crate::Result<Self> {
+        let invalid = |name: &str, message: String| {
+            GpuWorkerError::InvalidInput(format!("API key {:?}: {}", name, message))
+        };
+
+        let mut keys = Self::default();
+        let mut names = BTreeSet::new();
+        for entry in entries {
+            if entry.name.is_empty() || !names.insert(entry.name.clone()) {
+                return Err(invalid(
+                    &entry.name,
+                    "names must be unique and non-empty".into(),
+                ));
+            }
+            let hash = parse_hash(&entry.sha256)
+                .ok_or_else(|| invalid(&entry.name, "sha256 must be 64 hex digits".into()))?;
+            if entry.scopes.is_empty() {
+                return Err(invalid(&entry.name, "needs at least one scope".into()));
+            }
+            if let Some(unknown) = entry
+                .operations
+                .iter()
+                .flatten()
+                .find(|name| !OPERATION_NAMES.contains(&name.as_str()))
+            {
+                return Err(invalid(
+                    &entry.name,
+                    format!("unknown operation {:?}", unknown),
+                ));
+            }
+
+            let key = ApiKey {
+                name: entry.name,
+                scopes: entry.scopes,
+                operations: entry.operations,
+                max_priority: entry.max_priority,
+            };
+            if keys.by_hash.insert(hash, Arc::new(key)).is_some() {
+                return Err(invalid(
+                    &entry.sha256,
+                    "the same key is listed twice".into(),
+                ));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Reads the keys given inline and in the key file.
+    pub fn load(config: &AuthConfig) -> crate::Result<Self> {
+        let mut entries = Vec::new();
+        if let Some(keys) = &config.keys {
+            entries.extend(parse_entries(keys, "API_KEYS")?);
+        }
+        if let Some(path) = &config.key_file {
+            let keys = fs::read_to_string(path)?;
+            entries.extend(parse_entries(&keys, &path.display().to_string())?);
+        }
+        Self::new(entries)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    /// Whether no keys are configured, leaving every route open.
+    pub fn is_empty(&self) -> bool {
+        self.by_hash.is_empty()
+    }
+
+    /// The key configured for the secret `key`.
+    pub fn authenticate(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.by_hash
+            .get(&<[u8; 32]>::from(Sha256::digest(key.as_bytes())))
+            .cloned()
+    }
+}
+
+/// Hex SHA-256 hash of a key, as stored in key entries.
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_entries(json: &str, source: &str) -> crate::Result<Vec<ApiKeyEntry>> {
+    serde_json::from_str(json)
+        .map_err(|e| GpuWorkerError::InvalidInput(format!("Invalid API keys in {}: {}", source, e)))
+}
+
+fn parse_hash(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// The key that authenticated the request, if authentication is enabled.
+pub fn authenticated(req: &HttpRequest) -> Option<Arc<ApiKey>> {
+    req.extensions().get::<Arc<ApiKey>>().cloned()
+}
+
+/// Middleware that lets requests through only with a key that has the scope, see the
+/// [module documentation](self)
+///
+/// Keys are looked up in the [`ApiKeys`] registered as app data.
+#[derive(Debug, Clone, Copy)]
+pub struct RequireScope(pub Scope);
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequireScopeService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeService {
+            service: Rc::new(service),
+            scope: self.0,
+        }))
+    }
+}
+
+/// Service created by [`RequireScope`]
+pub struct RequireScopeService<S> {
+    service: Rc<S>,
+    scope: Scope,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        match authorize(&req, self.scope) {
+            Ok(key) => {
+                if let Some(key) = key {
+                    req.extensions_mut().insert(key);
+                }
+                let service = self.service.clone();
+                Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) })
+            }
+            Err(error) => {
+                let response = req.into_response(error.error_response());
+                Box::pin(ready(Ok(response.map_into_right_body())))
+            }
+        }
+    }
+}
+
+/// Finds the key of a request and checks its scope; `None` when authentication is disabled.
+fn authorize(req: &ServiceRequest, scope: Scope) -> crate::Result<Option<Arc<ApiKey>>> {
+    let keys = match req.app_data::<web::Data<ApiKeys>>() {
+        Some(keys) if !keys.is_empty() => keys,
+        _ => return Ok(None),
+    };
+
+    let headers = req.headers();
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            let (scheme, token) = value.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+        });
+    let presented = bearer.or_else(|| {
+        headers
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    });
+    let Some(presented) = presented.filter(|key| !key.is_empty()) else {
+        return Err(GpuWorkerError::Unauthorized(format!(
+            "Send an API key as Authorization: Bearer <key> or {}",
+            API_KEY_HEADER
+        )));
+    };
+
+    let key = keys
+        .authenticate(presented)
+        .ok_or_else(|| GpuWorkerError::Unauthorized("Unknown API key".to_string()))?;
+    if !key.allows(scope) {
+        return Err(GpuWorkerError::Forbidden(format!(
+            "API key {} lacks the {} scope",
+            key.name,
+            scope.name()
+        )));
+    }
+    Ok(Some(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, key: &str, scopes: &[Scope]) -> ApiKeyEntry {
+        ApiKeyEntry {
+            name: name.to_string(),
+            sha256: hash_key(key),
+            scopes: scopes.iter().copied().collect(),
+            operations: None,
+            max_priority: None,
+        }
+    }
+
+    #[test]
+    fn test_keys_are_found_by_hash() {
+        // SHA-256 of "test"
+        assert_eq!(
+            hash_key("test"),
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+
+        let keys = ApiKeys::new(vec![
+            entry("a", "secret-a", &[Scope::Transform]),
+            entry("root", "secret-root", &[Scope::Admin]),
+        ])
+        .unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let a = keys.authenticate("secret-a").unwrap();
+        assert_eq!(a.name, "a");
+        assert!(a.allows(Scope::Transform));
+        assert!(!a.allows(Scope::Jobs));
+        assert!(keys.authenticate("secret-b").is_none());
+
+        let root = keys.authenticate("secret-root").unwrap();
+        assert!(root.allows(Scope::Jobs) && root.allows(Scope::Transform));
+    }
+
+    #[test]
+    fn test_invalid_entries_are_rejected() {
+        let duplicate_name = vec![
+            entry("a", "one", &[Scope::Jobs]),
+            entry("a", "two", &[Scope::Jobs]),
+        ];
+        assert!(ApiKeys::new(duplicate_name).is_err());
+
+        let duplicate_key = vec![
+            entry("a", "one", &[Scope::Jobs]),
+            entry("b", "one", &[Scope::Jobs]),
+        ];
+        assert!(ApiKeys::new(duplicate_key).is_err());
+
+        assert!(ApiKeys::new(vec![entry("a", "one", &[])]).is_err());
+
+        let mut bad_hash = entry("a", "one", &[Scope::Jobs]);
+        bad_hash.sha256 = "one".to_string();
+        assert!(ApiKeys::new(vec![bad_hash]).is_err());
+
+        let mut bad_operation = entry("a", "one", &[Scope::Jobs]);
+        bad_operation.operations = Some(["warp".to_string()].into_iter().collect());
+        assert!(ApiKeys::new(vec![bad_operation]).is_err());
+
+        let unknown_field = r#"[{"name":"a","sha256":"","scopes":["jobs"],"key":"one"}]"#;
+        assert!(parse_entries(unknown_field, "test").is_err());
+    }
+
+    #[test]
+    fn test_operations_are_restricted() {
+        let json = format!(
+            r#"[{{"name":"a","sha256":"{}","scopes":["transform"],"operations":["mirror"]}}]"#,
+            hash_key("one")
+        );
+        let keys = ApiKeys::load(&AuthConfig {
+            keys: Some(json),
+            key_file: None,
+        })
+        .unwrap();
+        let key = keys.authenticate("one").unwrap();
+
+        let operations = [
+            Operation::Mirror {
+                axis: Default::default(),
+            },
+            Operation::Blur { radius: 3 },
+        ];
+        assert!(key.check_operations(&operations[..1]).is_ok());
+        assert!(key.check_operations(&[]).is_ok());
+        let error = key.check_operations(&operations).unwrap_err();
+        assert!(matches!(error, GpuWorkerError::Forbidden(_)));
+        assert_eq!(error.to_string(), "Forbidden: API key a may not run blur");
+    }
+
+    #[test]
+    fn test_priority_is_capped() {
+        let json = format!(
+            r#"[{{"name":"a","sha256":"{}","scopes":["jobs"],"max_priority":"default"}},
+                {{"name":"b","sha256":"{}","scopes":["jobs"]}}]"#,
+            hash_key("one"),
+            hash_key("two")
+        );
+        let keys = ApiKeys::load(&AuthConfig {
+            keys: Some(json),
+            key_file: None,
+        })
+        .unwrap();
+
+        let capped = keys.authenticate("one").unwrap();
+        assert_eq!(capped.priority(Priority::Interactive), Priority::Default);
+        assert_eq!(capped.priority(Priority::Default), Priority::Default);
+        assert_eq!(capped.priority(Priority::Bulk), Priority::Bulk);
+
+        let uncapped = keys.authenticate("two").unwrap();
+        assert_eq!(
+            uncapped.priority(Priority::Interactive),
+            Priority::Interactive
+        );
+
+        let unknown = r#"[{"name":"a","sha256":"","scopes":["jobs"],"max_priority":"urgent"}]"#;
+        assert!(parse_entries(unknown, "test").is_err());
+    }
+}
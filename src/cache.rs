@@ -0,0 +1,514 @@
+//! Cache of processed results.
+//!
+//! Results are keyed by a [`CacheKey`], a SHA-256 hash of the input bytes, a description of the
+//! processing (normalized operation list and output options) and the service version, so a
+//! deployment never serves results of an older build. The key doubles as the `ETag` of the
+//! response. Results live in an in-memory LRU tier and, with a directory configured, in a larger
+//! disk tier that survives restarts; both are bounded in bytes. A [`Recorder`] keeps a copy of a
+//! result while it streams to the client, so results are cached without holding up the response.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, ErrorKind, Write},
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+use bytes::Bytes;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::error::Result;
+
+/// Default size of the memory tier.
+pub const DEFAULT_CACHE_MEMORY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Default size of the largest result that is cached.
+pub const DEFAULT_CACHE_MAX_ENTRY_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Default size of the disk tier.
+pub const DEFAULT_CACHE_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Sizes and location of the cache tiers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Bytes of results kept in memory; 0 disables the memory tier.
+    pub memory_bytes: u64,
+    /// Results larger than this are not cached.
+    pub max_entry_bytes: u64,
+    /// Directory of the disk tier; there is no disk tier without one.
+    pub disk_dir: Option<PathBuf>,
+    /// Bytes of results kept on disk.
+    pub disk_bytes: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            memory_bytes: DEFAULT_CACHE_MEMORY_BYTES,
+            max_entry_bytes: DEFAULT_CACHE_MAX_ENTRY_BYTES,
+            disk_dir: None,
+            disk_bytes: DEFAULT_CACHE_DISK_BYTES,
+        }
+    }
+}
+
+/// Identity of a result: the hash of everything it depends on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey([u8; 32]);
+
+impl CacheKey {
+    /// Key of the result of processing `input` as described by `processing`
+    ///
+    /// `processing` must capture every option that affects the output, with defaults filled in
+    /// so that equivalent requests get the same key.
+    pub fn new(input: &[u8], processing: &impl Serialize) -> Self {
+        let description = serde_json::to_vec(processing).unwrap_or_default();
+        let mut hasher = Sha256::new();
+        for part in [
+            concat!("gpu-worker/", env!("CARGO_PKG_VERSION")).as_bytes(),
+            &description,
+            input,
+        ] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        Self(hasher.finalize().into())
+    }
+
+    /// Lowercase hex digest.
+    pub fn hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut key = [0; 32];
+        for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(Self(key))
+    }
+}
+
+/// Tier a cached result was found in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheTier {
+    Memory,
+    Disk,
+}
+
+/// Two-tier cache of results
+#[derive(Debug)]
+pub struct ResultCache {
+    memory: Mutex<Lru<Bytes>>,
+    disk: Option<DiskTier>,
+    /// 0 when neither tier holds any results.
+    max_entry_bytes: u64,
+}
+
+impl ResultCache {
+    /// Creates the cache, taking over the results left in the disk tier's directory.
+    pub fn new(config: CacheConfig) -> Result<Self> {
+        let disk = config
+            .disk_dir
+            .map(|dir| DiskTier::open(dir, config.disk_bytes))
+            .transpose()?;
+        let enabled = config.memory_bytes > 0 || disk.is_some();
+        Ok(Self {
+            memory: Mutex::new(Lru::new(config.memory_bytes)),
+            disk,
+            max_entry_bytes: if enabled { config.max_entry_bytes } else { 0 },
+        })
+    }
+
+    /// Size of the largest result that is cached; 0 if nothing is.
+    pub fn max_entry_bytes(&self) -> u64 {
+        self.max_entry_bytes
+    }
+
+    /// Looks a result up, first in memory, then on disk
+    ///
+    /// Blocks on disk reads. Results found on disk are promoted to the memory tier.
+    pub fn get(&self, key: &CacheKey) -> Option<(Bytes, CacheTier)> {
+        if let Some(data) = lock(&self.memory).get(key) {
+            return Some((data.clone(), CacheTier::Memory));
+        }
+        let data = Bytes::from(self.disk.as_ref()?.get(key)?);
+        self.remember(*key, data.clone());
+        Some((data, CacheTier::Disk))
+    }
+
+    /// Stores a result in both tiers, unless it is larger than the largest cached entry
+    ///
+    /// Blocks on disk writes. Failing to write to disk only costs a future cache miss, so it is
+    /// logged rather than returned.
+    pub fn insert(&self, key: CacheKey, data: Bytes) {
+        if data.len() as u64 > self.max_entry_bytes {
+            return;
+        }
+        if let Some(disk) = &self.disk {
+            if let Err(error) = disk.insert(&key, &data) {
+                log::warn!("Failed to cache {} on disk: {}", key.hex(), error);
+            }
+        }
+        self.remember(key, data);
+    }
+
+    fn remember(&self, key: CacheKey, data: Bytes) {
+        let size = data.len() as u64;
+        lock(&self.memory).insert(key, data, size);
+    }
+}
+
+/// Writer that passes output on while keeping a copy of it, up to a size
+///
+/// The copy is dropped as soon as the output grows past `max_bytes`, so outputs too large to
+/// cache are never held in memory whole.
+#[derive(Debug)]
+pub struct Recorder<W> {
+    inner: W,
+    copy: Option<Vec<u8>>,
+    max_bytes: u64,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(inner: W, max_bytes: u64) -> Self {
+        Self {
+            inner,
+            copy: Some(Vec::new()),
+            max_bytes,
+        }
+    }
+
+    /// Everything written, unless it grew past the size.
+    pub fn into_copy(self) -> Option<Bytes> {
+        self.copy.map(Bytes::from)
+    }
+}
+
+impl<W: Write> Write for Recorder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(data)?;
+        if let Some(copy) = &mut self.copy {
+            if (copy.len() + written) as u64 > self.max_bytes {
+                self.copy = None;
+            } else {
+                copy.extend_from_slice(&data[..written]);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Results stored as files named by their key, with an index in least recently used order
+#[derive(Debug)]
+struct DiskTier {
+    dir: PathBuf,
+    index: Mutex<Lru<()>>,
+}
+
+impl DiskTier {
+    fn open(dir: PathBuf, max_bytes: u64) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        // Oldest first, so that the most recently written results are kept
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            // Left behind by writes that were interrupted before the rename
+            if name
+                .strip_suffix(".tmp")
+                .and_then(CacheKey::from_hex)
+                .is_some()
+            {
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            let Some(key) = CacheKey::from_hex(name) else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            files.push((metadata.modified().ok(), key, metadata.len()));
+        }
+        files.sort_by_key(|(modified, _, _)| *modified);
+
+        let tier = Self {
+            dir,
+            index: Mutex::new(Lru::new(max_bytes)),
+        };
+        for (_, key, size) in files {
+            let evicted = lock(&tier.index).insert(key, (), size);
+            tier.delete(evicted);
+        }
+        log::info!(
+            "Disk cache at {} holds {} bytes",
+            tier.dir.display(),
+            lock(&tier.index).bytes
+        );
+        Ok(tier)
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        lock(&self.index).get(key)?;
+        match fs::read(self.path(key)) {
+            Ok(data) => Some(data),
+            Err(error) => {
+                if error.kind() != ErrorKind::NotFound {
+                    log::warn!("Failed to read cached {}: {}", key.hex(), error);
+                }
+                lock(&self.index).remove(key);
+                None
+            }
+        }
+    }
+
+    fn insert(&self, key: &CacheKey, data: &[u8]) -> Result<()> {
+        let path = self.path(key);
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, &path)?;
+        let evicted = lock(&self.index).insert(*key, (), data.len() as u64);
+        self.delete(evicted);
+        Ok(())
+    }
+
+    fn delete(&self, evicted: Vec<(CacheKey, ())>) {
+        for (key, ()) in evicted {
+            if let Err(error) = fs::remove_file(self.path(&key)) {
+                log::warn!("Failed to evict cached {}: {}", key.hex(), error);
+            }
+        }
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(key.hex())
+    }
+}
+
+/// Least recently used map bounded by the total size of its entries
+#[derive(Debug)]
+struct Lru<V> {
+    entries: HashMap<CacheKey, Entry<V>>,
+    /// Keys by the tick they were last used at.
+    order: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    bytes: u64,
+    max_bytes: u64,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    size: u64,
+    used: u64,
+}
+
+impl<V> Lru<V> {
+    fn new(max_bytes: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Looks an entry up and marks it as the most recently used.
+    fn get(&mut self, key: &CacheKey) -> Option<&V> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.used);
+        self.tick += 1;
+        entry.used = self.tick;
+        self.order.insert(self.tick, *key);
+        Some(&entry.value)
+    }
+
+    /// Inserts or replaces an entry, returning the entries evicted to make room
+    ///
+    /// Entries larger than the whole map are not inserted.
+    fn insert(&mut self, key: CacheKey, value: V, size: u64) -> Vec<(CacheKey, V)> {
+        self.remove(&key);
+        if size > self.max_bytes {
+            return Vec::new();
+        }
+
+        let mut evicted = Vec::new();
+        while self.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.size;
+                evicted.push((oldest, entry.value));
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key);
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                used: self.tick,
+            },
+        );
+        self.bytes += size;
+        evicted
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.used);
+        self.bytes -= entry.size;
+        Some(entry.value)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(n: u8) -> CacheKey {
+        CacheKey([n; 32])
+    }
+
+    #[test]
+    fn test_key_depends_on_input_and_processing() {
+        let ops = json!([{"op": "blur", "radius": 3}]);
+        let base = CacheKey::new(b"GIF89a", &ops);
+        assert_eq!(base, CacheKey::new(b"GIF89a", &ops));
+        assert_ne!(base, CacheKey::new(b"GIF89b", &ops));
+        assert_ne!(
+            base,
+            CacheKey::new(b"GIF89a", &json!([{"op": "blur", "radius": 4}]))
+        );
+        assert_eq!(base.hex().len(), 64);
+        assert_eq!(CacheKey::from_hex(&base.hex()), Some(base));
+        assert_eq!(CacheKey::from_hex("not a key"), None);
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let mut lru = Lru::new(10);
+        assert!(lru.insert(key(1), "a", 4).is_empty());
+        assert!(lru.insert(key(2), "b", 4).is_empty());
+        assert_eq!(lru.get(&key(1)), Some(&"a"));
+
+        let evicted = lru.insert(key(3), "c", 4);
+        assert_eq!(evicted, vec![(key(2), "b")]);
+        assert_eq!(lru.bytes, 8);
+
+        // Too large for the whole map
+        assert!(lru.insert(key(4), "d", 11).is_empty());
+        assert!(lru.get(&key(4)).is_none());
+
+        // Replacing an entry frees its old size first
+        assert!(lru.insert(key(1), "A", 6).is_empty());
+        assert_eq!(lru.bytes, 10);
+        assert_eq!(lru.get(&key(1)), Some(&"A"));
+    }
+
+    #[test]
+    fn test_disk_tier_survives_reopening_and_stays_bounded() {
+        let dir = std::env::temp_dir().join(format!("gpu-worker-cache-{}", uuid::Uuid::new_v4()));
+        let config = CacheConfig {
+            memory_bytes: 0,
+            disk_dir: Some(dir.clone()),
+            disk_bytes: 10,
+            ..CacheConfig::default()
+        };
+
+        let cache = ResultCache::new(config.clone()).unwrap();
+        cache.insert(key(1), Bytes::from_static(b"12345"));
+        cache.insert(key(2), Bytes::from_static(b"67890"));
+        assert_eq!(
+            cache.get(&key(1)),
+            Some((Bytes::from_static(b"12345"), CacheTier::Disk))
+        );
+        drop(cache);
+        let temporary = dir.join(format!("{}.tmp", key(4).hex()));
+        fs::write(&temporary, b"partial").unwrap();
+
+        let cache = ResultCache::new(config).unwrap();
+        assert!(!temporary.exists());
+        assert_eq!(
+            cache.get(&key(2)).map(|(data, _)| data),
+            Some(Bytes::from_static(b"67890"))
+        );
+        cache.insert(key(3), Bytes::from_static(b"abcde"));
+        assert!(cache.get(&key(3)).is_some());
+        let files = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_tier_is_checked_first() {
+        let cache = ResultCache::new(CacheConfig {
+            max_entry_bytes: 4,
+            ..CacheConfig::default()
+        })
+        .unwrap();
+        cache.insert(key(1), Bytes::from_static(b"GIF"));
+        assert_eq!(
+            cache.get(&key(1)),
+            Some((Bytes::from_static(b"GIF"), CacheTier::Memory))
+        );
+        cache.insert(key(2), Bytes::from_static(b"GIF89a"));
+        assert!(cache.get(&key(2)).is_none());
+    }
+
+    #[test]
+    fn test_recorder_keeps_small_outputs() {
+        let mut output = Vec::new();
+        let mut recorder = Recorder::new(&mut output, 8);
+        recorder.write_all(b"GIF").unwrap();
+        recorder.write_all(b"89a").unwrap();
+        assert_eq!(recorder.into_copy(), Some(Bytes::from_static(b"GIF89a")));
+
+        let mut recorder = Recorder::new(&mut output, 8);
+        recorder.write_all(b"GIF89a").unwrap();
+        recorder.write_all(b"GIF89a").unwrap();
+        assert_eq!(recorder.into_copy(), None);
+        // Everything is passed on either way
+        assert_eq!(output, b"GIF89aGIF89aGIF89a");
+    }
+
+    #[test]
+    fn test_cache_without_tiers_caches_nothing() {
+        let cache = ResultCache::new(CacheConfig {
+            memory_bytes: 0,
+            ..CacheConfig::default()
+        })
+        .unwrap();
+        assert_eq!(cache.max_entry_bytes(), 0);
+        assert_eq!(
+            ResultCache::new(CacheConfig::default())
+                .unwrap()
+                .max_entry_bytes(),
+            DEFAULT_CACHE_MAX_ENTRY_BYTES
+        );
+    }
+}
@@ -0,0 +1,661 @@
+//! Completion callbacks for asynchronous jobs.
+//!
+//! A job submitted with a `callback_url` has its outcome posted there as JSON once it succeeds or
+//! fails, so clients don't have to poll. With a secret configured, the timestamp of the attempt
+//! and the body are signed with HMAC-SHA256 and sent as [`TIMESTAMP_HEADER`] and
+//! [`SIGNATURE_HEADER`]. Failed deliveries are retried with exponential backoff; every attempt is
+//! recorded on the job.
+//!
+//! Callbacks are only posted to public addresses, checked after resolving the host, unless the
+//! host is one of the configured allowed hosts; with allowed hosts configured, no other host is
+//! accepted. Redirects are not followed.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    error::{FieldError, GpuWorkerError, Result},
+    jobs::{JobInfo, JobResult, JobStatus},
+};
+
+/// Header carrying the signature of the timestamp and body, as `sha256=<hex digest>`.
+pub const SIGNATURE_HEADER: &str = "X-Gpu-Worker-Signature-256";
+
+/// Header carrying the Unix time in seconds of the attempt, which is part of the signature.
+pub const TIMESTAMP_HEADER: &str = "X-Gpu-Worker-Timestamp";
+
+/// Default number of delivery attempts.
+pub const DEFAULT_CALLBACK_ATTEMPTS: u32 = 5;
+
+/// Default pause before the first retry; each further retry waits twice as long.
+pub const DEFAULT_CALLBACK_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Default time to wait for the callback endpoint to answer.
+pub const DEFAULT_CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest pause between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// Longest accepted callback URL.
+const MAX_URL_LEN: usize = 2048;
+
+/// Delivery settings shared by all callbacks
+#[derive(Clone, PartialEq, Eq)]
+pub struct CallbackConfig {
+    /// Key the bodies are signed with; bodies are not signed without one.
+    pub secret: Option<String>,
+    /// Attempts before a delivery is given up.
+    pub max_attempts: u32,
+    /// Pause before the first retry.
+    pub backoff: Duration,
+    /// Time to wait for each attempt.
+    pub timeout: Duration,
+    /// Hosts callbacks may be posted to, even at private addresses. When empty, any host with
+    /// only public addresses is accepted.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for CallbackConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            max_attempts: DEFAULT_CALLBACK_ATTEMPTS,
+            backoff: DEFAULT_CALLBACK_BACKOFF,
+            timeout: DEFAULT_CALLBACK_TIMEOUT,
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for CallbackConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CallbackConfig")
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("timeout", &self.timeout)
+            .field("allowed_hosts", &self.allowed_hosts)
+            .finish()
+    }
+}
+
+/// Callback options of a job submission.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CallbackOptions {
+    /// URL the outcome of the job is posted to.
+    pub callback_url: Option<String>,
+    /// Include the result, base64 encoded, in the payload of succeeded jobs.
+    #[serde(default)]
+    pub callback_result: bool,
+}
+
+impl CallbackOptions {
+    /// The requested callback, if any
+    ///
+    /// Fails with [`GpuWorkerError::InvalidFields`] unless the URL is an absolute `http` or
+    /// `https` URL.
+    pub fn callback(&self) -> Result<Option<Callback>> {
+        let Some(url) = &self.callback_url else {
+            return Ok(None);
+        };
+        let invalid = |message: &str| {
+            GpuWorkerError::InvalidFields(vec![FieldError::new("callback_url", message)])
+        };
+        if url.len() > MAX_URL_LEN {
+            return Err(invalid(&format!("must be at most {} bytes", MAX_URL_LEN)));
+        }
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|_| invalid("must be an absolute http or https URL"))?;
+        if !matches!(parsed.scheme(), "http" | "https") || parsed.host().is_none() {
+            return Err(invalid("must be an absolute http or https URL"));
+        }
+        Ok(Some(Callback {
+            url: parsed.to_string(),
+            include_result: self.callback_result,
+        }))
+    }
+}
+
+/// Where and how the outcome of a job is posted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Callback {
+    pub url: String,
+    pub include_result: bool,
+}
+
+/// Progress of a callback delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// One attempt at delivering a callback.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryAttempt {
+    /// Unix timestamp in seconds.
+    pub at: u64,
+    /// HTTP status the endpoint answered with.
+    pub status: Option<u16>,
+    /// Why the attempt failed.
+    pub error: Option<String>,
+}
+
+/// Delivery state of a job's callback, as reported to clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallbackState {
+    pub url: String,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+}
+
+impl CallbackState {
+    pub fn pending(callback: &Callback) -> Self {
+        Self {
+            url: callback.url.clone(),
+            status: DeliveryStatus::Pending,
+            attempts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    /// `job.succeeded` or `job.failed`.
+    event: &'static str,
+    job: &'a JobInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<ResultPayload<'a>>,
+}
+
+#[derive(Serialize)]
+struct ResultPayload<'a> {
+    content_type: &'a str,
+    filename: &'a str,
+    /// Base64 encoded.
+    data: String,
+}
+
+/// JSON body posted for a finished job, with its result if given.
+pub fn payload(job: &JobInfo, result: Option<&JobResult>) -> Vec<u8> {
+    let payload = Payload {
+        event: match job.status {
+            JobStatus::Succeeded => "job.succeeded",
+            _ => "job.failed",
+        },
+        job,
+        result: result.map(|result| ResultPayload {
+            content_type: &result.content_type,
+            filename: &result.filename,
+            data: base64::engine::general_purpose::STANDARD.encode(&result.data),
+        }),
+    };
+    serde_json::to_vec(&payload).unwrap_or_default()
+}
+
+/// Signature of `body` sent at `timestamp` under `secret`, as sent in [`SIGNATURE_HEADER`]
+///
+/// The signed message is the timestamp in decimal, a dot and the body, so receivers can reject
+/// replayed callbacks by their age.
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Posts callbacks, retrying failed deliveries
+///
+/// Each attempt resolves the host again and uses a client pinned to the checked address. Clients
+/// are created on the delivery threads: the blocking client must not be created inside an async
+/// runtime.
+pub struct Notifier {
+    config: CallbackConfig,
+}
+
+/// Why a callback is not posted
+enum Refused {
+    /// The host could not be resolved; worth retrying.
+    Unresolved(String),
+    /// The host is not allowed, or resolves to a non-public address.
+    Forbidden(String),
+}
+
+impl Notifier {
+    pub fn new(config: CallbackConfig) -> Self {
+        Self { config }
+    }
+
+    /// Posts `body` to `url` until it is accepted or the attempts run out
+    ///
+    /// Blocks while waiting between attempts. Each attempt is passed to `record`; delivery stops
+    /// early when it returns `false`, or when the destination is not allowed.
+    pub fn deliver(
+        &self,
+        url: &str,
+        body: &[u8],
+        mut record: impl FnMut(&DeliveryAttempt) -> bool,
+    ) -> DeliveryStatus {
+        let mut backoff = self.config.backoff;
+        for attempt in 1..=self.config.max_attempts.max(1) {
+            let at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs());
+            let (result, retry) = match self.destination(url) {
+                Ok(pinned) => (self.post(url, pinned, at, body), true),
+                Err(Refused::Unresolved(error)) => (DeliveryAttempt::failed(at, None, error), true),
+                Err(Refused::Forbidden(error)) => (DeliveryAttempt::failed(at, None, error), false),
+            };
+            if !record(&result) {
+                return DeliveryStatus::Failed;
+            }
+            if result.error.is_none() {
+                log::info!("Delivered callback to {} on attempt {}", url, attempt);
+                return DeliveryStatus::Delivered;
+            }
+            log::warn!(
+                "Callback to {} failed on attempt {}: {}",
+                url,
+                attempt,
+                result.error.as_deref().unwrap_or_default()
+            );
+            if !retry {
+                break;
+            }
+            if attempt < self.config.max_attempts {
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+        DeliveryStatus::Failed
+    }
+
+    /// Checks where `url` points to, returning the domain and the address to pin it to for hosts
+    /// that are not IP addresses.
+    fn destination(&self, url: &str) -> std::result::Result<Option<(String, SocketAddr)>, Refused> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| Refused::Forbidden(e.to_string()))?;
+        let name = parsed
+            .host_str()
+            .ok_or_else(|| Refused::Forbidden("URL has no host".to_string()))?;
+        let port = parsed.port_or_known_default().unwrap_or(80);
+
+        let listed = self
+            .config
+            .allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(name));
+        if !self.config.allowed_hosts.is_empty() && !listed {
+            return Err(Refused::Forbidden(format!(
+                "{} is not an allowed callback host",
+                name
+            )));
+        }
+
+        // IPv6 hosts are written in brackets
+        let literal = name.trim_start_matches('[').trim_end_matches(']');
+        let (domain, addresses) = match literal.parse::<IpAddr>() {
+            Ok(ip) => (None, vec![SocketAddr::new(ip, port)]),
+            Err(_) => {
+                let addresses: Vec<SocketAddr> = (name, port)
+                    .to_socket_addrs()
+                    .map_err(|e| Refused::Unresolved(format!("Failed to resolve {}: {}", name, e)))?
+                    .collect();
+                (Some(name.to_string()), addresses)
+            }
+        };
+        if !listed {
+            if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+                return Err(Refused::Forbidden(format!(
+                    "{} resolves to the non-public address {}",
+                    name,
+                    address.ip()
+                )));
+            }
+        }
+        let address = *addresses
+            .first()
+            .ok_or_else(|| Refused::Unresolved(format!("{} has no addresses", name)))?;
+        Ok(domain.map(|domain| (domain, address)))
+    }
+
+    fn post(
+        &self,
+        url: &str,
+        pinned: Option<(String, SocketAddr)>,
+        at: u64,
+        body: &[u8],
+    ) -> DeliveryAttempt {
+        let mut builder = reqwest::blocking::Client::builder()
+            .timeout(self.config.timeout)
+            .redirect(reqwest::redirect::Policy::none());
+        if let Some((domain, address)) = pinned {
+            builder = builder.resolve(&domain, address);
+        }
+        let client = match builder.build() {
+            Ok(client) => client,
+            Err(error) => return DeliveryAttempt::failed(at, None, error.to_string()),
+        };
+
+        let mut request = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, at)
+            .body(body.to_vec());
+        if let Some(secret) = &self.config.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, at, body));
+        }
+        match request.send() {
+            Ok(response) if response.status().is_success() => DeliveryAttempt {
+                at,
+                status: Some(response.status().as_u16()),
+                error: None,
+            },
+            Ok(response) => DeliveryAttempt::failed(
+                at,
+                Some(response.status().as_u16()),
+                format!("Endpoint answered {}", response.status()),
+            ),
+            Err(error) => DeliveryAttempt::failed(at, None, error.to_string()),
+        }
+    }
+}
+
+impl DeliveryAttempt {
+    fn failed(at: u64, status: Option<u16>, error: String) -> Self {
+        Self {
+            at,
+            status,
+            error: Some(error),
+        }
+    }
+}
+
+/// Whether an address is reachable on the public internet, as opposed to loopback, link-local,
+/// private, shared and other special-purpose ranges. IPv6 addresses that embed an IPv4 address
+/// are judged by that address.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64)
+                // Benchmarking, 198.18.0.0/15
+                || (first == 198 && second & 0xfe == 18)
+                // Reserved, 240.0.0.0/4
+                || first >= 240)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7, and link-local, fe80::/10
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The IPv4 address carried by an IPv4-mapped, ::ffff:0:0/96, IPv4-compatible, ::/96, NAT64,
+/// 64:ff9b::/96, or 6to4, 2002::/16, address.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0 | 0xffff, high, low]
+        | [0x64, 0xff9b, 0, 0, 0, 0, high, low]
+        | [0x2002, high, low, ..] => Some(Ipv4Addr::from(u32::from(high) << 16 | u32::from(low))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    /// Header lines and body of each request received.
+    type Requests = Vec<(Vec<String>, Vec<u8>)>;
+
+    /// Serves one request per status in `statuses`, answering with that status.
+    fn serve(statuses: &'static [u16]) -> (String, thread::JoinHandle<Requests>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            statuses
+                .iter()
+                .map(|status| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    let mut headers = Vec::new();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let line = line.trim_end().to_string();
+                        if line.is_empty() {
+                            break;
+                        }
+                        headers.push(line);
+                    }
+                    let length = headers
+                        .iter()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    write!(
+                        reader.get_mut(),
+                        "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        status
+                    )
+                    .unwrap();
+                    (headers, body)
+                })
+                .collect()
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn test_callback_options() {
+        let options = |url: &str| CallbackOptions {
+            callback_url: Some(url.to_string()),
+            callback_result: true,
+        };
+        let callback = options("https://example.com/hooks?job=1")
+            .callback()
+            .unwrap()
+            .unwrap();
+        assert_eq!(callback.url, "https://example.com/hooks?job=1");
+        assert!(callback.include_result);
+        assert!(CallbackOptions::default().callback().unwrap().is_none());
+
+        for url in [
+            "/relative",
+            "ftp://example.com/",
+            "not a url",
+            "file:///etc/passwd",
+        ] {
+            let Err(GpuWorkerError::InvalidFields(fields)) = options(url).callback() else {
+                panic!("{} should be rejected", url);
+            };
+            assert_eq!(fields[0].field, "callback_url");
+        }
+    }
+
+    #[test]
+    fn test_sign_matches_known_digest() {
+        // Key and body of RFC 4231, test case 2, behind a timestamp
+        assert_eq!(
+            sign("Jefe", 1_700_000_000, b"what do ya want for nothing?"),
+            "sha256=1cdd0650c8be1cb0974b1788d458b1e781206cfef59b85faafc582d2e182c57e"
+        );
+    }
+
+    #[test]
+    fn test_only_public_addresses_are_public() {
+        for ip in [
+            "93.184.216.34",
+            "198.20.0.1",
+            "2606:2800:220:1:248:1893:25c8:1946",
+            "::ffff:93.184.216.34",
+            "64:ff9b::93.184.216.34",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::192.168.1.1",
+            "2002:7f00:1::1",
+            "2002:c0a8:101::",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_non_public_and_unlisted_hosts_are_refused() {
+        let notifier = Notifier::new(CallbackConfig {
+            backoff: Duration::from_millis(1),
+            ..CallbackConfig::default()
+        });
+        for url in [
+            "http://127.0.0.1:9/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://localhost/hook",
+        ] {
+            let mut attempts = Vec::new();
+            let status = notifier.deliver(url, b"{}", |attempt| {
+                attempts.push(attempt.clone());
+                true
+            });
+            // Refused destinations are not retried
+            assert_eq!(status, DeliveryStatus::Failed);
+            assert_eq!(attempts.len(), 1, "{}", url);
+            assert!(attempts[0].error.as_ref().unwrap().contains("non-public"));
+        }
+
+        let notifier = Notifier::new(CallbackConfig {
+            allowed_hosts: vec!["hooks.example.com".to_string()],
+            ..CallbackConfig::default()
+        });
+        let mut error = None;
+        notifier.deliver("https://example.com/hook", b"{}", |attempt| {
+            error = attempt.error.clone();
+            true
+        });
+        assert_eq!(
+            error.unwrap(),
+            "example.com is not an allowed callback host"
+        );
+    }
+
+    #[test]
+    fn test_delivery_retries_and_signs() {
+        let (url, server) = serve(&[500, 503, 204]);
+        let notifier = Notifier::new(CallbackConfig {
+            secret: Some("secret".to_string()),
+            max_attempts: 3,
+            backoff: Duration::from_millis(1),
+            timeout: Duration::from_secs(5),
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+        });
+
+        let mut attempts = Vec::new();
+        let status = notifier.deliver(&url, br#"{"event":"job.succeeded"}"#, |attempt| {
+            attempts.push(attempt.clone());
+            true
+        });
+        assert_eq!(status, DeliveryStatus::Delivered);
+        let statuses: Vec<_> = attempts.iter().map(|attempt| attempt.status).collect();
+        assert_eq!(statuses, vec![Some(500), Some(503), Some(204)]);
+        assert!(attempts[0].error.is_some());
+        assert!(attempts[2].error.is_none());
+
+        let requests = server.join().unwrap();
+        let (headers, body) = &requests[2];
+        assert!(headers[0].starts_with("POST /hook "));
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+                .unwrap()
+        };
+        let timestamp = header("x-gpu-worker-timestamp").parse().unwrap();
+        assert_eq!(attempts[2].at, timestamp);
+        assert_eq!(
+            header("x-gpu-worker-signature-256"),
+            sign("secret", timestamp, body)
+        );
+        assert_eq!(body, br#"{"event":"job.succeeded"}"#);
+    }
+
+    #[test]
+    fn test_delivery_gives_up() {
+        let (url, server) = serve(&[500, 500]);
+        let notifier = Notifier::new(CallbackConfig {
+            max_attempts: 2,
+            backoff: Duration::from_millis(1),
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+            ..CallbackConfig::default()
+        });
+        let mut attempts = 0;
+        let status = notifier.deliver(&url, b"{}", |_| {
+            attempts += 1;
+            true
+        });
+        assert_eq!(status, DeliveryStatus::Failed);
+        assert_eq!(attempts, 2);
+        let (headers, _) = &server.join().unwrap()[0];
+        assert!(!headers
+            .iter()
+            .any(|line| line.starts_with("x-gpu-worker-signature-256")));
+    }
+}
@@ -1,4 +1,5 @@
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{http::header, HttpResponse, ResponseError};
+use serde::Serialize;
 use thiserror::Error;
 
 #[allow(dead_code)]
@@ -13,11 +14,14 @@ pub enum GpuWorkerError {
     #[error("Invalid input: {0}")]
     InvalidInput(String),
 
+    #[error("Invalid input: {}", FieldError::join(.0))]
+    InvalidFields(Vec<FieldError>),
+
     #[error("Internal server error: {0}")]
     Internal(String),
 
     #[error("IO error: {0}")]
-    Io(#[from] std::io::Error),
+    Io(std::io::Error),
 
     #[error("WGPU device request error: {0}")]
     WgpuDevice(#[from] wgpu::RequestDeviceError),
@@ -26,16 +30,144 @@ pub enum GpuWorkerError {
     Image(#[from] image::ImageError),
 
     #[error("GIF decoding error: {0}")]
-    GifDecode(#[from] gif::DecodingError),
+    GifDecode(gif::DecodingError),
 
     #[error("GIF encoding error: {0}")]
     GifEncode(#[from] gif::EncodingError),
 
+    #[error("PNG decoding error: {0}")]
+    PngDecode(png::DecodingError),
+
+    #[error("PNG encoding error: {0}")]
+    PngEncode(#[from] png::EncodingError),
+
     #[error("Transformation error: {0}")]
     Transformation(#[from] transformations::TransformationError),
 
     #[error("Multipart error: {0}")]
-    Multipart(#[from] actix_multipart::MultipartError),
+    Multipart(String),
+
+    #[error("Request body error: {0}")]
+    Payload(String),
+
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("Not acceptable: {0}")]
+    NotAcceptable(String),
+
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Cancelled: {0}")]
+    Cancelled(String),
+
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("Deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+
+    /// Too much work is waiting; the client should retry after the given delay.
+    #[error("Service unavailable: {0}")]
+    Overloaded(String, std::time::Duration),
+}
+
+/// A problem with one field of a structured request, such as `operations[1].radius`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    /// Path of the field within the request.
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    fn join(errors: &[FieldError]) -> String {
+        errors
+            .iter()
+            .map(|error| format!("{}: {}", error.field, error.message))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+// Multipart errors can wrap `actix_web::Error`, which is not `Send`. Keeping only the message
+// lets `GpuWorkerError` travel from the blocking processing threads back to the handlers.
+impl From<actix_multipart::MultipartError> for GpuWorkerError {
+    fn from(err: actix_multipart::MultipartError) -> Self {
+        match err {
+            actix_multipart::MultipartError::Payload(err) => err.into(),
+            err => GpuWorkerError::Multipart(err.to_string()),
+        }
+    }
+}
+
+impl From<actix_web::error::PayloadError> for GpuWorkerError {
+    fn from(err: actix_web::error::PayloadError) -> Self {
+        match err {
+            actix_web::error::PayloadError::Overflow => GpuWorkerError::PayloadTooLarge(
+                "Request body is larger than the upload limit".to_string(),
+            ),
+            err => GpuWorkerError::Payload(err.to_string()),
+        }
+    }
+}
+
+// Errors raised while feeding a request body to a blocking decoder travel through `Read` as I/O
+// errors wrapping a `GpuWorkerError`, and often come back wrapped in a decoding error as well.
+// Unwrapping them keeps e.g. an upload limit a 413 rather than a decoding failure.
+impl From<std::io::Error> for GpuWorkerError {
+    fn from(err: std::io::Error) -> Self {
+        let wraps_error = err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<GpuWorkerError>());
+        if !wraps_error {
+            return GpuWorkerError::Io(err);
+        }
+
+        let inner = err.into_inner().expect("checked above");
+        *inner.downcast().expect("checked above")
+    }
+}
+
+impl From<gif::DecodingError> for GpuWorkerError {
+    fn from(err: gif::DecodingError) -> Self {
+        match err {
+            gif::DecodingError::Io(err) => err.into(),
+            err => GpuWorkerError::GifDecode(err),
+        }
+    }
+}
+
+impl From<png::DecodingError> for GpuWorkerError {
+    fn from(err: png::DecodingError) -> Self {
+        match err {
+            png::DecodingError::IoError(err) => err.into(),
+            err => GpuWorkerError::PngDecode(err),
+        }
+    }
 }
 
 impl ResponseError for GpuWorkerError {
@@ -44,21 +176,53 @@ impl ResponseError for GpuWorkerError {
             Self::Gpu(_) | Self::WgpuDevice(_) | Self::Internal(_) | Self::Io(_) => {
                 (HttpResponse::InternalServerError(), "internal_error")
             }
-            Self::ImageProcessing(_) | Self::Image(_) | Self::GifDecode(_) | Self::GifEncode(_) => {
-                (HttpResponse::UnprocessableEntity(), "processing_error")
-            }
-            Self::InvalidInput(_) | Self::Multipart(_) => {
-                (HttpResponse::BadRequest(), "invalid_request")
-            }
+            Self::ImageProcessing(_)
+            | Self::Image(_)
+            | Self::GifDecode(_)
+            | Self::GifEncode(_)
+            | Self::PngDecode(_)
+            | Self::PngEncode(_) => (HttpResponse::UnprocessableEntity(), "processing_error"),
+            Self::InvalidInput(_)
+            | Self::InvalidFields(_)
+            | Self::Multipart(_)
+            | Self::Payload(_) => (HttpResponse::BadRequest(), "invalid_request"),
             Self::Transformation(_) => {
                 (HttpResponse::InternalServerError(), "transformation_error")
             }
+            Self::UnsupportedMediaType(_) => (
+                HttpResponse::UnsupportedMediaType(),
+                "unsupported_media_type",
+            ),
+            Self::NotAcceptable(_) => (HttpResponse::NotAcceptable(), "not_acceptable"),
+            Self::PayloadTooLarge(_) => (HttpResponse::PayloadTooLarge(), "payload_too_large"),
+            Self::LimitExceeded(_) => (HttpResponse::UnprocessableEntity(), "limit_exceeded"),
+            Self::Unauthorized(_) => (HttpResponse::Unauthorized(), "unauthorized"),
+            Self::Forbidden(_) => (HttpResponse::Forbidden(), "forbidden"),
+            Self::NotFound(_) => (HttpResponse::NotFound(), "not_found"),
+            Self::Conflict(_) => (HttpResponse::Conflict(), "conflict"),
+            Self::Cancelled(_) => (HttpResponse::Conflict(), "cancelled"),
+            Self::Unavailable(_) | Self::Overloaded(..) => {
+                (HttpResponse::ServiceUnavailable(), "service_unavailable")
+            }
+            Self::DeadlineExceeded(_) => (HttpResponse::GatewayTimeout(), "deadline_exceeded"),
         };
 
-        status.json(serde_json::json!({
+        let mut body = serde_json::json!({
             "error": error_type,
             "message": self.to_string()
-        }))
+        });
+        if let Self::InvalidFields(fields) = self {
+            body["fields"] = serde_json::json!(fields);
+        }
+        if let Self::Overloaded(_, retry_after) = self {
+            // Whole seconds, rounded up so that clients never retry too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            status.insert_header((header::RETRY_AFTER, seconds.max(1)));
+        }
+        if let Self::Unauthorized(_) = self {
+            status.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        status.json(body)
     }
 }
 
@@ -84,5 +248,80 @@ mod tests {
 
         let processing_error = GpuWorkerError::ImageProcessing("Failed to process".to_string());
         assert_eq!(processing_error.error_response().status(), 422);
+
+        let media_type_error = GpuWorkerError::UnsupportedMediaType("image/x-icon".to_string());
+        assert_eq!(media_type_error.error_response().status(), 415);
+
+        let not_acceptable = GpuWorkerError::NotAcceptable("image/avif".to_string());
+        assert_eq!(not_acceptable.error_response().status(), 406);
+
+        let too_large = GpuWorkerError::PayloadTooLarge("2 GB".to_string());
+        assert_eq!(too_large.error_response().status(), 413);
+
+        let limit = GpuWorkerError::LimitExceeded("65535x65535".to_string());
+        assert_eq!(limit.error_response().status(), 422);
+
+        let unauthorized = GpuWorkerError::Unauthorized("missing key".to_string());
+        let response = unauthorized.error_response();
+        assert_eq!(response.status(), 401);
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer"
+        );
+
+        let forbidden = GpuWorkerError::Forbidden("admin scope".to_string());
+        assert_eq!(forbidden.error_response().status(), 403);
+
+        let not_found = GpuWorkerError::NotFound("job".to_string());
+        assert_eq!(not_found.error_response().status(), 404);
+
+        let conflict = GpuWorkerError::Conflict("still running".to_string());
+        assert_eq!(conflict.error_response().status(), 409);
+
+        let unavailable = GpuWorkerError::Unavailable("queue full".to_string());
+        assert_eq!(unavailable.error_response().status(), 503);
+
+        let deadline = GpuWorkerError::DeadlineExceeded("120000 ms".to_string());
+        assert_eq!(deadline.error_response().status(), 504);
+
+        let overloaded = GpuWorkerError::Overloaded(
+            "GPU queue full".to_string(),
+            std::time::Duration::from_millis(1500),
+        );
+        let response = overloaded.error_response();
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+    }
+
+    #[test]
+    fn test_field_errors_are_listed() {
+        let error = GpuWorkerError::InvalidFields(vec![
+            FieldError::new("operations[0].op", "unknown operation"),
+            FieldError::new("operations[1].radius", "must be between 1 and 32"),
+        ]);
+        assert_eq!(
+            error.to_string(),
+            "Invalid input: operations[0].op: unknown operation; \
+             operations[1].radius: must be between 1 and 32"
+        );
+
+        let response = error.error_response();
+        assert_eq!(response.status(), 400);
+        let body = actix_web::body::to_bytes(response.into_body());
+        let body: serde_json::Value =
+            serde_json::from_slice(&pollster::block_on(body).unwrap()).unwrap();
+        assert_eq!(body["error"], "invalid_request");
+        assert_eq!(body["fields"][1]["field"], "operations[1].radius");
+    }
+
+    #[test]
+    fn test_wrapped_errors_are_unwrapped() {
+        let limit = GpuWorkerError::PayloadTooLarge("too big".to_string());
+        let io = std::io::Error::new(std::io::ErrorKind::Other, limit);
+        let error = GpuWorkerError::from(gif::DecodingError::Io(io));
+        assert!(matches!(error, GpuWorkerError::PayloadTooLarge(_)));
+
+        let io = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof");
+        assert!(matches!(GpuWorkerError::from(io), GpuWorkerError::Io(_)));
     }
 }
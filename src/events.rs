@@ -0,0 +1,264 @@
+//! Progress events of asynchronous jobs.
+//!
+//! Every job keeps a log of what happened to it: when it started, when its input was decoded,
+//! each processed frame, when encoding was finishing and how the job ended. Events are stamped
+//! with the milliseconds since the job started and numbered, so that clients following the log as
+//! server-sent events can resume with `Last-Event-ID`.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+/// Something that happened to a job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JobEvent {
+    /// A worker picked the job up; `attempt` counts runs interrupted by restarts.
+    Started {
+        attempt: u32,
+    },
+    /// The input has been read and its frames counted.
+    Decoded {
+        frames_total: usize,
+    },
+    /// One more frame has been processed.
+    Frame {
+        /// One-based.
+        index: usize,
+        frames_total: Option<usize>,
+        /// Time spent on this frame.
+        frame_ms: u64,
+    },
+    /// Every frame has been processed and the output is being finished.
+    Encoding,
+    Succeeded,
+    Failed {
+        error: String,
+    },
+    Cancelled,
+}
+
+impl JobEvent {
+    /// Name of the event in the event stream.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Started { .. } => "started",
+            Self::Decoded { .. } => "decoded",
+            Self::Frame { .. } => "frame",
+            Self::Encoding => "encoding",
+            Self::Succeeded => "succeeded",
+            Self::Failed { .. } => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether no events follow this one.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            Self::Succeeded | Self::Failed { .. } | Self::Cancelled
+        )
+    }
+}
+
+/// An event as recorded in the log of a job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobEventRecord {
+    /// Position in the log, starting at 1.
+    pub id: u64,
+    /// Milliseconds since the job started, or 0 before it has.
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub event: JobEvent,
+}
+
+impl JobEventRecord {
+    /// The record as a server-sent event.
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id,
+            self.event.name(),
+            data
+        )
+    }
+}
+
+/// Event log of one job.
+#[derive(Debug)]
+pub struct EventLog {
+    inner: Mutex<Inner>,
+    /// Id of the latest record.
+    latest: watch::Sender<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    records: Vec<JobEventRecord>,
+    started: Option<Instant>,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::default(),
+            latest: watch::channel(0).0,
+        }
+    }
+}
+
+impl EventLog {
+    /// Appends an event
+    ///
+    /// [`JobEvent::Started`] restarts the clock. Once the final event is in, the frame events
+    /// but the last are dropped to keep finished jobs small; their ids are not reused.
+    pub fn record(&self, event: JobEvent) {
+        let mut inner = self
+            .inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if matches!(event, JobEvent::Started { .. }) {
+            inner.started = Some(Instant::now());
+        }
+        let elapsed_ms = inner.started.map_or(0, |started| millis(started.elapsed()));
+        let id = inner.records.last().map_or(0, |record| record.id) + 1;
+        let is_final = event.is_final();
+        inner.records.push(JobEventRecord {
+            id,
+            elapsed_ms,
+            event,
+        });
+
+        if is_final {
+            let last_frame = inner
+                .records
+                .iter()
+                .rev()
+                .find(|record| matches!(record.event, JobEvent::Frame { .. }))
+                .map(|record| record.id);
+            inner.records.retain(|record| {
+                !matches!(record.event, JobEvent::Frame { .. }) || Some(record.id) == last_frame
+            });
+        }
+        self.latest.send_replace(id);
+    }
+
+    /// Records after the one with id `after`, oldest first.
+    pub fn since(&self, after: u64) -> Vec<JobEventRecord> {
+        let inner = self
+            .inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        inner
+            .records
+            .iter()
+            .filter(|record| record.id > after)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Subscription to the event log of a job
+pub struct JobEvents {
+    log: Arc<EventLog>,
+    latest: watch::Receiver<u64>,
+}
+
+impl JobEvents {
+    pub fn new(log: Arc<EventLog>) -> Self {
+        let latest = log.latest.subscribe();
+        Self { log, latest }
+    }
+
+    /// Records after the one with id `after`, oldest first.
+    pub fn since(&mut self, after: u64) -> Vec<JobEventRecord> {
+        // Marked as seen before reading, so that anything recorded later wakes up `changed`
+        self.latest.borrow_and_update();
+        self.log.since(after)
+    }
+
+    /// Waits until an event is recorded that was not there when the log was last read.
+    pub async fn changed(&mut self) {
+        // The log holds the sender, so this only returns once something is recorded
+        let _ = self.latest.changed().await;
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_final_event_drops_frames_but_the_last() {
+        let log = EventLog::default();
+        log.record(JobEvent::Started { attempt: 1 });
+        log.record(JobEvent::Decoded { frames_total: 3 });
+        for index in 1..=3 {
+            log.record(JobEvent::Frame {
+                index,
+                frames_total: Some(3),
+                frame_ms: 1,
+            });
+        }
+        assert_eq!(log.since(0).len(), 5);
+        assert_eq!(log.since(4).len(), 1);
+
+        log.record(JobEvent::Succeeded);
+        let names: Vec<_> = log
+            .since(0)
+            .iter()
+            .map(|record| (record.id, record.event.name()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (1, "started"),
+                (2, "decoded"),
+                (5, "frame"),
+                (6, "succeeded")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_as_server_sent_event() {
+        let record = JobEventRecord {
+            id: 3,
+            elapsed_ms: 12,
+            event: JobEvent::Frame {
+                index: 2,
+                frames_total: Some(4),
+                frame_ms: 5,
+            },
+        };
+        assert_eq!(
+            record.to_sse(),
+            "id: 3\nevent: frame\ndata: {\"id\":3,\"elapsed_ms\":12,\"event\":\"frame\",\
+             \"index\":2,\"frames_total\":4,\"frame_ms\":5}\n\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_subscription_wakes_on_new_events() {
+        let log = Arc::new(EventLog::default());
+        let mut events = JobEvents::new(log.clone());
+        assert!(events.since(0).is_empty());
+
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            log.record(JobEvent::Cancelled);
+        });
+        events.changed().await;
+        assert_eq!(events.since(0)[0].event, JobEvent::Cancelled);
+        writer.join().unwrap();
+    }
+}
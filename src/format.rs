@@ -0,0 +1,593 @@
+//! Image format detection and still image codecs.
+//!
+//! Uploads are identified by their magic bytes rather than by file names or declared content
+//! types, which clients often get wrong. GIFs and animated PNGs go through the streaming frame
+//! pipeline; the other formats are stills that are decoded into a single RGBA image with the
+//! `image` crate and, by default, re-encoded in the format they came in.
+
+use std::io::{Cursor, Write};
+
+use image::{DynamicImage, ImageOutputFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{GpuWorkerError, Result},
+    limits::DecodeLimits,
+    pipeline::AnimationFormat,
+};
+
+/// Number of leading bytes needed to recognize every supported format.
+pub const SNIFF_LEN: usize = 12;
+
+/// Quality used when encoding JPEG output.
+const JPEG_QUALITY: u8 = 90;
+
+/// Longest file name stem kept in `Content-Disposition` headers.
+const MAX_FILE_STEM_LEN: usize = 100;
+
+/// An image format the service can read and write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Gif,
+    Apng,
+    Png,
+    Jpeg,
+    WebP,
+    Bmp,
+    Tiff,
+}
+
+impl Format {
+    /// Identifies a format from the first [`SNIFF_LEN`] bytes of a file.
+    ///
+    /// Animated PNGs are reported as [`Format::Png`]; see [`png_is_animated`].
+    pub fn sniff(header: &[u8]) -> Option<Self> {
+        match header {
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Self::Gif),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(Self::Png),
+            [0xFF, 0xD8, 0xFF, ..] => Some(Self::Jpeg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(Self::WebP),
+            [b'B', b'M', ..] => Some(Self::Bmp),
+            [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Some(Self::Tiff),
+            _ => None,
+        }
+    }
+
+    /// Like [`Format::sniff`], but fails with `415 Unsupported Media Type`.
+    pub fn sniff_supported(header: &[u8]) -> Result<Self> {
+        Self::sniff(header).ok_or_else(|| {
+            GpuWorkerError::UnsupportedMediaType(
+                "Expected a GIF, PNG, JPEG, WebP, BMP or TIFF image".to_string(),
+            )
+        })
+    }
+
+    /// The MIME type of the format.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Gif => "image/gif",
+            Self::Apng => "image/apng",
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::WebP => "image/webp",
+            Self::Bmp => "image/bmp",
+            Self::Tiff => "image/tiff",
+        }
+    }
+
+    /// The usual file extension of the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gif => "gif",
+            Self::Apng | Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::WebP => "webp",
+            Self::Bmp => "bmp",
+            Self::Tiff => "tiff",
+        }
+    }
+
+    /// Returns `true` for formats handled by the frame pipeline rather than as stills.
+    pub fn is_animated(self) -> bool {
+        self.animation_format().is_some()
+    }
+
+    /// The format frames are encoded in when this format is the output of the frame pipeline.
+    pub fn animation_format(self) -> Option<AnimationFormat> {
+        match self {
+            Self::Gif => Some(AnimationFormat::Gif),
+            Self::Apng => Some(AnimationFormat::Apng),
+            _ => None,
+        }
+    }
+
+    fn image_format(self) -> image::ImageFormat {
+        match self {
+            Self::Gif => image::ImageFormat::Gif,
+            Self::Apng | Self::Png => image::ImageFormat::Png,
+            Self::Jpeg => image::ImageFormat::Jpeg,
+            Self::WebP => image::ImageFormat::WebP,
+            Self::Bmp => image::ImageFormat::Bmp,
+            Self::Tiff => image::ImageFormat::Tiff,
+        }
+    }
+
+    fn output_format(self) -> ImageOutputFormat {
+        match self {
+            Self::Gif => ImageOutputFormat::Gif,
+            Self::Apng | Self::Png => ImageOutputFormat::Png,
+            Self::Jpeg => ImageOutputFormat::Jpeg(JPEG_QUALITY),
+            Self::WebP => ImageOutputFormat::WebP,
+            Self::Bmp => ImageOutputFormat::Bmp,
+            Self::Tiff => ImageOutputFormat::Tiff,
+        }
+    }
+}
+
+/// Tells an animated PNG from a still one by looking for an `acTL` chunk before the image data
+///
+/// `data` is the start of a PNG file. Returns `None` when it ends before either chunk is found.
+pub fn png_is_animated(data: &[u8]) -> Option<bool> {
+    let mut pos = 8;
+    while pos + 8 <= data.len() {
+        match &data[pos + 4..pos + 8] {
+            b"acTL" => return Some(true),
+            b"IDAT" => return Some(false),
+            _ => {}
+        }
+        let length = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+        pos += 12 + length as usize;
+    }
+    None
+}
+
+/// Format of a response body: an encoded image or an archive of frames
+///
+/// Serializes as its [name](OutputFormat::name).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum OutputFormat {
+    Image(Format),
+    /// A ZIP archive with one PNG per frame.
+    Zip,
+}
+
+impl OutputFormat {
+    /// Formats a result can be returned in for an input of `input` format, most preferred first
+    ///
+    /// Animations can be encoded as GIF or APNG or split into a frame archive; stills can be
+    /// encoded in any still format. The input format always comes first.
+    pub fn candidates(input: Format) -> Vec<Self> {
+        let others: &[Format] = if input.is_animated() {
+            &[Format::Gif, Format::Apng]
+        } else {
+            &[
+                Format::Png,
+                Format::WebP,
+                Format::Jpeg,
+                Format::Bmp,
+                Format::Tiff,
+            ]
+        };
+
+        let mut candidates = vec![Self::Image(input)];
+        candidates.extend(
+            others
+                .iter()
+                .filter(|&&format| format != input)
+                .map(|&format| Self::Image(format)),
+        );
+        if input.is_animated() {
+            candidates.push(Self::Zip);
+        }
+        candidates
+    }
+
+    /// Looks up a format by the name used in the `format` parameter.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let format = match name.to_ascii_lowercase().as_str() {
+            "gif" => Format::Gif,
+            "apng" => Format::Apng,
+            "png" => Format::Png,
+            "jpeg" | "jpg" => Format::Jpeg,
+            "webp" => Format::WebP,
+            "bmp" => Format::Bmp,
+            "tiff" | "tif" => Format::Tiff,
+            "zip" => return Some(Self::Zip),
+            _ => return None,
+        };
+        Some(Self::Image(format))
+    }
+
+    /// The name of the format in the `format` parameter.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Image(Format::Gif) => "gif",
+            Self::Image(Format::Apng) => "apng",
+            Self::Image(Format::Png) => "png",
+            Self::Image(Format::Jpeg) => "jpeg",
+            Self::Image(Format::WebP) => "webp",
+            Self::Image(Format::Bmp) => "bmp",
+            Self::Image(Format::Tiff) => "tiff",
+            Self::Zip => "zip",
+        }
+    }
+
+    /// The MIME type of the response body.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Image(format) => format.mime_type(),
+            Self::Zip => "application/zip",
+        }
+    }
+
+    /// The usual file extension of the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Image(format) => format.extension(),
+            Self::Zip => "zip",
+        }
+    }
+
+    /// The format frames are encoded in when this is the output of the frame pipeline.
+    pub fn animation_format(self) -> Option<AnimationFormat> {
+        match self {
+            Self::Image(format) => format.animation_format(),
+            Self::Zip => Some(AnimationFormat::Zip),
+        }
+    }
+
+    /// Suggested file name for a result derived from an upload named `upload_name`
+    ///
+    /// Keeps the stem of the upload name, reduced to characters that are safe in a header, and
+    /// replaces the extension. Falls back to `fallback` when no usable name was given.
+    pub fn file_name(self, upload_name: Option<&str>, fallback: &str) -> String {
+        let stem = upload_name
+            .map(|name| name.rsplit(['/', '\\']).next().unwrap_or(name))
+            .map(|name| name.rsplit_once('.').map_or(name, |(stem, _)| stem))
+            .map(|stem| {
+                stem.chars()
+                    .take(MAX_FILE_STEM_LEN)
+                    .map(|c| match c {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+                        _ => '_',
+                    })
+                    .collect::<String>()
+            })
+            .filter(|stem| !stem.trim_matches(['.', '_']).is_empty())
+            .unwrap_or_else(|| fallback.to_string());
+
+        format!("{}.{}", stem, self.extension())
+    }
+}
+
+impl From<OutputFormat> for String {
+    fn from(format: OutputFormat) -> Self {
+        format.name().to_string()
+    }
+}
+
+impl TryFrom<String> for OutputFormat {
+    type Error = String;
+
+    fn try_from(name: String) -> std::result::Result<Self, Self::Error> {
+        Self::from_name(&name).ok_or_else(|| format!("unknown format {:?}", name))
+    }
+}
+
+/// Output format requested with the `format` query parameter or form field.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OutputOptions {
+    /// Name of the format to return; takes precedence over the `Accept` header.
+    pub format: Option<String>,
+}
+
+impl OutputOptions {
+    /// Picks the output format among `candidates`, which are listed most preferred first
+    ///
+    /// An explicit `format` wins. Otherwise the `Accept` header is honoured, preferring higher
+    /// quality values and then earlier candidates; without one the first candidate is used.
+    /// Requests that none of the candidates can satisfy fail with `406 Not Acceptable`.
+    pub fn negotiate(
+        &self,
+        accept: Option<&str>,
+        candidates: &[OutputFormat],
+    ) -> Result<OutputFormat> {
+        let supported = || {
+            candidates
+                .iter()
+                .map(|candidate| candidate.mime_type())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        if let Some(name) = &self.format {
+            return OutputFormat::from_name(name)
+                .filter(|format| candidates.contains(format))
+                .ok_or_else(|| {
+                    GpuWorkerError::NotAcceptable(format!(
+                        "Cannot return {:?} here, supported formats are {}",
+                        name,
+                        supported()
+                    ))
+                });
+        }
+
+        let accept = match accept.map(str::trim) {
+            Some(accept) if !accept.is_empty() => accept,
+            _ => {
+                return candidates.first().copied().ok_or_else(|| {
+                    GpuWorkerError::Internal("No output formats to choose from".to_string())
+                })
+            }
+        };
+
+        let mut best: Option<(OutputFormat, f32)> = None;
+        for &candidate in candidates {
+            let quality = accept_quality(accept, candidate.mime_type());
+            if quality > 0.0 && best.map_or(true, |(_, best)| quality > best) {
+                best = Some((candidate, quality));
+            }
+        }
+
+        best.map(|(format, _)| format).ok_or_else(|| {
+            GpuWorkerError::NotAcceptable(format!(
+                "None of the accepted types can be returned, supported formats are {}",
+                supported()
+            ))
+        })
+    }
+}
+
+/// Quality the `Accept` header gives `mime_type`, using the most specific matching media range
+///
+/// Returns zero when no range matches.
+fn accept_quality(accept: &str, mime_type: &str) -> f32 {
+    let (kind, _) = mime_type.split_once('/').unwrap_or((mime_type, ""));
+    let mut best: Option<(u8, f32)> = None;
+
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media_range = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let specificity = match media_range.split_once('/') {
+            Some(("*", "*")) => 0,
+            Some((range_kind, "*")) if range_kind == kind => 1,
+            _ if media_range == mime_type => 2,
+            _ => continue,
+        };
+
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0)
+            .clamp(0.0, 1.0);
+
+        if best.map_or(true, |(best, _)| specificity > best) {
+            best = Some((specificity, quality));
+        }
+    }
+
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+/// Decodes a still image into RGBA
+///
+/// The dimensions are read from the header and checked against `limits` before decoding.
+pub fn decode_still(data: &[u8], format: Format, limits: &DecodeLimits) -> Result<RgbaImage> {
+    let reader = || image::io::Reader::with_format(Cursor::new(data), format.image_format());
+    let (width, height) = reader().into_dimensions()?;
+    limits.check_canvas(width, height)?;
+    Ok(reader().decode()?.to_rgba8())
+}
+
+/// Encodes a still image, dropping the alpha channel for formats without one
+///
+/// Lossless formats keep the pixels exactly; WebP is written losslessly as well.
+pub fn encode_still<W: Write>(image: RgbaImage, format: Format, mut output: W) -> Result<()> {
+    let image = match format {
+        Format::Jpeg => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).into_rgb8()),
+        _ => DynamicImage::ImageRgba8(image),
+    };
+
+    // Some encoders (TIFF) need to seek, so encode into memory first
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, format.output_format())?;
+    output.write_all(encoded.get_ref())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    const STILL_FORMATS: [Format; 5] = [
+        Format::Png,
+        Format::Jpeg,
+        Format::WebP,
+        Format::Bmp,
+        Format::Tiff,
+    ];
+
+    fn test_image() -> RgbaImage {
+        RgbaImage::from_fn(8, 4, |x, _| {
+            if x < 4 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn test_encoded_stills_are_sniffed_back() {
+        for format in STILL_FORMATS {
+            let mut encoded = Vec::new();
+            encode_still(test_image(), format, &mut encoded).unwrap();
+            assert_eq!(Format::sniff(&encoded), Some(format), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_still_round_trip() {
+        for format in STILL_FORMATS {
+            let mut encoded = Vec::new();
+            encode_still(test_image(), format, &mut encoded).unwrap();
+
+            let decoded = decode_still(&encoded, format, &DecodeLimits::default()).unwrap();
+            assert_eq!(decoded.dimensions(), (8, 4), "{:?}", format);
+            if format != Format::Jpeg {
+                assert_eq!(decoded, test_image(), "{:?} should be lossless", format);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sniff_gif() {
+        assert_eq!(
+            Format::sniff(b"GIF89a\x01\x00\x01\x00\x00\x00"),
+            Some(Format::Gif)
+        );
+        assert_eq!(
+            Format::sniff(b"GIF87a\x01\x00\x01\x00\x00\x00"),
+            Some(Format::Gif)
+        );
+    }
+
+    #[test]
+    fn test_png_is_animated() {
+        let mut still = Vec::new();
+        encode_still(test_image(), Format::Png, &mut still).unwrap();
+        assert_eq!(Format::sniff(&still), Some(Format::Png));
+        assert_eq!(png_is_animated(&still), Some(false));
+        assert_eq!(png_is_animated(&still[..20]), None);
+
+        let mut animated = Vec::new();
+        let mut encoder = png::Encoder::new(&mut animated, 1, 1);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_animated(1, 0).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0, 0, 0, 0]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(png_is_animated(&animated), Some(true));
+    }
+
+    #[test]
+    fn test_output_candidates() {
+        assert_eq!(
+            OutputFormat::candidates(Format::Apng),
+            vec![
+                OutputFormat::Image(Format::Apng),
+                OutputFormat::Image(Format::Gif),
+                OutputFormat::Zip
+            ]
+        );
+
+        let stills = OutputFormat::candidates(Format::Jpeg);
+        assert_eq!(stills[0], OutputFormat::Image(Format::Jpeg));
+        assert_eq!(stills.len(), 5);
+        assert!(!stills.contains(&OutputFormat::Zip));
+        assert!(!stills.contains(&OutputFormat::Image(Format::Gif)));
+    }
+
+    #[test]
+    fn test_negotiate_format_parameter() {
+        let candidates = OutputFormat::candidates(Format::Gif);
+        let options = |name: &str| OutputOptions {
+            format: Some(name.to_string()),
+        };
+
+        assert_eq!(
+            options("APNG").negotiate(None, &candidates).unwrap(),
+            OutputFormat::Image(Format::Apng)
+        );
+        // The parameter wins over the header
+        assert_eq!(
+            options("zip")
+                .negotiate(Some("image/gif"), &candidates)
+                .unwrap(),
+            OutputFormat::Zip
+        );
+        for name in ["jpeg", "avif"] {
+            assert!(matches!(
+                options(name).negotiate(None, &candidates),
+                Err(GpuWorkerError::NotAcceptable(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_negotiate_accept_header() {
+        let candidates = OutputFormat::candidates(Format::Png);
+        let negotiate = |accept| OutputOptions::default().negotiate(accept, &candidates);
+
+        assert_eq!(negotiate(None).unwrap(), OutputFormat::Image(Format::Png));
+        assert_eq!(
+            negotiate(Some("*/*")).unwrap(),
+            OutputFormat::Image(Format::Png)
+        );
+        assert_eq!(
+            negotiate(Some("image/webp,image/*;q=0.8,*/*;q=0.5")).unwrap(),
+            OutputFormat::Image(Format::WebP)
+        );
+        assert_eq!(
+            negotiate(Some("image/png;q=0.5, image/jpeg")).unwrap(),
+            OutputFormat::Image(Format::Jpeg)
+        );
+        assert_eq!(
+            negotiate(Some("image/*, image/png;q=0")).unwrap(),
+            OutputFormat::Image(Format::WebP)
+        );
+        assert!(matches!(
+            negotiate(Some("application/json")),
+            Err(GpuWorkerError::NotAcceptable(_))
+        ));
+    }
+
+    #[test]
+    fn test_output_format_names_round_trip() {
+        for format in [Format::Gif, Format::Apng, Format::Png, Format::Jpeg]
+            .into_iter()
+            .flat_map(OutputFormat::candidates)
+        {
+            assert_eq!(OutputFormat::from_name(format.name()), Some(format));
+            let json = serde_json::to_string(&format).unwrap();
+            assert_eq!(serde_json::from_str::<OutputFormat>(&json).unwrap(), format);
+        }
+        assert!(serde_json::from_str::<OutputFormat>("\"svg\"").is_err());
+        assert_eq!(serde_json::to_string(&Format::WebP).unwrap(), "\"webp\"");
+    }
+
+    #[test]
+    fn test_output_file_name() {
+        let png = OutputFormat::Image(Format::Png);
+        assert_eq!(png.file_name(Some("cat.gif"), "image"), "cat.png");
+        assert_eq!(
+            OutputFormat::Zip.file_name(Some("C:\\pics\\my cat.v2.gif"), "image"),
+            "my_cat.v2.zip"
+        );
+        assert_eq!(
+            png.file_name(Some("\"quoted\";.gif"), "image"),
+            "_quoted__.png"
+        );
+        assert_eq!(png.file_name(Some("../.."), "image"), "image.png");
+        assert_eq!(png.file_name(None, "frame"), "frame.png");
+    }
+
+    #[test]
+    fn test_sniff_rejects_unknown_data() {
+        for data in [
+            &b"\x00\x00\x01\x00icon"[..],
+            b"RIFF\x00\x00\x00\x00WAVE",
+            b"",
+            b"GIF",
+        ] {
+            assert_eq!(Format::sniff(data), None);
+            assert!(matches!(
+                Format::sniff_supported(data),
+                Err(GpuWorkerError::UnsupportedMediaType(_))
+            ));
+        }
+    }
+}
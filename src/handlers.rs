@@ -1,305 +1,1203 @@
-use crate::error::{GpuWorkerError, Result};
-use actix_multipart::Multipart;
-use actix_web::{web, HttpResponse};
-use futures_util::TryStreamExt;
-use gif::{Encoder, Frame, Repeat};
-use std::io::Cursor;
-use transformations::MirrorProcessor;
+use std::{
+    convert::Infallible,
+    io::{Read, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_multipart::{Field, Multipart};
+use actix_web::{
+    error::PayloadError,
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType},
+    web, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use bytes::{Bytes, BytesMut};
+use futures_util::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use transformations::{BlurProcessor, MirrorProcessor, ResizeProcessor};
+
+use crate::{
+    assemble::{self, AssembleOptions},
+    auth::{self, Scope},
+    cache::{CacheKey, Recorder, ResultCache},
+    callbacks::CallbackOptions,
+    error::{GpuWorkerError, Result},
+    events::JobEventRecord,
+    format::{self, Format, OutputFormat, OutputOptions, SNIFF_LEN},
+    inspect,
+    jobs::{JobQueue, JobRequest, JobRunner, Tracked},
+    limits::{DecodeLimits, WithDeadline},
+    metadata::{MetadataOptions, MetadataTap},
+    ops::{Axis, Blur, BlurOptions, Chain, Operation, Processors, TransformOptions},
+    pipeline::{self, AnimationFrames, EncodeOptions, FrameOp, QuantizePool},
+    preview::{self, ContactSheetOptions, FrameSelection},
+    scheduler::{GpuPermit, GpuScheduler, Priority, Requester},
+    stream::{self, ChannelReader},
+    timing::{TimingOptions, TrimOptions},
+};
+
+/// Largest accepted text form field, in bytes.
+const MAX_FORM_FIELD_SIZE: usize = 4096;
+
+/// Response header reporting whether a result was served from the cache.
+pub const CACHE_HEADER: &str = "X-Cache";
+
+/// Request header naming the client that GPU work is shared fairly between.
+pub const CLIENT_ID_HEADER: &str = "X-Client-Id";
+
+/// Request header with the [`Priority`] class of the request or job.
+pub const PRIORITY_HEADER: &str = "X-Priority";
+
+/// Longest accepted client id, in bytes.
+const MAX_CLIENT_ID_LEN: usize = 128;
+
+/// Longest silence on a job event stream before a keep-alive comment is sent.
+const EVENT_KEEP_ALIVE: Duration = Duration::from_secs(15);
 
 /// Handles the mirror GIF endpoint
 ///
-/// Accepts a multipart form with a GIF file and returns the vertically mirrored version
+/// Accepts a GIF as the `file` field of a multipart form or as the raw request body and streams
+/// back the vertically mirrored version.
+/// Frames are decoded from the upload, mirrored and re-encoded as they arrive, so peak memory is
+/// bounded by a few frames rather than by the size of the GIF. Trim and timing options given in
+/// the query string are applied as well. Animated PNGs are processed the same way. PNG, JPEG,
+/// WebP, BMP and TIFF stills are mirrored too. The output format is negotiated from `format` or
+/// the `Accept` header and defaults to the input format.
 pub async fn mirror_gif(
-    payload: Multipart,
+    req: HttpRequest,
+    payload: web::Payload,
+    mirror_processor: web::Data<MirrorProcessor>,
+) -> Result<HttpResponse> {
+    let mirror_processor = mirror_processor.into_inner();
+
+    process_upload(&req, payload, |_| {
+        let operations = vec![Operation::Mirror {
+            axis: Axis::Vertical,
+        }];
+        let process = move |rgba: &[u8], width, height| {
+            Ok(pollster::block_on(
+                mirror_processor.mirror_vertically(rgba, width, height),
+            )?)
+        };
+        Ok((operations, process))
+    })
+    .await
+}
+
+/// Handles the blur GIF endpoint
+///
+/// Blurs every frame of the uploaded image with the box radius given as `radius` in the query
+/// string or as a form field, 1 to 32 pixels and 3 by default. Accepts the same uploads, trim,
+/// timing and output options as the mirror endpoint.
+pub async fn blur_gif(
+    req: HttpRequest,
+    payload: web::Payload,
+    blur_processor: web::Data<BlurProcessor>,
+) -> Result<HttpResponse> {
+    let blur_processor = blur_processor.into_inner();
+
+    process_upload(&req, payload, |fields| {
+        let options = form_options::<BlurOptions>(&req, fields, "blur")?;
+        options.validate()?;
+        let blur = Blur {
+            processor: blur_processor,
+            radius: options.radius(),
+        };
+        Ok((
+            vec![Operation::Blur {
+                radius: blur.radius,
+            }],
+            blur,
+        ))
+    })
+    .await
+}
+
+/// Handles the transform endpoint
+///
+/// Runs every frame of the uploaded image through the ordered `operations` given as a JSON array
+/// in the query string or as a form field, e.g.
+/// `[{"op":"mirror","axis":"horizontal"},{"op":"blur","radius":3}]`. The whole list is validated
+/// before any frame is processed and every invalid field is reported in one `400` response.
+/// Operations may change the frame size; the output canvas is sized to the result. Accepts the
+/// same uploads, trim, timing and output options as the mirror endpoint.
+pub async fn transform(
+    req: HttpRequest,
+    payload: web::Payload,
     mirror_processor: web::Data<MirrorProcessor>,
+    blur_processor: web::Data<BlurProcessor>,
+    resize_processor: web::Data<ResizeProcessor>,
+) -> Result<HttpResponse> {
+    let processors = Processors {
+        mirror: mirror_processor.into_inner(),
+        blur: blur_processor.into_inner(),
+        resize: resize_processor.into_inner(),
+    };
+    let limits = decode_limits(&req);
+
+    process_upload(&req, payload, |fields| {
+        let operations =
+            form_options::<TransformOptions>(&req, fields, "transform")?.operations()?;
+        let chain = Chain::new(&operations, &processors, limits)?;
+        Ok((operations, chain))
+    })
+    .await
+}
+
+/// Handles job submission
+///
+/// Takes the same upload, `operations` and output options as the transform endpoint, validates
+/// them and reads the whole upload, then queues the transformation and answers `202 Accepted`
+/// with the job's state and its URL in `Location`. With a `callback_url`, the outcome is posted
+/// there once the job succeeds or fails. Fails with `503 Service Unavailable` when the job queue
+/// is full.
+pub async fn submit_job(
+    req: HttpRequest,
+    payload: web::Payload,
+    jobs: web::Data<JobQueue>,
 ) -> Result<HttpResponse> {
-    let gif_data = extract_gif_from_multipart(payload).await?;
-    let mirrored_gif = process_gif_mirror(&gif_data, &mirror_processor).await?;
+    let upload = extract_upload(&req, payload).await?;
+    let format = upload.format;
+    let operations =
+        form_options::<TransformOptions>(&req, &upload.fields, "transform")?.operations()?;
+    check_operations(&req, &operations)?;
+    let output_format = negotiate_output(&req, &upload.fields, format)?;
+    let callback = form_options::<CallbackOptions>(&req, &upload.fields, "callback")?.callback()?;
+    let job = TransformJob {
+        format,
+        output_format,
+        operations,
+        metadata: form_options::<MetadataOptions>(&req, &upload.fields, "metadata")?,
+        requester: requester(&req)?,
+    };
+    let request = JobRequest {
+        content_type: output_format.mime_type().to_string(),
+        filename: output_format.file_name(upload.filename.as_deref(), "image"),
+        spec: serde_json::to_value(&job).map_err(|e| GpuWorkerError::Internal(e.to_string()))?,
+        callback,
+    };
 
+    let mut data = upload.data;
+    let queue = jobs.get_ref().clone();
+    let info = web::block(move || {
+        let mut input = Vec::new();
+        data.read_to_end(&mut input)?;
+        queue.submit(request, input.into())
+    })
+    .await
+    .map_err(|e| GpuWorkerError::Internal(e.to_string()))??;
+
+    Ok(HttpResponse::Accepted()
+        .insert_header((header::LOCATION, job_url(&req, info.id)))
+        .json(info))
+}
+
+/// Work of a job submitted to the jobs endpoint, as stored in the spool
+#[derive(Serialize, Deserialize)]
+struct TransformJob {
+    format: Format,
+    output_format: OutputFormat,
+    operations: Vec<Operation>,
+    metadata: MetadataOptions,
+    /// Absent from jobs spooled by older versions.
+    #[serde(default)]
+    requester: Requester,
+}
+
+/// Runs the jobs queued by [`submit_job`] with the given processors, limits and quantizer pool
+///
+/// Jobs that use the GPU wait for a permit from `scheduler` first, without ever being rejected.
+pub fn transform_job_runner(
+    processors: Processors,
+    limits: DecodeLimits,
+    pool: QuantizePool,
+    scheduler: GpuScheduler,
+) -> JobRunner {
+    Arc::new(move |spec, input, context, output| {
+        let job: TransformJob = serde_json::from_value(spec.clone())
+            .map_err(|e| GpuWorkerError::Internal(format!("Invalid job spec: {}", e)))?;
+        let options = ProcessOptions {
+            trim: TrimOptions::default(),
+            timing: TimingOptions::default(),
+            metadata: job.metadata,
+            limits,
+            pool: pool.clone(),
+        };
+        let chain = Chain::new(&job.operations, &processors, limits)?;
+        context.set_frames_total(pipeline::count_frames(input, job.format, &limits)?);
+        let _permit = job
+            .operations
+            .iter()
+            .any(Operation::uses_gpu)
+            .then(|| scheduler.acquire_blocking(&job.requester));
+        let op = Tracked {
+            op: chain,
+            context: context.clone(),
+        };
+        process_input(input, job.format, job.output_format, &options, output, op)
+    })
+}
+
+/// Handles job status requests, reporting the state and per-frame progress of a job
+///
+/// Like the other job endpoints, answers `404 Not Found` for jobs submitted with another API
+/// key, unless the request's key has the `admin` scope.
+pub async fn job_status(
+    req: HttpRequest,
+    path: web::Path<String>,
+    jobs: web::Data<JobQueue>,
+) -> Result<HttpResponse> {
+    let info = jobs.info(owned_job_id(&req, &path, &jobs)?)?;
+    Ok(HttpResponse::Ok().json(info))
+}
+
+/// Handles job result downloads
+///
+/// Fails with `409 Conflict` until the job has succeeded.
+pub async fn job_result(
+    req: HttpRequest,
+    path: web::Path<String>,
+    jobs: web::Data<JobQueue>,
+) -> Result<HttpResponse> {
+    let result = jobs.result(owned_job_id(&req, &path, &jobs)?)?;
     Ok(HttpResponse::Ok()
-        .content_type("image/gif")
-        .body(mirrored_gif))
+        .content_type(result.content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Inline,
+            parameters: vec![DispositionParam::Filename(result.filename)],
+        })
+        .body(result.data))
 }
 
-/// Extracts GIF data from multipart form data
-async fn extract_gif_from_multipart(mut payload: Multipart) -> Result<Vec<u8>> {
-    while let Some(mut field) = payload.try_next().await? {
-        let content_disposition = field.content_disposition();
+/// Handles job event streams
+///
+/// Streams the events of a job as server-sent events: `started`, `decoded`, one `frame` per
+/// processed frame, `encoding` and finally `succeeded`, `failed` or `cancelled`, after which the
+/// stream ends. Each event carries the milliseconds since the job started. Past events are sent
+/// first; a `Last-Event-ID` header skips those the client has already seen.
+pub async fn job_events(
+    req: HttpRequest,
+    path: web::Path<String>,
+    jobs: web::Data<JobQueue>,
+) -> Result<HttpResponse> {
+    let events = jobs.events(owned_job_id(&req, &path, &jobs)?)?;
+    let after = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse::<u64>().ok())
+        .unwrap_or(0);
 
-        if content_disposition.get_name() == Some("file") {
-            let mut gif_data = Vec::new();
-            while let Some(chunk) = field.try_next().await? {
-                gif_data.extend_from_slice(&chunk);
+    let stream = futures_util::stream::unfold(Some((events, after)), |state| async move {
+        let (mut events, after) = state?;
+        loop {
+            let records = events.since(after);
+            if let Some(last) = records.last() {
+                let next = (!last.event.is_final()).then_some(last.id);
+                let body: String = records.iter().map(JobEventRecord::to_sse).collect();
+                let state = next.map(|after| (events, after));
+                return Some((Ok::<_, Infallible>(Bytes::from(body)), state));
             }
-
-            if gif_data.is_empty() {
-                return Err(GpuWorkerError::InvalidInput(
-                    "Empty file provided".to_string(),
-                ));
+            let changed = tokio::time::timeout(EVENT_KEEP_ALIVE, events.changed()).await;
+            if changed.is_err() {
+                let keep_alive = Bytes::from_static(b": keep-alive\n\n");
+                return Some((Ok(keep_alive), Some((events, after))));
             }
-
-            return Ok(gif_data);
         }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(stream))
+}
+
+/// Handles job cancellation
+///
+/// Cancels a queued or running job and returns its state, or deletes a finished job and its
+/// result with `204 No Content`.
+pub async fn cancel_job(
+    req: HttpRequest,
+    path: web::Path<String>,
+    jobs: web::Data<JobQueue>,
+) -> Result<HttpResponse> {
+    Ok(match jobs.cancel(owned_job_id(&req, &path, &jobs)?)? {
+        Some(info) => HttpResponse::Ok().json(info),
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+fn job_id(id: &str) -> Result<uuid::Uuid> {
+    uuid::Uuid::parse_str(id).map_err(|_| GpuWorkerError::NotFound(format!("No job {}", id)))
+}
+
+/// Parses a job id, failing with `404 Not Found` as for unknown jobs when the request's API key
+/// is neither the one the job was submitted with nor an admin key
+fn owned_job_id(req: &HttpRequest, id: &str, jobs: &JobQueue) -> Result<uuid::Uuid> {
+    let id = job_id(id)?;
+    let Some(key) = auth::authenticated(req) else {
+        return Ok(id);
+    };
+    if key.allows(Scope::Admin) {
+        return Ok(id);
+    }
+    let spec = jobs.spec(id)?;
+    let owner = spec
+        .get("requester")
+        .and_then(|requester| requester.get("client_id"))
+        .and_then(serde_json::Value::as_str);
+    if owner != Some(key.name.as_str()) {
+        return Err(GpuWorkerError::NotFound(format!("No job {}", id)));
     }
+    Ok(id)
+}
 
-    Err(GpuWorkerError::InvalidInput(
-        "No file field found in multipart data".to_string(),
-    ))
+/// URL of a job's state, under the same prefix as the submission
+fn job_url(req: &HttpRequest, id: uuid::Uuid) -> String {
+    format!("{}/{}", req.path().trim_end_matches('/'), id)
+}
+
+/// Handles the GPU queue endpoint
+///
+/// Reports the load of the GPU scheduler as JSON: permits in use, requests waiting, the
+/// configured limits and how many requests have been turned away.
+pub async fn gpu_queue(scheduler: web::Data<GpuScheduler>) -> HttpResponse {
+    HttpResponse::Ok().json(scheduler.stats())
 }
 
-/// Processes a GIF by mirroring each frame vertically
-async fn process_gif_mirror(
-    gif_data: &[u8],
-    mirror_processor: &MirrorProcessor,
-) -> Result<Vec<u8>> {
-    let (frames, width, height) = decode_gif(gif_data)?;
-    encode_mirrored_gif(frames, width, height, mirror_processor).await
+/// Handles the retime GIF endpoint
+///
+/// Applies the trim and timing options from the query string (frame or time range, speed, delay
+/// clamping, reverse, ping-pong, frame rate) without changing any pixels.
+pub async fn retime_gif(req: HttpRequest, payload: web::Payload) -> Result<HttpResponse> {
+    process_upload(&req, payload, |_| {
+        Ok((Vec::new(), |rgba: &[u8], _, _| Ok(rgba.to_vec())))
+    })
+    .await
 }
 
-/// Decodes a GIF and returns its frames with metadata
-fn decode_gif(gif_data: &[u8]) -> Result<(Vec<gif::Frame<'static>>, u32, u32)> {
-    let cursor = Cursor::new(gif_data);
-    let mut decoder = gif::DecodeOptions::new();
-    decoder.set_color_output(gif::ColorOutput::RGBA);
-    let mut decoder = decoder.read_info(cursor)?;
+/// Handles the extract frame endpoint
+///
+/// Returns a single frame of the uploaded GIF as a PNG, selected by `index` or `time_ms` in the
+/// query string. Defaults to the first frame. Other still formats can be negotiated.
+pub async fn extract_frame(req: HttpRequest, payload: web::Payload) -> Result<HttpResponse> {
+    let selection = query_options::<FrameSelection>(&req, "frame selection")?;
+    selection.validate()?;
+    let upload = extract_gif(&req, payload).await?;
+    let (output_format, still_format) = negotiate_still(&req, &upload.fields)?;
+    let response = output_response(output_format, upload.filename.as_deref(), "frame");
+    let limits = decode_limits(&req);
 
-    let width = decoder.width() as u32;
-    let height = decoder.height() as u32;
-    let mut frames = Vec::new();
+    let (writer, body) = stream::body_channel();
+    stream::spawn_writer(writer, move |output| {
+        let frame = preview::extract_frame(upload.data, &selection, &limits)?;
+        format::encode_still(frame, still_format, output)
+    });
 
-    while let Some(frame) = decoder.read_next_frame()? {
-        frames.push(frame.clone());
-    }
+    body.into_response(response).await
+}
+
+/// Handles the contact sheet endpoint
+///
+/// Returns a PNG grid of evenly spaced frames of the uploaded GIF, configured by the query string
+/// (`frames`, `columns`, `thumb_width`, `labels`). Other still formats can be negotiated.
+pub async fn contact_sheet(req: HttpRequest, payload: web::Payload) -> Result<HttpResponse> {
+    let options = query_options::<ContactSheetOptions>(&req, "contact sheet")?;
+    options.validate()?;
+    let mut upload = extract_gif(&req, payload).await?;
+    let (output_format, still_format) = negotiate_still(&req, &upload.fields)?;
+    let response = output_response(output_format, upload.filename.as_deref(), "contact-sheet");
+    let limits = decode_limits(&req);
+
+    let (writer, body) = stream::body_channel();
+    stream::spawn_writer(writer, move |output| {
+        // Evenly spacing the frames needs the frame count up front, so the GIF is read twice
+        let mut data = Vec::new();
+        upload.data.read_to_end(&mut data)?;
+        let sheet = preview::contact_sheet(&data, &options, &limits)?;
+        format::encode_still(sheet, still_format, output)
+    });
+
+    body.into_response(response).await
+}
+
+/// Handles the inspect endpoint
+///
+/// Reads the structure of the uploaded GIF without decoding any pixels and returns its metadata
+/// as JSON: logical screen, per-frame rectangles, delays and disposal, loop count, palettes,
+/// comments and whether the file is truncated. GIFs with more frames than the decode limits
+/// allow are rejected with `422 Unprocessable Entity`.
+pub async fn inspect_gif(req: HttpRequest, payload: web::Payload) -> Result<HttpResponse> {
+    let upload = extract_gif(&req, payload).await?;
+    let limits = decode_limits(&req);
+
+    let info = tokio::task::spawn_blocking(move || inspect::inspect_gif(upload.data, &limits))
+        .await
+        .map_err(|e| GpuWorkerError::Internal(format!("Inspection task failed: {}", e)))??;
 
-    if frames.is_empty() {
+    Ok(HttpResponse::Ok().json(info))
+}
+
+/// Handles the assemble GIF endpoint
+///
+/// Accepts a multipart form with several image parts (PNG, JPEG or GIF stills) and turns them
+/// into an animated GIF, one frame per image in upload order. Options may be given as query
+/// parameters or as form fields: canvas `width`/`height`, `fit`, one `delay` or per-frame
+/// `delays`, and `loop_count`. Images are scaled onto the canvas on the GPU. APNG output or a
+/// frame archive can be negotiated instead of GIF. API keys need the `resize` operation.
+pub async fn assemble_gif(
+    req: HttpRequest,
+    payload: web::Payload,
+    resize_processor: web::Data<ResizeProcessor>,
+) -> Result<HttpResponse> {
+    let limits = decode_limits(&req);
+    let deadline = limits.deadline();
+    let requester = requester(&req)?;
+    let payload = limited_payload(&req, payload, &limits)?;
+    let (images, fields) =
+        extract_images_from_multipart(Multipart::new(req.headers(), payload)).await?;
+    let options = form_options::<AssembleOptions>(&req, &fields, "assemble")?;
+    options.validate()?;
+    // Every image is resized onto the canvas, whose size may depend on the first image; the
+    // key's operations are checked by name
+    check_operations(
+        &req,
+        &[Operation::Resize {
+            width: options.width.unwrap_or_default(),
+            height: options.height.unwrap_or_default(),
+        }],
+    )?;
+    if images.is_empty() {
         return Err(GpuWorkerError::InvalidInput(
-            "GIF contains no frames".to_string(),
+            "No image files found in multipart data".to_string(),
         ));
     }
+    options.frame_delays(images.len())?;
+    let output_format = negotiate_output(&req, &fields, Format::Gif)?;
+    let encode = EncodeOptions {
+        format: animation_format(output_format)?,
+        ..options.encode_options()
+    };
+    let response = output_response(output_format, None, "assembled");
 
-    log::info!(
-        "Decoded {} frames from GIF ({}x{})",
-        frames.len(),
-        width,
-        height
-    );
-    Ok((frames, width, height))
-}
-
-/// Encodes mirrored frames back into a GIF
-async fn encode_mirrored_gif(
-    frames: Vec<gif::Frame<'static>>,
-    width: u32,
-    height: u32,
-    mirror_processor: &MirrorProcessor,
-) -> Result<Vec<u8>> {
-    let mut output_gif = Vec::new();
-    let mut encoder = create_gif_encoder(&mut output_gif, width as u16, height as u16)?;
-
-    let total_frames = frames.len();
-    for (index, frame) in frames.into_iter().enumerate() {
-        log::info!("Processing frame {}/{}", index + 1, total_frames);
-
-        let rgba_data = normalize_frame_to_rgba(&frame, width, height)?;
-        let mirrored_data = mirror_processor
-            .mirror_vertically(&rgba_data, width, height)
-            .await?;
-        let mirrored_frame =
-            create_mirrored_frame(&frame, &mirrored_data, width as u16, height as u16);
-
-        encoder.write_frame(&mirrored_frame)?;
+    let pool = quantize_pool(&req);
+    let resize_processor = resize_processor.into_inner();
+
+    let permit = gpu_permit(&req, Some(&requester)).await?;
+    let (writer, body) = stream::body_channel();
+    stream::spawn_writer(writer, move |output| {
+        let _permit = permit;
+        let frames = assemble::assemble_frames(images, &options, &limits, |rgba, w, h, tw, th| {
+            Ok(pollster::block_on(
+                resize_processor.resize_image(rgba, w, h, tw, th),
+            )?)
+        })?;
+        let dimensions = frames.dimensions();
+        pipeline::process_frames(
+            frames,
+            dimensions,
+            output,
+            &encode,
+            &pool,
+            WithDeadline {
+                op: |rgba: &[u8], _, _| Ok(rgba.to_vec()),
+                deadline,
+            },
+        )
+    });
+
+    body.into_response(response).await
+}
+
+/// Runs an uploaded image through the operation built by `build_op` and returns it in the
+/// requested format
+///
+/// `build_op` receives the text form fields sent before the file, for operations with options of
+/// their own, and returns the operation along with its normalized [`Operation`] list. GIFs and
+/// APNGs are streamed frame by frame with the request's trim and timing options applied. Comment
+/// and application extensions of GIFs are kept in GIF output unless `strip_metadata` is set.
+/// Stills are decoded whole and processed once; trim and timing options are rejected for them.
+/// Processing stops between frames once the deadline of the request's [`DecodeLimits`] passes.
+///
+/// With a [`ResultCache`] registered with the app, uploads with a `Content-Length` no larger
+/// than the largest cached result are read whole instead and served through the cache, see
+/// [`cached_response`].
+async fn process_upload<B, O>(
+    req: &HttpRequest,
+    payload: web::Payload,
+    build_op: B,
+) -> Result<HttpResponse>
+where
+    B: FnOnce(&[(String, String)]) -> Result<(Vec<Operation>, O)>,
+    O: FrameOp + Send + 'static,
+{
+    let limits = decode_limits(req);
+    let deadline = limits.deadline();
+    let requester = requester(req)?;
+    let trim = query_options::<TrimOptions>(req, "trim")?;
+    trim.validate()?;
+    let timing = query_options::<TimingOptions>(req, "timing")?;
+    timing.validate()?;
+    let upload = extract_upload(req, payload).await?;
+    let format = upload.format;
+    let output_format = negotiate_output(req, &upload.fields, format)?;
+    let metadata = form_options::<MetadataOptions>(req, &upload.fields, "metadata")?;
+    let (operations, process) = build_op(&upload.fields)?;
+    check_operations(req, &operations)?;
+    let process = WithDeadline {
+        op: process,
+        deadline,
+    };
+
+    let gpu = operations
+        .iter()
+        .any(Operation::uses_gpu)
+        .then_some(requester);
+    let changes_timing = !trim.is_identity() || !timing.is_identity();
+    if !format.is_animated() && changes_timing {
+        return Err(GpuWorkerError::InvalidInput(format!(
+            "Trim and timing options only apply to animations, got {}",
+            format.mime_type()
+        )));
     }
 
-    drop(encoder);
-    Ok(output_gif)
-}
-
-/// Creates a GIF encoder with proper settings
-fn create_gif_encoder(
-    output: &mut Vec<u8>,
-    width: u16,
-    height: u16,
-) -> Result<Encoder<&mut Vec<u8>>> {
-    let mut encoder = Encoder::new(output, width, height, &[])?;
-    encoder.set_repeat(Repeat::Infinite)?;
-    Ok(encoder)
-}
-
-/// Normalizes frame buffer to RGBA format
-fn normalize_frame_to_rgba(frame: &gif::Frame, width: u32, height: u32) -> Result<Vec<u8>> {
-    let expected_rgba_len = (width * height * 4) as usize;
-    let expected_rgb_len = (width * height * 3) as usize;
-
-    match frame.buffer.len() {
-        len if len == expected_rgba_len => Ok(frame.buffer.to_vec()),
-        len if len == expected_rgb_len => {
-            // Convert RGB to RGBA
-            let mut rgba = Vec::with_capacity(expected_rgba_len);
-            for chunk in frame.buffer.chunks(3) {
-                rgba.extend_from_slice(chunk);
-                rgba.push(255); // Alpha channel
+    let options = ProcessOptions {
+        trim,
+        timing,
+        metadata,
+        limits,
+        pool: quantize_pool(req),
+    };
+    // The key covers the whole input, so only small uploads are worth buffering
+    let cache = req.app_data::<web::Data<ResultCache>>().filter(|cache| {
+        content_length(req).is_some_and(|length| length <= cache.max_entry_bytes())
+    });
+    if let Some(cache) = cache {
+        let processing = Processing {
+            operations,
+            output_format,
+            trim: options.trim.clone(),
+            timing: options.timing.clone(),
+            metadata: options.metadata,
+            limits: options.limits,
+        };
+        return cached_response(
+            req,
+            cache.clone(),
+            upload,
+            processing,
+            options,
+            process,
+            gpu,
+        )
+        .await;
+    }
+
+    let response = output_response(output_format, upload.filename.as_deref(), "image");
+    let permit = gpu_permit(req, gpu.as_ref()).await?;
+    let (writer, body) = stream::body_channel();
+    stream::spawn_writer(writer, move |output| {
+        let _permit = permit;
+        process_input(
+            upload.data,
+            format,
+            output_format,
+            &options,
+            output,
+            process,
+        )
+    });
+
+    body.into_response(response).await
+}
+
+/// Everything that determines the result of [`process_upload`] besides the input, hashed into
+/// its cache key
+#[derive(Serialize)]
+struct Processing {
+    operations: Vec<Operation>,
+    output_format: OutputFormat,
+    trim: TrimOptions,
+    timing: TimingOptions,
+    metadata: MetadataOptions,
+    /// Endpoints with stricter limits must not serve results of inputs they would reject.
+    limits: DecodeLimits,
+}
+
+/// Serves the result of processing an upload from `cache`, processing and caching it on a miss
+///
+/// The response carries the cache key as a strong `ETag` and `X-Cache: HIT` or `MISS`. A request
+/// whose `If-None-Match` lists the key gets `304 Not Modified` without any processing, whether or
+/// not the result is still cached. Only misses wait for the GPU, if `gpu` names whom for. Misses
+/// stream their output like uncached requests and are cached once complete, unless they grow
+/// larger than the largest cached result.
+async fn cached_response<O>(
+    req: &HttpRequest,
+    cache: web::Data<ResultCache>,
+    upload: Upload,
+    processing: Processing,
+    options: ProcessOptions,
+    process: O,
+    gpu: Option<Requester>,
+) -> Result<HttpResponse>
+where
+    O: FrameOp + Send + 'static,
+{
+    let format = upload.format;
+    let output_format = processing.output_format;
+    let mut response = output_response(output_format, upload.filename.as_deref(), "image");
+    let mut data = upload.data;
+    let (input, key) = web::block(move || {
+        let mut input = Vec::new();
+        data.read_to_end(&mut input)?;
+        let key = CacheKey::new(&input, &processing);
+        Ok::<_, GpuWorkerError>((input, key))
+    })
+    .await
+    .map_err(|e| GpuWorkerError::Internal(e.to_string()))??;
+
+    let etag = header::ETag(header::EntityTag::new_strong(key.hex()));
+    let not_modified = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return Ok(HttpResponse::NotModified().insert_header(etag).finish());
+    }
+
+    let cached = {
+        let cache = cache.clone();
+        web::block(move || cache.get(&key))
+            .await
+            .map_err(|e| GpuWorkerError::Internal(e.to_string()))?
+    };
+    response.insert_header(etag);
+    if let Some((output, tier)) = cached {
+        log::debug!("Cache hit for {} in {:?}", key.hex(), tier);
+        return Ok(response.insert_header((CACHE_HEADER, "HIT")).body(output));
+    }
+
+    let permit = gpu_permit(req, gpu.as_ref()).await?;
+    let max_bytes = cache.max_entry_bytes();
+    let (writer, body) = stream::body_channel();
+    stream::spawn_writer(writer, move |output| {
+        let _permit = permit;
+        let mut output = Recorder::new(output, max_bytes);
+        process_input(
+            &input[..],
+            format,
+            output_format,
+            &options,
+            &mut output,
+            process,
+        )?;
+        if let Some(output) = output.into_copy() {
+            cache.insert(key, output);
+        }
+        Ok(())
+    });
+
+    response.insert_header((CACHE_HEADER, "MISS"));
+    body.into_response(response).await
+}
+
+/// Everything besides the operation that [`process_input`] needs
+struct ProcessOptions {
+    trim: TrimOptions,
+    timing: TimingOptions,
+    metadata: MetadataOptions,
+    limits: DecodeLimits,
+    pool: QuantizePool,
+}
+
+/// Decodes an upload, runs it through `process` and encodes it in `output_format`
+fn process_input<R, W, O>(
+    data: R,
+    format: Format,
+    output_format: OutputFormat,
+    options: &ProcessOptions,
+    output: W,
+    process: O,
+) -> Result<()>
+where
+    R: Read + Send,
+    W: Write,
+    O: FrameOp,
+{
+    let ProcessOptions {
+        trim,
+        timing,
+        metadata,
+        limits,
+        pool,
+    } = options;
+
+    match output_format {
+        OutputFormat::Image(still) if !still.is_animated() => {
+            process_still(data, format, still, limits, output, process)
+        }
+        _ => {
+            let encode = EncodeOptions {
+                format: animation_format(output_format)?,
+                ..EncodeOptions::default()
+            };
+            if format == Format::Gif && !metadata.strip_metadata {
+                let data = MetadataTap::new(data);
+                let encode = EncodeOptions {
+                    metadata: data.metadata(),
+                    ..encode
+                };
+                let frames = pipeline::decode_animation(data, format, limits)?;
+                process_animation(frames, output, &encode, trim, timing, pool, process)
+            } else {
+                let frames = pipeline::decode_animation(data, format, limits)?;
+                process_animation(frames, output, &encode, trim, timing, pool, process)
             }
-            Ok(rgba)
         }
-        len => Err(GpuWorkerError::InvalidInput(format!(
-            "Unexpected frame buffer size: {} bytes (expected {} or {} bytes)",
-            len, expected_rgba_len, expected_rgb_len
-        ))),
     }
 }
 
-/// Creates a new GIF frame with mirrored data, preserving original frame properties
-fn create_mirrored_frame(
-    original: &gif::Frame,
-    mirrored_rgba: &[u8],
-    width: u16,
-    height: u16,
-) -> Frame<'static> {
-    // Convert RGBA back to RGB for GIF encoding
-    let rgb_data: Vec<u8> = mirrored_rgba
-        .chunks(4)
-        .flat_map(|rgba| &rgba[..3])
-        .copied()
-        .collect();
-
-    let mut frame = Frame::from_rgb_speed(width, height, &rgb_data, 10);
-
-    // Preserve original frame properties
-    frame.delay = original.delay;
-    frame.dispose = original.dispose;
-    frame.transparent = original.transparent;
-    frame.needs_user_input = original.needs_user_input;
-    frame.top = original.top;
-    frame.left = original.left;
-    // Keep the new dimensions we specified, not the original ones
-    // frame.width and frame.height are already set by from_rgb_speed
-    frame.interlaced = original.interlaced;
-
-    frame
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_normalize_rgba_buffer() {
-        let frame = gif::Frame {
-            buffer: vec![255; 12].into(), // 2x2 RGBA
-            ..Default::default()
-        };
+/// Picks the output format for a result derived from `input` from the `format` parameter (query
+/// string or form field) and the `Accept` header
+fn negotiate_output(
+    req: &HttpRequest,
+    fields: &[(String, String)],
+    input: Format,
+) -> Result<OutputFormat> {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
+    form_options::<OutputOptions>(req, fields, "output")?
+        .negotiate(accept, &OutputFormat::candidates(input))
+}
 
-        let result = normalize_frame_to_rgba(&frame, 2, 2);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap().len(), 16); // 2x2x4
+/// Like [`negotiate_output`], for endpoints that produce a still image
+fn negotiate_still(
+    req: &HttpRequest,
+    fields: &[(String, String)],
+) -> Result<(OutputFormat, Format)> {
+    match negotiate_output(req, fields, Format::Png)? {
+        output_format @ OutputFormat::Image(format) => Ok((output_format, format)),
+        OutputFormat::Zip => Err(GpuWorkerError::Internal(
+            "Still output negotiated an archive".to_string(),
+        )),
     }
+}
 
-    #[test]
-    fn test_normalize_rgb_to_rgba() {
-        let frame = gif::Frame {
-            buffer: vec![100, 150, 200, 50, 75, 100].into(), // 2x1 RGB
-            ..Default::default()
-        };
+/// Returns the frame pipeline encoding for an animated output format
+fn animation_format(output_format: OutputFormat) -> Result<pipeline::AnimationFormat> {
+    output_format.animation_format().ok_or_else(|| {
+        GpuWorkerError::Internal(format!(
+            "{} is not an animation format",
+            output_format.mime_type()
+        ))
+    })
+}
 
-        let result = normalize_frame_to_rgba(&frame, 2, 1).unwrap();
-        assert_eq!(result, vec![100, 150, 200, 255, 50, 75, 100, 255]);
-    }
+/// Starts a successful response for a body in `format`, named after the uploaded file
+///
+/// Images are served inline so browsers display them; frame archives are attachments.
+fn output_response(
+    format: OutputFormat,
+    upload_name: Option<&str>,
+    fallback: &str,
+) -> HttpResponseBuilder {
+    let disposition = match format {
+        OutputFormat::Zip => DispositionType::Attachment,
+        OutputFormat::Image(_) => DispositionType::Inline,
+    };
 
-    #[test]
-    fn test_create_mirrored_frame_preserves_properties() {
-        let original = gif::Frame {
-            delay: 10,
-            dispose: gif::DisposalMethod::Background,
-            transparent: Some(5),
-            ..Default::default()
-        };
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(format.mime_type())
+        .insert_header(ContentDisposition {
+            disposition,
+            parameters: vec![DispositionParam::Filename(
+                format.file_name(upload_name, fallback),
+            )],
+        });
+    response
+}
+
+/// Parses one group of options from the query string
+fn query_options<T: serde::de::DeserializeOwned>(req: &HttpRequest, name: &str) -> Result<T> {
+    parse_options(req.query_string(), name)
+}
+
+/// Parses one group of options from the query string combined with text form fields
+fn form_options<T: serde::de::DeserializeOwned>(
+    req: &HttpRequest,
+    fields: &[(String, String)],
+    name: &str,
+) -> Result<T> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        GpuWorkerError::InvalidInput(format!("Invalid {} options: {}", name, e))
+    };
+
+    let mut pairs: Vec<(String, String)> =
+        serde_urlencoded::from_str(req.query_string()).map_err(|e| invalid(&e))?;
+    pairs.extend_from_slice(fields);
+    let query = serde_urlencoded::to_string(&pairs).map_err(|e| invalid(&e))?;
+    parse_options(&query, name)
+}
 
-        let mirrored_rgba = vec![100, 150, 200, 255]; // 1x1 RGBA
-        let frame = create_mirrored_frame(&original, &mirrored_rgba, 1, 1);
+fn parse_options<T: serde::de::DeserializeOwned>(query: &str, name: &str) -> Result<T> {
+    web::Query::<T>::from_query(query)
+        .map(web::Query::into_inner)
+        .map_err(|e| GpuWorkerError::InvalidInput(format!("Invalid {} options: {}", name, e)))
+}
+
+/// Returns the quantization pool registered with the app, falling back to the shared default
+fn quantize_pool(req: &HttpRequest) -> QuantizePool {
+    req.app_data::<web::Data<QuantizePool>>()
+        .map(|pool| pool.get_ref().clone())
+        .unwrap_or_else(QuantizePool::global)
+}
 
-        assert_eq!(frame.delay, 10);
-        assert_eq!(frame.dispose, gif::DisposalMethod::Background);
-        assert_eq!(frame.transparent, Some(5));
+/// Waits for a permit from the GPU scheduler registered with the app, for GPU work of
+/// `requester`; work without a requester does not use the GPU
+///
+/// Without a scheduler, GPU work is not limited.
+async fn gpu_permit(req: &HttpRequest, requester: Option<&Requester>) -> Result<Option<GpuPermit>> {
+    match (req.app_data::<web::Data<GpuScheduler>>(), requester) {
+        (Some(scheduler), Some(requester)) => scheduler.acquire(requester).await.map(Some),
+        _ => Ok(None),
     }
+}
 
-    #[test]
-    fn test_normalize_frame_invalid_size() {
-        let frame = gif::Frame {
-            buffer: vec![255; 10].into(), // Invalid size
-            ..Default::default()
-        };
+/// Fails with `403 Forbidden` when the request's API key may not run all of `operations`.
+fn check_operations(req: &HttpRequest, operations: &[Operation]) -> Result<()> {
+    match auth::authenticated(req) {
+        Some(key) => key.check_operations(operations),
+        None => Ok(()),
+    }
+}
 
-        let result = normalize_frame_to_rgba(&frame, 2, 2);
-        assert!(result.is_err());
-        assert!(matches!(
-            result.unwrap_err(),
-            GpuWorkerError::InvalidInput(_)
-        ));
+/// Identifies whom the request's work is for: the name of the request's API key, the client
+/// named by the `X-Client-Id` header, or else the peer's IP address, at the priority given by
+/// the `X-Priority` header, capped at the key's maximum
+fn requester(req: &HttpRequest) -> Result<Requester> {
+    let header = |name: &str| -> Result<Option<&str>> {
+        req.headers()
+            .get(name)
+            .map(|value| {
+                value.to_str().map(str::trim).map_err(|_| {
+                    GpuWorkerError::InvalidInput(format!("{} must be visible ASCII", name))
+                })
+            })
+            .transpose()
+    };
+
+    let priority = match header(PRIORITY_HEADER)? {
+        Some(name) => Priority::from_name(name).ok_or_else(|| {
+            GpuWorkerError::InvalidInput(format!(
+                "{} must be interactive, default or bulk, got {:?}",
+                PRIORITY_HEADER, name
+            ))
+        })?,
+        None => Priority::default(),
+    };
+    let key = auth::authenticated(req);
+    let priority = match &key {
+        Some(key) => key.priority(priority),
+        None => priority,
+    };
+    let client_id = match (key, header(CLIENT_ID_HEADER)?.filter(|id| !id.is_empty())) {
+        // Clients with a key must not pass as one another
+        (Some(key), _) => key.name.clone(),
+        (None, Some(id)) if id.len() > MAX_CLIENT_ID_LEN => {
+            return Err(GpuWorkerError::InvalidInput(format!(
+                "{} must be at most {} bytes",
+                CLIENT_ID_HEADER, MAX_CLIENT_ID_LEN
+            )))
+        }
+        (None, Some(id)) => id.to_string(),
+        (None, None) => req
+            .peer_addr()
+            .map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string()),
+    };
+    Ok(Requester {
+        client_id,
+        priority,
+    })
+}
+
+/// Returns the decode limits registered with the app, falling back to the defaults
+fn decode_limits(req: &HttpRequest) -> DecodeLimits {
+    req.app_data::<web::Data<DecodeLimits>>()
+        .map(|limits| *limits.get_ref())
+        .unwrap_or_default()
+}
+
+/// Rejects a request whose declared length exceeds the upload limit and caps the body as it
+/// streams in, for bodies without a length or with a wrong one
+fn limited_payload(
+    req: &HttpRequest,
+    payload: web::Payload,
+    limits: &DecodeLimits,
+) -> Result<impl Stream<Item = std::result::Result<Bytes, PayloadError>> + Unpin> {
+    if let Some(length) = content_length(req) {
+        limits.check_upload_size(length)?;
     }
+    Ok(limits.limit_body(payload))
+}
+
+/// Declared length of the request body.
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+}
+
+/// An uploaded image whose format has been identified from its first bytes.
+struct Upload {
+    format: Format,
+    /// File name given by the client, if any.
+    filename: Option<String>,
+    /// Text fields sent before the file.
+    fields: Vec<(String, String)>,
+    data: ChannelReader,
+}
 
-    #[test]
-    fn test_create_gif_encoder_success() {
-        let mut output = Vec::new();
-        let result = create_gif_encoder(&mut output, 100, 100);
-        assert!(result.is_ok());
+/// Reads the uploaded image from a multipart form or, for any other content type, from the raw
+/// request body
+///
+/// Raw bodies must be declared as `image/*` or `application/octet-stream`; their options come
+/// from the query string alone.
+async fn extract_upload(req: &HttpRequest, payload: web::Payload) -> Result<Upload> {
+    let mime = req
+        .mime_type()
+        .map_err(|e| GpuWorkerError::InvalidInput(format!("Invalid Content-Type: {}", e)))?;
+    let mut payload = limited_payload(req, payload, &decode_limits(req))?;
+
+    match mime {
+        Some(mime) if mime.essence_str() == "multipart/form-data" => {
+            extract_multipart_upload(Multipart::new(req.headers(), payload)).await
+        }
+        Some(mime)
+            if mime.type_() == "image" || mime.essence_str() == "application/octet-stream" =>
+        {
+            let (format, head) = sniff_upload(&mut payload).await?;
+            Ok(Upload {
+                format,
+                filename: None,
+                fields: Vec::new(),
+                data: ChannelReader::spawn(head, payload, ()),
+            })
+        }
+        mime => Err(GpuWorkerError::UnsupportedMediaType(format!(
+            "Expected multipart/form-data or an image body, got {}",
+            mime.as_ref()
+                .map_or("no Content-Type", |mime| mime.essence_str())
+        ))),
     }
+}
 
-    #[test]
-    fn test_create_mirrored_frame_rgb_conversion() {
-        let original = gif::Frame::default();
-        let mirrored_rgba = vec![255, 0, 0, 255, 0, 255, 0, 255]; // 2x1 RGBA (red, green)
-        let frame = create_mirrored_frame(&original, &mirrored_rgba, 2, 1);
+/// Locates the `file` field in multipart form data, sniffs its format and streams its contents
+/// into a reader
+///
+/// Text fields before the file are collected; anything after it is ignored, since the file is
+/// streamed.
+async fn extract_multipart_upload(mut payload: Multipart) -> Result<Upload> {
+    let mut fields = Vec::new();
 
-        // Verify frame dimensions are correct
-        assert_eq!(frame.width, 2);
-        assert_eq!(frame.height, 1);
+    while let Some(mut field) = payload.try_next().await? {
+        let content_disposition = field.content_disposition();
+        let filename = content_disposition.get_filename().map(str::to_string);
+        let name = content_disposition
+            .get_name()
+            .unwrap_or_default()
+            .to_string();
 
-        // Note: Frame::from_rgb_speed may create a palette-based frame
-        // rather than storing raw RGB data, so we don't test the buffer contents
-        // The important thing is that the frame is created successfully with correct dimensions
+        if name != "file" {
+            if filename.is_none() {
+                let value = read_text_field(&mut field, &name).await?;
+                fields.push((name, value));
+            }
+            continue;
+        }
+
+        let (format, head) = sniff_upload(&mut field).await?;
+        return Ok(Upload {
+            format,
+            filename,
+            fields,
+            data: ChannelReader::spawn(head, field, payload),
+        });
     }
 
-    #[test]
-    fn test_decode_gif_empty_frames() {
-        // Create a minimal GIF header without frames
-        let gif_data = vec![
-            b'G', b'I', b'F', b'8', b'9', b'a', // Header
-            1, 0, 1, 0, // Width: 1, Height: 1
-            0, 0, 0,    // Global color table info
-            0x3B, // Trailer
-        ];
-
-        let result = decode_gif(&gif_data);
-        assert!(result.is_err());
-        // The GIF decoder will throw a decoding error for malformed GIF data
-        match result.unwrap_err() {
-            GpuWorkerError::GifDecode(_) | GpuWorkerError::InvalidInput(_) => (),
-            _ => panic!("Expected GifDecode or InvalidInput error"),
+    Err(GpuWorkerError::InvalidInput(
+        "No file field found in multipart data".to_string(),
+    ))
+}
+
+/// Reads enough of an uploaded file to identify its format and returns the bytes read so far
+///
+/// PNGs are buffered up to their image data to tell animated ones apart. Formats that cannot be
+/// processed are rejected with `415 Unsupported Media Type`.
+async fn sniff_upload<S, E>(body: &mut S) -> Result<(Format, Bytes)>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+    GpuWorkerError: From<E>,
+{
+    let mut head = BytesMut::new();
+    while head.len() < SNIFF_LEN {
+        match body.try_next().await? {
+            Some(chunk) => head.extend_from_slice(&chunk),
+            None => break,
         }
     }
 
-    #[test]
-    fn test_normalize_frame_edge_cases() {
-        // Test empty frame
-        let frame = gif::Frame {
-            buffer: vec![].into(),
-            ..Default::default()
-        };
-        let result = normalize_frame_to_rgba(&frame, 0, 0);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap().len(), 0);
-
-        // Test 1x1 RGBA frame
-        let frame = gif::Frame {
-            buffer: vec![255, 0, 0, 255].into(),
-            ..Default::default()
+    if head.is_empty() {
+        return Err(GpuWorkerError::InvalidInput(
+            "Empty file provided".to_string(),
+        ));
+    }
+    let mut format = Format::sniff_supported(&head)?;
+
+    if format == Format::Png {
+        let animated = loop {
+            if let Some(animated) = format::png_is_animated(&head) {
+                break animated;
+            }
+            match body.try_next().await? {
+                Some(chunk) => head.extend_from_slice(&chunk),
+                None => break false,
+            }
         };
-        let result = normalize_frame_to_rgba(&frame, 1, 1);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), vec![255, 0, 0, 255]);
+        if animated {
+            format = Format::Apng;
+        }
     }
 
-    // Note: Multipart extraction tests are better suited for integration tests
-    // due to the complexity of mocking the actix-multipart stream interface.
-    // These tests would require actual HTTP request context to work properly.
+    Ok((format, head.freeze()))
+}
+
+/// Like [`extract_upload`], for endpoints that only work on GIFs
+async fn extract_gif(req: &HttpRequest, payload: web::Payload) -> Result<Upload> {
+    let upload = extract_upload(req, payload).await?;
+    if upload.format != Format::Gif {
+        return Err(GpuWorkerError::UnsupportedMediaType(format!(
+            "This endpoint only accepts GIF images, got {}",
+            upload.format.mime_type()
+        )));
+    }
+    Ok(upload)
+}
+
+/// Reads a text form field of at most [`MAX_FORM_FIELD_SIZE`] bytes
+async fn read_text_field(field: &mut Field, name: &str) -> Result<String> {
+    let mut data = BytesMut::new();
+    while let Some(chunk) = field.try_next().await? {
+        if data.len() + chunk.len() > MAX_FORM_FIELD_SIZE {
+            return Err(GpuWorkerError::InvalidInput(format!(
+                "Form field {} is larger than {} bytes",
+                name, MAX_FORM_FIELD_SIZE
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    String::from_utf8(data.to_vec()).map_err(|_| {
+        GpuWorkerError::InvalidInput(format!("Form field {} is not valid UTF-8", name))
+    })
+}
+
+/// Collects every uploaded file in multipart form data, in order, along with the text fields
+async fn extract_images_from_multipart(
+    mut payload: Multipart,
+) -> Result<(Vec<Bytes>, Vec<(String, String)>)> {
+    let mut images = Vec::new();
+    let mut fields = Vec::new();
+
+    while let Some(mut field) = payload.try_next().await? {
+        let content_disposition = field.content_disposition();
+        let is_file = content_disposition.get_filename().is_some();
+        let name = content_disposition
+            .get_name()
+            .unwrap_or_default()
+            .to_string();
+
+        if !is_file {
+            let value = read_text_field(&mut field, &name).await?;
+            fields.push((name, value));
+            continue;
+        }
+
+        let mut data = BytesMut::new();
+        while let Some(chunk) = field.try_next().await? {
+            data.extend_from_slice(&chunk);
+        }
+
+        if data.is_empty() {
+            return Err(GpuWorkerError::InvalidInput(format!(
+                "Image {} is empty",
+                images.len() + 1
+            )));
+        }
+        images.push(data.freeze());
+    }
+
+    Ok((images, fields))
+}
+
+/// Applies trim and timing options to decoded animation frames and runs each through `process`
+fn process_animation<R, W, O>(
+    frames: AnimationFrames<R>,
+    output: W,
+    encode: &EncodeOptions,
+    trim: &TrimOptions,
+    timing: &TimingOptions,
+    pool: &QuantizePool,
+    process: O,
+) -> Result<()>
+where
+    R: Read + Send,
+    W: Write,
+    O: FrameOp,
+{
+    let dimensions = frames.dimensions();
+    let frames = trim.apply_to_frames(frames);
+    let frames = timing.apply_to_frames(frames)?;
+    pipeline::process_frames(frames, dimensions, output, encode, pool, process)
+}
+
+/// Decodes a still image, runs it through `process` and encodes it in `output_format`
+fn process_still<R, W, O>(
+    mut data: R,
+    format: Format,
+    output_format: Format,
+    limits: &DecodeLimits,
+    output: W,
+    process: O,
+) -> Result<()>
+where
+    R: Read,
+    W: Write,
+    O: FrameOp,
+{
+    let mut encoded = Vec::new();
+    data.read_to_end(&mut encoded)?;
+
+    let image = format::decode_still(&encoded, format, limits)?;
+    let (width, height) = image.dimensions();
+    log::info!(
+        "Processing {} still ({}x{})",
+        format.extension(),
+        width,
+        height
+    );
+
+    let (output_width, output_height) = pipeline::output_dimensions(&process, (width, height))?;
+    let processed = process.apply(image.as_raw(), width, height)?;
+    let processed =
+        image::RgbaImage::from_raw(output_width, output_height, processed).ok_or_else(|| {
+            GpuWorkerError::ImageProcessing(format!(
+                "Processed image does not match its {}x{} output size",
+                output_width, output_height
+            ))
+        })?;
+    format::encode_still(processed, output_format, output)
 }
@@ -0,0 +1,523 @@
+//! GIF inspection without decoding pixels.
+//!
+//! [`GifBlocks`] walks the block structure of a GIF (screen descriptor, extensions, image
+//! descriptors) and skips over compressed image data, so inspecting even a large animation only
+//! costs a pass over its bytes. [`inspect_gif`] summarizes the blocks into a [`GifInfo`].
+//! Unlike `gif::Decoder`, the scanner exposes comment and application extensions, which carry
+//! the loop count.
+
+use std::io::{self, BufRead, BufReader, Read};
+
+use serde::Serialize;
+
+use crate::{
+    error::{GpuWorkerError, Result},
+    limits::DecodeLimits,
+};
+
+const EXTENSION_INTRODUCER: u8 = 0x21;
+const IMAGE_SEPARATOR: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+
+/// Label of the graphic control extension.
+pub const GRAPHIC_CONTROL_LABEL: u8 = 0xF9;
+/// Label of the comment extension.
+pub const COMMENT_LABEL: u8 = 0xFE;
+/// Label of the application extension.
+pub const APPLICATION_LABEL: u8 = 0xFF;
+
+/// The logical screen descriptor at the start of every GIF.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScreenDescriptor {
+    /// `87a` or `89a`.
+    pub version: String,
+    pub width: u16,
+    pub height: u16,
+    /// Number of entries in the global color table, if there is one.
+    pub global_palette_size: Option<usize>,
+    pub background_index: u8,
+}
+
+/// The descriptor of one image (frame) in a GIF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageDescriptor {
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+    /// Number of entries in the local color table, if there is one.
+    pub local_palette_size: Option<usize>,
+    pub interlaced: bool,
+}
+
+/// One block of a GIF, see [`GifBlocks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block {
+    /// An extension with its label and the contents of its data sub-blocks.
+    Extension { label: u8, sub_blocks: Vec<Vec<u8>> },
+    /// An image descriptor; the image data following it has been skipped.
+    Image(ImageDescriptor),
+    /// The trailer marking the end of the GIF.
+    Trailer,
+}
+
+/// Iterates over the blocks of a GIF without decompressing image data.
+///
+/// A stream that ends before the trailer yields an [`io::ErrorKind::UnexpectedEof`] error, which
+/// callers can report as a truncated file.
+pub struct GifBlocks<R> {
+    reader: BufReader<R>,
+    screen: ScreenDescriptor,
+    done: bool,
+}
+
+impl<R> std::fmt::Debug for GifBlocks<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GifBlocks")
+            .field("screen", &self.screen)
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read> GifBlocks<R> {
+    /// Reads the header and logical screen descriptor.
+    pub fn new(gif_data: R) -> Result<Self> {
+        let mut reader = BufReader::new(gif_data);
+
+        let mut header = [0u8; 13];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => not_a_gif(),
+            _ => GpuWorkerError::Io(e),
+        })?;
+        if &header[..3] != b"GIF" || !matches!(&header[3..6], b"87a" | b"89a") {
+            return Err(not_a_gif());
+        }
+
+        let flags = header[10];
+        let global_palette_size = palette_size(flags);
+        if let Some(entries) = global_palette_size {
+            skip(&mut reader, entries * 3)?;
+        }
+
+        Ok(Self {
+            reader,
+            screen: ScreenDescriptor {
+                version: String::from_utf8_lossy(&header[3..6]).into_owned(),
+                width: u16::from_le_bytes([header[6], header[7]]),
+                height: u16::from_le_bytes([header[8], header[9]]),
+                global_palette_size,
+                background_index: header[11],
+            },
+            done: false,
+        })
+    }
+
+    /// The logical screen descriptor.
+    pub fn screen(&self) -> &ScreenDescriptor {
+        &self.screen
+    }
+
+    fn read_block(&mut self) -> io::Result<Block> {
+        match read_u8(&mut self.reader)? {
+            EXTENSION_INTRODUCER => {
+                let label = read_u8(&mut self.reader)?;
+                let mut sub_blocks = Vec::new();
+                loop {
+                    let len = read_u8(&mut self.reader)? as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let mut data = vec![0; len];
+                    self.reader.read_exact(&mut data)?;
+                    sub_blocks.push(data);
+                }
+                Ok(Block::Extension { label, sub_blocks })
+            }
+            IMAGE_SEPARATOR => {
+                let mut descriptor = [0u8; 9];
+                self.reader.read_exact(&mut descriptor)?;
+                let field = |i: usize| u16::from_le_bytes([descriptor[i], descriptor[i + 1]]);
+                let flags = descriptor[8];
+                let local_palette_size = palette_size(flags);
+                if let Some(entries) = local_palette_size {
+                    skip(&mut self.reader, entries * 3)?;
+                }
+
+                // LZW minimum code size, then the compressed data sub-blocks
+                read_u8(&mut self.reader)?;
+                loop {
+                    let len = read_u8(&mut self.reader)? as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    skip(&mut self.reader, len)?;
+                }
+
+                Ok(Block::Image(ImageDescriptor {
+                    left: field(0),
+                    top: field(2),
+                    width: field(4),
+                    height: field(6),
+                    local_palette_size,
+                    interlaced: flags & 0x40 != 0,
+                }))
+            }
+            TRAILER => Ok(Block::Trailer),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown GIF block type 0x{:02X}", other),
+            )),
+        }
+    }
+}
+
+impl<R: Read> Iterator for GifBlocks<R> {
+    type Item = io::Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let block = self.read_block();
+        self.done = matches!(block, Ok(Block::Trailer) | Err(_));
+        Some(block)
+    }
+}
+
+/// How a frame is disposed of before the next one is drawn.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disposal {
+    /// No disposal specified; decoders leave the frame in place.
+    #[default]
+    Unspecified,
+    /// Leave the frame in place.
+    Keep,
+    /// Clear the frame's area to the background.
+    Background,
+    /// Restore the area to what it was before the frame was drawn.
+    Previous,
+}
+
+impl Disposal {
+    fn from_flags(flags: u8) -> Self {
+        match (flags >> 2) & 0x07 {
+            1 => Self::Keep,
+            2 => Self::Background,
+            3 => Self::Previous,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+/// Metadata of one frame, as reported by [`inspect_gif`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FrameInfo {
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+    pub delay_ms: u32,
+    pub disposal: Disposal,
+    pub transparent_index: Option<u8>,
+    /// Number of entries in the frame's local color table, if it has one.
+    pub local_palette_size: Option<usize>,
+    pub interlaced: bool,
+}
+
+/// Metadata of a whole GIF, as reported by [`inspect_gif`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GifInfo {
+    pub version: String,
+    pub width: u16,
+    pub height: u16,
+    pub frame_count: usize,
+    pub total_duration_ms: u64,
+    /// Repetitions after the first play from the NETSCAPE2.0 extension; `0` loops forever and
+    /// `None` means the extension is missing and the animation plays once.
+    pub loop_count: Option<u16>,
+    /// Number of entries in the global color table, if there is one.
+    pub global_palette_size: Option<usize>,
+    pub background_index: u8,
+    pub comments: Vec<String>,
+    /// The file ends before the GIF trailer. A frame cut off by the end is not counted.
+    pub truncated: bool,
+    pub frames: Vec<FrameInfo>,
+}
+
+/// Reads the structure of a GIF and summarizes it without decoding any image data
+///
+/// Files that end early are reported with `truncated` set rather than rejected, so broken uploads
+/// can still be inspected. Data that is not a GIF at all, or has a corrupt block structure, is
+/// an error, and so are more frames than `limits` allow.
+pub fn inspect_gif<R: Read>(gif_data: R, limits: &DecodeLimits) -> Result<GifInfo> {
+    let mut blocks = GifBlocks::new(gif_data)?;
+    let screen = blocks.screen().clone();
+
+    let mut info = GifInfo {
+        version: screen.version,
+        width: screen.width,
+        height: screen.height,
+        frame_count: 0,
+        total_duration_ms: 0,
+        loop_count: None,
+        global_palette_size: screen.global_palette_size,
+        background_index: screen.background_index,
+        comments: Vec::new(),
+        truncated: false,
+        frames: Vec::new(),
+    };
+    let mut control: Option<(u8, u16, Option<u8>)> = None;
+
+    for block in &mut blocks {
+        let block = match block {
+            Ok(block) => block,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                info.truncated = true;
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Err(GpuWorkerError::InvalidInput(e.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match block {
+            Block::Extension { label, sub_blocks } => match label {
+                GRAPHIC_CONTROL_LABEL => {
+                    if let Some([flags, delay_lo, delay_hi, transparent, ..]) =
+                        sub_blocks.first().map(Vec::as_slice)
+                    {
+                        let transparent = (flags & 0x01 != 0).then_some(*transparent);
+                        let delay = u16::from_le_bytes([*delay_lo, *delay_hi]);
+                        control = Some((*flags, delay, transparent));
+                    }
+                }
+                COMMENT_LABEL => {
+                    let text = sub_blocks.concat();
+                    info.comments
+                        .push(String::from_utf8_lossy(&text).into_owned());
+                }
+                APPLICATION_LABEL => {
+                    if let Some(count) = loop_count(&sub_blocks) {
+                        info.loop_count = Some(count);
+                    }
+                }
+                _ => {}
+            },
+            Block::Image(descriptor) => {
+                limits.check_frames(info.frames.len() + 1)?;
+                let (flags, delay, transparent_index) = control.take().unwrap_or_default();
+                let delay_ms = delay as u32 * 10;
+                info.total_duration_ms += delay_ms as u64;
+                info.frames.push(FrameInfo {
+                    left: descriptor.left,
+                    top: descriptor.top,
+                    width: descriptor.width,
+                    height: descriptor.height,
+                    delay_ms,
+                    disposal: Disposal::from_flags(flags),
+                    transparent_index,
+                    local_palette_size: descriptor.local_palette_size,
+                    interlaced: descriptor.interlaced,
+                });
+            }
+            Block::Trailer => {}
+        }
+    }
+
+    info.frame_count = info.frames.len();
+    Ok(info)
+}
+
+/// Extracts the loop count from a NETSCAPE2.0 (or ANIMEXTS1.0) application extension
+pub fn loop_count(sub_blocks: &[Vec<u8>]) -> Option<u16> {
+    match sub_blocks {
+        [id, data, ..]
+            if matches!(id.as_slice(), b"NETSCAPE2.0" | b"ANIMEXTS1.0")
+                && data.len() >= 3
+                && data[0] == 1 =>
+        {
+            Some(u16::from_le_bytes([data[1], data[2]]))
+        }
+        _ => None,
+    }
+}
+
+/// Number of entries in the color table announced by a descriptor's packed `flags`, if any.
+pub(crate) fn palette_size(flags: u8) -> Option<usize> {
+    (flags & 0x80 != 0).then(|| 2usize << (flags & 0x07))
+}
+
+fn not_a_gif() -> GpuWorkerError {
+    GpuWorkerError::InvalidInput("Not a GIF file".to_string())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn skip<R: BufRead>(reader: &mut R, mut len: usize) -> io::Result<()> {
+    while len > 0 {
+        let available = reader.fill_buf()?.len();
+        if available == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let consumed = available.min(len);
+        reader.consume(consumed);
+        len -= consumed;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gif::{DisposalMethod, Encoder, Frame, Repeat};
+
+    fn create_gif(repeat: Option<Repeat>) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = Encoder::new(&mut output, 8, 6, &[0, 0, 0, 255, 255, 255]).unwrap();
+        if let Some(repeat) = repeat {
+            encoder.set_repeat(repeat).unwrap();
+        }
+
+        let mut first = Frame::from_rgb(8, 6, &[10; 8 * 6 * 3]);
+        first.delay = 5;
+        first.dispose = DisposalMethod::Background;
+        encoder.write_frame(&first).unwrap();
+
+        let mut second = Frame::from_indexed_pixels(2, 3, &[0, 1, 1, 0, 0, 1], Some(1));
+        second.left = 4;
+        second.top = 2;
+        second.delay = 12;
+        second.dispose = DisposalMethod::Previous;
+        encoder.write_frame(&second).unwrap();
+
+        drop(encoder);
+        output
+    }
+
+    #[test]
+    fn test_inspect_gif_reports_frames() {
+        let info = inspect_gif(
+            create_gif(Some(Repeat::Finite(3))).as_slice(),
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+
+        assert_eq!(info.version, "89a");
+        assert_eq!((info.width, info.height), (8, 6));
+        assert_eq!(info.global_palette_size, Some(2));
+        assert_eq!(info.frame_count, 2);
+        assert_eq!(info.total_duration_ms, 170);
+        assert_eq!(info.loop_count, Some(3));
+        assert!(!info.truncated);
+
+        let first = &info.frames[0];
+        assert_eq!(first.disposal, Disposal::Background);
+        assert_eq!(first.delay_ms, 50);
+        assert!(first.local_palette_size.is_some());
+
+        let second = &info.frames[1];
+        assert_eq!(
+            (second.left, second.top, second.width, second.height),
+            (4, 2, 2, 3)
+        );
+        assert_eq!(second.disposal, Disposal::Previous);
+        assert_eq!(second.transparent_index, Some(1));
+        assert_eq!(second.local_palette_size, None);
+    }
+
+    #[test]
+    fn test_inspect_gif_without_loop_extension() {
+        let info = inspect_gif(create_gif(None).as_slice(), &DecodeLimits::default()).unwrap();
+        assert_eq!(info.loop_count, None);
+
+        let info = inspect_gif(
+            create_gif(Some(Repeat::Infinite)).as_slice(),
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(info.loop_count, Some(0));
+    }
+
+    #[test]
+    fn test_inspect_gif_reads_comments() {
+        let gif_data = create_gif(None);
+        // Insert a comment extension split over two sub-blocks right before the trailer
+        let mut with_comment = gif_data[..gif_data.len() - 1].to_vec();
+        with_comment.extend_from_slice(&[0x21, 0xFE, 6]);
+        with_comment.extend_from_slice(b"hello ");
+        with_comment.extend_from_slice(&[5]);
+        with_comment.extend_from_slice(b"world");
+        with_comment.extend_from_slice(&[0, 0x3B]);
+
+        let info = inspect_gif(with_comment.as_slice(), &DecodeLimits::default()).unwrap();
+        assert_eq!(info.comments, vec!["hello world".to_string()]);
+        assert_eq!(info.frame_count, 2);
+    }
+
+    #[test]
+    fn test_inspect_gif_limits_frames() {
+        let limits = DecodeLimits {
+            max_frames: 1,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            inspect_gif(create_gif(None).as_slice(), &limits),
+            Err(GpuWorkerError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_inspect_gif_truncated() {
+        let gif_data = create_gif(None);
+        let info = inspect_gif(&gif_data[..gif_data.len() - 8], &DecodeLimits::default()).unwrap();
+        assert!(info.truncated);
+        assert_eq!(info.frame_count, 1);
+    }
+
+    #[test]
+    fn test_inspect_rejects_non_gif() {
+        for data in [&b"\x89PNG\r\n\x1a\n0000000"[..], b"GIF89a"] {
+            assert!(matches!(
+                inspect_gif(data, &DecodeLimits::default()),
+                Err(GpuWorkerError::InvalidInput(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_inspect_rejects_unknown_blocks() {
+        let gif_data = create_gif(None);
+        let mut corrupt = gif_data[..gif_data.len() - 1].to_vec();
+        corrupt.push(0x42);
+
+        assert!(matches!(
+            inspect_gif(corrupt.as_slice(), &DecodeLimits::default()),
+            Err(GpuWorkerError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_blocks_end_at_trailer() {
+        let gif_data = create_gif(None);
+        let blocks: Vec<_> = GifBlocks::new(gif_data.as_slice())
+            .unwrap()
+            .map(|block| block.unwrap())
+            .collect();
+        assert_eq!(blocks.last(), Some(&Block::Trailer));
+        assert_eq!(
+            blocks
+                .iter()
+                .filter(|block| matches!(block, Block::Image(_)))
+                .count(),
+            2
+        );
+    }
+}
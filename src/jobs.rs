@@ -0,0 +1,1301 @@
+//! Asynchronous jobs for long-running transformations.
+//!
+//! Large animations can take longer to process than proxies keep a connection open. A
+//! [`JobQueue`] accepts work up front, runs it on a fixed set of worker threads behind a bounded
+//! queue and keeps the result until it is downloaded, evicted or expired. Work reports per-frame
+//! progress and notices cancellation through its [`JobContext`]; wrapping the frame operation in
+//! [`Tracked`] does both between frames. Progress is also recorded as [`JobEvent`]s that clients
+//! can follow as they happen.
+//!
+//! A job is described by a serializable [`JobRequest`] and run by the queue's [`JobRunner`], so
+//! that with a spool directory configured, jobs, their inputs and their results are kept in a
+//! [`Spool`] and survive a restart. Jobs that were queued are resumed; jobs that were running are
+//! retried until they run out of attempts, and then fail. Jobs submitted with a [`Callback`] have
+//! their outcome posted to it once they succeed or fail.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    callbacks::{self, Callback, CallbackConfig, CallbackState, DeliveryStatus, Notifier},
+    error::{GpuWorkerError, Result},
+    events::{EventLog, JobEvent, JobEvents},
+    pipeline::FrameOp,
+    spool::{JobManifest, Spool},
+};
+
+/// Default number of jobs processed at once.
+pub const DEFAULT_JOB_WORKERS: usize = 2;
+
+/// Default number of jobs waiting for a worker before submissions are rejected.
+pub const DEFAULT_JOB_QUEUE_DEPTH: usize = 64;
+
+/// Default number of finished jobs kept, with their results, before the oldest are evicted.
+pub const DEFAULT_RETAINED_JOBS: usize = 256;
+
+/// Default time finished jobs and their results are kept.
+pub const DEFAULT_RESULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Default number of times a job is started before an interrupted job is failed.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Longest pause between two sweeps for expired jobs.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Sizing and persistence of a [`JobQueue`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobQueueConfig {
+    /// Number of worker threads.
+    pub workers: usize,
+    /// Jobs waiting for a worker; submissions beyond it fail with `503 Service Unavailable`.
+    pub queue_depth: usize,
+    /// Finished jobs kept before the oldest are evicted.
+    pub retained: usize,
+    /// Directory jobs are spooled to; without one, jobs only live in memory.
+    pub spool_dir: Option<PathBuf>,
+    /// How long finished jobs and their results are kept.
+    pub result_ttl: Duration,
+    /// Times a job is started, counting runs interrupted by a restart, before it is failed.
+    pub max_attempts: u32,
+    /// Delivery of completion callbacks.
+    pub callbacks: CallbackConfig,
+}
+
+impl Default for JobQueueConfig {
+    fn default() -> Self {
+        Self {
+            workers: DEFAULT_JOB_WORKERS,
+            queue_depth: DEFAULT_JOB_QUEUE_DEPTH,
+            retained: DEFAULT_RETAINED_JOBS,
+            spool_dir: None,
+            result_ttl: DEFAULT_RESULT_TTL,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            callbacks: CallbackConfig::default(),
+        }
+    }
+}
+
+/// Lifecycle of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Whether the job will not change any more.
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed | Self::Cancelled)
+    }
+}
+
+/// Frames processed so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobProgress {
+    pub frames_processed: usize,
+    /// Number of frames in the input, once known.
+    pub frames_total: Option<usize>,
+}
+
+/// State of a job as reported to clients
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub id: Uuid,
+    pub status: JobStatus,
+    pub progress: JobProgress,
+    /// Times the job has been started; more than one after a restart interrupted it.
+    pub attempts: u32,
+    /// Content type of the result.
+    pub content_type: String,
+    /// Unix timestamps in seconds.
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    /// Why the job failed.
+    pub error: Option<String>,
+    /// Delivery of the completion callback, once the job has finished.
+    pub callback: Option<CallbackState>,
+}
+
+/// What a job runs, as stored in the spool
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobRequest {
+    /// Content type of the result.
+    pub content_type: String,
+    /// File name the result is served as.
+    pub filename: String,
+    /// Description of the work, interpreted by the [`JobRunner`].
+    pub spec: serde_json::Value,
+    /// Where to post the outcome of the job.
+    #[serde(default)]
+    pub callback: Option<Callback>,
+}
+
+/// Result of a succeeded job.
+#[derive(Debug, Clone)]
+pub struct JobResult {
+    pub data: Bytes,
+    pub content_type: String,
+    pub filename: String,
+}
+
+/// Handle through which running work reports progress and learns about cancellation
+#[derive(Debug, Clone, Default)]
+pub struct JobContext {
+    state: Arc<ContextState>,
+}
+
+#[derive(Debug, Default)]
+struct ContextState {
+    cancelled: AtomicBool,
+    frames_processed: AtomicUsize,
+    /// Zero while unknown.
+    frames_total: AtomicUsize,
+    events: Arc<EventLog>,
+}
+
+impl JobContext {
+    fn with_progress(progress: JobProgress) -> Self {
+        let context = Self::default();
+        context
+            .state
+            .frames_processed
+            .store(progress.frames_processed, Ordering::Relaxed);
+        context
+            .state
+            .frames_total
+            .store(progress.frames_total.unwrap_or(0), Ordering::Relaxed);
+        context
+    }
+
+    /// Records the number of frames the job will process, once its input has been read.
+    pub fn set_frames_total(&self, frames: usize) {
+        self.state.frames_total.store(frames, Ordering::Relaxed);
+        self.record(JobEvent::Decoded {
+            frames_total: frames,
+        });
+    }
+
+    /// Records that one more frame has been processed, which took `duration`.
+    pub fn frame_processed(&self, duration: Duration) {
+        let index = self.state.frames_processed.fetch_add(1, Ordering::Relaxed) + 1;
+        let frames_total = self.progress().frames_total;
+        self.record(JobEvent::Frame {
+            index,
+            frames_total,
+            frame_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+        });
+        if frames_total == Some(index) {
+            self.record(JobEvent::Encoding);
+        }
+    }
+
+    /// Fails with [`GpuWorkerError::Cancelled`] once the job has been cancelled.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.state.cancelled.load(Ordering::Relaxed) {
+            return Err(GpuWorkerError::Cancelled("Job was cancelled".to_string()));
+        }
+        Ok(())
+    }
+
+    fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn record(&self, event: JobEvent) {
+        self.state.events.record(event);
+    }
+
+    fn events(&self) -> JobEvents {
+        JobEvents::new(self.state.events.clone())
+    }
+
+    fn progress(&self) -> JobProgress {
+        let frames_total = self.state.frames_total.load(Ordering::Relaxed);
+        JobProgress {
+            frames_processed: self.state.frames_processed.load(Ordering::Relaxed),
+            frames_total: (frames_total > 0).then_some(frames_total),
+        }
+    }
+}
+
+/// Frame operation that reports each processed frame and stops between frames once cancelled.
+pub struct Tracked<O> {
+    pub op: O,
+    pub context: JobContext,
+}
+
+impl<O: FrameOp> FrameOp for Tracked<O> {
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        self.op.output_dimensions(width, height)
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        self.context.check_cancelled()?;
+        let started = Instant::now();
+        let output = self.op.apply(rgba, width, height)?;
+        self.context.frame_processed(started.elapsed());
+        Ok(output)
+    }
+}
+
+/// Runs the work described by a job's spec on its input, writing the result into the buffer.
+pub type JobRunner =
+    Arc<dyn Fn(&serde_json::Value, &[u8], &JobContext, &mut Vec<u8>) -> Result<()> + Send + Sync>;
+
+struct Job {
+    request: JobRequest,
+    status: JobStatus,
+    context: JobContext,
+    attempts: u32,
+    created_at: u64,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+    error: Option<String>,
+    callback: Option<CallbackState>,
+    /// Input of a job that has not started yet; spooled jobs read it from disk instead.
+    input: Option<Bytes>,
+    /// Result of a succeeded job; spooled jobs read it from disk instead.
+    result: Option<Bytes>,
+}
+
+impl Job {
+    fn from_manifest(manifest: JobManifest) -> (Uuid, Self) {
+        let job = Self {
+            request: manifest.request,
+            status: manifest.status,
+            context: JobContext::with_progress(manifest.progress),
+            attempts: manifest.attempts,
+            created_at: manifest.created_at,
+            started_at: manifest.started_at,
+            finished_at: manifest.finished_at,
+            error: manifest.error,
+            callback: manifest.callback,
+            input: None,
+            result: None,
+        };
+        (manifest.id, job)
+    }
+
+    fn manifest(&self, id: Uuid) -> JobManifest {
+        JobManifest {
+            id,
+            status: self.status,
+            attempts: self.attempts,
+            request: self.request.clone(),
+            progress: self.context.progress(),
+            created_at: self.created_at,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            error: self.error.clone(),
+            callback: self.callback.clone(),
+        }
+    }
+
+    fn info(&self, id: Uuid) -> JobInfo {
+        JobInfo {
+            id,
+            status: self.status,
+            progress: self.context.progress(),
+            attempts: self.attempts,
+            content_type: self.request.content_type.clone(),
+            created_at: self.created_at,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            error: self.error.clone(),
+            callback: self.callback.clone(),
+        }
+    }
+
+    /// Records how a finished job ended in its event log.
+    fn record_outcome(&self) {
+        let event = match self.status {
+            JobStatus::Succeeded => JobEvent::Succeeded,
+            JobStatus::Failed => JobEvent::Failed {
+                error: self.error.clone().unwrap_or_default(),
+            },
+            JobStatus::Cancelled => JobEvent::Cancelled,
+            JobStatus::Queued | JobStatus::Running => return,
+        };
+        self.context.record(event);
+    }
+
+    /// Marks the callback of a succeeded or failed job as due.
+    fn schedule_callback(&mut self) {
+        if matches!(self.status, JobStatus::Succeeded | JobStatus::Failed) {
+            self.callback = self.request.callback.as_ref().map(CallbackState::pending);
+        }
+    }
+
+    fn callback_pending(&self) -> bool {
+        matches!(&self.callback, Some(state) if state.status == DeliveryStatus::Pending)
+    }
+}
+
+#[derive(Default)]
+struct Jobs {
+    by_id: HashMap<Uuid, Job>,
+    /// Finished jobs, oldest first.
+    finished: VecDeque<Uuid>,
+    retained: usize,
+    spool: Option<Spool>,
+}
+
+impl Jobs {
+    /// Number of jobs waiting for a worker.
+    fn queued(&self) -> usize {
+        self.by_id
+            .values()
+            .filter(|job| job.status == JobStatus::Queued)
+            .count()
+    }
+
+    /// Writes the manifest of a job to the spool, if there is one.
+    fn save(&self, id: Uuid) {
+        let (Some(spool), Some(job)) = (&self.spool, self.by_id.get(&id)) else {
+            return;
+        };
+        if let Err(error) = spool.save(&job.manifest(id)) {
+            log::error!("Failed to save job {}: {}", id, error);
+        }
+    }
+
+    fn finish(&mut self, id: Uuid, status: JobStatus) {
+        let Some(job) = self.by_id.get_mut(&id) else {
+            return;
+        };
+        job.status = status;
+        job.finished_at = Some(now());
+        job.input = None;
+        job.record_outcome();
+        job.schedule_callback();
+        self.finished.push_back(id);
+        self.save(id);
+        if let Some(spool) = &self.spool {
+            if let Err(error) = spool.remove_input(id) {
+                log::warn!("Failed to remove the input of job {}: {}", id, error);
+            }
+        }
+        self.evict();
+    }
+
+    /// Removes the oldest finished jobs beyond the number retained.
+    fn evict(&mut self) {
+        while self.finished.len() > self.retained {
+            if let Some(evicted) = self.finished.pop_front() {
+                log::info!("Evicting finished job {}", evicted);
+                self.remove(evicted);
+            }
+        }
+    }
+
+    /// Forgets a job and deletes everything spooled for it.
+    fn remove(&mut self, id: Uuid) {
+        self.by_id.remove(&id);
+        self.finished.retain(|finished| *finished != id);
+        if let Some(spool) = &self.spool {
+            if let Err(error) = spool.remove(id) {
+                log::warn!("Failed to remove job {} from the spool: {}", id, error);
+            }
+        }
+    }
+
+    /// Removes finished jobs that are older than `ttl`.
+    fn sweep(&mut self, ttl: Duration) {
+        let cutoff = now().saturating_sub(ttl.as_secs());
+        while let Some(&id) = self.finished.front() {
+            let finished_at = self.by_id.get(&id).and_then(|job| job.finished_at);
+            if matches!(finished_at, Some(finished_at) if finished_at > cutoff) {
+                break;
+            }
+            log::info!("Removing expired job {}", id);
+            self.remove(id);
+        }
+    }
+
+    /// Takes over a job from the spool, returning its id if it has to be queued again.
+    fn restore(&mut self, manifest: JobManifest, max_attempts: u32) -> Option<Uuid> {
+        let (id, mut job) = Job::from_manifest(manifest);
+        match job.status {
+            JobStatus::Queued => {
+                log::info!("Resuming job {}", id);
+            }
+            JobStatus::Running if job.attempts < max_attempts => {
+                log::warn!(
+                    "Retrying job {}, interrupted during attempt {}",
+                    id,
+                    job.attempts
+                );
+                job.status = JobStatus::Queued;
+                job.context = JobContext::default();
+                job.started_at = None;
+            }
+            JobStatus::Running => {
+                log::warn!("Failing job {}, interrupted during its last attempt", id);
+                job.error = Some(format!(
+                    "Job was interrupted by a restart during each of its {} attempts",
+                    job.attempts
+                ));
+                job.status = JobStatus::Failed;
+                job.finished_at = Some(now());
+                job.record_outcome();
+                job.schedule_callback();
+                self.by_id.insert(id, job);
+                self.finished.push_back(id);
+                self.save(id);
+                if let Some(spool) = &self.spool {
+                    let _ = spool.remove_input(id);
+                }
+                return None;
+            }
+            _ => {
+                job.record_outcome();
+                self.by_id.insert(id, job);
+                self.finished.push_back(id);
+                return None;
+            }
+        }
+        self.by_id.insert(id, job);
+        self.save(id);
+        Some(id)
+    }
+}
+
+struct Shared {
+    jobs: Mutex<Jobs>,
+    runner: JobRunner,
+    notifier: Notifier,
+    queue_depth: usize,
+    result_ttl: Duration,
+}
+
+/// Bounded queue of jobs run by a fixed set of worker threads
+///
+/// Clones share the same queue. The workers exit once every clone is dropped and the queue has
+/// drained.
+#[derive(Clone)]
+pub struct JobQueue {
+    shared: Arc<Shared>,
+    sender: Sender<Uuid>,
+}
+
+impl std::fmt::Debug for JobQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let jobs = lock(&self.shared.jobs);
+        f.debug_struct("JobQueue")
+            .field("jobs", &jobs.by_id.len())
+            .field("spool", &jobs.spool.as_ref().map(Spool::dir))
+            .finish_non_exhaustive()
+    }
+}
+
+impl JobQueue {
+    /// Starts the worker threads, which run jobs with `runner`
+    ///
+    /// With a spool directory configured, jobs left in it are taken over: queued and interrupted
+    /// jobs are queued again and finished ones can be downloaded until they expire.
+    pub fn new(config: JobQueueConfig, runner: JobRunner) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let mut jobs = Jobs {
+            retained: config.retained,
+            spool: config.spool_dir.map(Spool::open).transpose()?,
+            ..Jobs::default()
+        };
+
+        if let Some(spool) = jobs.spool.clone() {
+            let manifests = spool.load()?;
+            log::info!(
+                "Found {} jobs in the spool at {}",
+                manifests.len(),
+                spool.dir().display()
+            );
+            for manifest in manifests {
+                if let Some(id) = jobs.restore(manifest, config.max_attempts) {
+                    let _ = sender.send(id);
+                }
+            }
+            jobs.finished
+                .make_contiguous()
+                .sort_by_key(|id| jobs.by_id.get(id).and_then(|job| job.finished_at));
+            jobs.evict();
+            jobs.sweep(config.result_ttl);
+        }
+
+        let shared = Arc::new(Shared {
+            jobs: Mutex::new(jobs),
+            runner,
+            notifier: Notifier::new(config.callbacks),
+            queue_depth: config.queue_depth,
+            result_ttl: config.result_ttl,
+        });
+
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..config.workers.max(1) {
+            let receiver = receiver.clone();
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("job-worker-{}", index))
+                .spawn(move || work_loop(&receiver, &shared))
+                .map_err(|e| {
+                    GpuWorkerError::Internal(format!("Failed to spawn job worker: {}", e))
+                })?;
+        }
+
+        let pending: Vec<Uuid> = lock(&shared.jobs)
+            .by_id
+            .iter()
+            .filter(|(_, job)| job.callback_pending())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in pending {
+            notify(&shared, id);
+        }
+
+        let weak = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name("job-sweeper".to_string())
+            .spawn(move || sweep_loop(&weak))
+            .map_err(|e| GpuWorkerError::Internal(format!("Failed to spawn job sweeper: {}", e)))?;
+
+        Ok(Self { shared, sender })
+    }
+
+    /// Queues a job that runs `request` on `input`
+    ///
+    /// Spooled jobs have their input written to disk before this returns. Fails with
+    /// [`GpuWorkerError::Unavailable`] when the queue is full.
+    pub fn submit(&self, request: JobRequest, input: Bytes) -> Result<JobInfo> {
+        self.check_capacity(&lock(&self.shared.jobs))?;
+
+        let id = Uuid::new_v4();
+        let mut job = Job {
+            request,
+            status: JobStatus::Queued,
+            context: JobContext::default(),
+            attempts: 0,
+            created_at: now(),
+            started_at: None,
+            finished_at: None,
+            error: None,
+            callback: None,
+            input: Some(input),
+            result: None,
+        };
+        let spool = lock(&self.shared.jobs).spool.clone();
+        if let Some(spool) = &spool {
+            if let Some(input) = job.input.take() {
+                spool.write_input(id, &input)?;
+            }
+        }
+
+        let mut jobs = lock(&self.shared.jobs);
+        if let Err(error) = self.check_capacity(&jobs) {
+            if let Some(spool) = &spool {
+                let _ = spool.remove(id);
+            }
+            return Err(error);
+        }
+        let info = job.info(id);
+        jobs.by_id.insert(id, job);
+        jobs.save(id);
+        self.sender
+            .send(id)
+            .map_err(|_| GpuWorkerError::Internal("Job workers have stopped".to_string()))?;
+        log::info!("Queued job {}", id);
+        Ok(info)
+    }
+
+    /// Current state of a job.
+    pub fn info(&self, id: Uuid) -> Result<JobInfo> {
+        lock(&self.shared.jobs)
+            .by_id
+            .get(&id)
+            .map(|job| job.info(id))
+            .ok_or_else(|| not_found(id))
+    }
+
+    /// Description of a job's work, as submitted.
+    pub fn spec(&self, id: Uuid) -> Result<serde_json::Value> {
+        lock(&self.shared.jobs)
+            .by_id
+            .get(&id)
+            .map(|job| job.request.spec.clone())
+            .ok_or_else(|| not_found(id))
+    }
+
+    /// Result of a succeeded job; fails with [`GpuWorkerError::Conflict`] for other jobs.
+    pub fn result(&self, id: Uuid) -> Result<JobResult> {
+        self.shared.result(id)
+    }
+
+    /// Subscribes to the events of a job, past and future.
+    pub fn events(&self, id: Uuid) -> Result<JobEvents> {
+        lock(&self.shared.jobs)
+            .by_id
+            .get(&id)
+            .map(|job| job.context.events())
+            .ok_or_else(|| not_found(id))
+    }
+
+    /// Cancels a queued or running job, or forgets a finished one along with its result
+    ///
+    /// Returns the state of a cancelled job, or `None` if the job was removed. Running jobs stop
+    /// before their next frame.
+    pub fn cancel(&self, id: Uuid) -> Result<Option<JobInfo>> {
+        let mut jobs = lock(&self.shared.jobs);
+        let job = jobs.by_id.get(&id).ok_or_else(|| not_found(id))?;
+        if job.status.is_finished() {
+            jobs.remove(id);
+            log::info!("Removed job {}", id);
+            return Ok(None);
+        }
+
+        job.context.cancel();
+        jobs.finish(id, JobStatus::Cancelled);
+        log::info!("Cancelled job {}", id);
+        Ok(jobs.by_id.get(&id).map(|job| job.info(id)))
+    }
+
+    fn check_capacity(&self, jobs: &Jobs) -> Result<()> {
+        if jobs.queued() >= self.shared.queue_depth {
+            return Err(GpuWorkerError::Unavailable(
+                "Job queue is full, try again later".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn result(&self, id: Uuid) -> Result<JobResult> {
+        let (data, content_type, filename, spool) = {
+            let jobs = lock(&self.jobs);
+            let job = jobs.by_id.get(&id).ok_or_else(|| not_found(id))?;
+            if job.status != JobStatus::Succeeded {
+                return Err(GpuWorkerError::Conflict(format!(
+                    "Job {} is {}, its result is not available",
+                    id,
+                    status_name(job.status)
+                )));
+            }
+            (
+                job.result.clone(),
+                job.request.content_type.clone(),
+                job.request.filename.clone(),
+                jobs.spool.clone(),
+            )
+        };
+
+        let data = match (data, spool) {
+            (Some(data), _) => data,
+            (None, Some(spool)) => spool.read_result(id).map_err(|_| not_found(id))?.into(),
+            (None, None) => return Err(not_found(id)),
+        };
+        Ok(JobResult {
+            data,
+            content_type,
+            filename,
+        })
+    }
+}
+
+fn work_loop(receiver: &Mutex<Receiver<Uuid>>, shared: &Arc<Shared>) {
+    loop {
+        let next = lock(receiver).recv();
+        let Ok(id) = next else {
+            return;
+        };
+        run(shared, id);
+    }
+}
+
+fn sweep_loop(shared: &Weak<Shared>) {
+    loop {
+        let interval = match shared.upgrade() {
+            Some(shared) => shared
+                .result_ttl
+                .clamp(Duration::from_secs(1), SWEEP_INTERVAL),
+            None => return,
+        };
+        thread::sleep(interval);
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        lock(&shared.jobs).sweep(shared.result_ttl);
+    }
+}
+
+fn run(shared: &Arc<Shared>, id: Uuid) {
+    let (spec, input, context, spool) = {
+        let mut jobs = lock(&shared.jobs);
+        let Some(job) = jobs.by_id.get_mut(&id) else {
+            return;
+        };
+        if job.status != JobStatus::Queued {
+            return;
+        }
+        job.status = JobStatus::Running;
+        job.started_at = Some(now());
+        job.attempts += 1;
+        job.context.record(JobEvent::Started {
+            attempt: job.attempts,
+        });
+        let started = (
+            job.request.spec.clone(),
+            job.input.take(),
+            job.context.clone(),
+            jobs.spool.clone(),
+        );
+        jobs.save(id);
+        started
+    };
+
+    log::info!("Running job {}", id);
+    let mut output = Vec::new();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let input = match (input, &spool) {
+            (Some(input), _) => input,
+            (None, Some(spool)) => spool.read_input(id)?.into(),
+            (None, None) => return Err(GpuWorkerError::Internal("Job has no input".to_string())),
+        };
+        (shared.runner)(&spec, &input, &context, &mut output)?;
+        match &spool {
+            Some(spool) => spool.write_result(id, &output),
+            None => Ok(()),
+        }
+    }))
+    .unwrap_or_else(|_| Err(GpuWorkerError::Internal("Job panicked".to_string())));
+
+    let mut jobs = lock(&shared.jobs);
+    let Some(job) = jobs.by_id.get_mut(&id) else {
+        // Removed while running
+        if let Some(spool) = &spool {
+            let _ = spool.remove(id);
+        }
+        return;
+    };
+    if job.status != JobStatus::Running {
+        // Cancelled while running
+        if let Some(spool) = &spool {
+            let _ = spool.remove_result(id);
+        }
+        return;
+    }
+    match result {
+        Ok(()) => {
+            log::info!("Job {} succeeded with {} bytes", id, output.len());
+            if spool.is_none() {
+                job.result = Some(output.into());
+            }
+            jobs.finish(id, JobStatus::Succeeded);
+        }
+        Err(error) => {
+            log::warn!("Job {} failed: {}", id, error);
+            job.error = Some(error.to_string());
+            jobs.finish(id, JobStatus::Failed);
+        }
+    }
+    let pending = jobs.by_id.get(&id).is_some_and(Job::callback_pending);
+    drop(jobs);
+    if pending {
+        notify(shared, id);
+    }
+}
+
+/// Delivers the pending callback of a job on a thread of its own.
+fn notify(shared: &Arc<Shared>, id: Uuid) {
+    let shared = shared.clone();
+    let spawned = thread::Builder::new()
+        .name("job-callback".to_string())
+        .spawn(move || deliver(&shared, id));
+    if let Err(error) = spawned {
+        log::error!("Failed to start the callback of job {}: {}", id, error);
+    }
+}
+
+fn deliver(shared: &Shared, id: Uuid) {
+    let (info, include_result) = {
+        let jobs = lock(&shared.jobs);
+        let Some(job) = jobs.by_id.get(&id).filter(|job| job.callback_pending()) else {
+            return;
+        };
+        let include_result = job
+            .request
+            .callback
+            .as_ref()
+            .is_some_and(|callback| callback.include_result);
+        (job.info(id), include_result)
+    };
+    let Some(url) = info.callback.as_ref().map(|state| state.url.clone()) else {
+        return;
+    };
+
+    let result = if include_result && info.status == JobStatus::Succeeded {
+        match shared.result(id) {
+            Ok(result) => Some(result),
+            Err(error) => {
+                log::warn!(
+                    "Sending the callback of job {} without its result: {}",
+                    id,
+                    error
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let body = callbacks::payload(&info, result.as_ref());
+
+    let status = shared.notifier.deliver(&url, &body, |attempt| {
+        let mut jobs = lock(&shared.jobs);
+        let Some(state) = jobs
+            .by_id
+            .get_mut(&id)
+            .and_then(|job| job.callback.as_mut())
+        else {
+            // Removed while delivering
+            return false;
+        };
+        state.attempts.push(attempt.clone());
+        jobs.save(id);
+        true
+    });
+
+    let mut jobs = lock(&shared.jobs);
+    if let Some(state) = jobs
+        .by_id
+        .get_mut(&id)
+        .and_then(|job| job.callback.as_mut())
+    {
+        state.status = status;
+        jobs.save(id);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn not_found(id: Uuid) -> GpuWorkerError {
+    GpuWorkerError::NotFound(format!("No job {}", id))
+}
+
+fn status_name(status: JobStatus) -> &'static str {
+    match status {
+        JobStatus::Queued => "queued",
+        JobStatus::Running => "running",
+        JobStatus::Succeeded => "succeeded",
+        JobStatus::Failed => "failed",
+        JobStatus::Cancelled => "cancelled",
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Instant;
+
+    fn config(workers: usize, queue_depth: usize) -> JobQueueConfig {
+        JobQueueConfig {
+            workers,
+            queue_depth,
+            retained: 8,
+            ..JobQueueConfig::default()
+        }
+    }
+
+    /// Starts a queue whose jobs run test specs
+    ///
+    /// `{"frames": n}` reports `n` frames and echoes the input, `{"fail": message}` fails,
+    /// `{"panic": message}` panics and `{"block": true}` waits until the returned sender is
+    /// dropped or sent to.
+    fn queue(config: JobQueueConfig) -> (JobQueue, mpsc::Sender<()>) {
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Mutex::new(gate);
+        let runner: JobRunner = Arc::new(move |spec, input, context, output| {
+            if let Some(message) = spec["fail"].as_str() {
+                return Err(GpuWorkerError::InvalidInput(message.to_string()));
+            }
+            if let Some(message) = spec["panic"].as_str() {
+                panic!("{}", message);
+            }
+            if spec["block"] == true {
+                let _ = lock(&gate).recv();
+            }
+            let frames = spec["frames"].as_u64().unwrap_or(0) as usize;
+            context.set_frames_total(frames);
+            for _ in 0..frames {
+                context.frame_processed(Duration::ZERO);
+            }
+            output.extend_from_slice(input);
+            Ok(())
+        });
+        (JobQueue::new(config, runner).unwrap(), release)
+    }
+
+    fn request(spec: serde_json::Value) -> JobRequest {
+        JobRequest {
+            content_type: "image/gif".to_string(),
+            filename: "out.gif".to_string(),
+            spec,
+            callback: None,
+        }
+    }
+
+    fn spool_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("gpu-worker-spool-{}", Uuid::new_v4()))
+    }
+
+    fn wait_until_finished(queue: &JobQueue, id: Uuid) -> JobInfo {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let info = queue.info(id).unwrap();
+            if info.status.is_finished() {
+                return info;
+            }
+            assert!(Instant::now() < deadline, "job {} did not finish", id);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn wait_until_running(queue: &JobQueue, id: Uuid) {
+        while queue.info(id).unwrap().status != JobStatus::Running {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_job_succeeds_and_fails() {
+        let (queue, _release) = queue(config(2, 4));
+
+        let ok = queue
+            .submit(request(json!({"frames": 2})), Bytes::from_static(b"GIF89a"))
+            .unwrap();
+        assert_eq!(ok.status, JobStatus::Queued);
+
+        let failing = queue
+            .submit(request(json!({"fail": "bad frame"})), Bytes::new())
+            .unwrap();
+
+        let info = wait_until_finished(&queue, ok.id);
+        assert_eq!(info.status, JobStatus::Succeeded);
+        assert_eq!(info.attempts, 1);
+        assert_eq!(
+            info.progress,
+            JobProgress {
+                frames_processed: 2,
+                frames_total: Some(2)
+            }
+        );
+        let result = queue.result(ok.id).unwrap();
+        assert_eq!(result.data, "GIF89a");
+        assert_eq!(result.filename, "out.gif");
+
+        let info = wait_until_finished(&queue, failing.id);
+        assert_eq!(info.status, JobStatus::Failed);
+        assert!(info.error.unwrap().contains("bad frame"));
+        assert!(matches!(
+            queue.result(failing.id),
+            Err(GpuWorkerError::Conflict(_))
+        ));
+        assert!(matches!(
+            queue.info(Uuid::new_v4()),
+            Err(GpuWorkerError::NotFound(_))
+        ));
+
+        let panicking = queue
+            .submit(request(json!({"panic": "boom"})), Bytes::new())
+            .unwrap();
+        let info = wait_until_finished(&queue, panicking.id);
+        assert_eq!(info.status, JobStatus::Failed);
+        assert!(info.error.unwrap().contains("panicked"));
+    }
+
+    #[test]
+    fn test_full_queue_rejects_jobs() {
+        let (queue, release) = queue(config(1, 1));
+        let running = queue
+            .submit(request(json!({"block": true})), Bytes::new())
+            .unwrap();
+        wait_until_running(&queue, running.id);
+
+        queue
+            .submit(request(json!({"block": true})), Bytes::new())
+            .unwrap();
+        assert!(matches!(
+            queue.submit(request(json!({"block": true})), Bytes::new()),
+            Err(GpuWorkerError::Unavailable(_))
+        ));
+        drop(release);
+    }
+
+    #[test]
+    fn test_cancel_and_remove() {
+        let (queue, release) = queue(config(1, 4));
+        let running = queue
+            .submit(request(json!({"block": true})), Bytes::new())
+            .unwrap();
+        let queued = queue
+            .submit(
+                request(json!({"panic": "cancelled jobs never run"})),
+                Bytes::new(),
+            )
+            .unwrap();
+
+        let cancelled = queue.cancel(queued.id).unwrap().unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+        drop(release);
+        assert_eq!(
+            wait_until_finished(&queue, running.id).status,
+            JobStatus::Succeeded
+        );
+        assert_eq!(queue.info(queued.id).unwrap().status, JobStatus::Cancelled);
+
+        // Finished jobs are removed along with their result
+        assert!(queue.cancel(running.id).unwrap().is_none());
+        assert!(matches!(
+            queue.result(running.id),
+            Err(GpuWorkerError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_tracked_op_stops_between_frames() {
+        let context = JobContext::default();
+        let op = Tracked {
+            op: |rgba: &[u8], _, _| Ok(rgba.to_vec()),
+            context: context.clone(),
+        };
+
+        assert!(op.apply(&[0; 4], 1, 1).is_ok());
+        context.cancel();
+        assert!(matches!(
+            op.apply(&[0; 4], 1, 1),
+            Err(GpuWorkerError::Cancelled(_))
+        ));
+        assert_eq!(context.progress().frames_processed, 1);
+    }
+
+    #[test]
+    fn test_finished_jobs_are_evicted() {
+        let (queue, _release) = queue(JobQueueConfig {
+            workers: 1,
+            queue_depth: 8,
+            retained: 2,
+            ..JobQueueConfig::default()
+        });
+        let ids: Vec<Uuid> = (0..3)
+            .map(|_| queue.submit(request(json!({})), Bytes::new()).unwrap().id)
+            .collect();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while queue
+            .info(ids[2])
+            .map_or(true, |info| !info.status.is_finished())
+        {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(queue.info(ids[0]).is_err());
+        assert!(queue.info(ids[1]).is_ok());
+    }
+
+    #[test]
+    fn test_callback_is_delivered_and_recorded() {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/done", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                if line.trim_end().is_empty() {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        });
+
+        let (queue, _release) = queue(JobQueueConfig {
+            callbacks: CallbackConfig {
+                allowed_hosts: vec!["127.0.0.1".to_string()],
+                ..CallbackConfig::default()
+            },
+            ..config(1, 4)
+        });
+        let job = queue
+            .submit(
+                JobRequest {
+                    callback: Some(Callback {
+                        url,
+                        include_result: true,
+                    }),
+                    ..request(json!({"frames": 1}))
+                },
+                Bytes::from_static(b"GIF89a"),
+            )
+            .unwrap();
+
+        let payload = server.join().unwrap();
+        assert_eq!(payload["event"], "job.succeeded");
+        assert_eq!(payload["job"]["id"], job.id.to_string());
+        assert_eq!(payload["result"]["data"], "R0lGODlh");
+        assert_eq!(payload["result"]["content_type"], "image/gif");
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let callback = loop {
+            let callback = queue.info(job.id).unwrap().callback.unwrap();
+            if callback.status != DeliveryStatus::Pending {
+                break callback;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(callback.status, DeliveryStatus::Delivered);
+        assert_eq!(callback.attempts.len(), 1);
+        assert_eq!(callback.attempts[0].status, Some(200));
+    }
+
+    #[test]
+    fn test_spooled_results_survive_restart() {
+        let dir = spool_dir();
+        let config = JobQueueConfig {
+            spool_dir: Some(dir.clone()),
+            ..config(1, 4)
+        };
+
+        let (first, _release) = queue(config.clone());
+        let job = first
+            .submit(request(json!({"frames": 1})), Bytes::from_static(b"GIF89a"))
+            .unwrap();
+        assert_eq!(
+            wait_until_finished(&first, job.id).status,
+            JobStatus::Succeeded
+        );
+        let job_dir = dir.join(job.id.to_string());
+        assert!(job_dir.join("manifest.json").exists());
+        assert!(job_dir.join("result").exists());
+        assert!(!job_dir.join("input").exists());
+        drop(first);
+
+        let (second, _release) = queue(config);
+        let info = second.info(job.id).unwrap();
+        assert_eq!(info.status, JobStatus::Succeeded);
+        assert_eq!(info.progress.frames_processed, 1);
+        let result = second.result(job.id).unwrap();
+        assert_eq!(result.data, "GIF89a");
+        assert_eq!(result.content_type, "image/gif");
+
+        assert!(second.cancel(job.id).unwrap().is_none());
+        assert!(!job_dir.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_jobs_are_retried_or_failed() {
+        let dir = spool_dir();
+        let spool = Spool::open(&dir).unwrap();
+        let manifest = |status, attempts, finished_at| JobManifest {
+            id: Uuid::new_v4(),
+            status,
+            attempts,
+            request: request(json!({"frames": 1})),
+            progress: JobProgress {
+                frames_processed: 0,
+                frames_total: None,
+            },
+            created_at: now(),
+            started_at: None,
+            finished_at,
+            error: None,
+            callback: None,
+        };
+        let queued = manifest(JobStatus::Queued, 0, None);
+        let interrupted = manifest(JobStatus::Running, 1, None);
+        let exhausted = manifest(JobStatus::Running, 3, None);
+        let expired = manifest(JobStatus::Succeeded, 1, Some(1));
+        for job in [&queued, &interrupted, &exhausted, &expired] {
+            spool.save(job).unwrap();
+            spool.write_input(job.id, b"GIF89a").unwrap();
+        }
+        // An interrupted submission, a write that never finished and an unrelated file
+        let orphan = Uuid::new_v4();
+        spool.write_input(orphan, b"GIF89a").unwrap();
+        let temporary = dir.join(interrupted.id.to_string()).join("result.tmp");
+        std::fs::write(&temporary, b"GIF").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"kept").unwrap();
+
+        let (queue, _release) = queue(JobQueueConfig {
+            spool_dir: Some(dir.clone()),
+            result_ttl: Duration::from_secs(3600),
+            ..config(1, 4)
+        });
+
+        let info = wait_until_finished(&queue, queued.id);
+        assert_eq!(info.status, JobStatus::Succeeded);
+        assert_eq!(info.attempts, 1);
+        let info = wait_until_finished(&queue, interrupted.id);
+        assert_eq!(info.status, JobStatus::Succeeded);
+        assert_eq!(info.attempts, 2);
+        assert_eq!(queue.result(interrupted.id).unwrap().data, "GIF89a");
+
+        let info = queue.info(exhausted.id).unwrap();
+        assert_eq!(info.status, JobStatus::Failed);
+        assert!(info.error.unwrap().contains("interrupted"));
+        assert!(!dir.join(exhausted.id.to_string()).join("input").exists());
+
+        assert!(matches!(
+            queue.info(expired.id),
+            Err(GpuWorkerError::NotFound(_))
+        ));
+        assert!(!dir.join(expired.id.to_string()).exists());
+
+        assert!(!dir.join(orphan.to_string()).exists());
+        assert!(!temporary.exists());
+        assert!(dir.join("notes.txt").exists());
+
+        drop(queue);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
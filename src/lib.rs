@@ -10,6 +10,7 @@
 //!
 //! - **GPU Acceleration**: Uses WebGPU for hardware-accelerated image processing
 //! - **GIF Support**: Full support for animated GIF processing
+//! - **APNG Support**: Animated PNG input and output with full alpha
 //! - **RESTful API**: Simple HTTP endpoints for easy integration
 //! - **Async Processing**: Non-blocking request handling for high throughput
 //!
@@ -17,8 +18,27 @@
 //!
 //! The crate is organized into the following modules:
 //!
+//! - [`apng`]: Animated PNG decoding and encoding
+//! - [`archive`]: Streaming ZIP archives of frames
+//! - [`assemble`]: Assembling animated GIFs from still images
+//! - [`auth`]: API key authentication with scopes
+//! - [`cache`]: Two-tier cache of processed results, keyed by their inputs
+//! - [`callbacks`]: Signed completion callbacks for jobs, with retries
 //! - [`error`]: Error types and HTTP error responses
+//! - [`events`]: Progress events of jobs, streamed to clients
+//! - [`format`]: Image format sniffing and still image codecs
 //! - [`handlers`]: HTTP request handlers for API endpoints
+//! - [`inspect`]: GIF structure and metadata without decoding pixels
+//! - [`jobs`]: Asynchronous jobs on a bounded worker queue
+//! - [`limits`]: Upload and decode limits against decompression bombs
+//! - [`metadata`]: GIF comment and application extensions carried through processing
+//! - [`ops`]: Frame operations and the operation lists of the transform endpoint
+//! - [`pipeline`]: Pipelined decode, GPU and quantization stages for GIF frames
+//! - [`preview`]: Static previews: single frames and contact sheets as PNG
+//! - [`scheduler`]: Admission control and queueing for GPU work
+//! - [`spool`]: On-disk spool that lets jobs survive a restart
+//! - [`stream`]: Channels bridging async HTTP bodies and the blocking GIF codecs
+//! - [`timing`]: Animation timing operations (speed, reverse, ping-pong, frame rate)
 //!
 //! ## Example
 //!
@@ -44,8 +64,27 @@
 //! }
 //! ```
 
+pub mod apng;
+pub mod archive;
+pub mod assemble;
+pub mod auth;
+pub mod cache;
+pub mod callbacks;
 pub mod error;
+pub mod events;
+pub mod format;
 pub mod handlers;
+pub mod inspect;
+pub mod jobs;
+pub mod limits;
+pub mod metadata;
+pub mod ops;
+pub mod pipeline;
+pub mod preview;
+pub mod scheduler;
+pub mod spool;
+pub mod stream;
+pub mod timing;
 
 pub use error::{GpuWorkerError, Result};
 
@@ -0,0 +1,391 @@
+//! Limits on uploads and on the images decoded from them.
+//!
+//! A GIF of a few hundred bytes can declare a 65535x65535 canvas and thousands of frames, and
+//! decoding it naively would allocate gigabytes of RGBA buffers. [`DecodeLimits`] bounds the size
+//! of the request body and of everything decoded from it. Upload size is enforced while the body
+//! streams in; canvas size, frame count, total decoded pixels and animation duration are checked
+//! from frame headers before any pixel buffer is allocated. A processing deadline stops requests
+//! that take too long between two frames.
+//!
+//! Each endpoint can have limits of its own, see [`EndpointLimits`].
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use actix_web::error::PayloadError;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+
+use crate::{
+    error::{GpuWorkerError, Result},
+    pipeline::FrameOp,
+};
+
+/// Default largest accepted request body, in bytes.
+pub const DEFAULT_MAX_UPLOAD_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Default largest canvas (or single image), in pixels.
+pub const DEFAULT_MAX_CANVAS_PIXELS: u64 = 4096 * 4096;
+
+/// Default largest width or height of a canvas, the largest texture side GPUs must support.
+pub const DEFAULT_MAX_DIMENSION: u32 = 8192;
+
+/// Default largest number of frames in an animation.
+pub const DEFAULT_MAX_FRAMES: usize = 2000;
+
+/// Default largest number of pixels decoded across all frames of an animation.
+pub const DEFAULT_MAX_TOTAL_PIXELS: u64 = 500_000_000;
+
+/// Default longest animation, in milliseconds.
+pub const DEFAULT_MAX_DURATION_MS: u64 = 10 * 60 * 1000;
+
+/// Default longest time a request may spend processing, in milliseconds.
+pub const DEFAULT_MAX_PROCESSING_MS: u64 = 2 * 60 * 1000;
+
+/// Bounds on an upload and on the images decoded from it
+///
+/// Register it as app data to override the defaults, on the app or on a single resource. Violating
+/// the upload size returns `413 Payload Too Large`, missing the processing deadline returns
+/// `504 Gateway Timeout` and the other limits return `422 Unprocessable Entity`.
+///
+/// Serializes to the limits that decide whether an upload can be processed at all, as part of
+/// cache keys; the upload size is checked before a cache is consulted and hits need no time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DecodeLimits {
+    /// Largest accepted request body, in bytes.
+    #[serde(skip)]
+    pub max_upload_bytes: u64,
+    /// Largest canvas, still image or single frame, in pixels.
+    pub max_canvas_pixels: u64,
+    /// Largest width or height of a canvas, still image or single frame. GPU operations fail on
+    /// textures larger than the device supports, so this should not exceed its limit.
+    pub max_dimension: u32,
+    /// Largest number of frames decoded from one animation.
+    pub max_frames: usize,
+    /// Largest number of pixels decoded across all frames; every frame counts as a full canvas.
+    pub max_total_pixels: u64,
+    /// Longest animation, summed over the frame delays, in milliseconds.
+    pub max_duration_ms: u64,
+    /// Longest time a request may spend from its arrival to its last processed frame, in
+    /// milliseconds; 0 for no deadline. Jobs run without a deadline.
+    #[serde(skip)]
+    pub max_processing_ms: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_upload_bytes: DEFAULT_MAX_UPLOAD_BYTES,
+            max_canvas_pixels: DEFAULT_MAX_CANVAS_PIXELS,
+            max_dimension: DEFAULT_MAX_DIMENSION,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_total_pixels: DEFAULT_MAX_TOTAL_PIXELS,
+            max_duration_ms: DEFAULT_MAX_DURATION_MS,
+            max_processing_ms: DEFAULT_MAX_PROCESSING_MS,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Checks a declared or accumulated upload size.
+    pub fn check_upload_size(&self, bytes: u64) -> Result<()> {
+        if bytes > self.max_upload_bytes {
+            return Err(GpuWorkerError::PayloadTooLarge(format!(
+                "Upload is {} bytes, at most {} bytes are accepted",
+                bytes, self.max_upload_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks the size of a canvas, still image or frame before it is allocated.
+    pub fn check_canvas(&self, width: u32, height: u32) -> Result<()> {
+        if width > self.max_dimension || height > self.max_dimension {
+            return Err(GpuWorkerError::LimitExceeded(format!(
+                "Image is {}x{}, at most {} pixels wide and high are allowed",
+                width, height, self.max_dimension
+            )));
+        }
+        if width as u64 * height as u64 > self.max_canvas_pixels {
+            return Err(GpuWorkerError::LimitExceeded(format!(
+                "Image is {}x{}, at most {} pixels are allowed",
+                width, height, self.max_canvas_pixels
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks the number of frames of an animation.
+    pub fn check_frames(&self, frames: usize) -> Result<()> {
+        if frames > self.max_frames {
+            return Err(GpuWorkerError::LimitExceeded(format!(
+                "Animation has more than {} frames",
+                self.max_frames
+            )));
+        }
+        Ok(())
+    }
+
+    /// Starts tracking the frames of an animation decoded onto a `width`x`height` canvas.
+    pub fn frame_budget(&self, width: u32, height: u32) -> Result<FrameBudget> {
+        self.check_canvas(width, height)?;
+        Ok(FrameBudget {
+            limits: *self,
+            canvas_pixels: width as u64 * height as u64,
+            frames: 0,
+            pixels: 0,
+            duration_ms: 0,
+        })
+    }
+
+    /// Starts the processing deadline of a request.
+    pub fn deadline(&self) -> Deadline {
+        Deadline {
+            at: (self.max_processing_ms > 0)
+                .then(|| Instant::now() + Duration::from_millis(self.max_processing_ms)),
+            max_processing_ms: self.max_processing_ms,
+        }
+    }
+
+    /// Fails a request body with [`PayloadError::Overflow`] once it exceeds the upload limit.
+    pub fn limit_body<S>(
+        &self,
+        body: S,
+    ) -> impl Stream<Item = std::result::Result<Bytes, PayloadError>> + Unpin
+    where
+        S: Stream<Item = std::result::Result<Bytes, PayloadError>> + Unpin,
+    {
+        let max = self.max_upload_bytes;
+        let mut received = 0u64;
+        body.map(move |chunk| {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            if received > max {
+                return Err(PayloadError::Overflow);
+            }
+            Ok(chunk)
+        })
+    }
+}
+
+/// Running totals of an animation being decoded, see [`DecodeLimits::frame_budget`].
+#[derive(Debug, Clone)]
+pub struct FrameBudget {
+    limits: DecodeLimits,
+    canvas_pixels: u64,
+    frames: usize,
+    pixels: u64,
+    duration_ms: u64,
+}
+
+impl FrameBudget {
+    /// Accounts for the next frame from its header, before its pixels are decoded.
+    ///
+    /// `delay` is in centiseconds.
+    pub fn admit(&mut self, delay: u16) -> Result<()> {
+        self.frames += 1;
+        self.pixels += self.canvas_pixels;
+        self.duration_ms += delay as u64 * 10;
+
+        self.limits.check_frames(self.frames)?;
+        if self.pixels > self.limits.max_total_pixels {
+            return Err(GpuWorkerError::LimitExceeded(format!(
+                "Animation decodes to more than {} pixels",
+                self.limits.max_total_pixels
+            )));
+        }
+        if self.duration_ms > self.limits.max_duration_ms {
+            return Err(GpuWorkerError::LimitExceeded(format!(
+                "Animation is longer than {} ms",
+                self.limits.max_duration_ms
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Point in time by which a request must be done processing, see [`DecodeLimits::deadline`].
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    at: Option<Instant>,
+    max_processing_ms: u64,
+}
+
+impl Deadline {
+    /// Fails once the deadline has passed.
+    pub fn check(&self) -> Result<()> {
+        match self.at {
+            Some(at) if Instant::now() >= at => Err(GpuWorkerError::DeadlineExceeded(format!(
+                "Processing took longer than {} ms",
+                self.max_processing_ms
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Frame operation that stops between frames once its deadline has passed.
+pub struct WithDeadline<O> {
+    pub op: O,
+    pub deadline: Deadline,
+}
+
+impl<O: FrameOp> FrameOp for WithDeadline<O> {
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        self.op.output_dimensions(width, height)
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        self.deadline.check()?;
+        self.op.apply(rgba, width, height)
+    }
+}
+
+/// Limits of every endpoint: defaults, and overrides for the endpoints that differ from them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EndpointLimits {
+    pub default: DecodeLimits,
+    /// Limits by endpoint name, such as `transform` or `mirror-gif`.
+    pub endpoints: BTreeMap<String, DecodeLimits>,
+}
+
+impl EndpointLimits {
+    /// Limits of the endpoint called `name`.
+    pub fn get(&self, name: &str) -> DecodeLimits {
+        self.endpoints.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{stream, TryStreamExt};
+
+    #[test]
+    fn test_canvas_and_upload_limits() {
+        let limits = DecodeLimits {
+            max_upload_bytes: 100,
+            max_canvas_pixels: 100,
+            ..DecodeLimits::default()
+        };
+
+        assert!(limits.check_upload_size(100).is_ok());
+        assert!(matches!(
+            limits.check_upload_size(101),
+            Err(GpuWorkerError::PayloadTooLarge(_))
+        ));
+        assert!(limits.check_canvas(10, 10).is_ok());
+        assert!(matches!(
+            limits.check_canvas(65535, 65535),
+            Err(GpuWorkerError::LimitExceeded(_))
+        ));
+        assert!(limits.frame_budget(11, 10).is_err());
+
+        // Few enough pixels, but wider than a GPU texture may be
+        let defaults = DecodeLimits::default();
+        assert!(defaults.check_canvas(8192, 2048).is_ok());
+        assert!(matches!(
+            defaults.check_canvas(65535, 256),
+            Err(GpuWorkerError::LimitExceeded(_))
+        ));
+        assert!(defaults.check_canvas(256, 8193).is_err());
+    }
+
+    #[test]
+    fn test_frame_budget() {
+        let frames = DecodeLimits {
+            max_frames: 2,
+            ..DecodeLimits::default()
+        };
+        let mut budget = frames.frame_budget(10, 10).unwrap();
+        assert!(budget.admit(10).is_ok());
+        assert!(budget.admit(10).is_ok());
+        assert!(budget.admit(10).is_err());
+
+        let pixels = DecodeLimits {
+            max_total_pixels: 250,
+            ..DecodeLimits::default()
+        };
+        let mut budget = pixels.frame_budget(10, 10).unwrap();
+        assert!(budget.admit(0).is_ok());
+        assert!(budget.admit(0).is_ok());
+        assert!(budget.admit(0).is_err());
+
+        let duration = DecodeLimits {
+            max_duration_ms: 1000,
+            ..DecodeLimits::default()
+        };
+        let mut budget = duration.frame_budget(10, 10).unwrap();
+        assert!(budget.admit(60).is_ok());
+        assert!(budget.admit(40).is_ok());
+        assert!(budget.admit(1).is_err());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let expired = DecodeLimits {
+            max_processing_ms: 1,
+            ..DecodeLimits::default()
+        }
+        .deadline();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(matches!(
+            expired.check(),
+            Err(GpuWorkerError::DeadlineExceeded(_))
+        ));
+
+        let op = WithDeadline {
+            op: |rgba: &[u8], _, _| Ok(rgba.to_vec()),
+            deadline: expired,
+        };
+        assert!(op.apply(&[0; 4], 1, 1).is_err());
+
+        let unlimited = DecodeLimits {
+            max_processing_ms: 0,
+            ..DecodeLimits::default()
+        };
+        assert!(unlimited.deadline().check().is_ok());
+        assert!(DecodeLimits::default().deadline().check().is_ok());
+    }
+
+    #[test]
+    fn test_endpoint_limits_fall_back_to_defaults() {
+        let small = DecodeLimits {
+            max_upload_bytes: 10,
+            ..DecodeLimits::default()
+        };
+        let limits = EndpointLimits {
+            default: DecodeLimits::default(),
+            endpoints: [("inspect".to_string(), small)].into_iter().collect(),
+        };
+        assert_eq!(limits.get("inspect"), small);
+        assert_eq!(limits.get("transform"), DecodeLimits::default());
+    }
+
+    #[actix_web::test]
+    async fn test_limit_body() {
+        let limits = DecodeLimits {
+            max_upload_bytes: 5,
+            ..DecodeLimits::default()
+        };
+        let chunks = || {
+            stream::iter(vec![
+                Ok(Bytes::from_static(b"abc")),
+                Ok(Bytes::from_static(b"de")),
+                Ok(Bytes::from_static(b"f")),
+            ])
+        };
+
+        let mut body = limits.limit_body(chunks());
+        assert_eq!(body.try_next().await.unwrap().unwrap(), "abc");
+        assert_eq!(body.try_next().await.unwrap().unwrap(), "de");
+        assert!(matches!(body.try_next().await, Err(PayloadError::Overflow)));
+
+        let unlimited = DecodeLimits::default().limit_body(chunks());
+        let data: Vec<Bytes> = unlimited.try_collect().await.unwrap();
+        assert_eq!(data.len(), 3);
+    }
+}
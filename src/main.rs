@@ -1,16 +1,29 @@
 use actix_web::{middleware, web, App, HttpServer};
+use gpu_worker::{
+    auth::{ApiKeys, AuthConfig, RequireScope, Scope},
+    cache::{CacheConfig, ResultCache},
+    callbacks::CallbackConfig,
+    error,
+    handlers::{
+        assemble_gif, blur_gif, cancel_job, contact_sheet, extract_frame, gpu_queue, inspect_gif,
+        job_events, job_result, job_status, mirror_gif, retime_gif, submit_job, transform,
+        transform_job_runner,
+    },
+    jobs::{JobQueue, JobQueueConfig},
+    limits::{DecodeLimits, EndpointLimits},
+    ops::Processors,
+    pipeline::QuantizePool,
+    scheduler::{GpuScheduler, GpuSchedulerConfig},
+};
 use log::info;
-use std::sync::Arc;
-
-mod error;
-mod handlers;
-
-use handlers::mirror_gif;
-use transformations::MirrorProcessor;
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+use transformations::{BlurProcessor, MirrorProcessor, ResizeProcessor};
 
 #[derive(Clone)]
 struct AppState {
     mirror_processor: Arc<MirrorProcessor>,
+    blur_processor: Arc<BlurProcessor>,
+    resize_processor: Arc<ResizeProcessor>,
 }
 
 #[actix_web::main]
@@ -23,22 +36,55 @@ async fn main() -> std::io::Result<()> {
     info!("Server configuration: {:?}", config);
 
     let app_state = initialize_app_state().await?;
+    let quantize_pool = web::Data::new(QuantizePool::new(config.quantize_threads));
+    let decode_limits = web::Data::new(config.limits.default);
+    let limits = config.limits.clone();
+    let gpu_scheduler = GpuScheduler::new(config.gpu.clone());
+    let job_runner = transform_job_runner(
+        Processors {
+            mirror: app_state.mirror_processor.clone(),
+            blur: app_state.blur_processor.clone(),
+            resize: app_state.resize_processor.clone(),
+        },
+        config.limits.get("jobs"),
+        quantize_pool.get_ref().clone(),
+        gpu_scheduler.clone(),
+    );
+    let gpu_scheduler = web::Data::new(gpu_scheduler);
+    let job_queue = web::Data::new(
+        JobQueue::new(config.jobs.clone(), job_runner)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?,
+    );
+    let api_keys = web::Data::new(
+        ApiKeys::load(&config.auth)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?,
+    );
+    if api_keys.is_empty() {
+        log::warn!("No API keys configured; every endpoint is open");
+    } else {
+        info!("Loaded {} API keys", api_keys.len());
+    }
+    let result_cache = web::Data::new(
+        ResultCache::new(config.cache.clone())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?,
+    );
 
     info!("Starting server on {}:{}", config.host, config.port);
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(app_state.clone()))
+            .app_data(quantize_pool.clone())
+            .app_data(decode_limits.clone())
+            .app_data(job_queue.clone())
+            .app_data(result_cache.clone())
+            .app_data(gpu_scheduler.clone())
+            .app_data(api_keys.clone())
             .wrap(middleware::Logger::default())
             .wrap(middleware::NormalizePath::trim())
             .wrap(middleware::DefaultHeaders::new().add(("X-Version", env!("CARGO_PKG_VERSION"))))
-            .service(
-                web::scope("/api/v1")
-                    .route("/health", web::get().to(health_check))
-                    .route("/mirror-gif", web::post().to(mirror_gif_handler)),
-            )
-            .route("/health", web::get().to(health_check))
-            .route("/mirror-gif", web::post().to(mirror_gif_handler))
+            .service(web::scope("/api/v1").configure(|cfg| routes(cfg, &limits)))
+            .configure(|cfg| routes(cfg, &limits))
     })
     .bind((config.host.as_str(), config.port))?
     .workers(config.workers)
@@ -46,6 +92,67 @@ async fn main() -> std::io::Result<()> {
     .await
 }
 
+/// Every endpoint; served both under `/api/v1` and at the root. All but `/health` require an API
+/// key with the scope given here when keys are configured.
+fn routes(cfg: &mut web::ServiceConfig, limits: &EndpointLimits) {
+    use Scope::{Admin, Jobs, Transform};
+
+    cfg.route("/health", web::get().to(health_check))
+        .service(limited("mirror-gif", Transform, limits, mirror_gif_handler))
+        .service(limited("blur-gif", Transform, limits, blur_gif_handler))
+        .service(limited("transform", Transform, limits, transform_handler))
+        .service(limited("jobs", Jobs, limits, submit_job))
+        .service(
+            web::resource("/jobs/{id}")
+                .wrap(RequireScope(Jobs))
+                .route(web::get().to(job_status))
+                .route(web::delete().to(cancel_job)),
+        )
+        .service(
+            web::resource("/jobs/{id}/result")
+                .wrap(RequireScope(Jobs))
+                .route(web::get().to(job_result)),
+        )
+        .service(
+            web::resource("/jobs/{id}/events")
+                .wrap(RequireScope(Jobs))
+                .route(web::get().to(job_events)),
+        )
+        .service(
+            web::resource("/gpu-queue")
+                .wrap(RequireScope(Admin))
+                .route(web::get().to(gpu_queue)),
+        )
+        .service(limited("retime-gif", Transform, limits, retime_gif))
+        .service(limited("extract-frame", Transform, limits, extract_frame))
+        .service(limited("contact-sheet", Transform, limits, contact_sheet))
+        .service(limited(
+            "assemble-gif",
+            Transform,
+            limits,
+            assemble_gif_handler,
+        ))
+        .service(limited("inspect", Transform, limits, inspect_gif));
+}
+
+/// The POST endpoint called `name`, with the limits configured for it, for keys with `scope`
+fn limited<F, Args>(
+    name: &str,
+    scope: Scope,
+    limits: &EndpointLimits,
+    handler: F,
+) -> impl actix_web::dev::HttpServiceFactory
+where
+    F: actix_web::Handler<Args>,
+    Args: actix_web::FromRequest + 'static,
+    F::Output: actix_web::Responder + 'static,
+{
+    web::resource(format!("/{}", name))
+        .app_data(web::Data::new(limits.get(name)))
+        .wrap(RequireScope(scope))
+        .route(web::post().to(handler))
+}
+
 async fn initialize_app_state() -> std::io::Result<AppState> {
     info!("Initializing Mirror processor...");
 
@@ -54,16 +161,79 @@ async fn initialize_app_state() -> std::io::Result<AppState> {
         std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
     })?;
 
+    info!("Initializing Blur processor...");
+
+    let blur_processor = BlurProcessor::new().await.map_err(|e| {
+        log::error!("Failed to create BlurProcessor: {}", e);
+        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+    })?;
+
+    info!("Initializing Resize processor...");
+
+    let resize_processor = ResizeProcessor::new().await.map_err(|e| {
+        log::error!("Failed to create ResizeProcessor: {}", e);
+        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+    })?;
+
     Ok(AppState {
         mirror_processor: Arc::new(mirror_processor),
+        blur_processor: Arc::new(blur_processor),
+        resize_processor: Arc::new(resize_processor),
     })
 }
 
 async fn mirror_gif_handler(
-    payload: actix_multipart::Multipart,
+    req: actix_web::HttpRequest,
+    payload: web::Payload,
+    app_state: web::Data<AppState>,
+) -> Result<impl actix_web::Responder, error::GpuWorkerError> {
+    mirror_gif(
+        req,
+        payload,
+        web::Data::from(app_state.mirror_processor.clone()),
+    )
+    .await
+}
+
+async fn blur_gif_handler(
+    req: actix_web::HttpRequest,
+    payload: web::Payload,
     app_state: web::Data<AppState>,
 ) -> Result<impl actix_web::Responder, error::GpuWorkerError> {
-    mirror_gif(payload, web::Data::from(app_state.mirror_processor.clone())).await
+    blur_gif(
+        req,
+        payload,
+        web::Data::from(app_state.blur_processor.clone()),
+    )
+    .await
+}
+
+async fn transform_handler(
+    req: actix_web::HttpRequest,
+    payload: web::Payload,
+    app_state: web::Data<AppState>,
+) -> Result<impl actix_web::Responder, error::GpuWorkerError> {
+    transform(
+        req,
+        payload,
+        web::Data::from(app_state.mirror_processor.clone()),
+        web::Data::from(app_state.blur_processor.clone()),
+        web::Data::from(app_state.resize_processor.clone()),
+    )
+    .await
+}
+
+async fn assemble_gif_handler(
+    req: actix_web::HttpRequest,
+    payload: web::Payload,
+    app_state: web::Data<AppState>,
+) -> Result<impl actix_web::Responder, error::GpuWorkerError> {
+    assemble_gif(
+        req,
+        payload,
+        web::Data::from(app_state.resize_processor.clone()),
+    )
+    .await
 }
 
 async fn health_check() -> actix_web::Result<impl actix_web::Responder> {
@@ -71,7 +241,18 @@ async fn health_check() -> actix_web::Result<impl actix_web::Responder> {
         status: "healthy".to_string(),
         service: "gpu-worker".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
-        features: vec!["mirror-gif".to_string()],
+        features: vec![
+            "mirror-gif".to_string(),
+            "blur-gif".to_string(),
+            "transform".to_string(),
+            "jobs".to_string(),
+            "retime-gif".to_string(),
+            "extract-frame".to_string(),
+            "contact-sheet".to_string(),
+            "assemble-gif".to_string(),
+            "inspect".to_string(),
+            "gpu-queue".to_string(),
+        ],
     };
 
     Ok(web::Json(health_status))
@@ -90,6 +271,12 @@ struct Config {
     host: String,
     port: u16,
     workers: usize,
+    quantize_threads: usize,
+    limits: EndpointLimits,
+    jobs: JobQueueConfig,
+    cache: CacheConfig,
+    gpu: GpuSchedulerConfig,
+    auth: AuthConfig,
 }
 
 impl Config {
@@ -104,10 +291,158 @@ impl Config {
                 .ok()
                 .and_then(|w| w.parse().ok())
                 .unwrap_or_else(num_cpus::get),
+            quantize_threads: std::env::var("QUANTIZE_THREADS")
+                .ok()
+                .and_then(|t| t.parse().ok())
+                .unwrap_or_else(num_cpus::get),
+            limits: limits_from_env(),
+            jobs: jobs_from_env(),
+            cache: cache_from_env(),
+            gpu: gpu_from_env(),
+            auth: AuthConfig {
+                keys: std::env::var("API_KEYS").ok(),
+                key_file: std::env::var_os("API_KEY_FILE").map(PathBuf::from),
+            },
         }
     }
 }
 
+/// Endpoints that accept uploads, by name. Each one takes its limits from variables prefixed with
+/// its name in upper case with dashes as underscores, e.g. `TRANSFORM_MAX_UPLOAD_BYTES`, falling
+/// back to the unprefixed ones.
+const LIMITED_ENDPOINTS: [&str; 9] = [
+    "mirror-gif",
+    "blur-gif",
+    "transform",
+    "jobs",
+    "retime-gif",
+    "extract-frame",
+    "contact-sheet",
+    "assemble-gif",
+    "inspect",
+];
+
+fn limits_from_env() -> EndpointLimits {
+    let default = decode_limits_from_env("", DecodeLimits::default());
+    let endpoints = LIMITED_ENDPOINTS
+        .iter()
+        .filter_map(|name| {
+            let prefix = format!("{}_", name.to_uppercase().replace('-', "_"));
+            let limits = decode_limits_from_env(&prefix, default);
+            (limits != default).then(|| (name.to_string(), limits))
+        })
+        .collect();
+    EndpointLimits { default, endpoints }
+}
+
+fn decode_limits_from_env(prefix: &str, defaults: DecodeLimits) -> DecodeLimits {
+    let name = |name: &str| format!("{}{}", prefix, name);
+    DecodeLimits {
+        max_upload_bytes: env_var(&name("MAX_UPLOAD_BYTES"), defaults.max_upload_bytes),
+        max_canvas_pixels: env_var(&name("MAX_CANVAS_PIXELS"), defaults.max_canvas_pixels),
+        max_dimension: env_var(&name("MAX_DIMENSION"), defaults.max_dimension),
+        max_frames: env_var(&name("MAX_FRAMES"), defaults.max_frames),
+        max_total_pixels: env_var(&name("MAX_TOTAL_PIXELS"), defaults.max_total_pixels),
+        max_duration_ms: env_var(&name("MAX_DURATION_MS"), defaults.max_duration_ms),
+        max_processing_ms: env_var(&name("MAX_PROCESSING_MS"), defaults.max_processing_ms),
+    }
+}
+
+fn jobs_from_env() -> JobQueueConfig {
+    let defaults = JobQueueConfig::default();
+    JobQueueConfig {
+        workers: env_var("JOB_WORKERS", defaults.workers),
+        queue_depth: env_var("JOB_QUEUE_DEPTH", defaults.queue_depth),
+        retained: env_var("JOB_RETAINED", defaults.retained),
+        spool_dir: std::env::var_os("JOB_SPOOL_DIR").map(PathBuf::from),
+        result_ttl: Duration::from_secs(env_var(
+            "JOB_RESULT_TTL_SECS",
+            defaults.result_ttl.as_secs(),
+        )),
+        max_attempts: env_var("JOB_MAX_ATTEMPTS", defaults.max_attempts),
+        callbacks: callbacks_from_env(),
+    }
+}
+
+fn callbacks_from_env() -> CallbackConfig {
+    let defaults = CallbackConfig::default();
+    CallbackConfig {
+        secret: std::env::var("CALLBACK_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty()),
+        max_attempts: env_var("CALLBACK_MAX_ATTEMPTS", defaults.max_attempts),
+        backoff: Duration::from_millis(env_var(
+            "CALLBACK_BACKOFF_MS",
+            defaults.backoff.as_millis() as u64,
+        )),
+        timeout: Duration::from_secs(env_var("CALLBACK_TIMEOUT_SECS", defaults.timeout.as_secs())),
+        allowed_hosts: std::env::var("CALLBACK_ALLOWED_HOSTS")
+            .map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+fn gpu_from_env() -> GpuSchedulerConfig {
+    let defaults = GpuSchedulerConfig::default();
+    GpuSchedulerConfig {
+        max_concurrent: env_var("GPU_MAX_CONCURRENT", defaults.max_concurrent),
+        queue_depth: env_var("GPU_QUEUE_DEPTH", defaults.queue_depth),
+        retry_after: Duration::from_secs(env_var(
+            "GPU_RETRY_AFTER_SECS",
+            defaults.retry_after.as_secs(),
+        )),
+        client_weights: std::env::var("GPU_CLIENT_WEIGHTS")
+            .map(|weights| parse_client_weights(&weights))
+            .unwrap_or_default(),
+    }
+}
+
+/// Parses `client=weight` pairs separated by commas, skipping malformed ones with a warning.
+fn parse_client_weights(weights: &str) -> BTreeMap<String, u32> {
+    weights
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .filter_map(|pair| {
+            let parsed = pair
+                .split_once('=')
+                .and_then(|(client, weight)| Some((client.trim(), weight.trim().parse().ok()?)));
+            match parsed {
+                Some((client, weight)) if !client.is_empty() && weight > 0 => {
+                    Some((client.to_string(), weight))
+                }
+                _ => {
+                    log::warn!("Ignoring malformed GPU client weight {:?}", pair);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn cache_from_env() -> CacheConfig {
+    let defaults = CacheConfig::default();
+    CacheConfig {
+        memory_bytes: env_var("CACHE_MEMORY_BYTES", defaults.memory_bytes),
+        max_entry_bytes: env_var("CACHE_MAX_ENTRY_BYTES", defaults.max_entry_bytes),
+        disk_dir: std::env::var_os("CACHE_DIR").map(PathBuf::from),
+        disk_bytes: env_var("CACHE_DISK_BYTES", defaults.disk_bytes),
+    }
+}
+
+fn env_var<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
 fn init_logger() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
         .format_timestamp_secs()
@@ -124,6 +459,21 @@ mod tests {
         assert_eq!(config.port, 8080);
         assert_eq!(config.host, "0.0.0.0");
         assert!(config.workers > 0);
+        assert!(config.quantize_threads > 0);
+        assert_eq!(config.limits, EndpointLimits::default());
+        assert_eq!(config.jobs, JobQueueConfig::default());
+        assert_eq!(config.cache, CacheConfig::default());
+        assert_eq!(config.gpu, GpuSchedulerConfig::default());
+        assert_eq!(config.auth, AuthConfig::default());
+    }
+
+    #[test]
+    fn test_parse_client_weights() {
+        let weights = parse_client_weights("tenant-a=4, tenant-b = 2,broken,zero=0,");
+        assert_eq!(
+            weights.into_iter().collect::<Vec<_>>(),
+            vec![("tenant-a".to_string(), 4), ("tenant-b".to_string(), 2)]
+        );
     }
 
     #[test]
@@ -0,0 +1,403 @@
+//! GIF comment and application extensions carried through processing.
+//!
+//! `gif::Decoder` skips every extension except graphic control, so comments and application data
+//! such as embedded XMP would be lost on re-encoding. [`MetadataTap`] sits between the upload and
+//! the decoder and scans the GIF block structure as the bytes pass through, collecting those
+//! blocks into a [`Metadata`] handle that the encoder writes back. The `NETSCAPE2.0` looping
+//! extension is not collected, since the encoder writes the loop count itself.
+
+use std::{
+    io::{self, Read},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::inspect::{palette_size, APPLICATION_LABEL, COMMENT_LABEL};
+
+/// Most metadata bytes kept per GIF; blocks beyond it are dropped.
+pub const MAX_METADATA_BYTES: usize = 1024 * 1024;
+
+/// Application identifiers of the looping extension, which the encoder writes itself.
+const LOOP_IDENTIFIERS: [&[u8]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+/// Whether metadata is written back to the output.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MetadataOptions {
+    /// Drop comment and application extensions from the output.
+    #[serde(default)]
+    pub strip_metadata: bool,
+}
+
+/// A comment or application extension block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataBlock {
+    /// Extension label, [`COMMENT_LABEL`] or [`APPLICATION_LABEL`].
+    pub label: u8,
+    /// Contents of the data sub-blocks, each at most 255 bytes.
+    pub sub_blocks: Vec<Vec<u8>>,
+}
+
+impl MetadataBlock {
+    fn len(&self) -> usize {
+        self.sub_blocks.iter().map(Vec::len).sum()
+    }
+
+    fn is_preserved(&self) -> bool {
+        match self.label {
+            COMMENT_LABEL => true,
+            APPLICATION_LABEL => self
+                .sub_blocks
+                .first()
+                .is_some_and(|identifier| !LOOP_IDENTIFIERS.contains(&&identifier[..])),
+            _ => false,
+        }
+    }
+}
+
+/// Metadata blocks collected from an input, shared between the decoding and encoding threads
+///
+/// Blocks are handed out in input order as they are collected. An empty handle that is never fed
+/// by a tap simply yields nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    blocks: Arc<Mutex<MetadataState>>,
+}
+
+#[derive(Debug, Default)]
+struct MetadataState {
+    pending: Vec<MetadataBlock>,
+    bytes: usize,
+}
+
+impl Metadata {
+    /// Removes and returns the blocks collected since the last call.
+    pub fn take(&self) -> Vec<MetadataBlock> {
+        std::mem::take(&mut self.lock().pending)
+    }
+
+    fn push(&self, block: MetadataBlock) {
+        let mut state = self.lock();
+        if state.bytes + block.len() > MAX_METADATA_BYTES {
+            log::warn!("Dropping GIF metadata beyond {} bytes", MAX_METADATA_BYTES);
+            return;
+        }
+        state.bytes += block.len();
+        state.pending.push(block);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MetadataState> {
+        self.blocks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Where the scanner is in the GIF block structure.
+#[derive(Debug)]
+enum State {
+    /// Collecting the header and logical screen descriptor.
+    Header(Vec<u8>),
+    /// Skipping a color table; `image` tells whether image data follows.
+    Palette { remaining: usize, image: bool },
+    /// Expecting an extension introducer, image separator or trailer.
+    Block,
+    /// Expecting the label of an extension.
+    Label,
+    /// Collecting an image descriptor.
+    ImageDescriptor(Vec<u8>),
+    /// Expecting the LZW minimum code size of image data.
+    CodeSize,
+    /// Expecting the length of the next sub-block, or the terminator.
+    SubBlockLength,
+    /// Inside a sub-block with the given number of bytes left.
+    SubBlock(usize),
+    /// At the trailer, or lost after malformed input; the decoder reports errors.
+    Done,
+}
+
+/// Reader that collects GIF metadata blocks from the data read through it
+pub struct MetadataTap<R> {
+    inner: R,
+    state: State,
+    /// The extension being read, if it is collected.
+    current: Option<MetadataBlock>,
+    /// Bytes collected into `current` so far.
+    current_bytes: usize,
+    metadata: Metadata,
+}
+
+impl<R> std::fmt::Debug for MetadataTap<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetadataTap")
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read> MetadataTap<R> {
+    /// Wraps a reader over GIF data.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: State::Header(Vec::with_capacity(13)),
+            current: None,
+            current_bytes: 0,
+            metadata: Metadata::default(),
+        }
+    }
+
+    /// Handle to the blocks collected so far, and those collected later.
+    pub fn metadata(&self) -> Metadata {
+        self.metadata.clone()
+    }
+
+    fn scan(&mut self, mut data: &[u8]) {
+        while let Some((&byte, rest)) = data.split_first() {
+            match &mut self.state {
+                State::Header(buffer) => {
+                    if fill(buffer, &mut data, 13) {
+                        self.state = palette_state(buffer[10], false);
+                    }
+                    continue;
+                }
+                State::ImageDescriptor(buffer) => {
+                    if fill(buffer, &mut data, 9) {
+                        self.state = palette_state(buffer[8], true);
+                    }
+                    continue;
+                }
+                State::Palette { remaining, image } => {
+                    let len = (*remaining).min(data.len());
+                    *remaining -= len;
+                    data = &data[len..];
+                    if *remaining == 0 {
+                        self.state = if *image {
+                            State::CodeSize
+                        } else {
+                            State::Block
+                        };
+                    }
+                    continue;
+                }
+                State::Block => {
+                    self.state = match byte {
+                        0x21 => State::Label,
+                        0x2C => State::ImageDescriptor(Vec::with_capacity(9)),
+                        _ => State::Done,
+                    };
+                }
+                State::Label => {
+                    self.current =
+                        matches!(byte, COMMENT_LABEL | APPLICATION_LABEL).then(|| MetadataBlock {
+                            label: byte,
+                            sub_blocks: Vec::new(),
+                        });
+                    self.current_bytes = 0;
+                    self.state = State::SubBlockLength;
+                }
+                State::CodeSize => self.state = State::SubBlockLength,
+                State::SubBlockLength => {
+                    if byte == 0 {
+                        if let Some(block) = self.current.take() {
+                            if block.is_preserved() {
+                                self.metadata.push(block);
+                            }
+                        }
+                        self.state = State::Block;
+                    } else {
+                        // Drop oversized blocks while collecting rather than once complete
+                        self.current_bytes += byte as usize;
+                        if self.current.is_some() && self.current_bytes > MAX_METADATA_BYTES {
+                            log::warn!(
+                                "Dropping a GIF metadata block of more than {} bytes",
+                                MAX_METADATA_BYTES
+                            );
+                            self.current = None;
+                        }
+                        if let Some(block) = &mut self.current {
+                            block.sub_blocks.push(Vec::with_capacity(byte as usize));
+                        }
+                        self.state = State::SubBlock(byte as usize);
+                    }
+                }
+                State::SubBlock(remaining) => {
+                    let len = (*remaining).min(data.len());
+                    *remaining -= len;
+                    if let Some(sub_block) = self
+                        .current
+                        .as_mut()
+                        .and_then(|block| block.sub_blocks.last_mut())
+                    {
+                        sub_block.extend_from_slice(&data[..len]);
+                    }
+                    data = &data[len..];
+                    if *remaining == 0 {
+                        self.state = State::SubBlockLength;
+                    }
+                    continue;
+                }
+                State::Done => return,
+            }
+            data = rest;
+        }
+    }
+}
+
+impl<R: Read> Read for MetadataTap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.scan(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Appends bytes from `data` until `buffer` holds `wanted`; returns whether it does.
+fn fill(buffer: &mut Vec<u8>, data: &mut &[u8], wanted: usize) -> bool {
+    let len = (wanted - buffer.len()).min(data.len());
+    buffer.extend_from_slice(&data[..len]);
+    *data = &data[len..];
+    buffer.len() == wanted
+}
+
+/// The state after a descriptor with the given packed `flags`.
+fn palette_state(flags: u8, image: bool) -> State {
+    match palette_size(flags) {
+        Some(entries) => State::Palette {
+            remaining: entries * 3,
+            image,
+        },
+        None if image => State::CodeSize,
+        None => State::Block,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gif::{AnyExtension, Encoder, Frame, Repeat};
+
+    /// A GIF with a comment before the first frame, XMP between frames and a comment at the end.
+    fn create_gif_with_metadata() -> Vec<u8> {
+        let mut output = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut output, 2, 2, &[]).unwrap();
+            encoder.set_repeat(Repeat::Infinite).unwrap();
+            encoder
+                .write_raw_extension(AnyExtension(COMMENT_LABEL), &[b"made by"])
+                .unwrap();
+            let mut frame = Frame::from_rgb(2, 2, &[255; 12]);
+            encoder.write_frame(&frame).unwrap();
+            encoder
+                .write_raw_extension(
+                    AnyExtension(APPLICATION_LABEL),
+                    &[b"XMP DataXMP", &[7; 255], b"<x:xmpmeta/>"],
+                )
+                .unwrap();
+            frame.buffer = vec![0; 12].into();
+            encoder.write_frame(&frame).unwrap();
+            encoder
+                .write_raw_extension(AnyExtension(COMMENT_LABEL), &[b"the end"])
+                .unwrap();
+        }
+        output
+    }
+
+    /// Hands out data one byte per read, to split every block across reads.
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&byte, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = byte;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    fn expected_blocks() -> Vec<MetadataBlock> {
+        vec![
+            MetadataBlock {
+                label: COMMENT_LABEL,
+                sub_blocks: vec![b"made by".to_vec()],
+            },
+            MetadataBlock {
+                label: APPLICATION_LABEL,
+                sub_blocks: vec![
+                    b"XMP DataXMP".to_vec(),
+                    vec![7; 255],
+                    b"<x:xmpmeta/>".to_vec(),
+                ],
+            },
+            MetadataBlock {
+                label: COMMENT_LABEL,
+                sub_blocks: vec![b"the end".to_vec()],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_tap_collects_metadata() {
+        let gif_data = create_gif_with_metadata();
+        let mut tap = MetadataTap::new(gif_data.as_slice());
+        let metadata = tap.metadata();
+
+        let mut passed = Vec::new();
+        tap.read_to_end(&mut passed).unwrap();
+        assert_eq!(passed, gif_data);
+
+        // The NETSCAPE2.0 loop extension is left to the encoder
+        assert_eq!(metadata.take(), expected_blocks());
+        assert!(metadata.take().is_empty());
+    }
+
+    #[test]
+    fn test_tap_handles_split_reads() {
+        let gif_data = create_gif_with_metadata();
+        let mut tap = MetadataTap::new(ByteReader(&gif_data));
+        let metadata = tap.metadata();
+
+        std::io::copy(&mut tap, &mut std::io::sink()).unwrap();
+        assert_eq!(metadata.take(), expected_blocks());
+    }
+
+    #[test]
+    fn test_tap_drops_oversized_blocks() {
+        let large = vec![b'x'; 255];
+        let sub_blocks = vec![large.as_slice(); MAX_METADATA_BYTES / 255 + 1];
+        let mut gif_data = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut gif_data, 2, 2, &[]).unwrap();
+            encoder
+                .write_raw_extension(AnyExtension(COMMENT_LABEL), &sub_blocks)
+                .unwrap();
+            encoder
+                .write_raw_extension(AnyExtension(COMMENT_LABEL), &[b"small"])
+                .unwrap();
+            encoder
+                .write_frame(&Frame::from_rgb(2, 2, &[255; 12]))
+                .unwrap();
+        }
+
+        let mut tap = MetadataTap::new(gif_data.as_slice());
+        let metadata = tap.metadata();
+        std::io::copy(&mut tap, &mut std::io::sink()).unwrap();
+        assert_eq!(
+            metadata.take(),
+            vec![MetadataBlock {
+                label: COMMENT_LABEL,
+                sub_blocks: vec![b"small".to_vec()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tap_ignores_other_data() {
+        let mut tap = MetadataTap::new(&b"\x89PNG\r\n\x1a\n not a gif at all"[..]);
+        let metadata = tap.metadata();
+        std::io::copy(&mut tap, &mut std::io::sink()).unwrap();
+        assert!(metadata.take().is_empty());
+    }
+}
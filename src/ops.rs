@@ -0,0 +1,928 @@
+//! Frame operations and the operation lists of the transform endpoint.
+//!
+//! Each operation implements [`FrameOp`], reporting its output size up front so the pipeline can
+//! size the output canvas before the first frame is encoded. Rotation, cropping, padding and
+//! horizontal mirroring only move pixels and run on the CPU; vertical mirroring, blurring and
+//! resizing run on the GPU through the [`Processors`].
+//!
+//! [`Operation::parse_list`] reads a JSON array such as
+//! `[{"op":"mirror","axis":"horizontal"},{"op":"blur","radius":3}]`, reporting every invalid
+//! field at once, and [`Chain`] runs the parsed list over each frame.
+
+use std::{borrow::Cow, sync::Arc};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use transformations::{BlurProcessor, MirrorProcessor, ResizeProcessor};
+
+use crate::{
+    error::{FieldError, GpuWorkerError, Result},
+    limits::DecodeLimits,
+    pipeline::FrameOp,
+};
+
+/// Blur radius used when none is given, in pixels.
+pub const DEFAULT_BLUR_RADIUS: u32 = 3;
+
+/// Largest accepted blur radius, in pixels; the kernel samples `(2r + 1)²` pixels.
+pub const MAX_BLUR_RADIUS: u32 = 32;
+
+/// Query parameters and form fields of the blur endpoint
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct BlurOptions {
+    /// Blur radius in pixels, 1 to [`MAX_BLUR_RADIUS`].
+    pub radius: Option<u32>,
+}
+
+impl BlurOptions {
+    /// Checks that every option is within range.
+    pub fn validate(&self) -> Result<()> {
+        if self
+            .radius
+            .is_some_and(|radius| radius == 0 || radius > MAX_BLUR_RADIUS)
+        {
+            return Err(GpuWorkerError::InvalidInput(format!(
+                "radius must be between 1 and {}",
+                MAX_BLUR_RADIUS
+            )));
+        }
+        Ok(())
+    }
+
+    /// The blur radius, or [`DEFAULT_BLUR_RADIUS`].
+    pub fn radius(&self) -> u32 {
+        self.radius.unwrap_or(DEFAULT_BLUR_RADIUS)
+    }
+}
+
+/// Box blur of `radius` pixels on the GPU.
+#[derive(Clone)]
+pub struct Blur {
+    pub processor: Arc<BlurProcessor>,
+    pub radius: u32,
+}
+
+impl std::fmt::Debug for Blur {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Blur")
+            .field("radius", &self.radius)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FrameOp for Blur {
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        Ok((width, height))
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        Ok(pollster::block_on(self.processor.blur_image(
+            rgba,
+            width,
+            height,
+            self.radius as f32,
+        ))?)
+    }
+}
+
+/// Clockwise rotation by a multiple of 90 degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotate {
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+impl Rotate {
+    /// The rotation by `degrees` clockwise, which must be 90, 180 or 270.
+    pub fn from_degrees(degrees: u32) -> Result<Self> {
+        match degrees {
+            90 => Ok(Self::Quarter),
+            180 => Ok(Self::Half),
+            270 => Ok(Self::ThreeQuarters),
+            _ => Err(GpuWorkerError::InvalidInput(format!(
+                "Rotation must be 90, 180 or 270 degrees, got {}",
+                degrees
+            ))),
+        }
+    }
+}
+
+impl FrameOp for Rotate {
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        Ok(match self {
+            Self::Half => (width, height),
+            Self::Quarter | Self::ThreeQuarters => (height, width),
+        })
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        check_frame(rgba, width, height)?;
+        let (width, height) = (width as usize, height as usize);
+        let mut output = vec![0; rgba.len()];
+        for (index, pixel) in rgba.chunks_exact(4).enumerate() {
+            let (x, y) = (index % width, index / width);
+            let target = match self {
+                Self::Quarter => x * height + (height - 1 - y),
+                Self::Half => (height - 1 - y) * width + (width - 1 - x),
+                Self::ThreeQuarters => (width - 1 - x) * height + y,
+            };
+            output[target * 4..target * 4 + 4].copy_from_slice(pixel);
+        }
+        Ok(output)
+    }
+}
+
+/// Keeps the `width`x`height` region at (`x`, `y`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FrameOp for Crop {
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        let fits = self.x as u64 + self.width as u64 <= width as u64
+            && self.y as u64 + self.height as u64 <= height as u64;
+        if self.width == 0 || self.height == 0 || !fits {
+            return Err(GpuWorkerError::InvalidInput(format!(
+                "Crop {}x{} at ({}, {}) does not fit a {}x{} frame",
+                self.width, self.height, self.x, self.y, width, height
+            )));
+        }
+        Ok((self.width, self.height))
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        check_frame(rgba, width, height)?;
+        self.output_dimensions(width, height)?;
+        let row = width as usize * 4;
+        let (start, len) = (self.x as usize * 4, self.width as usize * 4);
+        Ok(rgba
+            .chunks_exact(row)
+            .skip(self.y as usize)
+            .take(self.height as usize)
+            .flat_map(|line| &line[start..start + len])
+            .copied()
+            .collect())
+    }
+}
+
+/// Surrounds the frame with borders of `color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pad {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+    /// RGBA fill of the added borders.
+    pub color: [u8; 4],
+}
+
+impl FrameOp for Pad {
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        let output_width = width
+            .checked_add(self.left)
+            .and_then(|w| w.checked_add(self.right));
+        let output_height = height
+            .checked_add(self.top)
+            .and_then(|h| h.checked_add(self.bottom));
+        output_width.zip(output_height).ok_or_else(|| {
+            GpuWorkerError::InvalidInput(format!("Padding a {}x{} frame overflows", width, height))
+        })
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        check_frame(rgba, width, height)?;
+        let (output_width, output_height) = self.output_dimensions(width, height)?;
+        let mut output = self
+            .color
+            .repeat(output_width as usize * output_height as usize);
+        let (row, output_row) = (width as usize * 4, output_width as usize * 4);
+        for (y, line) in rgba.chunks_exact(row).enumerate() {
+            let start = (y + self.top as usize) * output_row + self.left as usize * 4;
+            output[start..start + row].copy_from_slice(line);
+        }
+        Ok(output)
+    }
+}
+
+/// Scales the frame to `width`x`height` on the GPU.
+#[derive(Clone)]
+pub struct Resize {
+    pub processor: Arc<ResizeProcessor>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl std::fmt::Debug for Resize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resize")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FrameOp for Resize {
+    fn output_dimensions(&self, _width: u32, _height: u32) -> Result<(u32, u32)> {
+        Ok((self.width, self.height))
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        Ok(pollster::block_on(self.processor.resize_image(
+            rgba,
+            width,
+            height,
+            self.width,
+            self.height,
+        ))?)
+    }
+}
+
+/// Most operations in one transform request.
+pub const MAX_OPERATIONS: usize = 16;
+
+/// Largest `crop` or `resize` target and largest `pad` border, in pixels.
+pub const MAX_OPERATION_SIZE: u32 = 8192;
+
+/// Operation names accepted in the `op` field.
+pub const OPERATION_NAMES: [&str; 6] = ["mirror", "blur", "rotate", "crop", "pad", "resize"];
+
+/// Direction a `mirror` operation flips the frame in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Axis {
+    /// Left to right.
+    Horizontal,
+    /// Top to bottom, like the mirror endpoint.
+    #[default]
+    Vertical,
+}
+
+/// Flips the frame, vertically on the GPU or horizontally on the CPU.
+#[derive(Clone)]
+pub struct Mirror {
+    pub processor: Arc<MirrorProcessor>,
+    pub axis: Axis,
+}
+
+impl std::fmt::Debug for Mirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mirror")
+            .field("axis", &self.axis)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FrameOp for Mirror {
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        Ok((width, height))
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        match self.axis {
+            Axis::Vertical => Ok(pollster::block_on(
+                self.processor.mirror_vertically(rgba, width, height),
+            )?),
+            Axis::Horizontal => {
+                check_frame(rgba, width, height)?;
+                Ok(rgba
+                    .chunks_exact(width as usize * 4)
+                    .flat_map(|row| row.chunks_exact(4).rev().flatten())
+                    .copied()
+                    .collect())
+            }
+        }
+    }
+}
+
+/// GPU processors shared by the operations of a [`Chain`]
+#[derive(Clone)]
+pub struct Processors {
+    pub mirror: Arc<MirrorProcessor>,
+    pub blur: Arc<BlurProcessor>,
+    pub resize: Arc<ResizeProcessor>,
+}
+
+/// One validated entry of an operation list
+///
+/// Serializes back to the JSON form with every default filled in, which is also how spooled jobs
+/// store it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Mirror {
+        axis: Axis,
+    },
+    Blur {
+        radius: u32,
+    },
+    Rotate {
+        degrees: u32,
+    },
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Pad {
+        top: u32,
+        right: u32,
+        bottom: u32,
+        left: u32,
+        color: [u8; 4],
+    },
+    Resize {
+        width: u32,
+        height: u32,
+    },
+}
+
+impl Operation {
+    /// Parses a JSON array of operations
+    ///
+    /// Every entry is checked before anything is returned; all problems are reported together as
+    /// [`GpuWorkerError::InvalidFields`] with paths such as `operations[1].radius`.
+    pub fn parse_list(json: &str) -> Result<Vec<Operation>> {
+        let invalid = |message: String| {
+            GpuWorkerError::InvalidFields(vec![FieldError::new("operations", message)])
+        };
+
+        let value: Value = serde_json::from_str(json)
+            .map_err(|e| invalid(format!("must be a JSON array: {}", e)))?;
+        let Value::Array(entries) = value else {
+            return Err(invalid("must be a JSON array of operations".to_string()));
+        };
+        if entries.is_empty() || entries.len() > MAX_OPERATIONS {
+            return Err(invalid(format!(
+                "must contain between 1 and {} operations, got {}",
+                MAX_OPERATIONS,
+                entries.len()
+            )));
+        }
+
+        let mut errors = Vec::new();
+        let operations: Vec<_> = entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| Self::parse(index, entry, &mut errors))
+            .collect();
+        if !errors.is_empty() {
+            return Err(GpuWorkerError::InvalidFields(errors));
+        }
+        Ok(operations)
+    }
+
+    /// The `op` name of the operation, one of [`OPERATION_NAMES`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mirror { .. } => "mirror",
+            Self::Blur { .. } => "blur",
+            Self::Rotate { .. } => "rotate",
+            Self::Crop { .. } => "crop",
+            Self::Pad { .. } => "pad",
+            Self::Resize { .. } => "resize",
+        }
+    }
+
+    /// Whether the operation runs on the GPU rather than the CPU.
+    pub fn uses_gpu(&self) -> bool {
+        match self {
+            Self::Mirror { axis } => *axis == Axis::Vertical,
+            Self::Blur { .. } | Self::Resize { .. } => true,
+            Self::Rotate { .. } | Self::Crop { .. } | Self::Pad { .. } => false,
+        }
+    }
+
+    fn parse(index: usize, entry: &Value, errors: &mut Vec<FieldError>) -> Option<Self> {
+        let path = format!("operations[{}]", index);
+        let Some(object) = entry.as_object() else {
+            errors.push(FieldError::new(path, "must be an object"));
+            return None;
+        };
+        let mut fields = Fields {
+            path,
+            object,
+            errors,
+            known: Vec::new(),
+            valid: true,
+        };
+
+        let operation = match fields.required::<String>("op")?.as_str() {
+            "mirror" => Self::Mirror {
+                axis: fields.optional("axis").unwrap_or_default(),
+            },
+            "blur" => Self::Blur {
+                radius: fields
+                    .sized("radius", 1, MAX_BLUR_RADIUS)
+                    .unwrap_or(DEFAULT_BLUR_RADIUS),
+            },
+            "rotate" => {
+                let degrees = fields.required::<u32>("degrees");
+                if degrees.is_some_and(|degrees| Rotate::from_degrees(degrees).is_err()) {
+                    fields.error("degrees", "must be 90, 180 or 270");
+                }
+                Self::Rotate {
+                    degrees: degrees.unwrap_or_default(),
+                }
+            }
+            "crop" => Self::Crop {
+                x: fields.optional("x").unwrap_or_default(),
+                y: fields.optional("y").unwrap_or_default(),
+                width: fields.required_size("width", 1, MAX_OPERATION_SIZE),
+                height: fields.required_size("height", 1, MAX_OPERATION_SIZE),
+            },
+            "pad" => Self::Pad {
+                top: fields.sized("top", 0, MAX_OPERATION_SIZE).unwrap_or(0),
+                right: fields.sized("right", 0, MAX_OPERATION_SIZE).unwrap_or(0),
+                bottom: fields.sized("bottom", 0, MAX_OPERATION_SIZE).unwrap_or(0),
+                left: fields.sized("left", 0, MAX_OPERATION_SIZE).unwrap_or(0),
+                color: fields.optional("color").unwrap_or_default(),
+            },
+            "resize" => Self::Resize {
+                width: fields.required_size("width", 1, MAX_OPERATION_SIZE),
+                height: fields.required_size("height", 1, MAX_OPERATION_SIZE),
+            },
+            other => {
+                fields.error(
+                    "op",
+                    format!(
+                        "unknown operation `{}`, expected one of {}",
+                        other,
+                        OPERATION_NAMES.join(", ")
+                    ),
+                );
+                return None;
+            }
+        };
+        fields.finish().then_some(operation)
+    }
+
+    /// The frame operation running this entry on `processors`
+    ///
+    /// Fails for rotations other than 90, 180 or 270 degrees, which [`Operation::parse_list`]
+    /// rejects but a job spec read back from the spool may still contain.
+    pub fn build(&self, processors: &Processors) -> Result<Box<dyn FrameOp + Send>> {
+        Ok(match *self {
+            Self::Mirror { axis } => Box::new(Mirror {
+                processor: processors.mirror.clone(),
+                axis,
+            }),
+            Self::Blur { radius } => Box::new(Blur {
+                processor: processors.blur.clone(),
+                radius,
+            }),
+            Self::Rotate { degrees } => Box::new(Rotate::from_degrees(degrees)?),
+            Self::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => Box::new(Crop {
+                x,
+                y,
+                width,
+                height,
+            }),
+            Self::Pad {
+                top,
+                right,
+                bottom,
+                left,
+                color,
+            } => Box::new(Pad {
+                top,
+                right,
+                bottom,
+                left,
+                color,
+            }),
+            Self::Resize { width, height } => Box::new(Resize {
+                processor: processors.resize.clone(),
+                width,
+                height,
+            }),
+        })
+    }
+}
+
+/// The fields of one operation object, collecting errors under its path.
+struct Fields<'a> {
+    path: String,
+    object: &'a Map<String, Value>,
+    errors: &'a mut Vec<FieldError>,
+    known: Vec<&'static str>,
+    valid: bool,
+}
+
+impl Fields<'_> {
+    fn optional<T: DeserializeOwned>(&mut self, name: &'static str) -> Option<T> {
+        self.known.push(name);
+        let value = self.object.get(name)?;
+        T::deserialize(value)
+            .map_err(|e| self.error(name, e.to_string()))
+            .ok()
+    }
+
+    fn required<T: DeserializeOwned>(&mut self, name: &'static str) -> Option<T> {
+        if !self.object.contains_key(name) {
+            self.known.push(name);
+            self.error(name, "is required");
+            return None;
+        }
+        self.optional(name)
+    }
+
+    /// An optional size in pixels between `min` and `max`.
+    fn sized(&mut self, name: &'static str, min: u32, max: u32) -> Option<u32> {
+        let size = self.optional::<u32>(name)?;
+        if size < min || size > max {
+            self.error(name, format!("must be between {} and {}", min, max));
+            return None;
+        }
+        Some(size)
+    }
+
+    /// A required size in pixels between `min` and `max`; zero once reported missing.
+    fn required_size(&mut self, name: &'static str, min: u32, max: u32) -> u32 {
+        if !self.object.contains_key(name) {
+            self.known.push(name);
+            self.error(name, "is required");
+            return 0;
+        }
+        self.sized(name, min, max).unwrap_or_default()
+    }
+
+    fn error(&mut self, name: &str, message: impl Into<String>) {
+        self.errors
+            .push(FieldError::new(format!("{}.{}", self.path, name), message));
+        self.valid = false;
+    }
+
+    /// Reports unknown fields; returns whether every field was valid.
+    fn finish(mut self) -> bool {
+        let unknown: Vec<String> = self
+            .object
+            .keys()
+            .filter(|key| !self.known.contains(&key.as_str()))
+            .cloned()
+            .collect();
+        for key in unknown {
+            self.error(&key, "unknown field");
+        }
+        self.valid
+    }
+}
+
+/// Operations applied one after another, each to the output of the previous one
+///
+/// Intermediate frames are held to the canvas limit as well, so a list cannot blow a frame up
+/// and crop it back down.
+pub struct Chain {
+    steps: Vec<Box<dyn FrameOp + Send>>,
+    limits: DecodeLimits,
+}
+
+impl Chain {
+    pub fn new(
+        operations: &[Operation],
+        processors: &Processors,
+        limits: DecodeLimits,
+    ) -> Result<Self> {
+        Ok(Self {
+            steps: operations
+                .iter()
+                .map(|operation| operation.build(processors))
+                .collect::<Result<_>>()?,
+            limits,
+        })
+    }
+}
+
+impl FrameOp for Chain {
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        let mut dimensions = (width, height);
+        for (index, step) in self.steps.iter().enumerate() {
+            dimensions =
+                step.output_dimensions(dimensions.0, dimensions.1)
+                    .map_err(|e| match e {
+                        GpuWorkerError::InvalidInput(message) => GpuWorkerError::InvalidFields(
+                            vec![FieldError::new(format!("operations[{}]", index), message)],
+                        ),
+                        e => e,
+                    })?;
+            self.limits.check_canvas(dimensions.0, dimensions.1)?;
+        }
+        Ok(dimensions)
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        let mut frame = Cow::Borrowed(rgba);
+        let (mut width, mut height) = (width, height);
+        for step in &self.steps {
+            let output = step.apply(&frame, width, height)?;
+            (width, height) = step.output_dimensions(width, height)?;
+            frame = Cow::Owned(output);
+        }
+        Ok(frame.into_owned())
+    }
+}
+
+/// Query parameters and form fields of the transform endpoint
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TransformOptions {
+    /// JSON array of operations, see [`Operation::parse_list`].
+    pub operations: Option<String>,
+}
+
+impl TransformOptions {
+    /// Parses and validates the operation list.
+    pub fn operations(&self) -> Result<Vec<Operation>> {
+        let operations = self.operations.as_deref().ok_or_else(|| {
+            GpuWorkerError::InvalidFields(vec![FieldError::new("operations", "is required")])
+        })?;
+        Operation::parse_list(operations)
+    }
+}
+
+fn check_frame(rgba: &[u8], width: u32, height: u32) -> Result<()> {
+    if rgba.len() != width as usize * height as usize * 4 {
+        return Err(GpuWorkerError::ImageProcessing(format!(
+            "Expected {}x{} RGBA data, got {} bytes",
+            width,
+            height,
+            rgba.len()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        limits::DecodeLimits,
+        pipeline::{decode_gif, process_frames, EncodeOptions, QuantizePool},
+    };
+
+    /// A 3x2 frame whose pixels are numbered 0 to 5 in their red channel.
+    fn numbered_frame() -> Vec<u8> {
+        (0..6).flat_map(|n| [n, 0, 0, 255]).collect()
+    }
+
+    fn red(rgba: &[u8]) -> Vec<u8> {
+        rgba.chunks_exact(4).map(|pixel| pixel[0]).collect()
+    }
+
+    #[test]
+    fn test_blur_options() {
+        assert_eq!(BlurOptions::default().radius(), DEFAULT_BLUR_RADIUS);
+        assert!(BlurOptions { radius: Some(1) }.validate().is_ok());
+        assert!(BlurOptions {
+            radius: Some(MAX_BLUR_RADIUS)
+        }
+        .validate()
+        .is_ok());
+        assert!(BlurOptions { radius: Some(0) }.validate().is_err());
+        assert!(BlurOptions {
+            radius: Some(MAX_BLUR_RADIUS + 1)
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_operation_list() {
+        let operations = Operation::parse_list(
+            r#"[{"op":"mirror","axis":"horizontal"},{"op":"blur","radius":3},
+                {"op":"rotate","degrees":90},{"op":"crop","width":2,"height":1},{"op":"blur"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            operations,
+            [
+                Operation::Mirror {
+                    axis: Axis::Horizontal
+                },
+                Operation::Blur { radius: 3 },
+                Operation::Rotate { degrees: 90 },
+                Operation::Crop {
+                    x: 0,
+                    y: 0,
+                    width: 2,
+                    height: 1
+                },
+                Operation::Blur {
+                    radius: DEFAULT_BLUR_RADIUS
+                },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&operations[0]).unwrap(),
+            serde_json::json!({"op": "mirror", "axis": "horizontal"})
+        );
+    }
+
+    #[test]
+    fn test_operation_list_errors_name_fields() {
+        let fields = |json: &str| match Operation::parse_list(json) {
+            Err(GpuWorkerError::InvalidFields(errors)) => errors
+                .into_iter()
+                .map(|error| error.field)
+                .collect::<Vec<_>>(),
+            other => panic!("expected field errors, got {:?}", other),
+        };
+
+        assert_eq!(
+            fields(
+                r#"[{"op":"blur","radius":0},{"op":"spin"},{"op":"mirror","axis":"diagonal"},
+                    {"op":"rotate"},{"op":"resize","width":"big","height":10,"depth":1},
+                    {"radius":3},7]"#
+            ),
+            [
+                "operations[0].radius",
+                "operations[1].op",
+                "operations[2].axis",
+                "operations[3].degrees",
+                "operations[4].width",
+                "operations[4].depth",
+                "operations[5].op",
+                "operations[6]",
+            ]
+        );
+        assert_eq!(fields("[]"), ["operations"]);
+        assert_eq!(fields(r#"{"op":"blur"}"#), ["operations"]);
+        assert_eq!(fields("not json"), ["operations"]);
+        assert_eq!(
+            fields(r#"[{"op":"rotate","degrees":45}]"#),
+            ["operations[0].degrees"]
+        );
+        assert!(matches!(
+            TransformOptions::default().operations(),
+            Err(GpuWorkerError::InvalidFields(_))
+        ));
+    }
+
+    #[test]
+    fn test_chain_tracks_dimensions() {
+        let steps: Vec<Box<dyn FrameOp + Send>> = vec![
+            Box::new(Rotate::Quarter),
+            Box::new(Crop {
+                x: 0,
+                y: 1,
+                width: 2,
+                height: 2,
+            }),
+        ];
+        let chain = Chain {
+            steps,
+            limits: DecodeLimits::default(),
+        };
+        // Rotated to 2x3, then the bottom 2x2
+        assert_eq!(chain.output_dimensions(3, 2).unwrap(), (2, 2));
+        assert_eq!(
+            red(&chain.apply(&numbered_frame(), 3, 2).unwrap()),
+            [4, 1, 5, 2]
+        );
+        assert!(matches!(
+            chain.output_dimensions(1, 1),
+            Err(GpuWorkerError::InvalidFields(errors)) if errors[0].field == "operations[1]"
+        ));
+
+        let huge = Chain {
+            steps: vec![Box::new(Pad {
+                top: 0,
+                right: 5000,
+                bottom: 5000,
+                left: 0,
+                color: [0; 4],
+            })],
+            limits: DecodeLimits::default(),
+        };
+        assert!(matches!(
+            huge.output_dimensions(10, 10),
+            Err(GpuWorkerError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let frame = numbered_frame();
+        // 0 1 2
+        // 3 4 5
+        let quarter = Rotate::Quarter;
+        assert_eq!(quarter.output_dimensions(3, 2).unwrap(), (2, 3));
+        assert_eq!(
+            red(&quarter.apply(&frame, 3, 2).unwrap()),
+            [3, 0, 4, 1, 5, 2]
+        );
+        assert_eq!(
+            red(&Rotate::Half.apply(&frame, 3, 2).unwrap()),
+            [5, 4, 3, 2, 1, 0]
+        );
+        assert_eq!(
+            red(&Rotate::ThreeQuarters.apply(&frame, 3, 2).unwrap()),
+            [2, 5, 1, 4, 0, 3]
+        );
+        assert!(Rotate::from_degrees(45).is_err());
+    }
+
+    #[test]
+    fn test_crop_and_pad() {
+        let frame = numbered_frame();
+        let crop = Crop {
+            x: 1,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        assert_eq!(crop.output_dimensions(3, 2).unwrap(), (2, 2));
+        assert_eq!(red(&crop.apply(&frame, 3, 2).unwrap()), [1, 2, 4, 5]);
+        assert!(Crop { x: 2, ..crop }.output_dimensions(3, 2).is_err());
+
+        let pad = Pad {
+            top: 1,
+            right: 0,
+            bottom: 0,
+            left: 1,
+            color: [9, 0, 0, 255],
+        };
+        assert_eq!(pad.output_dimensions(3, 2).unwrap(), (4, 3));
+        assert_eq!(
+            red(&pad.apply(&frame, 3, 2).unwrap()),
+            [9, 9, 9, 9, 9, 0, 1, 2, 9, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_sizes_output_canvas() {
+        let mut gif_data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif_data, 3, 2, &[]).unwrap();
+            for _ in 0..3 {
+                let mut pixels = numbered_frame();
+                encoder
+                    .write_frame(&gif::Frame::from_rgba(3, 2, &mut pixels))
+                    .unwrap();
+            }
+        }
+        let limits = DecodeLimits::default();
+        let frames = decode_gif(gif_data.as_slice(), &limits).unwrap();
+        let dimensions = frames.dimensions();
+
+        let mut output = Vec::new();
+        process_frames(
+            frames,
+            dimensions,
+            &mut output,
+            &EncodeOptions::default(),
+            &QuantizePool::new(1),
+            Rotate::Quarter,
+        )
+        .unwrap();
+
+        let rotated = decode_gif(output.as_slice(), &limits).unwrap();
+        assert_eq!(rotated.dimensions(), (2, 3));
+        let frames: Vec<_> = rotated.collect::<Result<_>>().unwrap();
+        assert_eq!(frames.len(), 3);
+        assert!(frames
+            .iter()
+            .all(|frame| (frame.width, frame.height) == (2, 3)));
+    }
+
+    #[test]
+    fn test_pipeline_rejects_inconsistent_frame_sizes() {
+        let gif_data = {
+            let mut gif_data = Vec::new();
+            let mut encoder = gif::Encoder::new(&mut gif_data, 3, 2, &[]).unwrap();
+            for _ in 0..2 {
+                let mut pixels = numbered_frame();
+                encoder
+                    .write_frame(&gif::Frame::from_rgba(3, 2, &mut pixels))
+                    .unwrap();
+            }
+            drop(encoder);
+            gif_data
+        };
+        let frames = decode_gif(gif_data.as_slice(), &DecodeLimits::default()).unwrap();
+        let dimensions = frames.dimensions();
+
+        // Claims to keep the size but shrinks every frame
+        let result = process_frames(
+            frames,
+            dimensions,
+            Vec::new(),
+            &EncodeOptions::default(),
+            &QuantizePool::new(1),
+            |rgba: &[u8], _, _| Ok(rgba[..4].to_vec()),
+        );
+        match result {
+            Err(GpuWorkerError::ImageProcessing(message)) => assert!(message.contains("3x2")),
+            other => panic!("expected a frame size error, got {:?}", other.err()),
+        }
+    }
+}
@@ -0,0 +1,1145 @@
+//! Frame pipeline for GIF processing.
+//!
+//! A GIF is processed in three overlapping stages: a decode thread turns the input into RGBA
+//! frames, a GPU thread runs the per-frame transformation, and palette quantization (NeuQuant,
+//! the most CPU-heavy step) is farmed out to a shared [`QuantizePool`]. The calling thread
+//! writes finished frames into the encoder in their original order, so the output is identical
+//! to a sequential run no matter which frame finishes first.
+//!
+//! APNG input and output go through the same stages; for APNG output and frame archives the
+//! pool compresses frames instead of quantizing them.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+};
+
+use gif::{Encoder, Frame, Repeat};
+
+use crate::{
+    apng::{self, ApngEncoder, ApngFrames, CompressedFrame},
+    archive::ZipWriter,
+    error::{GpuWorkerError, Result},
+    format::{self, Format},
+    limits::{DecodeLimits, FrameBudget},
+    metadata::Metadata,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fixed-size pool of threads used for palette quantization.
+///
+/// The pool is shared by all requests, so the number of threads bounds the CPU spent on
+/// quantization across the whole service. Cloning the pool is cheap and shares its threads.
+#[derive(Clone)]
+pub struct QuantizePool {
+    sender: Sender<Job>,
+    threads: usize,
+}
+
+impl QuantizePool {
+    /// Starts a pool with `threads` worker threads (at least one).
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("gif-quantize-{}", index))
+                .spawn(move || loop {
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => {
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                log::error!("Quantization job panicked");
+                            }
+                        }
+                        Err(_) => return,
+                    }
+                })
+                .expect("Failed to spawn quantization thread");
+        }
+
+        Self { sender, threads }
+    }
+
+    /// Returns a process-wide pool sized to the number of CPUs.
+    ///
+    /// Used when the application has not registered a pool of its own.
+    pub fn global() -> Self {
+        static POOL: OnceLock<QuantizePool> = OnceLock::new();
+        POOL.get_or_init(|| QuantizePool::new(num_cpus::get()))
+            .clone()
+    }
+
+    /// Number of worker threads in the pool.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        if self.sender.send(Box::new(job)).is_err() {
+            log::error!("Quantization pool has shut down");
+        }
+    }
+}
+
+impl std::fmt::Debug for QuantizePool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuantizePool")
+            .field("threads", &self.threads)
+            .finish()
+    }
+}
+
+/// Container format of an encoded animation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnimationFormat {
+    /// Palette-based GIF with binary transparency.
+    #[default]
+    Gif,
+    /// Animated PNG with true color and full alpha.
+    Apng,
+    /// ZIP archive of PNG frames; delays and loop count are not kept.
+    Zip,
+}
+
+/// Settings for the encoded output animation.
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    /// How often the animation repeats.
+    pub repeat: Repeat,
+    /// Format the animation is encoded in.
+    pub format: AnimationFormat,
+    /// Comment and application extensions written back into GIF output as they are collected.
+    pub metadata: Metadata,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            repeat: Repeat::Infinite,
+            format: AnimationFormat::Gif,
+            metadata: Metadata::default(),
+        }
+    }
+}
+
+/// A processed frame ready to be written by the encoder.
+enum EncodedFrame {
+    Gif(Frame<'static>),
+    Apng(CompressedFrame),
+    Png(Vec<u8>),
+}
+
+/// Encoder for the output animation.
+enum AnimationEncoder<W: Write> {
+    Gif {
+        encoder: Encoder<W>,
+        metadata: Metadata,
+    },
+    Apng(ApngEncoder<W>),
+    Zip {
+        archive: ZipWriter<W>,
+        frames: usize,
+    },
+}
+
+impl<W: Write> AnimationEncoder<W> {
+    fn new(output: W, (width, height): (u32, u32), encode: &EncodeOptions) -> Result<Self> {
+        Ok(match encode.format {
+            AnimationFormat::Gif => Self::Gif {
+                encoder: create_gif_encoder(output, width as u16, height as u16, encode.repeat)?,
+                metadata: encode.metadata.clone(),
+            },
+            AnimationFormat::Apng => {
+                Self::Apng(ApngEncoder::new(output, width, height, encode.repeat))
+            }
+            AnimationFormat::Zip => Self::Zip {
+                archive: ZipWriter::new(output),
+                frames: 0,
+            },
+        })
+    }
+
+    fn write_frame(&mut self, frame: EncodedFrame) -> Result<()> {
+        match (self, frame) {
+            (Self::Gif { encoder, metadata }, EncodedFrame::Gif(frame)) => {
+                write_metadata(encoder, metadata)?;
+                encoder.write_frame(&frame)?;
+            }
+            (Self::Apng(encoder), EncodedFrame::Apng(frame)) => encoder.write_frame(frame),
+            (Self::Zip { archive, frames }, EncodedFrame::Png(png)) => {
+                archive.add_file(&format!("frame-{:04}.png", frames), &png)?;
+                *frames += 1;
+            }
+            _ => {
+                return Err(GpuWorkerError::Internal(
+                    "Frame was encoded for a different output format".to_string(),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Gif {
+                mut encoder,
+                metadata,
+            } => {
+                write_metadata(&mut encoder, &metadata)?;
+                encoder.into_inner()?;
+            }
+            Self::Apng(encoder) => encoder.finish()?,
+            Self::Zip { archive, .. } => {
+                archive.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A transformation applied to every frame by [`process_frames`]
+///
+/// The output size is asked for once, before anything is encoded, and sizes the output canvas
+/// (the GIF logical screen). Plain closures over `(rgba, width, height)` are operations that keep
+/// the frame size.
+pub trait FrameOp: Sync {
+    /// Size of the frames produced from `width`x`height` frames.
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)>;
+
+    /// Transforms the RGBA data of one `width`x`height` frame.
+    ///
+    /// Must return RGBA data of the size given by [`FrameOp::output_dimensions`].
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>>;
+}
+
+impl<F> FrameOp for F
+where
+    F: Fn(&[u8], u32, u32) -> Result<Vec<u8>> + Sync,
+{
+    fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        Ok((width, height))
+    }
+
+    fn apply(&self, rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+        self(rgba, width, height)
+    }
+}
+
+/// Asks `op` for its output size and checks that it can be encoded.
+pub fn output_dimensions<O: FrameOp + ?Sized>(
+    op: &O,
+    (width, height): (u32, u32),
+) -> Result<(u32, u32)> {
+    let (output_width, output_height) = op.output_dimensions(width, height)?;
+    let max = u16::MAX as u32;
+    if output_width == 0 || output_height == 0 || output_width > max || output_height > max {
+        return Err(GpuWorkerError::InvalidInput(format!(
+            "Operations turn {}x{} frames into {}x{}, output must be between 1x1 and {}x{}",
+            width, height, output_width, output_height, max, max
+        )));
+    }
+    Ok((output_width, output_height))
+}
+
+/// A decoded frame on its way to the GPU stage.
+struct DecodedFrame {
+    index: usize,
+    frame: Frame<'static>,
+    rgba: Vec<u8>,
+}
+
+/// Runs every frame through `op` and encodes the results into `output`.
+///
+/// `frames` must be full-canvas frames of `width`x`height`, as produced by [`GifFrames`] and
+/// [`ApngFrames`].
+/// `op` receives their RGBA data; the output canvas is sized to its output dimensions and every
+/// frame must come out at that size. The output is encoded according to `encode`. Up to
+/// `pool.threads() + 2` frames are in flight at once, which keeps the pool busy while bounding
+/// memory to a few frames per request.
+pub fn process_frames<I, W, O>(
+    frames: I,
+    (width, height): (u32, u32),
+    output: W,
+    encode: &EncodeOptions,
+    pool: &QuantizePool,
+    op: O,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<Frame<'static>>> + Send,
+    W: Write,
+    O: FrameOp,
+{
+    let output_dimensions = output_dimensions(&op, (width, height))?;
+    let (decoded_tx, decoded_rx) = mpsc::sync_channel(1);
+    let (encoded_tx, encoded_rx) = mpsc::channel();
+    let (permit_tx, permit_rx) = mpsc::sync_channel(pool.threads() + 2);
+    let op = &op;
+
+    thread::scope(|scope| {
+        scope.spawn(move || decode_stage(frames, (width, height), decoded_tx));
+        scope.spawn(move || {
+            gpu_stage(
+                decoded_rx,
+                permit_tx,
+                encoded_tx,
+                pool,
+                op,
+                [(width, height), output_dimensions],
+                encode.format,
+            );
+        });
+
+        let encoder = AnimationEncoder::new(output, output_dimensions, encode)?;
+        write_stage(encoder, encoded_rx, permit_rx)
+    })
+}
+
+/// Decodes frames and separates their RGBA data from the metadata for the GPU stage.
+fn decode_stage<I>(
+    frames: I,
+    (width, height): (u32, u32),
+    decoded: SyncSender<Result<DecodedFrame>>,
+) where
+    I: Iterator<Item = Result<Frame<'static>>>,
+{
+    let expected_len = (width * height * 4) as usize;
+
+    for (index, frame) in frames.enumerate() {
+        let decoded_frame = frame.and_then(|mut frame| {
+            let rgba = std::mem::take(&mut frame.buffer).into_owned();
+            if rgba.len() != expected_len {
+                return Err(GpuWorkerError::Internal(format!(
+                    "Frame {} is {} bytes, expected a {}x{} canvas",
+                    index + 1,
+                    rgba.len(),
+                    width,
+                    height
+                )));
+            }
+            Ok(DecodedFrame { index, frame, rgba })
+        });
+
+        let failed = decoded_frame.is_err();
+        if decoded.send(decoded_frame).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Runs the per-frame transformation and hands the result to the quantization pool.
+///
+/// `dimensions` are the input and output frame sizes.
+fn gpu_stage<O>(
+    decoded: Receiver<Result<DecodedFrame>>,
+    permits: SyncSender<()>,
+    encoded: Sender<(usize, Result<EncodedFrame>)>,
+    pool: &QuantizePool,
+    op: &O,
+    [(width, height), (output_width, output_height)]: [(u32, u32); 2],
+    format: AnimationFormat,
+) where
+    O: FrameOp + ?Sized,
+{
+    for decoded_frame in decoded {
+        let DecodedFrame { index, frame, rgba } = match decoded_frame {
+            Ok(decoded_frame) => decoded_frame,
+            Err(e) => {
+                let _ = encoded.send((usize::MAX, Err(e)));
+                return;
+            }
+        };
+
+        // Wait for the writer to catch up before putting more frames in flight.
+        if permits.send(()).is_err() {
+            return;
+        }
+
+        log::info!("Processing frame {}", index + 1);
+        let expected_len = (output_width * output_height * 4) as usize;
+        let processed = op.apply(&rgba, width, height).and_then(|processed| {
+            if processed.len() != expected_len {
+                return Err(GpuWorkerError::ImageProcessing(format!(
+                    "Frame {} came out as {} bytes, but every frame must be {}x{} RGBA",
+                    index + 1,
+                    processed.len(),
+                    output_width,
+                    output_height
+                )));
+            }
+            Ok(processed)
+        });
+
+        match processed {
+            Ok(processed) => {
+                let encoded = encoded.clone();
+                pool.execute(move || {
+                    let frame = match format {
+                        AnimationFormat::Gif => Ok(EncodedFrame::Gif(create_mirrored_frame(
+                            &frame,
+                            &processed,
+                            output_width as u16,
+                            output_height as u16,
+                        ))),
+                        AnimationFormat::Apng => apng::compress_frame(
+                            &processed,
+                            output_width,
+                            output_height,
+                            frame.delay,
+                        )
+                        .map(EncodedFrame::Apng),
+                        AnimationFormat::Zip => {
+                            encode_png_frame(processed, output_width, output_height)
+                                .map(EncodedFrame::Png)
+                        }
+                    };
+                    let _ = encoded.send((index, frame));
+                });
+            }
+            Err(e) => {
+                let _ = encoded.send((index, Err(e)));
+                return;
+            }
+        }
+    }
+}
+
+/// Writes quantized frames in their original order as they come back from the pool.
+fn write_stage<W: Write>(
+    mut encoder: AnimationEncoder<W>,
+    encoded: Receiver<(usize, Result<EncodedFrame>)>,
+    permits: Receiver<()>,
+) -> Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut next_index = 0;
+
+    for (index, frame) in encoded {
+        pending.insert(index, frame?);
+
+        while let Some(frame) = pending.remove(&next_index) {
+            encoder.write_frame(frame)?;
+            let _ = permits.recv();
+            next_index += 1;
+        }
+    }
+
+    if !pending.is_empty() {
+        return Err(GpuWorkerError::Internal(format!(
+            "Frame {} was lost during processing",
+            next_index + 1
+        )));
+    }
+
+    encoder.finish()?;
+    log::info!("Encoded {} frames", next_index);
+    Ok(())
+}
+
+/// Streams decoded frames out of a GIF, holding only the frame being read in memory
+///
+/// Frames are composited onto the logical screen according to the disposal method of the frame
+/// before them, so every yielded frame covers the whole canvas (`top`/`left` are zero and the
+/// buffer is canvas-sized RGBA). Frames can therefore be transformed, reordered or dropped
+/// independently of each other. Each frame is checked against the decode limits before its
+/// pixels are decoded.
+pub struct GifFrames<R: Read> {
+    decoder: gif::Decoder<R>,
+    pending: Option<Frame<'static>>,
+    compositor: Compositor,
+    limits: DecodeLimits,
+    budget: FrameBudget,
+    width: u32,
+    height: u32,
+}
+
+impl<R: Read> std::fmt::Debug for GifFrames<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GifFrames")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read> GifFrames<R> {
+    /// Size of the logical screen every frame is composited onto.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn read_frame(&mut self) -> Result<Option<Frame<'static>>> {
+        let Some(mut frame) = self.decoder.next_frame_info()?.cloned() else {
+            return Ok(None);
+        };
+        self.limits
+            .check_canvas(frame.width as u32, frame.height as u32)?;
+        self.budget.admit(frame.delay)?;
+
+        let mut buffer = vec![0; self.decoder.buffer_size()];
+        self.decoder.read_into_buffer(&mut buffer)?;
+        frame.buffer = buffer.into();
+        frame.interlaced = false;
+        Ok(Some(frame))
+    }
+}
+
+impl<R: Read> Iterator for GifFrames<R> {
+    type Item = Result<Frame<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = match self.pending.take() {
+            Some(frame) => frame,
+            None => match self.read_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+
+        Some(self.compositor.compose(frame))
+    }
+}
+
+/// Tracks the logical screen while frames are drawn onto it.
+struct Compositor {
+    width: u32,
+    height: u32,
+    canvas: Vec<u8>,
+    /// Canvas contents to restore after a frame with `DisposalMethod::Previous`.
+    saved: Option<Vec<u8>>,
+    /// Disposal of the last drawn frame and the canvas area it covered.
+    last: Option<(gif::DisposalMethod, [u32; 4])>,
+}
+
+impl Compositor {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            canvas: vec![0; (width * height * 4) as usize],
+            saved: None,
+            last: None,
+        }
+    }
+
+    /// Draws `frame` onto the canvas and returns it as a full-canvas frame.
+    fn compose(&mut self, mut frame: Frame<'static>) -> Result<Frame<'static>> {
+        match self.last.take() {
+            Some((gif::DisposalMethod::Background, rect)) => self.fill(rect, &[0; 4]),
+            Some((gif::DisposalMethod::Previous, _)) => {
+                if let Some(saved) = self.saved.take() {
+                    self.canvas = saved;
+                }
+            }
+            _ => {}
+        }
+
+        if frame.dispose == gif::DisposalMethod::Previous {
+            self.saved = Some(self.canvas.clone());
+        }
+
+        let rgba = normalize_frame_to_rgba(&frame, frame.width as u32, frame.height as u32)?;
+        let rect = self.clip(&frame);
+        let [left, top, right, bottom] = rect;
+        let frame_width = frame.width as usize;
+
+        for y in top..bottom {
+            let src_row = (y - frame.top as u32) as usize * frame_width;
+            for x in left..right {
+                let src = (src_row + (x - frame.left as u32) as usize) * 4;
+                if rgba[src + 3] == 0 {
+                    continue;
+                }
+                let dst = ((y * self.width + x) * 4) as usize;
+                self.canvas[dst..dst + 4].copy_from_slice(&rgba[src..src + 4]);
+            }
+        }
+
+        self.last = Some((frame.dispose, rect));
+        frame.buffer = self.canvas.clone().into();
+        frame.left = 0;
+        frame.top = 0;
+        frame.width = self.width as u16;
+        frame.height = self.height as u16;
+        Ok(frame)
+    }
+
+    /// Returns the part of the canvas covered by `frame` as `[left, top, right, bottom]`.
+    fn clip(&self, frame: &Frame) -> [u32; 4] {
+        let left = (frame.left as u32).min(self.width);
+        let top = (frame.top as u32).min(self.height);
+        let right = (frame.left as u32 + frame.width as u32).min(self.width);
+        let bottom = (frame.top as u32 + frame.height as u32).min(self.height);
+        [left, top, right, bottom]
+    }
+
+    fn fill(&mut self, [left, top, right, bottom]: [u32; 4], color: &[u8; 4]) {
+        for y in top..bottom {
+            for x in left..right {
+                let dst = ((y * self.width + x) * 4) as usize;
+                self.canvas[dst..dst + 4].copy_from_slice(color);
+            }
+        }
+    }
+}
+
+/// Opens a GIF for frame-by-frame decoding
+///
+/// The first frame is decoded eagerly so that malformed or empty GIFs, and GIFs whose canvas
+/// exceeds `limits`, are rejected before any output is produced.
+pub fn decode_gif<R: Read>(gif_data: R, limits: &DecodeLimits) -> Result<GifFrames<R>> {
+    let mut decoder = gif::DecodeOptions::new();
+    decoder.set_color_output(gif::ColorOutput::RGBA);
+    let decoder = decoder.read_info(gif_data)?;
+
+    let width = decoder.width() as u32;
+    let height = decoder.height() as u32;
+    let budget = limits.frame_budget(width, height)?;
+    let mut frames = GifFrames {
+        decoder,
+        pending: None,
+        compositor: Compositor::new(width, height),
+        limits: *limits,
+        budget,
+        width,
+        height,
+    };
+
+    frames.pending = frames.read_frame()?;
+    if frames.pending.is_none() {
+        return Err(GpuWorkerError::InvalidInput(
+            "GIF contains no frames".to_string(),
+        ));
+    }
+
+    log::info!("Decoding GIF ({}x{})", width, height);
+    Ok(frames)
+}
+
+/// Frames of an animation in one of the supported input formats.
+#[derive(Debug)]
+pub enum AnimationFrames<R: Read> {
+    Gif(Box<GifFrames<R>>),
+    Apng(Box<ApngFrames<R>>),
+}
+
+impl<R: Read> AnimationFrames<R> {
+    /// Size of the canvas every frame covers.
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Self::Gif(frames) => frames.dimensions(),
+            Self::Apng(frames) => frames.dimensions(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for AnimationFrames<R> {
+    type Item = Result<Frame<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Gif(frames) => frames.next(),
+            Self::Apng(frames) => frames.next(),
+        }
+    }
+}
+
+/// Opens an animation of the given format for frame-by-frame decoding
+pub fn decode_animation<R: Read>(
+    data: R,
+    format: Format,
+    limits: &DecodeLimits,
+) -> Result<AnimationFrames<R>> {
+    match format {
+        Format::Gif => {
+            decode_gif(data, limits).map(|frames| AnimationFrames::Gif(Box::new(frames)))
+        }
+        Format::Apng => {
+            apng::decode_apng(data, limits).map(|frames| AnimationFrames::Apng(Box::new(frames)))
+        }
+        _ => Err(GpuWorkerError::Internal(format!(
+            "{} is not an animation format",
+            format.mime_type()
+        ))),
+    }
+}
+
+/// Counts the frames of a GIF without converting or compositing any pixels
+///
+/// Stops with an error once the count exceeds the frame limit.
+pub fn count_gif_frames<R: Read>(gif_data: R, limits: &DecodeLimits) -> Result<usize> {
+    let mut decoder = gif::DecodeOptions::new().read_info(gif_data)?;
+
+    let mut count = 0;
+    while decoder.next_frame_info()?.is_some() {
+        count += 1;
+        limits.check_frames(count)?;
+    }
+    Ok(count)
+}
+
+/// Counts the frames of an upload in any supported format; stills count as one frame
+pub fn count_frames(data: &[u8], format: Format, limits: &DecodeLimits) -> Result<usize> {
+    match format {
+        Format::Gif => count_gif_frames(data, limits),
+        Format::Apng => {
+            let reader = png::Decoder::new(data).read_info()?;
+            let frames = reader
+                .info()
+                .animation_control
+                .map_or(1, |animation| animation.num_frames as usize);
+            limits.check_frames(frames)?;
+            Ok(frames)
+        }
+        _ => Ok(1),
+    }
+}
+
+/// Writes the metadata blocks collected since the last call
+///
+/// Blocks are written before the next frame, so they end up close to where they were in the
+/// input; blocks after the last frame are written before the trailer.
+fn write_metadata<W: Write>(encoder: &mut Encoder<W>, metadata: &Metadata) -> Result<()> {
+    for block in metadata.take() {
+        let sub_blocks: Vec<&[u8]> = block.sub_blocks.iter().map(Vec::as_slice).collect();
+        encoder.write_raw_extension(gif::AnyExtension(block.label), &sub_blocks)?;
+    }
+    Ok(())
+}
+
+/// Creates a GIF encoder with proper settings
+fn create_gif_encoder<W: Write>(
+    output: W,
+    width: u16,
+    height: u16,
+    repeat: Repeat,
+) -> Result<Encoder<W>> {
+    let mut encoder = Encoder::new(output, width, height, &[])?;
+    encoder.set_repeat(repeat)?;
+    Ok(encoder)
+}
+
+/// Encodes a full-canvas RGBA frame as a standalone PNG
+fn encode_png_frame(rgba: Vec<u8>, width: u32, height: u32) -> Result<Vec<u8>> {
+    let image = image::RgbaImage::from_raw(width, height, rgba).ok_or_else(|| {
+        GpuWorkerError::Internal("Processed frame does not match the canvas size".to_string())
+    })?;
+    let mut png = Vec::new();
+    format::encode_still(image, Format::Png, &mut png)?;
+    Ok(png)
+}
+
+/// Normalizes frame buffer to RGBA format
+fn normalize_frame_to_rgba(frame: &gif::Frame, width: u32, height: u32) -> Result<Vec<u8>> {
+    let expected_rgba_len = (width * height * 4) as usize;
+    let expected_rgb_len = (width * height * 3) as usize;
+
+    match frame.buffer.len() {
+        len if len == expected_rgba_len => Ok(frame.buffer.to_vec()),
+        len if len == expected_rgb_len => {
+            // Convert RGB to RGBA
+            let mut rgba = Vec::with_capacity(expected_rgba_len);
+            for chunk in frame.buffer.chunks(3) {
+                rgba.extend_from_slice(chunk);
+                rgba.push(255); // Alpha channel
+            }
+            Ok(rgba)
+        }
+        len => Err(GpuWorkerError::InvalidInput(format!(
+            "Unexpected frame buffer size: {} bytes (expected {} or {} bytes)",
+            len, expected_rgba_len, expected_rgb_len
+        ))),
+    }
+}
+
+/// Creates a new GIF frame with mirrored data, preserving original frame properties
+///
+/// GIF transparency is all or nothing, so pixels less than half opaque become transparent and
+/// the rest opaque. Every frame is a composite of the whole canvas and is disposed to the
+/// background, since disposal applies before the next frame is drawn: a frame left in place
+/// would show through the transparent pixels of the next one, whatever order frames come in.
+fn create_mirrored_frame(
+    original: &gif::Frame,
+    mirrored_rgba: &[u8],
+    width: u16,
+    height: u16,
+) -> Frame<'static> {
+    if mirrored_rgba.chunks(4).any(|pixel| pixel[3] < 128) {
+        let mut rgba: Vec<u8> = mirrored_rgba
+            .chunks(4)
+            .flat_map(|pixel| match pixel[3] {
+                0..=127 => [0, 0, 0, 0],
+                _ => [pixel[0], pixel[1], pixel[2], 255],
+            })
+            .collect();
+
+        let mut frame = Frame::from_rgba_speed(width, height, &mut rgba, 10);
+        frame.delay = original.delay;
+        frame.dispose = gif::DisposalMethod::Background;
+        frame.needs_user_input = original.needs_user_input;
+        frame.interlaced = original.interlaced;
+        return frame;
+    }
+
+    // Convert RGBA back to RGB for GIF encoding
+    let rgb_data: Vec<u8> = mirrored_rgba
+        .chunks(4)
+        .flat_map(|rgba| &rgba[..3])
+        .copied()
+        .collect();
+
+    let mut frame = Frame::from_rgb_speed(width, height, &rgb_data, 10);
+
+    // Preserve original frame properties
+    frame.delay = original.delay;
+    frame.dispose = gif::DisposalMethod::Background;
+    // The palette is new, so the original transparent index would punch holes into it
+    frame.transparent = None;
+    frame.needs_user_input = original.needs_user_input;
+    frame.top = original.top;
+    frame.left = original.left;
+    // Keep the new dimensions we specified, not the original ones
+    // frame.width and frame.height are already set by from_rgb_speed
+    frame.interlaced = original.interlaced;
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_rgba_buffer() {
+        let frame = gif::Frame {
+            buffer: vec![255; 12].into(), // 2x2 RGBA
+            ..Default::default()
+        };
+
+        let result = normalize_frame_to_rgba(&frame, 2, 2);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().len(), 16); // 2x2x4
+    }
+
+    #[test]
+    fn test_normalize_rgb_to_rgba() {
+        let frame = gif::Frame {
+            buffer: vec![100, 150, 200, 50, 75, 100].into(), // 2x1 RGB
+            ..Default::default()
+        };
+
+        let result = normalize_frame_to_rgba(&frame, 2, 1).unwrap();
+        assert_eq!(result, vec![100, 150, 200, 255, 50, 75, 100, 255]);
+    }
+
+    #[test]
+    fn test_create_mirrored_frame_preserves_properties() {
+        let original = gif::Frame {
+            delay: 10,
+            dispose: gif::DisposalMethod::Keep,
+            transparent: Some(5),
+            ..Default::default()
+        };
+
+        let mirrored_rgba = vec![100, 150, 200, 255]; // 1x1 RGBA
+        let frame = create_mirrored_frame(&original, &mirrored_rgba, 1, 1);
+
+        assert_eq!(frame.delay, 10);
+        // The next frame may have transparent pixels, which must not show this one
+        assert_eq!(frame.dispose, gif::DisposalMethod::Background);
+        // Every pixel is opaque, so no palette entry may be transparent
+        assert_eq!(frame.transparent, None);
+    }
+
+    #[test]
+    fn test_create_mirrored_frame_keeps_transparency() {
+        let original = gif::Frame {
+            delay: 7,
+            ..Default::default()
+        };
+
+        // Opaque red, then a pixel that is mostly transparent
+        let rgba = vec![255, 0, 0, 255, 0, 255, 0, 100];
+        let frame = create_mirrored_frame(&original, &rgba, 2, 1);
+
+        let transparent = frame
+            .transparent
+            .expect("frame should have a transparent index");
+        assert_eq!(frame.buffer[1], transparent);
+        assert_ne!(frame.buffer[0], transparent);
+        assert_eq!(frame.delay, 7);
+        assert_eq!(frame.dispose, gif::DisposalMethod::Background);
+    }
+
+    #[test]
+    fn test_normalize_frame_invalid_size() {
+        let frame = gif::Frame {
+            buffer: vec![255; 10].into(), // Invalid size
+            ..Default::default()
+        };
+
+        let result = normalize_frame_to_rgba(&frame, 2, 2);
+        assert!(result.is_err());
+        assert!(matches!(
+            result.unwrap_err(),
+            GpuWorkerError::InvalidInput(_)
+        ));
+    }
+
+    #[test]
+    fn test_create_gif_encoder_success() {
+        let mut output = Vec::new();
+        let result = create_gif_encoder(&mut output, 100, 100, Repeat::Infinite);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_create_mirrored_frame_rgb_conversion() {
+        let original = gif::Frame::default();
+        let mirrored_rgba = vec![255, 0, 0, 255, 0, 255, 0, 255]; // 2x1 RGBA (red, green)
+        let frame = create_mirrored_frame(&original, &mirrored_rgba, 2, 1);
+
+        // Verify frame dimensions are correct
+        assert_eq!(frame.width, 2);
+        assert_eq!(frame.height, 1);
+
+        // Note: Frame::from_rgb_speed may create a palette-based frame
+        // rather than storing raw RGB data, so we don't test the buffer contents
+        // The important thing is that the frame is created successfully with correct dimensions
+    }
+
+    #[test]
+    fn test_decode_gif_empty_frames() {
+        // Create a minimal GIF header without frames
+        let gif_data = vec![
+            b'G', b'I', b'F', b'8', b'9', b'a', // Header
+            1, 0, 1, 0, // Width: 1, Height: 1
+            0, 0, 0,    // Global color table info
+            0x3B, // Trailer
+        ];
+
+        let result = decode_gif(gif_data.as_slice(), &DecodeLimits::default());
+        assert!(result.is_err());
+        // The GIF decoder will throw a decoding error for malformed GIF data
+        match result.unwrap_err() {
+            GpuWorkerError::GifDecode(_) | GpuWorkerError::InvalidInput(_) => (),
+            _ => panic!("Expected GifDecode or InvalidInput error"),
+        }
+    }
+
+    #[test]
+    fn test_normalize_frame_edge_cases() {
+        // Test empty frame
+        let frame = gif::Frame {
+            buffer: vec![].into(),
+            ..Default::default()
+        };
+        let result = normalize_frame_to_rgba(&frame, 0, 0);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().len(), 0);
+
+        // Test 1x1 RGBA frame
+        let frame = gif::Frame {
+            buffer: vec![255, 0, 0, 255].into(),
+            ..Default::default()
+        };
+        let result = normalize_frame_to_rgba(&frame, 1, 1);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), vec![255, 0, 0, 255]);
+    }
+
+    fn create_animated_gif(frame_count: u16) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = create_gif_encoder(&mut output, 4, 4, Repeat::Infinite).unwrap();
+        for index in 0..frame_count {
+            let mut frame = Frame::from_rgb(4, 4, &[index as u8 * 20; 4 * 4 * 3]);
+            frame.delay = index + 1;
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        output
+    }
+
+    fn encode_frames(width: u16, height: u16, frames: Vec<Frame<'static>>) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = create_gif_encoder(&mut output, width, height, Repeat::Infinite).unwrap();
+        for frame in frames {
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        output
+    }
+
+    fn rgba_frame(width: u16, height: u16, color: [u8; 4]) -> Frame<'static> {
+        let mut pixels = color.repeat(width as usize * height as usize);
+        Frame::from_rgba(width, height, &mut pixels)
+    }
+
+    #[test]
+    fn test_count_gif_frames() {
+        assert_eq!(
+            count_gif_frames(create_animated_gif(7).as_slice(), &DecodeLimits::default()).unwrap(),
+            7
+        );
+    }
+
+    #[test]
+    fn test_compositor_fills_canvas_from_sub_frames() {
+        let background = rgba_frame(4, 4, [255, 0, 0, 255]);
+        let mut patch = rgba_frame(2, 2, [0, 0, 255, 255]);
+        patch.left = 2;
+        patch.top = 2;
+
+        let gif_data = encode_frames(4, 4, vec![background, patch]);
+        let frames: Vec<_> = decode_gif(gif_data.as_slice(), &DecodeLimits::default())
+            .unwrap()
+            .map(|frame| frame.unwrap())
+            .collect();
+
+        let second = &frames[1];
+        assert_eq!((second.width, second.height), (4, 4));
+        assert_eq!((second.left, second.top), (0, 0));
+        assert_eq!(&second.buffer[..4], &[255, 0, 0, 255]);
+        let bottom_right = (3 * 4 + 3) * 4;
+        assert_eq!(
+            &second.buffer[bottom_right..bottom_right + 4],
+            &[0, 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_compositor_applies_disposal() {
+        let mut background = rgba_frame(2, 1, [255, 0, 0, 255]);
+        background.dispose = gif::DisposalMethod::Background;
+        let mut patch = rgba_frame(1, 1, [0, 255, 0, 255]);
+        patch.dispose = gif::DisposalMethod::Previous;
+        let mut last = rgba_frame(1, 1, [0, 0, 255, 255]);
+        last.left = 1;
+
+        let gif_data = encode_frames(2, 1, vec![background, patch, last]);
+        let frames: Vec<_> = decode_gif(gif_data.as_slice(), &DecodeLimits::default())
+            .unwrap()
+            .map(|frame| frame.unwrap().buffer.into_owned())
+            .collect();
+
+        // The red background is cleared, the green patch is drawn and then restored away
+        assert_eq!(frames[1], vec![0, 255, 0, 255, 0, 0, 0, 0]);
+        assert_eq!(frames[2], vec![0, 0, 0, 0, 0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_quantize_pool_runs_jobs() {
+        let pool = QuantizePool::new(2);
+        assert_eq!(pool.threads(), 2);
+
+        let (tx, rx) = mpsc::channel();
+        for value in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(value).unwrap());
+        }
+        drop(tx);
+
+        let mut values: Vec<i32> = rx.iter().collect();
+        values.sort();
+        assert_eq!(values, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_quantize_pool_survives_panics() {
+        let pool = QuantizePool::new(1);
+        pool.execute(|| panic!("quantization failed"));
+
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap());
+        assert!(rx.recv().is_ok());
+    }
+
+    #[test]
+    fn test_process_frames_preserves_order() {
+        let gif_data = create_animated_gif(12);
+        let frames = decode_gif(gif_data.as_slice(), &DecodeLimits::default()).unwrap();
+        let dimensions = frames.dimensions();
+        let pool = QuantizePool::new(4);
+
+        let mut output = Vec::new();
+        process_frames(
+            frames,
+            dimensions,
+            &mut output,
+            &EncodeOptions::default(),
+            &pool,
+            |rgba: &[u8], _, _| {
+                // Make early frames slower so they finish out of order
+                thread::sleep(std::time::Duration::from_millis(u64::from(rgba[0]) / 20));
+                Ok(rgba.to_vec())
+            },
+        )
+        .unwrap();
+
+        let delays: Vec<u16> = decode_gif(output.as_slice(), &DecodeLimits::default())
+            .unwrap()
+            .map(|frame| frame.unwrap().delay)
+            .collect();
+        assert_eq!(delays, (1..=12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_process_frames_propagates_errors() {
+        let gif_data = create_animated_gif(6);
+        let frames = decode_gif(gif_data.as_slice(), &DecodeLimits::default()).unwrap();
+        let dimensions = frames.dimensions();
+        let pool = QuantizePool::new(2);
+
+        let mut output = Vec::new();
+        let result = process_frames(
+            frames,
+            dimensions,
+            &mut output,
+            &EncodeOptions::default(),
+            &pool,
+            |rgba: &[u8], _, _| {
+                if rgba[0] == 60 {
+                    Err(GpuWorkerError::Gpu("device lost".to_string()))
+                } else {
+                    Ok(rgba.to_vec())
+                }
+            },
+        );
+
+        assert!(matches!(result, Err(GpuWorkerError::Gpu(_))));
+    }
+
+    #[test]
+    fn test_process_frames_rejects_truncated_gif() {
+        let gif_data = create_animated_gif(4);
+        let truncated = &gif_data[..gif_data.len() - 40];
+        let frames = decode_gif(truncated, &DecodeLimits::default()).unwrap();
+        let dimensions = frames.dimensions();
+
+        let mut output = Vec::new();
+        let pool = QuantizePool::new(1);
+        let result = process_frames(
+            frames,
+            dimensions,
+            &mut output,
+            &EncodeOptions::default(),
+            &pool,
+            |rgba: &[u8], _, _| Ok(rgba.to_vec()),
+        );
+
+        assert!(result.is_err());
+    }
+}
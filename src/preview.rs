@@ -0,0 +1,469 @@
+//! Static previews of animated GIFs.
+//!
+//! A single frame can be extracted by index or by timestamp, and a contact sheet lays out evenly
+//! spaced frames in a grid, optionally labelled with their frame numbers. Both are built from the
+//! composited frames yielded by [`decode_gif`], so every preview shows what a viewer would see at
+//! that point of the animation.
+
+use std::io::Read;
+
+use image::{imageops, Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::{
+    error::{GpuWorkerError, Result},
+    limits::DecodeLimits,
+    pipeline::{count_gif_frames, decode_gif},
+};
+
+/// Most frames a contact sheet can show.
+pub const MAX_SHEET_FRAMES: usize = 100;
+
+/// Range of accepted thumbnail widths, in pixels.
+pub const THUMB_WIDTH_RANGE: std::ops::RangeInclusive<u32> = 16..=1024;
+
+/// Space around and between thumbnails.
+const SHEET_GAP: u32 = 4;
+
+/// Background color of contact sheets.
+const SHEET_BACKGROUND: Rgba<u8> = Rgba([32, 32, 32, 255]);
+
+/// Scale applied to the 3x5 label font.
+const LABEL_SCALE: u32 = 2;
+
+/// Selects the frame to extract, by index or by timestamp. Defaults to the first frame.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FrameSelection {
+    /// Zero-based frame index.
+    pub index: Option<usize>,
+    /// Time into the animation in milliseconds; selects the frame shown at that moment.
+    pub time_ms: Option<u32>,
+}
+
+impl FrameSelection {
+    /// Checks that at most one way of selecting a frame was used.
+    pub fn validate(&self) -> Result<()> {
+        if self.index.is_some() && self.time_ms.is_some() {
+            return Err(GpuWorkerError::InvalidInput(
+                "Select a frame by index or by time_ms, not both".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Options for [`contact_sheet`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContactSheetOptions {
+    /// Number of evenly spaced frames to show. GIFs with fewer frames show all of them.
+    #[serde(default = "ContactSheetOptions::default_frames")]
+    pub frames: usize,
+    /// Number of grid columns; defaults to a roughly square grid.
+    pub columns: Option<usize>,
+    /// Width of each thumbnail in pixels. Heights follow the GIF's aspect ratio.
+    #[serde(default = "ContactSheetOptions::default_thumb_width")]
+    pub thumb_width: u32,
+    /// Draw the source frame number in the corner of each thumbnail.
+    #[serde(default)]
+    pub labels: bool,
+}
+
+impl Default for ContactSheetOptions {
+    fn default() -> Self {
+        Self {
+            frames: Self::default_frames(),
+            columns: None,
+            thumb_width: Self::default_thumb_width(),
+            labels: false,
+        }
+    }
+}
+
+impl ContactSheetOptions {
+    fn default_frames() -> usize {
+        9
+    }
+
+    fn default_thumb_width() -> u32 {
+        160
+    }
+
+    /// Checks that every option is within range.
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=MAX_SHEET_FRAMES).contains(&self.frames) {
+            return Err(GpuWorkerError::InvalidInput(format!(
+                "frames must be between 1 and {}, got {}",
+                MAX_SHEET_FRAMES, self.frames
+            )));
+        }
+
+        if self.columns == Some(0) {
+            return Err(GpuWorkerError::InvalidInput(
+                "columns must be at least 1".to_string(),
+            ));
+        }
+
+        if !THUMB_WIDTH_RANGE.contains(&self.thumb_width) {
+            return Err(GpuWorkerError::InvalidInput(format!(
+                "thumb_width must be between {} and {}, got {}",
+                THUMB_WIDTH_RANGE.start(),
+                THUMB_WIDTH_RANGE.end(),
+                self.thumb_width
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes a GIF up to the selected frame and returns it as a full-canvas image
+pub fn extract_frame<R: Read>(
+    gif_data: R,
+    selection: &FrameSelection,
+    limits: &DecodeLimits,
+) -> Result<RgbaImage> {
+    let frames = decode_gif(gif_data, limits)?;
+    let (width, height) = frames.dimensions();
+
+    let mut elapsed_ms = 0u32;
+    let mut frame_count = 0;
+    for (index, frame) in frames.enumerate() {
+        let frame = frame?;
+        frame_count += 1;
+
+        let end_ms = elapsed_ms + frame.delay as u32 * 10;
+        let selected = match (selection.index, selection.time_ms) {
+            (Some(wanted), _) => index == wanted,
+            (None, Some(time_ms)) => time_ms < end_ms,
+            (None, None) => true,
+        };
+        if selected {
+            return frame_to_image(frame.buffer.into_owned(), width, height);
+        }
+        elapsed_ms = end_ms;
+    }
+
+    Err(match selection.index {
+        Some(index) => GpuWorkerError::InvalidInput(format!(
+            "Frame {} is out of range, the GIF has {} frames",
+            index, frame_count
+        )),
+        None => GpuWorkerError::InvalidInput(format!(
+            "time_ms {} is past the end of the animation ({} ms)",
+            selection.time_ms.unwrap_or(0),
+            elapsed_ms
+        )),
+    })
+}
+
+/// Renders evenly spaced frames of a GIF into a grid of thumbnails
+///
+/// The GIF is read twice: once to count its frames and once to decode the selected ones, so only
+/// a single full-size frame is held in memory at a time.
+pub fn contact_sheet(
+    gif_data: &[u8],
+    options: &ContactSheetOptions,
+    limits: &DecodeLimits,
+) -> Result<RgbaImage> {
+    let frame_count = count_gif_frames(gif_data, limits)?;
+    if frame_count == 0 {
+        return Err(GpuWorkerError::InvalidInput(
+            "GIF contains no frames".to_string(),
+        ));
+    }
+
+    let shown = options.frames.min(frame_count);
+    let selected: Vec<usize> = (0..shown).map(|i| i * frame_count / shown).collect();
+
+    let frames = decode_gif(gif_data, limits)?;
+    let (width, height) = frames.dimensions();
+    let thumb_width = options.thumb_width;
+    // Tall, narrow frames would otherwise give thumbnails of any height
+    let thumb_height = ((thumb_width as u64 * height as u64 + width as u64 / 2) / width as u64)
+        .clamp(1, *THUMB_WIDTH_RANGE.end() as u64) as u32;
+
+    let columns = options
+        .columns
+        .unwrap_or_else(|| (shown as f64).sqrt().ceil() as usize)
+        .min(shown);
+    let rows = (shown + columns - 1) / columns;
+    let (sheet_width, sheet_height) = (
+        sheet_extent(columns, thumb_width)?,
+        sheet_extent(rows, thumb_height)?,
+    );
+    limits.check_canvas(sheet_width, sheet_height)?;
+    let mut sheet = RgbaImage::from_pixel(sheet_width, sheet_height, SHEET_BACKGROUND);
+
+    let mut cells = selected.iter().copied().enumerate().peekable();
+    for (index, frame) in frames.enumerate() {
+        let Some(&(cell, wanted)) = cells.peek() else {
+            break;
+        };
+        let frame = frame?;
+        if index != wanted {
+            continue;
+        }
+        cells.next();
+
+        let image = frame_to_image(frame.buffer.into_owned(), width, height)?;
+        let thumbnail = imageops::resize(
+            &image,
+            thumb_width,
+            thumb_height,
+            imageops::FilterType::Triangle,
+        );
+
+        let x = SHEET_GAP + (cell % columns) as u32 * (thumb_width + SHEET_GAP);
+        let y = SHEET_GAP + (cell / columns) as u32 * (thumb_height + SHEET_GAP);
+        imageops::overlay(&mut sheet, &thumbnail, x as i64, y as i64);
+        if options.labels {
+            draw_label(&mut sheet, x, y, index);
+        }
+    }
+
+    Ok(sheet)
+}
+
+fn frame_to_image(buffer: Vec<u8>, width: u32, height: u32) -> Result<RgbaImage> {
+    RgbaImage::from_raw(width, height, buffer).ok_or_else(|| {
+        GpuWorkerError::Internal("Decoded frame does not match the canvas size".to_string())
+    })
+}
+
+/// Size of a sheet axis holding `cells` thumbnails of `extent` pixels with gaps around them
+fn sheet_extent(cells: usize, extent: u32) -> Result<u32> {
+    (cells as u64 * (extent as u64 + SHEET_GAP as u64) + SHEET_GAP as u64)
+        .try_into()
+        .map_err(|_| GpuWorkerError::InvalidInput("Contact sheet would be too large".to_string()))
+}
+
+/// Rows of the 3x5 pixel font used for labels, one entry per digit, three bits per row.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Draws `number` in white on a black box with its top-left corner at (`x`, `y`)
+fn draw_label(image: &mut RgbaImage, x: u32, y: u32, number: usize) {
+    let digits = number.to_string();
+    let advance = 4 * LABEL_SCALE;
+    let box_width = digits.len() as u32 * advance + LABEL_SCALE;
+    let box_height = 7 * LABEL_SCALE;
+
+    let mut put = |px: u32, py: u32, color: Rgba<u8>| {
+        if px < image.width() && py < image.height() {
+            image.put_pixel(px, py, color);
+        }
+    };
+
+    for dy in 0..box_height {
+        for dx in 0..box_width {
+            put(x + dx, y + dy, Rgba([0, 0, 0, 255]));
+        }
+    }
+
+    for (position, digit) in digits.bytes().enumerate() {
+        let glyph = DIGITS[(digit - b'0') as usize];
+        let origin_x = x + LABEL_SCALE + position as u32 * advance;
+        let origin_y = y + LABEL_SCALE;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for sy in 0..LABEL_SCALE {
+                    for sx in 0..LABEL_SCALE {
+                        put(
+                            origin_x + column * LABEL_SCALE + sx,
+                            origin_y + row as u32 * LABEL_SCALE + sy,
+                            Rgba([255, 255, 255, 255]),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gif::{Encoder, Frame, Repeat};
+
+    /// Builds a 4x2 GIF whose frame `i` is filled with gray level `i * 20` and lasts `i + 1` cs
+    fn create_animated_gif(frame_count: u8) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = Encoder::new(&mut output, 4, 2, &[]).unwrap();
+        encoder.set_repeat(Repeat::Infinite).unwrap();
+        for index in 0..frame_count {
+            let mut frame = Frame::from_rgb(4, 2, &[index * 20; 4 * 2 * 3]);
+            frame.delay = index as u16 + 1;
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        output
+    }
+
+    fn gray_level(image: &RgbaImage, x: u32, y: u32) -> u8 {
+        image.get_pixel(x, y)[0]
+    }
+
+    #[test]
+    fn test_extract_frame_by_index() {
+        let gif_data = create_animated_gif(5);
+        let selection = FrameSelection {
+            index: Some(3),
+            time_ms: None,
+        };
+
+        let image =
+            extract_frame(gif_data.as_slice(), &selection, &DecodeLimits::default()).unwrap();
+        assert_eq!(image.dimensions(), (4, 2));
+        assert_eq!(gray_level(&image, 0, 0), 60);
+    }
+
+    #[test]
+    fn test_extract_frame_by_time() {
+        // Frames last 10, 20, 30, ... ms, so 35 ms falls into the third frame
+        let gif_data = create_animated_gif(5);
+        let selection = FrameSelection {
+            index: None,
+            time_ms: Some(35),
+        };
+
+        let image =
+            extract_frame(gif_data.as_slice(), &selection, &DecodeLimits::default()).unwrap();
+        assert_eq!(gray_level(&image, 0, 0), 40);
+    }
+
+    #[test]
+    fn test_extract_frame_out_of_range() {
+        let gif_data = create_animated_gif(3);
+        for selection in [
+            FrameSelection {
+                index: Some(3),
+                time_ms: None,
+            },
+            FrameSelection {
+                index: None,
+                time_ms: Some(60),
+            },
+        ] {
+            let result = extract_frame(gif_data.as_slice(), &selection, &DecodeLimits::default());
+            assert!(matches!(result, Err(GpuWorkerError::InvalidInput(_))));
+        }
+    }
+
+    #[test]
+    fn test_frame_selection_rejects_both() {
+        let selection = FrameSelection {
+            index: Some(0),
+            time_ms: Some(0),
+        };
+        assert!(selection.validate().is_err());
+    }
+
+    #[test]
+    fn test_contact_sheet_layout() {
+        let gif_data = create_animated_gif(10);
+        let options = ContactSheetOptions {
+            frames: 5,
+            columns: Some(3),
+            thumb_width: 16,
+            labels: false,
+        };
+
+        let sheet = contact_sheet(&gif_data, &options, &DecodeLimits::default()).unwrap();
+        // 16x8 thumbnails in a 3x2 grid with 4px gaps
+        assert_eq!(sheet.dimensions(), (3 * 20 + 4, 2 * 12 + 4));
+
+        // Frames 0, 2, 4, 6, 8 are shown in order
+        let cell_levels: Vec<u8> = (0..5)
+            .map(|cell| gray_level(&sheet, 4 + (cell % 3) * 20 + 8, 4 + (cell / 3) * 12 + 4))
+            .collect();
+        assert_eq!(cell_levels, vec![0, 40, 80, 120, 160]);
+
+        // The unused last cell stays background
+        assert_eq!(*sheet.get_pixel(4 + 2 * 20 + 8, 16 + 4), SHEET_BACKGROUND);
+    }
+
+    #[test]
+    fn test_contact_sheet_with_few_frames_and_labels() {
+        let gif_data = create_animated_gif(2);
+        let options = ContactSheetOptions {
+            frames: 9,
+            columns: None,
+            thumb_width: 32,
+            labels: true,
+        };
+
+        let sheet = contact_sheet(&gif_data, &options, &DecodeLimits::default()).unwrap();
+        assert_eq!(sheet.dimensions(), (2 * 36 + 4, 20 + 4));
+
+        // Label box in the corner of the second thumbnail, with a white pixel of the "1" glyph
+        assert_eq!(*sheet.get_pixel(40, 4), Rgba([0, 0, 0, 255]));
+        assert_eq!(
+            *sheet.get_pixel(40 + LABEL_SCALE + LABEL_SCALE, 4 + LABEL_SCALE),
+            Rgba([255, 255, 255, 255])
+        );
+    }
+
+    #[test]
+    fn test_contact_sheet_of_tall_narrow_gif() {
+        let mut gif_data = Vec::new();
+        let mut encoder = Encoder::new(&mut gif_data, 1, 4096, &[]).unwrap();
+        encoder
+            .write_frame(&Frame::from_rgb(1, 4096, &[200; 4096 * 3]))
+            .unwrap();
+        drop(encoder);
+        let options = ContactSheetOptions {
+            frames: 1,
+            columns: None,
+            thumb_width: 1024,
+            labels: false,
+        };
+
+        // Thumbnails are at most as high as the widest thumbnail is wide
+        let sheet = contact_sheet(&gif_data, &options, &DecodeLimits::default()).unwrap();
+        assert_eq!(sheet.dimensions(), (1024 + 8, 1024 + 8));
+
+        // The sheet itself is checked against the canvas limit before it is allocated
+        let limits = DecodeLimits {
+            max_canvas_pixels: 1024 * 1024,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            contact_sheet(&gif_data, &options, &limits),
+            Err(GpuWorkerError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_contact_sheet_options_validation() {
+        assert!(ContactSheetOptions::default().validate().is_ok());
+        for options in [
+            ContactSheetOptions {
+                frames: 0,
+                ..Default::default()
+            },
+            ContactSheetOptions {
+                columns: Some(0),
+                ..Default::default()
+            },
+            ContactSheetOptions {
+                thumb_width: 4096,
+                ..Default::default()
+            },
+        ] {
+            assert!(options.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,560 @@
+//! Admission control for GPU work.
+//!
+//! Requests and jobs that run frames through the GPU first take a [`GpuPermit`] from the
+//! [`GpuScheduler`]. At most `max_concurrent` permits are out at once; further callers wait, up
+//! to `queue_depth` of them. Once the wait queue is full, requests are turned away at once with
+//! `503 Service Unavailable` and a `Retry-After` header instead of piling up behind work they
+//! would time out waiting for. [`GpuScheduler::stats`] reports the current load.
+//!
+//! Every caller is a [`Requester`]: a client and a [`Priority`] class. Free permits go to the
+//! most urgent class with anyone waiting. Within a class, clients are served by self-clocked
+//! weighted fair queuing: each waiter is tagged with the virtual time its client's share would
+//! be used up by, and the smallest tag goes first. A client queueing thousands of requests thus
+//! only delays the others by its share, and a client of weight 2 gets twice the share of a client
+//! of weight 1. Waiters of one client are served in arrival order.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::error::{GpuWorkerError, Result};
+
+/// Default number of requests and jobs using the GPU at once.
+pub const DEFAULT_GPU_MAX_CONCURRENT: usize = 2;
+
+/// Default number of requests waiting for the GPU before further ones are rejected.
+pub const DEFAULT_GPU_QUEUE_DEPTH: usize = 32;
+
+/// Default delay suggested to rejected clients.
+pub const DEFAULT_GPU_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Virtual time a client of weight 1 uses per permit.
+const SHARE: u64 = 1 << 20;
+
+/// Urgency of a request or job, most urgent first
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Someone is waiting for the response.
+    Interactive,
+    #[default]
+    Default,
+    /// Batch work that can wait for everything else.
+    Bulk,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Self::Interactive, Self::Default, Self::Bulk];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Interactive => "interactive",
+            Self::Default => "default",
+            Self::Bulk => "bulk",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|priority| priority.name().eq_ignore_ascii_case(name))
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Who GPU work is for and how urgent it is
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Requester {
+    /// Identity of the client, shared by all of its requests and jobs.
+    pub client_id: String,
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// Concurrency and queueing of GPU work
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuSchedulerConfig {
+    /// Permits handed out at once, at least 1.
+    pub max_concurrent: usize,
+    /// Requests waiting for a permit before further ones are rejected.
+    pub queue_depth: usize,
+    /// Sent as `Retry-After` with rejections, rounded up to whole seconds.
+    pub retry_after: Duration,
+    /// Share of each client within its class relative to the others; 1 for unlisted clients.
+    pub client_weights: BTreeMap<String, u32>,
+}
+
+impl Default for GpuSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: DEFAULT_GPU_MAX_CONCURRENT,
+            queue_depth: DEFAULT_GPU_QUEUE_DEPTH,
+            retry_after: DEFAULT_GPU_RETRY_AFTER,
+            client_weights: BTreeMap::new(),
+        }
+    }
+}
+
+impl GpuSchedulerConfig {
+    fn weight(&self, client_id: &str) -> u64 {
+        self.client_weights
+            .get(client_id)
+            .map_or(1, |&weight| weight.max(1) as u64)
+    }
+}
+
+/// Current load of the scheduler
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GpuSchedulerStats {
+    /// Permits currently held.
+    pub running: usize,
+    /// Callers waiting for a permit.
+    pub waiting: usize,
+    /// Callers waiting for a permit in each class.
+    pub waiting_by_priority: BTreeMap<Priority, usize>,
+    pub max_concurrent: usize,
+    pub queue_depth: usize,
+    /// Requests rejected since startup because the queue was full.
+    pub rejected: u64,
+}
+
+/// Hands out permits to use the GPU, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct GpuScheduler {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    config: GpuSchedulerConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: usize,
+    /// Wait queues by [`Priority::index`].
+    classes: [ClassQueue; 3],
+    waiting: usize,
+    next_waiter: u64,
+    rejected: u64,
+}
+
+/// Waiters of one priority class, in weighted fair order
+#[derive(Debug, Default)]
+struct ClassQueue {
+    /// Tag of the waiter served last.
+    virtual_time: u64,
+    /// Tag of the latest waiter of each client whose share runs past the virtual time.
+    last_tags: HashMap<String, u64>,
+    /// Waiters by tag, then by arrival.
+    waiters: BTreeMap<(u64, u64), Waiter>,
+}
+
+/// A caller in a [`ClassQueue`]
+#[derive(Debug)]
+struct Waiter {
+    client_id: String,
+    wake: oneshot::Sender<()>,
+}
+
+impl ClassQueue {
+    /// Queues a waiter of `client_id`, returning its key.
+    fn push(&mut self, client_id: &str, weight: u64, id: u64, wake: oneshot::Sender<()>) -> u64 {
+        let start = self
+            .last_tags
+            .get(client_id)
+            .map_or(self.virtual_time, |&tag| tag.max(self.virtual_time));
+        let tag = start + SHARE / weight;
+        self.last_tags.insert(client_id.to_string(), tag);
+        let waiter = Waiter {
+            client_id: client_id.to_string(),
+            wake,
+        };
+        self.waiters.insert((tag, id), waiter);
+        tag
+    }
+
+    fn pop(&mut self) -> Option<oneshot::Sender<()>> {
+        let ((tag, _), waiter) = self.waiters.pop_first()?;
+        self.virtual_time = tag;
+        // Clients that are not ahead of the virtual time start from it anyway
+        let virtual_time = self.virtual_time;
+        self.last_tags.retain(|_, tag| *tag > virtual_time);
+        Some(waiter.wake)
+    }
+
+    /// Removes a waiter that gave up, returning whether it was still queued.
+    fn remove(&mut self, key: (u64, u64)) -> bool {
+        let Some(waiter) = self.waiters.remove(&key) else {
+            return false;
+        };
+        // The share the waiter would have used goes back to its client, whose next waiter then
+        // starts from its latest remaining one, or from the virtual time
+        let last_tag = self
+            .waiters
+            .iter()
+            .filter(|(_, other)| other.client_id == waiter.client_id)
+            .map(|(&(tag, _), _)| tag)
+            .max();
+        match last_tag {
+            Some(tag) => self.last_tags.insert(waiter.client_id, tag),
+            None => self.last_tags.remove(&waiter.client_id),
+        };
+        true
+    }
+}
+
+impl GpuScheduler {
+    pub fn new(config: GpuSchedulerConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                config: GpuSchedulerConfig {
+                    max_concurrent: config.max_concurrent.max(1),
+                    ..config
+                },
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Waits for a permit, or fails at once with [`GpuWorkerError::Overloaded`] when the wait
+    /// queue is full
+    ///
+    /// Dropping the returned future gives up the place in the queue.
+    pub async fn acquire(&self, requester: &Requester) -> Result<GpuPermit> {
+        self.acquire_inner(requester, true).await
+    }
+
+    /// Blocks until a permit is free, for background work that has already been admitted
+    ///
+    /// Callers wait in the same queues as requests and count towards their depth, but are never
+    /// rejected.
+    pub fn acquire_blocking(&self, requester: &Requester) -> GpuPermit {
+        pollster::block_on(self.acquire_inner(requester, false))
+            .expect("waiting without a limit cannot fail")
+    }
+
+    async fn acquire_inner(&self, requester: &Requester, bounded: bool) -> Result<GpuPermit> {
+        let config = &self.shared.config;
+        let (key, wake) = {
+            let mut state = self.shared.lock();
+            if state.running < config.max_concurrent && state.waiting == 0 {
+                state.running += 1;
+                return Ok(self.permit());
+            }
+            if bounded && state.waiting >= config.queue_depth {
+                state.rejected += 1;
+                return Err(GpuWorkerError::Overloaded(
+                    format!("{} requests are already waiting for the GPU", state.waiting),
+                    config.retry_after,
+                ));
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            let id = state.next_waiter;
+            state.next_waiter += 1;
+            state.waiting += 1;
+            let class = requester.priority.index();
+            let weight = config.weight(&requester.client_id);
+            let tag = state.classes[class].push(&requester.client_id, weight, id, sender);
+            ((class, (tag, id)), receiver)
+        };
+
+        let mut waiting = Waiting {
+            shared: &self.shared,
+            key,
+            wake,
+            granted: false,
+        };
+        // The sender is only dropped along with the scheduler, which `self` keeps alive
+        let _ = (&mut waiting.wake).await;
+        waiting.granted = true;
+        Ok(self.permit())
+    }
+
+    /// Current load.
+    pub fn stats(&self) -> GpuSchedulerStats {
+        let state = self.shared.lock();
+        GpuSchedulerStats {
+            running: state.running,
+            waiting: state.waiting,
+            waiting_by_priority: Priority::ALL
+                .into_iter()
+                .map(|priority| (priority, state.classes[priority.index()].waiters.len()))
+                .collect(),
+            max_concurrent: self.shared.config.max_concurrent,
+            queue_depth: self.shared.config.queue_depth,
+            rejected: state.rejected,
+        }
+    }
+
+    fn permit(&self) -> GpuPermit {
+        GpuPermit {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Passes a returned permit on to the next waiter, if any.
+    fn release(&self) {
+        let mut state = self.lock();
+        for class in 0..state.classes.len() {
+            while let Some(wake) = state.classes[class].pop() {
+                state.waiting -= 1;
+                if wake.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+/// Place of a caller in the wait queue, given up when the caller stops waiting
+struct Waiting<'a> {
+    shared: &'a Shared,
+    /// Class index and key within the class.
+    key: (usize, (u64, u64)),
+    wake: oneshot::Receiver<()>,
+    /// Whether the permit passed on has been taken over.
+    granted: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        let mut state = self.shared.lock();
+        let (class, key) = self.key;
+        if state.classes[class].remove(key) {
+            state.waiting -= 1;
+            return;
+        }
+        drop(state);
+        // Left the queue because a permit was passed on, which nobody will hold now
+        self.shared.release();
+    }
+}
+
+/// Permission to use the GPU, returned to the scheduler when dropped.
+#[derive(Debug)]
+pub struct GpuPermit {
+    shared: Arc<Shared>,
+}
+
+impl Drop for GpuPermit {
+    fn drop(&mut self) {
+        self.shared.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(max_concurrent: usize, queue_depth: usize) -> GpuScheduler {
+        GpuScheduler::new(GpuSchedulerConfig {
+            max_concurrent,
+            queue_depth,
+            ..GpuSchedulerConfig::default()
+        })
+    }
+
+    fn requester(client_id: &str, priority: Priority) -> Requester {
+        Requester {
+            client_id: client_id.to_string(),
+            priority,
+        }
+    }
+
+    /// Queues one waiter per requester while `first` holds the only permit, then releases it
+    /// and returns the order the waiters were served in
+    async fn serve_order(
+        scheduler: &GpuScheduler,
+        first: GpuPermit,
+        requesters: Vec<Requester>,
+    ) -> Vec<String> {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for (index, requester) in requesters.into_iter().enumerate() {
+            let waiter = scheduler.clone();
+            let order = order.clone();
+            tasks.push(actix_web::rt::spawn(async move {
+                let _permit = waiter.acquire(&requester).await.unwrap();
+                order.lock().unwrap().push(requester.client_id);
+            }));
+            while scheduler.stats().waiting <= index {
+                actix_web::rt::task::yield_now().await;
+            }
+        }
+
+        drop(first);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(scheduler.stats().running, 0);
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    #[test]
+    fn test_priority_names() {
+        for priority in Priority::ALL {
+            assert_eq!(Priority::from_name(priority.name()), Some(priority));
+        }
+        assert_eq!(Priority::from_name("BULK"), Some(Priority::Bulk));
+        assert_eq!(Priority::from_name("urgent"), None);
+        assert_eq!(Priority::default(), Priority::Default);
+    }
+
+    #[actix_web::test]
+    async fn test_full_queue_is_rejected() {
+        let scheduler = scheduler(1, 1);
+        let anyone = Requester::default();
+        let first = scheduler.acquire(&anyone).await.unwrap();
+
+        let waiting = {
+            let scheduler = scheduler.clone();
+            let anyone = anyone.clone();
+            actix_web::rt::spawn(async move { scheduler.acquire(&anyone).await.map(|_| ()) })
+        };
+        while scheduler.stats().waiting == 0 {
+            actix_web::rt::task::yield_now().await;
+        }
+
+        let error = scheduler.acquire(&anyone).await.unwrap_err();
+        assert!(matches!(
+            error,
+            GpuWorkerError::Overloaded(_, retry_after) if retry_after == DEFAULT_GPU_RETRY_AFTER
+        ));
+        assert_eq!(
+            scheduler.stats(),
+            GpuSchedulerStats {
+                running: 1,
+                waiting: 1,
+                waiting_by_priority: [
+                    (Priority::Interactive, 0),
+                    (Priority::Default, 1),
+                    (Priority::Bulk, 0)
+                ]
+                .into_iter()
+                .collect(),
+                max_concurrent: 1,
+                queue_depth: 1,
+                rejected: 1,
+            }
+        );
+
+        drop(first);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(scheduler.stats().running, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_permits_go_to_waiters_in_order() {
+        let scheduler = scheduler(1, 8);
+        let first = scheduler.acquire(&Requester::default()).await.unwrap();
+        let requesters = ["a", "b", "c"]
+            .into_iter()
+            .map(|client_id| requester(client_id, Priority::Default))
+            .collect();
+        assert_eq!(
+            serve_order(&scheduler, first, requesters).await,
+            ["a", "b", "c"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_clients_share_fairly_within_a_class() {
+        let scheduler = GpuScheduler::new(GpuSchedulerConfig {
+            max_concurrent: 1,
+            queue_depth: 16,
+            client_weights: [("heavy".to_string(), 2)].into_iter().collect(),
+            ..GpuSchedulerConfig::default()
+        });
+        let first = scheduler.acquire(&Requester::default()).await.unwrap();
+
+        // A batch queued first does not keep later clients waiting until it is done, and a
+        // client of weight 2 is served twice as often
+        let mut requesters = vec![requester("batch", Priority::Default); 4];
+        requesters.extend(vec![requester("other", Priority::Default); 2]);
+        requesters.extend(vec![requester("heavy", Priority::Default); 3]);
+        requesters.push(requester("bulk", Priority::Bulk));
+        requesters.push(requester("urgent", Priority::Interactive));
+        assert_eq!(
+            serve_order(&scheduler, first, requesters).await,
+            [
+                "urgent", "heavy", "batch", "other", "heavy", "heavy", "batch", "other", "batch",
+                "batch", "bulk"
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_abandoned_waits_leave_the_queue() {
+        let scheduler = scheduler(1, 1);
+        let anyone = Requester::default();
+        let first = scheduler.acquire(&anyone).await.unwrap();
+
+        // Polled once to enter the queue, then dropped
+        let mut waiting = Box::pin(scheduler.acquire(&anyone));
+        assert!(futures_util::poll!(&mut waiting).is_pending());
+        assert_eq!(scheduler.stats().waiting, 1);
+        drop(waiting);
+        assert_eq!(scheduler.stats().waiting, 0);
+
+        // A waiter dropped after being handed a permit passes it on
+        let mut waiting = Box::pin(scheduler.acquire(&anyone));
+        assert!(futures_util::poll!(&mut waiting).is_pending());
+        drop(first);
+        assert_eq!(scheduler.stats().running, 1);
+        drop(waiting);
+        assert_eq!(scheduler.stats().running, 0);
+
+        let blocking = {
+            let scheduler = scheduler.clone();
+            std::thread::spawn(move || drop(scheduler.acquire_blocking(&anyone)))
+        };
+        blocking.join().unwrap();
+        assert_eq!(scheduler.stats().running, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_abandoned_waits_do_not_count_against_the_client() {
+        let scheduler = scheduler(1, 8);
+        let first = scheduler.acquire(&Requester::default()).await.unwrap();
+
+        let a = requester("a", Priority::Default);
+        let mut abandoned = Vec::new();
+        for _ in 0..3 {
+            let mut waiting = Box::pin(scheduler.acquire(&a));
+            assert!(futures_util::poll!(&mut waiting).is_pending());
+            abandoned.push(waiting);
+        }
+        drop(abandoned);
+        assert_eq!(scheduler.stats().waiting, 0);
+
+        // Had the abandoned waits kept their shares, b would be served first
+        let requesters = vec![a, requester("b", Priority::Default)];
+        assert_eq!(serve_order(&scheduler, first, requesters).await, ["a", "b"]);
+    }
+}
@@ -0,0 +1,169 @@
+//! On-disk spool of asynchronous jobs.
+//!
+//! With a spool directory configured, every job gets a directory named after its id that holds a
+//! small JSON manifest, the uploaded input while the job is pending and the result once it has
+//! succeeded. Files are written under a temporary name and renamed into place, so a crash leaves
+//! either the previous or the new version behind, never a partial one. On startup the
+//! [`JobQueue`](crate::jobs::JobQueue) reads the manifests back to resume pending jobs.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    callbacks::CallbackState,
+    error::Result,
+    jobs::{JobProgress, JobRequest, JobStatus},
+};
+
+const MANIFEST: &str = "manifest.json";
+const INPUT: &str = "input";
+const RESULT: &str = "result";
+
+/// Persistent state of a job, stored as its `manifest.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobManifest {
+    pub id: Uuid,
+    pub status: JobStatus,
+    /// Times the job has been started, including runs interrupted by a restart.
+    pub attempts: u32,
+    pub request: JobRequest,
+    pub progress: JobProgress,
+    /// Unix timestamps in seconds.
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub callback: Option<CallbackState>,
+}
+
+/// Directory holding one subdirectory per job
+#[derive(Debug, Clone)]
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    /// Opens the spool in `dir`, creating the directory if needed.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Reads every manifest, oldest job first
+    ///
+    /// Job directories without a readable manifest, such as those of jobs whose submission was
+    /// interrupted, are deleted with a warning, and so are temporary files left by interrupted
+    /// writes. Other entries are skipped.
+    pub fn load(&self) -> Result<Vec<JobManifest>> {
+        let mut manifests = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let dir = entry?.path();
+            let is_job = dir.is_dir()
+                && dir
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| Uuid::parse_str(name).is_ok());
+            let path = dir.join(MANIFEST);
+            let manifest = fs::read(&path).map_err(|e| e.to_string()).and_then(|data| {
+                serde_json::from_slice::<JobManifest>(&data).map_err(|e| e.to_string())
+            });
+            match manifest {
+                Ok(manifest) => {
+                    remove_temporary_files(&dir)?;
+                    manifests.push(manifest);
+                }
+                Err(error) if is_job => {
+                    log::warn!("Removing {}: {}", dir.display(), error);
+                    fs::remove_dir_all(&dir)?;
+                }
+                Err(error) => log::warn!("Skipping {}: {}", path.display(), error),
+            }
+        }
+        manifests.sort_by_key(|manifest| manifest.created_at);
+        Ok(manifests)
+    }
+
+    /// Writes the manifest of a job.
+    pub fn save(&self, manifest: &JobManifest) -> Result<()> {
+        let data = serde_json::to_vec_pretty(manifest)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        self.write(manifest.id, MANIFEST, &data)
+    }
+
+    pub fn write_input(&self, id: Uuid, data: &[u8]) -> Result<()> {
+        self.write(id, INPUT, data)
+    }
+
+    pub fn read_input(&self, id: Uuid) -> Result<Vec<u8>> {
+        Ok(fs::read(self.job_dir(id).join(INPUT))?)
+    }
+
+    /// Deletes the input of a job that no longer needs it.
+    pub fn remove_input(&self, id: Uuid) -> Result<()> {
+        self.remove_file(id, INPUT)
+    }
+
+    pub fn write_result(&self, id: Uuid, data: &[u8]) -> Result<()> {
+        self.write(id, RESULT, data)
+    }
+
+    pub fn read_result(&self, id: Uuid) -> Result<Vec<u8>> {
+        Ok(fs::read(self.job_dir(id).join(RESULT))?)
+    }
+
+    pub fn remove_result(&self, id: Uuid) -> Result<()> {
+        self.remove_file(id, RESULT)
+    }
+
+    /// Deletes everything stored for a job.
+    pub fn remove(&self, id: Uuid) -> Result<()> {
+        match fs::remove_dir_all(self.job_dir(id)) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn job_dir(&self, id: Uuid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    /// Replaces a file of a job through a temporary file and a rename.
+    fn write(&self, id: Uuid, name: &str, data: &[u8]) -> Result<()> {
+        let dir = self.job_dir(id);
+        fs::create_dir_all(&dir)?;
+        let temporary = dir.join(format!("{}.tmp", name));
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, dir.join(name))?;
+        Ok(())
+    }
+
+    fn remove_file(&self, id: Uuid, name: &str) -> Result<()> {
+        match fs::remove_file(self.job_dir(id).join(name)) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Deletes the `*.tmp` files of writes that never got renamed into place.
+fn remove_temporary_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
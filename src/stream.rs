@@ -0,0 +1,267 @@
+//! Bridges between async HTTP bodies and the blocking GIF codecs.
+//!
+//! The `gif` crate decodes from [`Read`] and encodes into [`Write`], while actix-web hands us
+//! request payloads as async streams and expects response bodies as async streams. The types in
+//! this module connect the two through bounded channels so a blocking worker can decode, process
+//! and encode frame by frame while memory stays bounded by a handful of chunks.
+
+use std::io::{self, Read, Write};
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::error::{GpuWorkerError, Result};
+
+/// Size of the chunks emitted into the response body.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks buffered in each direction before the producer has to wait.
+const CHANNEL_DEPTH: usize = 4;
+
+/// Blocking reader fed by an async byte stream.
+pub struct ChannelReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl ChannelReader {
+    /// Spawns a local task that forwards `first` followed by the rest of `body` into a reader.
+    ///
+    /// `guard` is kept alive alongside the stream and dropped once it is exhausted, which lets
+    /// callers tie the lifetime of e.g. a multipart field to its parent payload. Stream errors
+    /// reach the reader as I/O errors wrapping the [`GpuWorkerError`] they convert to.
+    pub fn spawn<S, E, G>(first: Bytes, body: S, guard: G) -> Self
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin + 'static,
+        E: Into<GpuWorkerError>,
+        G: 'static,
+    {
+        let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+
+        actix_web::rt::spawn(async move {
+            let mut body = body;
+            if tx.send(Ok(first)).await.is_err() {
+                return;
+            }
+
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.map_err(|e| io::Error::new(io::ErrorKind::Other, e.into()));
+                let failed = chunk.is_err();
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+
+            drop(body);
+            drop(guard);
+        });
+
+        Self {
+            rx,
+            current: Bytes::new(),
+        }
+    }
+
+    /// Creates a reader over data that is already in memory.
+    pub fn from_bytes(data: Bytes) -> Self {
+        let (_, rx) = mpsc::channel(1);
+        Self { rx, current: data }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.current.len());
+        buf[..len].copy_from_slice(&self.current.split_to(len));
+        Ok(len)
+    }
+}
+
+/// Blocking writer that emits [`CHUNK_SIZE`] chunks into a streaming response body.
+pub struct ChannelWriter {
+    tx: mpsc::Sender<Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+/// Receiving half of [`ChannelWriter`], turned into a response once output starts flowing.
+pub struct BodyReceiver {
+    rx: mpsc::Receiver<Result<Bytes>>,
+}
+
+/// Creates a connected writer/body pair.
+pub fn body_channel() -> (ChannelWriter, BodyReceiver) {
+    let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+    let writer = ChannelWriter {
+        tx,
+        buf: Vec::with_capacity(CHUNK_SIZE),
+    };
+    (writer, BodyReceiver { rx })
+}
+
+impl ChannelWriter {
+    /// Flushes any buffered output and closes the body.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Aborts the body with `error`, discarding any output that has not been sent yet.
+    pub fn fail(self, error: GpuWorkerError) {
+        let _ = self.tx.blocking_send(Err(error));
+    }
+
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buf,
+            Vec::with_capacity(CHUNK_SIZE),
+        ));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "response body was dropped"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buffered()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()
+    }
+}
+
+impl BodyReceiver {
+    /// Waits for the first chunk of output and turns the channel into a chunked response.
+    ///
+    /// Errors raised before any output was produced are returned as-is so they map onto a
+    /// regular error response. Errors raised later can only abort the body.
+    pub async fn into_response(mut self, mut builder: HttpResponseBuilder) -> Result<HttpResponse> {
+        let first = match self.rx.recv().await {
+            Some(chunk) => chunk?,
+            None => {
+                return Err(GpuWorkerError::Internal(
+                    "Processing ended without producing any output".to_string(),
+                ))
+            }
+        };
+
+        let rest = stream::unfold(self.rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        });
+
+        Ok(builder.streaming(stream::once(async move { Ok(first) }).chain(rest)))
+    }
+}
+
+/// Runs `work` on the blocking thread pool, streaming whatever it writes into `writer`.
+///
+/// The writer is flushed when `work` succeeds; on failure the error is forwarded to the body.
+pub fn spawn_writer<F>(mut writer: ChannelWriter, work: F)
+where
+    F: FnOnce(&mut ChannelWriter) -> Result<()> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || match work(&mut writer) {
+        Ok(()) => {
+            if let Err(e) = writer.finish() {
+                log::debug!("Response body closed early: {}", e);
+            }
+        }
+        Err(e) => writer.fail(e),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_reader_from_bytes() {
+        let mut reader = ChannelReader::from_bytes(Bytes::from_static(b"hello"));
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello");
+    }
+
+    #[actix_web::test]
+    async fn test_channel_reader_streams_chunks() {
+        let chunks = vec![
+            Ok::<_, io::Error>(Bytes::from_static(b"b")),
+            Ok(Bytes::new()),
+            Ok(Bytes::from_static(b"cd")),
+        ];
+        let reader = ChannelReader::spawn(Bytes::from_static(b"a"), stream::iter(chunks), ());
+
+        let out = tokio::task::spawn_blocking(move || {
+            let mut reader = reader;
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).map(|_| out)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(out, b"abcd");
+    }
+
+    #[actix_web::test]
+    async fn test_channel_reader_forwards_errors() {
+        let chunks = vec![Err::<Bytes, _>(GpuWorkerError::Payload(
+            "connection reset".to_string(),
+        ))];
+        let reader = ChannelReader::spawn(Bytes::from_static(b"a"), stream::iter(chunks), ());
+
+        let result = tokio::task::spawn_blocking(move || {
+            let mut reader = reader;
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out)
+        })
+        .await
+        .unwrap();
+
+        let error = GpuWorkerError::from(result.unwrap_err());
+        assert!(matches!(error, GpuWorkerError::Payload(_)));
+    }
+
+    #[actix_web::test]
+    async fn test_body_channel_chunks_output() {
+        let (writer, body) = body_channel();
+        spawn_writer(writer, |w| {
+            w.write_all(&vec![1; CHUNK_SIZE + 10])?;
+            Ok(())
+        });
+
+        let response = body.into_response(HttpResponse::Ok()).await.unwrap();
+        let bytes = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(bytes.len(), CHUNK_SIZE + 10);
+    }
+
+    #[actix_web::test]
+    async fn test_body_channel_early_error() {
+        let (writer, body) = body_channel();
+        spawn_writer(writer, |_| {
+            Err(GpuWorkerError::InvalidInput("bad".to_string()))
+        });
+
+        let result = body.into_response(HttpResponse::Ok()).await;
+        assert!(matches!(result, Err(GpuWorkerError::InvalidInput(_))));
+    }
+}
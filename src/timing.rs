@@ -0,0 +1,780 @@
+//! Animation timing operations.
+//!
+//! Timing operations never touch pixels. They work on a [`Timeline`]: the ordered list of
+//! source frames to show and how long to show each one for. Speed and delay clamping only
+//! rewrite delays and can be applied while frames stream through the pipeline; reversing,
+//! ping-pong and frame rate resampling reorder or duplicate frames and need the whole animation
+//! decoded first.
+//!
+//! Trimming to a frame or time range is also handled here, before any other timing operation.
+//!
+//! GIF delays are stored in hundredths of a second. The HTTP API takes delays in milliseconds and
+//! rounds them to the nearest representable value.
+
+use gif::Frame;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{GpuWorkerError, Result};
+
+/// Browsers replace delays below this (in hundredths of a second) with a much slower default,
+/// so speeding an animation up must not push delays under it.
+pub const MIN_BROWSER_DELAY: u16 = 2;
+
+/// Highest frame rate a GIF can express: one frame per hundredth of a second.
+pub const MAX_FPS: f32 = 100.0;
+
+/// One entry of the output animation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimelineEntry {
+    /// Index of the decoded frame to show.
+    pub source: usize,
+    /// How long to show it, in hundredths of a second.
+    pub delay: u16,
+}
+
+/// Ordered list of frames making up an animation.
+pub type Timeline = Vec<TimelineEntry>;
+
+/// Builds the identity timeline for frames with the given delays.
+pub fn timeline(delays: &[u16]) -> Timeline {
+    delays
+        .iter()
+        .enumerate()
+        .map(|(source, &delay)| TimelineEntry { source, delay })
+        .collect()
+}
+
+/// Divides every delay by `factor`, so `2.0` plays twice as fast.
+///
+/// Non-zero delays are kept at or above [`MIN_BROWSER_DELAY`] when speeding up.
+pub fn scale(timeline: &mut Timeline, factor: f32) {
+    for entry in timeline.iter_mut() {
+        if entry.delay == 0 {
+            continue;
+        }
+        let scaled = (entry.delay as f32 / factor).round();
+        let floor = if factor > 1.0 {
+            MIN_BROWSER_DELAY.min(entry.delay)
+        } else {
+            1
+        };
+        entry.delay = (scaled.min(u16::MAX as f32) as u16).max(floor);
+    }
+}
+
+/// Clamps every delay into `min..=max`.
+pub fn clamp(timeline: &mut Timeline, min: Option<u16>, max: Option<u16>) {
+    for entry in timeline.iter_mut() {
+        if let Some(min) = min {
+            entry.delay = entry.delay.max(min);
+        }
+        if let Some(max) = max {
+            entry.delay = entry.delay.min(max);
+        }
+    }
+}
+
+/// Plays the animation backwards. Each frame keeps its own delay.
+pub fn reverse(timeline: &mut Timeline) {
+    timeline.reverse();
+}
+
+/// Plays the animation forwards and then backwards.
+///
+/// The first and last frames are not repeated at the turning points, so the result loops
+/// smoothly: `0 1 2 3` becomes `0 1 2 3 2 1`.
+pub fn ping_pong(timeline: &mut Timeline) {
+    if timeline.len() < 3 {
+        return;
+    }
+    let backwards: Vec<_> = timeline[1..timeline.len() - 1]
+        .iter()
+        .rev()
+        .copied()
+        .collect();
+    timeline.extend(backwards);
+}
+
+/// Resamples the animation to a constant frame rate.
+///
+/// Output frame `k` shows whichever source frame is visible at time `k / fps`, so frames are
+/// dropped when the target rate is lower than the source and duplicated when it is higher. The
+/// total duration is preserved up to rounding. Delays are derived from cumulative timestamps so
+/// rounding errors do not accumulate.
+pub fn resample(timeline: &Timeline, fps: f32) -> Timeline {
+    let interval = 100.0 / fps;
+    let total: u32 = timeline.iter().map(|entry| entry.delay as u32).sum();
+
+    if total == 0 {
+        let delay = interval.round().max(1.0) as u16;
+        return timeline
+            .iter()
+            .map(|entry| TimelineEntry { delay, ..*entry })
+            .collect();
+    }
+
+    let count = ((total as f32 / interval).round() as usize).max(1);
+    let mut resampled = Vec::with_capacity(count);
+    let mut current = 0;
+    let mut current_end = timeline[0].delay as u32;
+
+    for k in 0..count {
+        let start = k as f32 * interval;
+        while current + 1 < timeline.len() && start >= current_end as f32 {
+            current += 1;
+            current_end += timeline[current].delay as u32;
+        }
+
+        let end = if k + 1 == count {
+            total as f32
+        } else {
+            (k + 1) as f32 * interval
+        };
+        let delay = (end.round() - start.round()).max(1.0) as u16;
+        resampled.push(TimelineEntry {
+            source: timeline[current].source,
+            delay,
+        });
+    }
+
+    resampled
+}
+
+/// Timing options accepted by the GIF endpoints as query parameters.
+///
+/// When several options are given they are applied in this order: reverse, ping-pong, speed,
+/// delay clamping, frame rate resampling.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TimingOptions {
+    /// Playback speed factor; `2.0` is twice as fast, `0.5` half as fast.
+    pub speed: Option<f32>,
+    /// Minimum frame delay in milliseconds.
+    pub min_delay: Option<u32>,
+    /// Maximum frame delay in milliseconds.
+    pub max_delay: Option<u32>,
+    /// Play the animation backwards.
+    #[serde(default)]
+    pub reverse: bool,
+    /// Play the animation forwards, then backwards.
+    #[serde(default)]
+    pub ping_pong: bool,
+    /// Resample to a constant frame rate, dropping or duplicating frames.
+    pub fps: Option<f32>,
+}
+
+impl TimingOptions {
+    /// Checks that every option is within range.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(speed) = self.speed {
+            if !speed.is_finite() || speed <= 0.0 {
+                return Err(GpuWorkerError::InvalidInput(format!(
+                    "speed must be a positive number, got {}",
+                    speed
+                )));
+            }
+        }
+
+        if let Some(fps) = self.fps {
+            if !fps.is_finite() || fps <= 0.0 || fps > MAX_FPS {
+                return Err(GpuWorkerError::InvalidInput(format!(
+                    "fps must be greater than 0 and at most {}, got {}",
+                    MAX_FPS, fps
+                )));
+            }
+        }
+
+        for (name, delay) in [("min_delay", self.min_delay), ("max_delay", self.max_delay)] {
+            if delay.is_some_and(|delay| delay > u16::MAX as u32 * 10) {
+                return Err(GpuWorkerError::InvalidInput(format!(
+                    "{} must be at most {} ms",
+                    name,
+                    u16::MAX as u32 * 10
+                )));
+            }
+        }
+
+        if let (Some(min), Some(max)) = (self.min_delay, self.max_delay) {
+            if min > max {
+                return Err(GpuWorkerError::InvalidInput(format!(
+                    "min_delay ({} ms) is greater than max_delay ({} ms)",
+                    min, max
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the options leave the animation untouched.
+    pub fn is_identity(&self) -> bool {
+        self.speed.is_none()
+            && self.min_delay.is_none()
+            && self.max_delay.is_none()
+            && !self.needs_all_frames()
+    }
+
+    /// Returns `true` if frames are reordered, dropped or duplicated, which requires decoding
+    /// the whole animation before the first output frame can be produced.
+    pub fn needs_all_frames(&self) -> bool {
+        self.reverse || self.ping_pong || self.fps.is_some()
+    }
+
+    /// Applies the options to a timeline.
+    pub fn apply(&self, mut timeline: Timeline) -> Timeline {
+        if self.reverse {
+            reverse(&mut timeline);
+        }
+        if self.ping_pong {
+            ping_pong(&mut timeline);
+        }
+        if let Some(speed) = self.speed {
+            scale(&mut timeline, speed);
+        }
+        if self.min_delay.is_some() || self.max_delay.is_some() {
+            clamp(
+                &mut timeline,
+                self.min_delay.map(ms_to_delay),
+                self.max_delay.map(ms_to_delay),
+            );
+        }
+        if let Some(fps) = self.fps {
+            timeline = resample(&timeline, fps);
+        }
+        timeline
+    }
+
+    /// Applies the options to a stream of decoded frames.
+    ///
+    /// Delay-only options are applied frame by frame. Options that reorder frames collect the
+    /// whole animation first; frames used once are moved and only duplicates are copied.
+    pub fn apply_to_frames<I>(&self, frames: I) -> Result<TimedFrames<I>>
+    where
+        I: Iterator<Item = Result<Frame<'static>>>,
+    {
+        if !self.needs_all_frames() {
+            return Ok(TimedFrames::Streaming {
+                frames,
+                options: self.clone(),
+            });
+        }
+
+        let mut decoded = frames
+            .map(|frame| frame.map(Some))
+            .collect::<Result<Vec<_>>>()?;
+        let delays: Vec<u16> = decoded
+            .iter()
+            .map(|frame| frame.as_ref().map_or(0, |frame| frame.delay))
+            .collect();
+        let timeline = self.apply(timeline(&delays));
+
+        let mut remaining_uses = vec![0usize; decoded.len()];
+        for entry in &timeline {
+            remaining_uses[entry.source] += 1;
+        }
+
+        let frames: Vec<_> = timeline
+            .into_iter()
+            .map(|entry| {
+                remaining_uses[entry.source] -= 1;
+                let mut frame = if remaining_uses[entry.source] == 0 {
+                    decoded[entry.source].take()
+                } else {
+                    decoded[entry.source].clone()
+                }
+                .expect("timeline refers to a frame that was already used");
+                frame.delay = entry.delay;
+                Ok(frame)
+            })
+            .collect();
+
+        Ok(TimedFrames::Buffered(frames.into_iter()))
+    }
+}
+
+/// Frames with timing options applied, see [`TimingOptions::apply_to_frames`].
+pub enum TimedFrames<I> {
+    Streaming { frames: I, options: TimingOptions },
+    Buffered(std::vec::IntoIter<Result<Frame<'static>>>),
+}
+
+impl<I> Iterator for TimedFrames<I>
+where
+    I: Iterator<Item = Result<Frame<'static>>>,
+{
+    type Item = Result<Frame<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Streaming { frames, options } => Some(frames.next()?.map(|mut frame| {
+                let entry = TimelineEntry {
+                    source: 0,
+                    delay: frame.delay,
+                };
+                frame.delay = options.apply(vec![entry])[0].delay;
+                frame
+            })),
+            Self::Buffered(frames) => frames.next(),
+        }
+    }
+}
+
+/// Trim options accepted by the GIF endpoints as query parameters.
+///
+/// A trim selects either a frame range (`start_frame`/`end_frame`, zero-based, end exclusive) or
+/// a time range in milliseconds (`start_ms`/`end_ms`) computed from frame delays. Frames before
+/// the start are still decoded and composited, so the first kept frame is complete. An end past
+/// the last frame is clamped to the end of the animation.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrimOptions {
+    /// First frame to keep.
+    pub start_frame: Option<usize>,
+    /// First frame to drop after the kept range.
+    pub end_frame: Option<usize>,
+    /// Start of the kept range in milliseconds.
+    pub start_ms: Option<u32>,
+    /// End of the kept range in milliseconds.
+    pub end_ms: Option<u32>,
+}
+
+impl TrimOptions {
+    /// Checks that the options describe a single, non-empty range.
+    pub fn validate(&self) -> Result<()> {
+        let by_frame = self.start_frame.is_some() || self.end_frame.is_some();
+        let by_time = self.start_ms.is_some() || self.end_ms.is_some();
+        if by_frame && by_time {
+            return Err(GpuWorkerError::InvalidInput(
+                "Trim by frame range or by time range, not both".to_string(),
+            ));
+        }
+
+        if let (Some(start), Some(end)) = (self.start_frame, self.end_frame) {
+            if start >= end {
+                return Err(GpuWorkerError::InvalidInput(format!(
+                    "end_frame ({}) must be greater than start_frame ({})",
+                    end, start
+                )));
+            }
+        }
+
+        if let (Some(start), Some(end)) = (self.start_ms, self.end_ms) {
+            if start >= end {
+                return Err(GpuWorkerError::InvalidInput(format!(
+                    "end_ms ({}) must be greater than start_ms ({})",
+                    end, start
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if no trim was requested.
+    pub fn is_identity(&self) -> bool {
+        self.start_frame.is_none()
+            && self.end_frame.is_none()
+            && self.start_ms.is_none()
+            && self.end_ms.is_none()
+    }
+
+    /// Applies the trim to a stream of decoded frames.
+    ///
+    /// Decoding stops as soon as the end of the range is reached. If the stream ends before the
+    /// start of the range, the iterator yields an [`GpuWorkerError::InvalidInput`] error.
+    pub fn apply_to_frames<I>(&self, frames: I) -> Trimmed<I>
+    where
+        I: Iterator<Item = Result<Frame<'static>>>,
+    {
+        Trimmed {
+            frames,
+            options: self.clone(),
+            index: 0,
+            elapsed_ms: 0,
+            kept: 0,
+            done: false,
+        }
+    }
+
+    /// Decides whether the frame at `index`, shown from `start_ms` for `delay_ms`, is kept,
+    /// and for how long it is shown if so.
+    fn keep(&self, index: usize, start_ms: u32, delay_ms: u32) -> Keep {
+        if self.start_frame.is_some() || self.end_frame.is_some() {
+            return if self.end_frame.is_some_and(|end| index >= end) {
+                Keep::Done
+            } else if self.start_frame.is_some_and(|start| index < start) {
+                Keep::Skip
+            } else {
+                Keep::Frame(None)
+            };
+        }
+
+        let range_start = self.start_ms.unwrap_or(0);
+        let range_end = self.end_ms.unwrap_or(u32::MAX);
+        let end_ms = start_ms.saturating_add(delay_ms);
+
+        if start_ms >= range_end {
+            Keep::Done
+        } else if end_ms <= range_start && !(delay_ms == 0 && start_ms >= range_start) {
+            Keep::Skip
+        } else if delay_ms == 0 {
+            Keep::Frame(None)
+        } else {
+            let visible = end_ms.min(range_end) - start_ms.max(range_start);
+            Keep::Frame(Some(visible))
+        }
+    }
+
+    fn out_of_range(&self, frame_count: usize, duration_ms: u32) -> GpuWorkerError {
+        match self.start_frame {
+            Some(start) => GpuWorkerError::InvalidInput(format!(
+                "start_frame {} is out of range, the GIF has {} frames",
+                start, frame_count
+            )),
+            None => GpuWorkerError::InvalidInput(format!(
+                "start_ms {} is past the end of the animation ({} ms)",
+                self.start_ms.unwrap_or(0),
+                duration_ms
+            )),
+        }
+    }
+}
+
+enum Keep {
+    Skip,
+    /// Keep the frame, optionally shortening it to the given number of milliseconds.
+    Frame(Option<u32>),
+    Done,
+}
+
+/// Frames with a trim applied, see [`TrimOptions::apply_to_frames`].
+pub struct Trimmed<I> {
+    frames: I,
+    options: TrimOptions,
+    index: usize,
+    elapsed_ms: u32,
+    kept: usize,
+    done: bool,
+}
+
+impl<I> Iterator for Trimmed<I>
+where
+    I: Iterator<Item = Result<Frame<'static>>>,
+{
+    type Item = Result<Frame<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let mut frame = match self.frames.next() {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.done = true;
+                    if self.kept == 0 {
+                        return Some(Err(self.options.out_of_range(self.index, self.elapsed_ms)));
+                    }
+                    return None;
+                }
+            };
+
+            let delay_ms = frame.delay as u32 * 10;
+            let keep = self.options.keep(self.index, self.elapsed_ms, delay_ms);
+            self.index += 1;
+            self.elapsed_ms = self.elapsed_ms.saturating_add(delay_ms);
+
+            match keep {
+                Keep::Skip => continue,
+                Keep::Done => {
+                    self.done = true;
+                    if self.kept == 0 {
+                        return Some(Err(self.options.out_of_range(self.index, self.elapsed_ms)));
+                    }
+                }
+                Keep::Frame(visible_ms) => {
+                    if let Some(visible_ms) = visible_ms {
+                        frame.delay = ms_to_delay(visible_ms).max(1);
+                    }
+                    self.kept += 1;
+                    return Some(Ok(frame));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Converts milliseconds to the nearest GIF delay.
+pub(crate) fn ms_to_delay(ms: u32) -> u16 {
+    ((ms + 5) / 10).min(u16::MAX as u32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(timeline: &Timeline) -> Vec<usize> {
+        timeline.iter().map(|entry| entry.source).collect()
+    }
+
+    fn delays(timeline: &Timeline) -> Vec<u16> {
+        timeline.iter().map(|entry| entry.delay).collect()
+    }
+
+    fn frames_with_delays(delays: &[u16]) -> impl Iterator<Item = Result<Frame<'static>>> {
+        delays
+            .iter()
+            .enumerate()
+            .map(|(index, &delay)| {
+                Ok(Frame {
+                    delay,
+                    buffer: vec![index as u8].into(),
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn trim(options: TrimOptions, delays: &[u16]) -> Result<Vec<(u8, u16)>> {
+        options
+            .apply_to_frames(frames_with_delays(delays))
+            .map(|frame| frame.map(|frame| (frame.buffer[0], frame.delay)))
+            .collect()
+    }
+
+    #[test]
+    fn test_scale_speeds_up_and_slows_down() {
+        let mut fast = timeline(&[10, 20, 0]);
+        scale(&mut fast, 2.0);
+        assert_eq!(delays(&fast), vec![5, 10, 0]);
+
+        let mut slow = timeline(&[10, 20]);
+        scale(&mut slow, 0.5);
+        assert_eq!(delays(&slow), vec![20, 40]);
+    }
+
+    #[test]
+    fn test_scale_respects_browser_minimum() {
+        let mut fast = timeline(&[4, 1]);
+        scale(&mut fast, 10.0);
+        assert_eq!(delays(&fast), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_clamp_delays() {
+        let mut frames = timeline(&[1, 5, 50]);
+        clamp(&mut frames, Some(2), Some(10));
+        assert_eq!(delays(&frames), vec![2, 5, 10]);
+    }
+
+    #[test]
+    fn test_reverse_keeps_delays_with_frames() {
+        let mut frames = timeline(&[1, 2, 3]);
+        reverse(&mut frames);
+        assert_eq!(sources(&frames), vec![2, 1, 0]);
+        assert_eq!(delays(&frames), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_ping_pong_skips_end_frames() {
+        let mut frames = timeline(&[1, 1, 1, 1]);
+        ping_pong(&mut frames);
+        assert_eq!(sources(&frames), vec![0, 1, 2, 3, 2, 1]);
+
+        let mut short = timeline(&[1, 1]);
+        ping_pong(&mut short);
+        assert_eq!(sources(&short), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_resample_drops_frames() {
+        // 10 frames at 100 fps resampled to 50 fps keeps every other frame
+        let frames = timeline(&[1; 10]);
+        let resampled = resample(&frames, 50.0);
+        assert_eq!(sources(&resampled), vec![0, 2, 4, 6, 8]);
+        assert_eq!(delays(&resampled), vec![2; 5]);
+    }
+
+    #[test]
+    fn test_resample_duplicates_frames() {
+        let frames = timeline(&[20, 20]);
+        let resampled = resample(&frames, 25.0);
+        assert_eq!(sources(&resampled), vec![0, 0, 0, 0, 0, 1, 1, 1, 1, 1]);
+        assert_eq!(resampled.iter().map(|e| e.delay as u32).sum::<u32>(), 40);
+    }
+
+    #[test]
+    fn test_resample_preserves_duration_with_uneven_interval() {
+        let frames = timeline(&[10, 10, 10]);
+        let resampled = resample(&frames, 30.0);
+        assert_eq!(resampled.len(), 9);
+        assert_eq!(resampled.iter().map(|e| e.delay as u32).sum::<u32>(), 30);
+    }
+
+    #[test]
+    fn test_options_validation() {
+        let valid = TimingOptions {
+            speed: Some(1.5),
+            min_delay: Some(20),
+            max_delay: Some(100),
+            fps: Some(25.0),
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+
+        for invalid in [
+            TimingOptions {
+                speed: Some(0.0),
+                ..Default::default()
+            },
+            TimingOptions {
+                fps: Some(120.0),
+                ..Default::default()
+            },
+            TimingOptions {
+                min_delay: Some(200),
+                max_delay: Some(100),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                invalid.validate(),
+                Err(GpuWorkerError::InvalidInput(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_options_apply_in_order() {
+        let options = TimingOptions {
+            reverse: true,
+            ping_pong: true,
+            speed: Some(2.0),
+            min_delay: Some(30),
+            ..Default::default()
+        };
+        assert!(options.needs_all_frames());
+
+        let result = options.apply(timeline(&[4, 8, 12]));
+        assert_eq!(sources(&result), vec![2, 1, 0, 1]);
+        assert_eq!(delays(&result), vec![6, 4, 3, 4]);
+    }
+
+    #[test]
+    fn test_apply_to_frames_buffers_reordering_options() {
+        let frames = (0..3u16).map(|index| {
+            Ok(Frame {
+                delay: index + 1,
+                buffer: vec![index as u8; 4].into(),
+                ..Default::default()
+            })
+        });
+        let options = TimingOptions {
+            ping_pong: true,
+            ..Default::default()
+        };
+
+        let output: Vec<_> = options
+            .apply_to_frames(frames)
+            .unwrap()
+            .map(|frame| frame.unwrap())
+            .collect();
+        let buffers: Vec<u8> = output.iter().map(|frame| frame.buffer[0]).collect();
+        assert_eq!(buffers, vec![0, 1, 2, 1]);
+    }
+
+    #[test]
+    fn test_apply_to_frames_streams_delay_options() {
+        let frames = vec![Ok(Frame {
+            delay: 10,
+            ..Default::default()
+        })];
+        let options = TimingOptions {
+            speed: Some(2.0),
+            ..Default::default()
+        };
+
+        let output: Vec<_> = options
+            .apply_to_frames(frames.into_iter())
+            .unwrap()
+            .map(|frame| frame.unwrap().delay)
+            .collect();
+        assert_eq!(output, vec![5]);
+    }
+
+    #[test]
+    fn test_trim_by_frame_range() {
+        let options = TrimOptions {
+            start_frame: Some(1),
+            end_frame: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            trim(options, &[10, 10, 10, 10]).unwrap(),
+            vec![(1, 10), (2, 10)]
+        );
+
+        let open_ended = TrimOptions {
+            start_frame: Some(2),
+            end_frame: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(trim(open_ended, &[10, 10, 10]).unwrap(), vec![(2, 10)]);
+    }
+
+    #[test]
+    fn test_trim_by_time_range_shortens_partial_frames() {
+        // Frames span 0-100, 100-200, 200-300 and 300-400 ms
+        let options = TrimOptions {
+            start_ms: Some(150),
+            end_ms: Some(320),
+            ..Default::default()
+        };
+        assert_eq!(
+            trim(options, &[10, 10, 10, 10]).unwrap(),
+            vec![(1, 5), (2, 10), (3, 2)]
+        );
+    }
+
+    #[test]
+    fn test_trim_out_of_range() {
+        let by_frame = TrimOptions {
+            start_frame: Some(4),
+            ..Default::default()
+        };
+        assert!(matches!(
+            trim(by_frame, &[10, 10]),
+            Err(GpuWorkerError::InvalidInput(_))
+        ));
+
+        let by_time = TrimOptions {
+            start_ms: Some(500),
+            ..Default::default()
+        };
+        assert!(matches!(
+            trim(by_time, &[10, 10]),
+            Err(GpuWorkerError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_trim_validation() {
+        let mixed = TrimOptions {
+            start_frame: Some(1),
+            end_ms: Some(100),
+            ..Default::default()
+        };
+        assert!(mixed.validate().is_err());
+
+        let empty = TrimOptions {
+            start_frame: Some(3),
+            end_frame: Some(3),
+            ..Default::default()
+        };
+        assert!(empty.validate().is_err());
+
+        assert!(TrimOptions::default().is_identity());
+    }
+}
@@ -1,6 +1,19 @@
 use actix_web::{test, web, App};
-use gpu_worker::handlers::mirror_gif;
-use transformations::MirrorProcessor;
+use gpu_worker::{
+    auth::{hash_key, ApiKeyEntry, ApiKeys, RequireScope, Scope},
+    cache::{CacheConfig, ResultCache},
+    handlers::{
+        assemble_gif, blur_gif, cancel_job, contact_sheet, extract_frame, gpu_queue, inspect_gif,
+        job_events, job_result, job_status, mirror_gif, retime_gif, submit_job, transform,
+        transform_job_runner,
+    },
+    jobs::{JobContext, JobQueue, JobQueueConfig},
+    limits::DecodeLimits,
+    ops,
+    pipeline::{decode_gif, QuantizePool},
+    scheduler::{GpuScheduler, GpuSchedulerConfig, Requester},
+};
+use transformations::{BlurProcessor, MirrorProcessor, ResizeProcessor};
 
 async fn health_check() -> actix_web::Result<impl actix_web::Responder> {
     Ok(web::Json(serde_json::json!({
@@ -9,6 +22,42 @@ async fn health_check() -> actix_web::Result<impl actix_web::Responder> {
     })))
 }
 
+/// GPU processors shared by every test
+///
+/// They are created once and never dropped: on GLES backends, dropping a wgpu device can
+/// terminate the EGL display that the devices of concurrently running tests share.
+struct Processors {
+    mirror: web::Data<MirrorProcessor>,
+    blur: web::Data<BlurProcessor>,
+    resize: web::Data<ResizeProcessor>,
+}
+
+fn processors() -> &'static Processors {
+    static PROCESSORS: std::sync::OnceLock<Processors> = std::sync::OnceLock::new();
+
+    PROCESSORS.get_or_init(|| {
+        pollster::block_on(async {
+            Processors {
+                mirror: web::Data::new(
+                    MirrorProcessor::new()
+                        .await
+                        .expect("Failed to create MirrorProcessor"),
+                ),
+                blur: web::Data::new(
+                    BlurProcessor::new()
+                        .await
+                        .expect("Failed to create BlurProcessor"),
+                ),
+                resize: web::Data::new(
+                    ResizeProcessor::new()
+                        .await
+                        .expect("Failed to create ResizeProcessor"),
+                ),
+            }
+        })
+    })
+}
+
 fn create_test_gif() -> Vec<u8> {
     // Create a minimal valid 1x1 GIF
     // This is a complete, valid GIF file with proper LZW encoding
@@ -41,15 +90,33 @@ fn create_test_gif() -> Vec<u8> {
     ]
 }
 
+fn create_animated_gif(width: u16, height: u16, frame_count: u8) -> Vec<u8> {
+    let mut output = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut output, width, height, &[]).unwrap();
+        encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+        for index in 0..frame_count {
+            let mut pixels = Vec::with_capacity(width as usize * height as usize * 3);
+            for y in 0..height {
+                for x in 0..width {
+                    pixels.extend_from_slice(&[(x as u8).wrapping_mul(index + 1), y as u8, index]);
+                }
+            }
+            let mut frame = gif::Frame::from_rgb(width, height, &pixels);
+            frame.delay = 5;
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    output
+}
+
 #[actix_web::test]
 async fn test_health_endpoint() {
-    let mirror_processor = MirrorProcessor::new()
-        .await
-        .expect("Failed to create MirrorProcessor");
+    let mirror_processor = processors().mirror.clone();
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(mirror_processor))
+            .app_data(mirror_processor)
             .route("/health", web::get().to(health_check))
             .route("/mirror-gif", web::post().to(mirror_gif)),
     )
@@ -67,13 +134,11 @@ async fn test_health_endpoint() {
 
 #[actix_web::test]
 async fn test_mirror_gif_success() {
-    let mirror_processor = MirrorProcessor::new()
-        .await
-        .expect("Failed to create MirrorProcessor");
+    let mirror_processor = processors().mirror.clone();
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(mirror_processor))
+            .app_data(mirror_processor)
             .route("/health", web::get().to(health_check))
             .route("/mirror-gif", web::post().to(mirror_gif)),
     )
@@ -106,25 +171,26 @@ async fn test_mirror_gif_success() {
 }
 
 #[actix_web::test]
-async fn test_mirror_gif_no_file() {
-    let mirror_processor = MirrorProcessor::new()
-        .await
-        .expect("Failed to create MirrorProcessor");
+async fn test_mirror_gif_streams_animated_gif() {
+    let mirror_processor = processors().mirror.clone();
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(mirror_processor))
-            .route("/health", web::get().to(health_check))
+            .app_data(mirror_processor)
             .route("/mirror-gif", web::post().to(mirror_gif)),
     )
     .await;
 
+    let gif_data = create_animated_gif(200, 150, 4);
     let boundary = "----boundary----";
 
     let mut data = Vec::new();
     data.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
-    data.extend_from_slice(b"Content-Disposition: form-data; name=\"other\"\r\n\r\n");
-    data.extend_from_slice(b"some data");
+    data.extend_from_slice(
+        b"Content-Disposition: form-data; name=\"file\"; filename=\"animated.gif\"\r\n",
+    );
+    data.extend_from_slice(b"Content-Type: image/gif\r\n\r\n");
+    data.extend_from_slice(&gif_data);
     data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
 
     let req = test::TestRequest::post()
@@ -137,74 +203,459 @@ async fn test_mirror_gif_no_file() {
         .to_request();
 
     let resp = test::call_service(&app, req).await;
-    assert_eq!(resp.status(), 400);
+    assert!(resp.status().is_success());
 
-    let body: serde_json::Value = test::read_body_json(resp).await;
-    assert_eq!(body["error"], "invalid_request");
+    let body = test::read_body(resp).await;
+    let mut decoder = gif::DecodeOptions::new()
+        .read_info(body.as_ref())
+        .expect("Response should be a valid GIF");
+    assert_eq!((decoder.width(), decoder.height()), (200, 150));
+
+    let mut frame_count = 0;
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        assert_eq!(frame.delay, 5);
+        frame_count += 1;
+    }
+    assert_eq!(frame_count, 4);
+}
+
+fn multipart_gif_body(boundary: &str, gif_data: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+    data.extend_from_slice(
+        b"Content-Disposition: form-data; name=\"file\"; filename=\"test.gif\"\r\n",
+    );
+    data.extend_from_slice(b"Content-Type: image/gif\r\n\r\n");
+    data.extend_from_slice(gif_data);
+    data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    data
+}
+
+fn read_gif_delays(data: &[u8]) -> Vec<u16> {
+    let mut decoder = gif::DecodeOptions::new()
+        .read_info(data)
+        .expect("Response should be a valid GIF");
+    let mut delays = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        delays.push(frame.delay);
+    }
+    delays
 }
 
 #[actix_web::test]
-async fn test_mirror_gif_empty_file() {
-    let mirror_processor = MirrorProcessor::new()
-        .await
-        .expect("Failed to create MirrorProcessor");
+async fn test_retime_gif_ping_pong_and_speed() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/retime-gif?ping_pong=true&speed=0.5")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(boundary, &create_animated_gif(8, 8, 4)))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let body = test::read_body(resp).await;
+    assert_eq!(read_gif_delays(&body), vec![10; 6]);
+}
+
+#[actix_web::test]
+async fn test_mirror_gif_with_timing_options() {
+    let mirror_processor = processors().mirror.clone();
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(mirror_processor))
-            .route("/health", web::get().to(health_check))
+            .app_data(mirror_processor)
             .route("/mirror-gif", web::post().to(mirror_gif)),
     )
     .await;
 
     let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/mirror-gif?reverse=true&fps=10")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(boundary, &create_animated_gif(8, 8, 4)))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    // Four frames of 50ms at 10 fps become two frames of 100ms
+    let body = test::read_body(resp).await;
+    assert_eq!(read_gif_delays(&body), vec![10, 10]);
+}
+
+#[actix_web::test]
+async fn test_retime_gif_trim_time_range() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    // Four 50ms frames; keep 75ms..175ms
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/retime-gif?start_ms=75&end_ms=175")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(boundary, &create_animated_gif(8, 8, 4)))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let body = test::read_body(resp).await;
+    assert_eq!(read_gif_delays(&body), vec![3, 5, 3]);
+}
+
+#[actix_web::test]
+async fn test_retime_gif_trim_out_of_range() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    for query in [
+        "start_frame=4",
+        "start_ms=1000",
+        "start_frame=2&end_frame=1",
+    ] {
+        let boundary = "----boundary----";
+        let req = test::TestRequest::post()
+            .uri(&format!("/retime-gif?{}", query))
+            .insert_header((
+                "content-type",
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_gif_body(boundary, &create_animated_gif(8, 8, 4)))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400, "query {} should be rejected", query);
+    }
+}
+
+#[actix_web::test]
+async fn test_retime_gif_invalid_options() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    for query in [
+        "speed=0",
+        "fps=500",
+        "min_delay=100&max_delay=10",
+        "reverse=maybe",
+    ] {
+        let boundary = "----boundary----";
+        let req = test::TestRequest::post()
+            .uri(&format!("/retime-gif?{}", query))
+            .insert_header((
+                "content-type",
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_gif_body(boundary, &create_animated_gif(8, 8, 2)))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400, "query {} should be rejected", query);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_request");
+    }
+}
+
+#[actix_web::test]
+async fn test_extract_frame_as_png() {
+    let app =
+        test::init_service(App::new().route("/extract-frame", web::post().to(extract_frame))).await;
+
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/extract-frame?index=2")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(boundary, &create_animated_gif(6, 4, 3)))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+
+    let body = test::read_body(resp).await;
+    let image = image::load_from_memory_with_format(&body, image::ImageFormat::Png).unwrap();
+    assert_eq!((image.width(), image.height()), (6, 4));
+}
+
+#[actix_web::test]
+async fn test_extract_frame_out_of_range() {
+    let app =
+        test::init_service(App::new().route("/extract-frame", web::post().to(extract_frame))).await;
+
+    for query in ["index=3", "time_ms=150", "index=0&time_ms=0"] {
+        let boundary = "----boundary----";
+        let req = test::TestRequest::post()
+            .uri(&format!("/extract-frame?{}", query))
+            .insert_header((
+                "content-type",
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_gif_body(boundary, &create_animated_gif(6, 4, 3)))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400, "query {} should be rejected", query);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_request");
+    }
+}
+
+#[actix_web::test]
+async fn test_contact_sheet_as_png() {
+    let app =
+        test::init_service(App::new().route("/contact-sheet", web::post().to(contact_sheet))).await;
+
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/contact-sheet?frames=4&columns=2&thumb_width=20&labels=true")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(boundary, &create_animated_gif(10, 5, 8)))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+
+    // Two rows of two 20x10 thumbnails with 4px gaps
+    let body = test::read_body(resp).await;
+    let image = image::load_from_memory_with_format(&body, image::ImageFormat::Png).unwrap();
+    assert_eq!((image.width(), image.height()), (52, 32));
+}
+
+#[actix_web::test]
+async fn test_inspect_gif_metadata() {
+    let app = test::init_service(App::new().route("/inspect", web::post().to(inspect_gif))).await;
+
+    let gif_data = create_animated_gif(12, 8, 3);
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/inspect")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(boundary, &gif_data))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let info: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(info["width"], 12);
+    assert_eq!(info["height"], 8);
+    assert_eq!(info["frame_count"], 3);
+    assert_eq!(info["total_duration_ms"], 150);
+    assert_eq!(info["truncated"], false);
+    assert_eq!(info["frames"].as_array().unwrap().len(), 3);
+    assert_eq!(info["frames"][0]["delay_ms"], 50);
+    assert_eq!(info["frames"][0]["width"], 12);
+}
+
+#[actix_web::test]
+async fn test_inspect_truncated_and_invalid_uploads() {
+    let app = test::init_service(App::new().route("/inspect", web::post().to(inspect_gif))).await;
+
+    let gif_data = create_animated_gif(12, 8, 3);
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/inspect")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(
+            boundary,
+            &gif_data[..gif_data.len() - 10],
+        ))
+        .to_request();
 
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    let info: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(info["truncated"], true);
+
+    let req = test::TestRequest::post()
+        .uri("/inspect")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(boundary, b"definitely not a gif"))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 415);
+}
+
+fn create_png(width: u32, height: u32, color: [u8; 4]) -> Vec<u8> {
+    let image = image::RgbaImage::from_pixel(width, height, image::Rgba(color));
+    let mut output = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut output, image::ImageOutputFormat::Png)
+        .unwrap();
+    output.into_inner()
+}
+
+/// A multipart part as `(name, filename, data)`; parts without a filename are text fields
+type Part<'a> = (&'a str, Option<&'a str>, &'a [u8]);
+
+fn multipart_body(boundary: &str, parts: &[Part]) -> Vec<u8> {
     let mut data = Vec::new();
-    data.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
-    data.extend_from_slice(
-        b"Content-Disposition: form-data; name=\"file\"; filename=\"empty.gif\"\r\n",
+    for (name, filename, content) in parts {
+        data.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        match filename {
+            Some(filename) => data.extend_from_slice(
+                format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+                     Content-Type: application/octet-stream\r\n\r\n",
+                    name, filename
+                )
+                .as_bytes(),
+            ),
+            None => data.extend_from_slice(
+                format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name).as_bytes(),
+            ),
+        }
+        data.extend_from_slice(content);
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    data
+}
+
+#[actix_web::test]
+async fn test_assemble_gif_from_images() {
+    let resize_processor = processors().resize.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(resize_processor)
+            .route("/assemble-gif", web::post().to(assemble_gif)),
+    )
+    .await;
+
+    let red = create_png(16, 8, [255, 0, 0, 255]);
+    let green = create_png(4, 4, [0, 255, 0, 255]);
+    let blue = create_png(8, 8, [0, 0, 255, 255]);
+    let boundary = "----boundary----";
+    let body = multipart_body(
+        boundary,
+        &[
+            ("file", Some("red.png"), &red),
+            ("file", Some("green.png"), &green),
+            ("file", Some("blue.png"), &blue),
+            ("delays", None, b"100,200,300"),
+            ("fit", None, b"fill"),
+        ],
     );
-    data.extend_from_slice(b"Content-Type: image/gif\r\n\r\n");
-    // No file data
-    data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
 
     let req = test::TestRequest::post()
-        .uri("/mirror-gif")
+        .uri("/assemble-gif?width=8&height=8&loop_count=2")
         .insert_header((
             "content-type",
             format!("multipart/form-data; boundary={}", boundary),
         ))
-        .set_payload(data)
+        .set_payload(body)
         .to_request();
 
     let resp = test::call_service(&app, req).await;
-    assert_eq!(resp.status(), 400);
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/gif");
+
+    let body = test::read_body(resp).await;
+    let decoder = gif::DecodeOptions::new().read_info(&body[..]).unwrap();
+    assert_eq!((decoder.width(), decoder.height()), (8, 8));
+    assert_eq!(read_gif_delays(&body), vec![10, 20, 30]);
+
+    // NETSCAPE2.0 application extension carrying the loop count
+    let netscape = body
+        .windows(11)
+        .position(|window| window == b"NETSCAPE2.0")
+        .expect("GIF should have a loop count");
+    assert_eq!(&body[netscape + 11..netscape + 15], &[3, 1, 2, 0]);
 }
 
 #[actix_web::test]
-async fn test_mirror_gif_invalid_gif() {
-    let mirror_processor = MirrorProcessor::new()
-        .await
-        .expect("Failed to create MirrorProcessor");
+async fn test_assemble_gif_invalid_requests() {
+    let resize_processor = processors().resize.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(resize_processor)
+            .route("/assemble-gif", web::post().to(assemble_gif)),
+    )
+    .await;
+
+    let image = create_png(4, 4, [0, 0, 0, 255]);
+    let cases: Vec<(&str, Vec<Part>)> = vec![
+        ("no images", vec![("delay", None, b"100")]),
+        (
+            "delay count mismatch",
+            vec![
+                ("file", Some("a.png"), &image),
+                ("delays", None, b"100,200"),
+            ],
+        ),
+        (
+            "unknown fit mode",
+            vec![("file", Some("a.png"), &image), ("fit", None, b"stretch")],
+        ),
+    ];
+
+    for (case, parts) in cases {
+        let boundary = "----boundary----";
+        let req = test::TestRequest::post()
+            .uri("/assemble-gif")
+            .insert_header((
+                "content-type",
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_body(boundary, &parts))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400, "{} should be rejected", case);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_request");
+    }
+}
+
+#[actix_web::test]
+async fn test_mirror_gif_no_file() {
+    let mirror_processor = processors().mirror.clone();
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(mirror_processor))
+            .app_data(mirror_processor)
             .route("/health", web::get().to(health_check))
             .route("/mirror-gif", web::post().to(mirror_gif)),
     )
     .await;
 
     let boundary = "----boundary----";
-    let invalid_gif = b"This is not a GIF file";
 
     let mut data = Vec::new();
     data.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
-    data.extend_from_slice(
-        b"Content-Disposition: form-data; name=\"file\"; filename=\"invalid.gif\"\r\n",
-    );
-    data.extend_from_slice(b"Content-Type: image/gif\r\n\r\n");
-    data.extend_from_slice(invalid_gif);
+    data.extend_from_slice(b"Content-Disposition: form-data; name=\"other\"\r\n\r\n");
+    data.extend_from_slice(b"some data");
     data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
 
     let req = test::TestRequest::post()
@@ -217,38 +668,33 @@ async fn test_mirror_gif_invalid_gif() {
         .to_request();
 
     let resp = test::call_service(&app, req).await;
-    assert_eq!(resp.status(), 422);
+    assert_eq!(resp.status(), 400);
 
     let body: serde_json::Value = test::read_body_json(resp).await;
-    assert_eq!(body["error"], "processing_error");
+    assert_eq!(body["error"], "invalid_request");
 }
 
 #[actix_web::test]
-async fn test_mirror_gif_large_file() {
-    let mirror_processor = MirrorProcessor::new()
-        .await
-        .expect("Failed to create MirrorProcessor");
+async fn test_mirror_gif_empty_file() {
+    let mirror_processor = processors().mirror.clone();
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(mirror_processor))
+            .app_data(mirror_processor)
             .route("/health", web::get().to(health_check))
             .route("/mirror-gif", web::post().to(mirror_gif)),
     )
     .await;
 
-    // Use the valid test GIF (it's small but valid)
-    let large_gif = create_test_gif();
-
     let boundary = "----boundary----";
 
     let mut data = Vec::new();
     data.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
     data.extend_from_slice(
-        b"Content-Disposition: form-data; name=\"file\"; filename=\"large.gif\"\r\n",
+        b"Content-Disposition: form-data; name=\"file\"; filename=\"empty.gif\"\r\n",
     );
     data.extend_from_slice(b"Content-Type: image/gif\r\n\r\n");
-    data.extend_from_slice(&large_gif);
+    // No file data
     data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
 
     let req = test::TestRequest::post()
@@ -261,44 +707,153 @@ async fn test_mirror_gif_large_file() {
         .to_request();
 
     let resp = test::call_service(&app, req).await;
-    // Should succeed with a valid GIF
-    assert!(resp.status().is_success());
-    assert_eq!(resp.headers().get("content-type").unwrap(), "image/gif");
+    assert_eq!(resp.status(), 400);
 }
 
 #[actix_web::test]
-async fn test_mirror_gif_wrong_content_type() {
-    let mirror_processor = MirrorProcessor::new()
-        .await
-        .expect("Failed to create MirrorProcessor");
+async fn test_mirror_gif_invalid_gif() {
+    let mirror_processor = processors().mirror.clone();
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(mirror_processor))
+            .app_data(mirror_processor)
             .route("/health", web::get().to(health_check))
             .route("/mirror-gif", web::post().to(mirror_gif)),
     )
     .await;
 
-    let req = test::TestRequest::post()
-        .uri("/mirror-gif")
-        .insert_header(("content-type", "application/json"))
-        .set_payload("{\"file\": \"test\"}")
-        .to_request();
+    let boundary = "----boundary----";
+    let invalid_gif = b"This is not a GIF file";
 
-    let resp = test::call_service(&app, req).await;
-    assert_eq!(resp.status(), 400);
-}
+    let mut data = Vec::new();
+    data.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+    data.extend_from_slice(
+        b"Content-Disposition: form-data; name=\"file\"; filename=\"invalid.gif\"\r\n",
+    );
+    data.extend_from_slice(b"Content-Type: image/gif\r\n\r\n");
+    data.extend_from_slice(invalid_gif);
+    data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    let req = test::TestRequest::post()
+        .uri("/mirror-gif")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(data)
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 415);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "unsupported_media_type");
+}
+
+#[actix_web::test]
+async fn test_mirror_gif_corrupt_gif() {
+    let mirror_processor = processors().mirror.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(mirror_processor)
+            .route("/mirror-gif", web::post().to(mirror_gif)),
+    )
+    .await;
+
+    // Recognized as a GIF, but the logical screen descriptor is cut short
+    let boundary = "----boundary----";
+    let data = multipart_gif_body(boundary, b"GIF89a\x01\x00\x01\x00garbage");
+
+    let req = test::TestRequest::post()
+        .uri("/mirror-gif")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(data)
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 422);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "processing_error");
+}
+
+#[actix_web::test]
+async fn test_mirror_gif_large_file() {
+    let mirror_processor = processors().mirror.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(mirror_processor)
+            .route("/health", web::get().to(health_check))
+            .route("/mirror-gif", web::post().to(mirror_gif)),
+    )
+    .await;
+
+    // Use the valid test GIF (it's small but valid)
+    let large_gif = create_test_gif();
+
+    let boundary = "----boundary----";
+
+    let mut data = Vec::new();
+    data.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+    data.extend_from_slice(
+        b"Content-Disposition: form-data; name=\"file\"; filename=\"large.gif\"\r\n",
+    );
+    data.extend_from_slice(b"Content-Type: image/gif\r\n\r\n");
+    data.extend_from_slice(&large_gif);
+    data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    let req = test::TestRequest::post()
+        .uri("/mirror-gif")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(data)
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    // Should succeed with a valid GIF
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/gif");
+}
+
+#[actix_web::test]
+async fn test_mirror_gif_wrong_content_type() {
+    let mirror_processor = processors().mirror.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(mirror_processor)
+            .route("/health", web::get().to(health_check))
+            .route("/mirror-gif", web::post().to(mirror_gif)),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/mirror-gif")
+        .insert_header(("content-type", "application/json"))
+        .set_payload("{\"file\": \"test\"}")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 415);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "unsupported_media_type");
+}
 
 #[actix_web::test]
 async fn test_health_check_multiple_sequential() {
-    let mirror_processor = MirrorProcessor::new()
-        .await
-        .expect("Failed to create MirrorProcessor");
+    let mirror_processor = processors().mirror.clone();
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(mirror_processor))
+            .app_data(mirror_processor)
             .route("/health", web::get().to(health_check))
             .route("/mirror-gif", web::post().to(mirror_gif)),
     )
@@ -333,3 +888,1620 @@ mod unit_tests {
         assert_eq!(error.to_string(), "GPU error: GPU failed");
     }
 }
+
+#[actix_web::test]
+async fn test_mirror_png_still() {
+    let mirror_processor = processors().mirror.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(mirror_processor)
+            .route("/mirror-gif", web::post().to(mirror_gif)),
+    )
+    .await;
+
+    // Top half red, bottom half blue
+    let image = image::RgbaImage::from_fn(4, 4, |_, y| {
+        if y < 2 {
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            image::Rgba([0, 0, 255, 255])
+        }
+    });
+    let mut png = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, image::ImageOutputFormat::Png)
+        .unwrap();
+
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/mirror-gif")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(boundary, png.get_ref()))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+
+    let body = test::read_body(resp).await;
+    let mirrored = image::load_from_memory_with_format(&body, image::ImageFormat::Png)
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(mirrored.dimensions(), (4, 4));
+    assert_eq!(mirrored.get_pixel(0, 0), &image::Rgba([0, 0, 255, 255]));
+    assert_eq!(mirrored.get_pixel(0, 3), &image::Rgba([255, 0, 0, 255]));
+}
+
+#[actix_web::test]
+async fn test_blur_gif() {
+    let blur_processor = processors().blur.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(blur_processor)
+            .route("/blur-gif", web::post().to(blur_gif)),
+    )
+    .await;
+
+    // Left half black, right half white
+    let image = image::RgbaImage::from_fn(8, 8, |x, _| {
+        if x < 4 {
+            image::Rgba([0, 0, 0, 255])
+        } else {
+            image::Rgba([255, 255, 255, 255])
+        }
+    });
+    let mut png = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, image::ImageOutputFormat::Png)
+        .unwrap();
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/blur-gif?radius=2", png.get_ref()).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body = test::read_body(resp).await;
+    let blurred = image::load_from_memory_with_format(&body, image::ImageFormat::Png)
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(blurred.dimensions(), (8, 8));
+    let edge = blurred.get_pixel(3, 4)[0];
+    assert!(
+        edge > 0 && edge < 255,
+        "edge pixel was not blurred: {}",
+        edge
+    );
+
+    // Radius as a form field, applied to every frame of an animation
+    let boundary = "----boundary----";
+    let gif_data = create_animated_gif(8, 8, 3);
+    let req = test::TestRequest::post()
+        .uri("/blur-gif")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_body(
+            boundary,
+            &[
+                ("radius", None, b"1"),
+                ("file", Some("anim.gif"), &gif_data),
+            ],
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/gif");
+    let body = test::read_body(resp).await;
+    assert_eq!(read_gif_delays(&body), vec![5, 5, 5]);
+
+    for radius in ["0", "33", "-1", "wide"] {
+        let resp = test::call_service(
+            &app,
+            multipart_request(&format!("/blur-gif?radius={}", radius), &gif_data).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 400, "radius {}", radius);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_request");
+    }
+}
+
+macro_rules! transform_app {
+    () => {{
+        let Processors {
+            mirror,
+            blur,
+            resize,
+        } = processors();
+        test::init_service(
+            App::new()
+                .app_data(mirror.clone())
+                .app_data(blur.clone())
+                .app_data(resize.clone())
+                .route("/api/v1/transform", web::post().to(transform)),
+        )
+        .await
+    }};
+}
+
+#[actix_web::test]
+async fn test_transform_operation_list() {
+    let app = transform_app!();
+
+    // Left column red, the rest blue
+    let image = image::RgbaImage::from_fn(4, 2, |x, _| {
+        if x == 0 {
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            image::Rgba([0, 0, 255, 255])
+        }
+    });
+    let mut png = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, image::ImageOutputFormat::Png)
+        .unwrap();
+
+    let boundary = "----boundary----";
+    let operations = br#"[{"op":"mirror","axis":"horizontal"},{"op":"rotate","degrees":90}]"#;
+    let req = test::TestRequest::post()
+        .uri("/api/v1/transform")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_body(
+            boundary,
+            &[
+                ("operations", None, operations),
+                ("file", Some("image.png"), png.get_ref()),
+            ],
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body = test::read_body(resp).await;
+    let transformed = image::load_from_memory_with_format(&body, image::ImageFormat::Png)
+        .unwrap()
+        .to_rgba8();
+    // The red column moves right, then becomes the bottom row
+    assert_eq!(transformed.dimensions(), (2, 4));
+    assert_eq!(transformed.get_pixel(0, 3), &image::Rgba([255, 0, 0, 255]));
+    assert_eq!(transformed.get_pixel(0, 0), &image::Rgba([0, 0, 255, 255]));
+
+    // Size-changing operations resize the GIF canvas
+    let gif_data = create_animated_gif(6, 4, 3);
+    let operations = r#"[{"op":"pad","top":1,"bottom":1},{"op":"resize","width":3,"height":3}]"#;
+    let uri = format!(
+        "/api/v1/transform?{}",
+        serde_urlencoded::to_string([("operations", operations)]).unwrap()
+    );
+    let resp = test::call_service(&app, multipart_request(&uri, &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    let body = test::read_body(resp).await;
+    let decoder = gif::DecodeOptions::new()
+        .read_info(std::io::Cursor::new(&body[..]))
+        .unwrap();
+    assert_eq!((decoder.width(), decoder.height()), (3, 3));
+    assert_eq!(read_gif_delays(&body), vec![5, 5, 5]);
+}
+
+#[actix_web::test]
+async fn test_transform_reports_invalid_fields() {
+    let app = transform_app!();
+    let gif_data = create_animated_gif(4, 4, 2);
+
+    let request = |operations: &str| {
+        let uri = format!(
+            "/api/v1/transform?{}",
+            serde_urlencoded::to_string([("operations", operations)]).unwrap()
+        );
+        multipart_request(&uri, &gif_data).to_request()
+    };
+
+    let resp = test::call_service(
+        &app,
+        request(r#"[{"op":"mirror"},{"op":"blur","radius":99},{"op":"warp"}]"#),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "invalid_request");
+    let fields: Vec<&str> = body["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|field| field["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["operations[1].radius", "operations[2].op"]);
+
+    // Sizes are checked against the upload before any frame is processed
+    let resp = test::call_service(
+        &app,
+        request(r#"[{"op":"crop","x":2,"width":4,"height":4}]"#),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["fields"][0]["field"], "operations[0]");
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/api/v1/transform", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["fields"][0]["field"], "operations");
+}
+
+#[actix_web::test]
+async fn test_transform_results_are_cached() {
+    let Processors {
+        mirror,
+        blur,
+        resize,
+    } = processors();
+    let app = test::init_service(
+        App::new()
+            .app_data(mirror.clone())
+            .app_data(blur.clone())
+            .app_data(resize.clone())
+            .app_data(web::Data::new(
+                ResultCache::new(CacheConfig::default()).unwrap(),
+            ))
+            .route("/api/v1/transform", web::post().to(transform))
+            .route("/api/v1/blur-gif", web::post().to(blur_gif)),
+    )
+    .await;
+    let gif_data = create_animated_gif(4, 4, 2);
+    let uri = format!(
+        "/api/v1/transform?{}",
+        serde_urlencoded::to_string([("operations", r#"[{"op":"blur"}]"#)]).unwrap()
+    );
+
+    let resp = test::call_service(&app, multipart_request(&uri, &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("x-cache").unwrap(), "MISS");
+    let etag = resp.headers().get("etag").unwrap().clone();
+    let first = test::read_body(resp).await;
+
+    let resp = test::call_service(&app, multipart_request(&uri, &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("x-cache").unwrap(), "HIT");
+    assert_eq!(resp.headers().get("etag").unwrap(), etag);
+    assert_eq!(test::read_body(resp).await, first);
+
+    // The blur endpoint with the default radius normalizes to the same operation list
+    let resp = test::call_service(
+        &app,
+        multipart_request("/api/v1/blur-gif?radius=3", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.headers().get("x-cache").unwrap(), "HIT");
+    assert_eq!(resp.headers().get("etag").unwrap(), etag);
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/api/v1/blur-gif?radius=4", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.headers().get("x-cache").unwrap(), "MISS");
+    assert_ne!(resp.headers().get("etag").unwrap(), etag);
+
+    let resp = test::call_service(
+        &app,
+        multipart_request(&uri, &gif_data)
+            .insert_header(("if-none-match", etag.clone()))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 304);
+    assert_eq!(resp.headers().get("etag").unwrap(), etag);
+    assert!(test::read_body(resp).await.is_empty());
+
+    // Uploads of unknown length bypass the cache and are streamed as usual
+    let mut request = multipart_request(&uri, &gif_data).to_request();
+    request
+        .headers_mut()
+        .remove(actix_web::http::header::CONTENT_LENGTH);
+    let resp = test::call_service(&app, request).await;
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().get("x-cache").is_none());
+    assert_eq!(test::read_body(resp).await, first);
+}
+
+#[actix_web::test]
+async fn test_cached_results_respect_endpoint_limits() {
+    let Processors {
+        mirror,
+        blur,
+        resize,
+    } = processors();
+    let one_frame = DecodeLimits {
+        max_frames: 1,
+        ..DecodeLimits::default()
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(mirror.clone())
+            .app_data(blur.clone())
+            .app_data(resize.clone())
+            .app_data(web::Data::new(
+                ResultCache::new(CacheConfig::default()).unwrap(),
+            ))
+            .route("/api/v1/transform", web::post().to(transform))
+            .service(
+                web::resource("/api/v1/mirror-gif")
+                    .app_data(web::Data::new(one_frame))
+                    .route(web::post().to(mirror_gif)),
+            ),
+    )
+    .await;
+    let gif_data = create_animated_gif(4, 4, 2);
+    let uri = format!(
+        "/api/v1/transform?{}",
+        serde_urlencoded::to_string([("operations", r#"[{"op":"mirror"}]"#)]).unwrap()
+    );
+
+    let resp = test::call_service(&app, multipart_request(&uri, &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("x-cache").unwrap(), "MISS");
+    test::read_body(resp).await;
+    let resp = test::call_service(&app, multipart_request(&uri, &gif_data).to_request()).await;
+    assert_eq!(resp.headers().get("x-cache").unwrap(), "HIT");
+
+    // The same operation on an endpoint that allows fewer frames is not served from the cache
+    let resp = test::call_service(
+        &app,
+        multipart_request("/api/v1/mirror-gif", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 422);
+}
+
+#[actix_web::test]
+async fn test_job_runner_rejects_invalid_rotations() {
+    let Processors {
+        mirror,
+        blur,
+        resize,
+    } = processors();
+    let runner = transform_job_runner(
+        ops::Processors {
+            mirror: mirror.clone().into_inner(),
+            blur: blur.clone().into_inner(),
+            resize: resize.clone().into_inner(),
+        },
+        DecodeLimits::default(),
+        QuantizePool::global(),
+        GpuScheduler::new(GpuSchedulerConfig::default()),
+    );
+
+    // Spooled specs are not validated like submissions, so they may hold any angle
+    let spec = serde_json::json!({
+        "format": "gif",
+        "output_format": "gif",
+        "operations": [{"op": "rotate", "degrees": 45}],
+        "metadata": {}
+    });
+    let mut output = Vec::new();
+    let error = runner(
+        &spec,
+        &create_animated_gif(4, 2, 1),
+        &JobContext::default(),
+        &mut output,
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Invalid input: Rotation must be 90, 180 or 270 degrees, got 45"
+    );
+}
+
+#[actix_web::test]
+async fn test_job_lifecycle() {
+    let Processors {
+        mirror,
+        blur,
+        resize,
+    } = processors();
+    let runner = transform_job_runner(
+        ops::Processors {
+            mirror: mirror.clone().into_inner(),
+            blur: blur.clone().into_inner(),
+            resize: resize.clone().into_inner(),
+        },
+        DecodeLimits::default(),
+        QuantizePool::global(),
+        GpuScheduler::new(GpuSchedulerConfig::default()),
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(
+                JobQueue::new(JobQueueConfig::default(), runner).unwrap(),
+            ))
+            .route("/api/v1/jobs", web::post().to(submit_job))
+            .route("/api/v1/jobs/{id}", web::get().to(job_status))
+            .route("/api/v1/jobs/{id}", web::delete().to(cancel_job))
+            .route("/api/v1/jobs/{id}/result", web::get().to(job_result))
+            .route("/api/v1/jobs/{id}/events", web::get().to(job_events)),
+    )
+    .await;
+
+    let gif_data = create_animated_gif(6, 4, 3);
+    let uri = format!(
+        "/api/v1/jobs?{}",
+        serde_urlencoded::to_string([(
+            "operations",
+            r#"[{"op":"mirror","axis":"horizontal"},{"op":"rotate","degrees":90}]"#
+        )])
+        .unwrap()
+    );
+    let resp = test::call_service(&app, multipart_request(&uri, &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 202);
+    let location = resp.headers().get("location").unwrap().to_str().unwrap();
+    let location = location.to_string();
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    let id = job["id"].as_str().unwrap().to_string();
+    assert_eq!(location, format!("/api/v1/jobs/{}", id));
+    assert_eq!(job["content_type"], "image/gif");
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    let job = loop {
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri(&location).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let job: serde_json::Value = test::read_body_json(resp).await;
+        if job["status"] != "queued" && job["status"] != "running" {
+            break job;
+        }
+        assert!(std::time::Instant::now() < deadline, "job did not finish");
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+    assert_eq!(job["status"], "succeeded", "{}", job);
+    assert_eq!(job["progress"]["frames_processed"], 3);
+    assert_eq!(job["progress"]["frames_total"], 3);
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&format!("{}/result", location))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/gif");
+    let body = test::read_body(resp).await;
+    let decoder = gif::DecodeOptions::new()
+        .read_info(std::io::Cursor::new(&body[..]))
+        .unwrap();
+    assert_eq!((decoder.width(), decoder.height()), (4, 6));
+    assert_eq!(read_gif_delays(&body), vec![5, 5, 5]);
+
+    // The event stream of a finished job replays its events and ends
+    let events_uri = format!("{}/events", location);
+    let resp =
+        test::call_service(&app, test::TestRequest::get().uri(&events_uri).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    let events: Vec<serde_json::Value> = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    let names: Vec<&str> = events
+        .iter()
+        .map(|event| event["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        vec!["started", "decoded", "frame", "encoding", "succeeded"]
+    );
+    assert_eq!(events[1]["frames_total"], 3);
+    assert_eq!(events[2]["index"], 3);
+    assert!(events[4]["elapsed_ms"].is_u64());
+    assert!(body.contains("event: succeeded\n"));
+
+    let last_id = events[3]["id"].to_string();
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&events_uri)
+            .insert_header(("Last-Event-ID", last_id))
+            .to_request(),
+    )
+    .await;
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    assert_eq!(body.matches("data: ").count(), 1);
+    assert!(body.contains("event: succeeded\n"));
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::delete().uri(&location).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 204);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&location).to_request()).await;
+    assert_eq!(resp.status(), 404);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "not_found");
+
+    // Invalid operations are rejected before a job is created
+    let uri = format!(
+        "/api/v1/jobs?{}",
+        serde_urlencoded::to_string([("operations", r#"[{"op":"blur","radius":0}]"#)]).unwrap()
+    );
+    let resp = test::call_service(&app, multipart_request(&uri, &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["fields"][0]["field"], "operations[0].radius");
+
+    let uri = format!(
+        "/api/v1/jobs?{}",
+        serde_urlencoded::to_string([
+            ("operations", r#"[{"op":"rotate","degrees":90}]"#),
+            ("callback_url", "ftp://example.com/done"),
+        ])
+        .unwrap()
+    );
+    let resp = test::call_service(&app, multipart_request(&uri, &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["fields"][0]["field"], "callback_url");
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/v1/jobs/not-a-job")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 404);
+}
+
+#[actix_web::test]
+async fn test_retime_rejects_timing_options_for_stills() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/retime-gif?speed=2")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(
+            boundary,
+            &create_png(2, 2, [0, 255, 0, 255]),
+        ))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_web::test]
+async fn test_gif_only_endpoints_reject_stills() {
+    let app = test::init_service(App::new().route("/inspect", web::post().to(inspect_gif))).await;
+
+    let boundary = "----boundary----";
+    let req = test::TestRequest::post()
+        .uri("/inspect")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_gif_body(
+            boundary,
+            &create_png(2, 2, [0, 255, 0, 255]),
+        ))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 415);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "unsupported_media_type");
+}
+
+/// Builds an APNG from full-size RGBA frames with delays in centiseconds
+fn create_apng(width: u32, height: u32, frames: &[(&[u8], u16)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0).unwrap();
+    let mut writer = encoder.write_header().unwrap();
+    for (rgba, delay) in frames {
+        writer.set_frame_delay(*delay, 100).unwrap();
+        writer.write_image_data(rgba).unwrap();
+    }
+    writer.finish().unwrap();
+    data
+}
+
+/// Decodes every frame of an APNG as `(rgba, delay_num, delay_den)`
+fn read_apng_frames(data: &[u8]) -> Vec<(Vec<u8>, u16, u16)> {
+    let mut reader = png::Decoder::new(data).read_info().unwrap();
+    let count = reader.info().animation_control.unwrap().num_frames;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    (0..count)
+        .map(|_| {
+            let info = reader.next_frame(&mut buffer).unwrap();
+            let control = reader.info().frame_control.unwrap();
+            (
+                buffer[..info.buffer_size()].to_vec(),
+                control.delay_num,
+                control.delay_den,
+            )
+        })
+        .collect()
+}
+
+fn multipart_request(uri: &str, file: &[u8]) -> test::TestRequest {
+    let boundary = "----boundary----";
+    let body = multipart_gif_body(boundary, file);
+    test::TestRequest::post()
+        .uri(uri)
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .insert_header(("content-length", body.len()))
+        .set_payload(body)
+}
+
+#[actix_web::test]
+async fn test_convert_gif_to_apng() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let gif_data = create_animated_gif(4, 4, 3);
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif?format=apng", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/apng");
+
+    let body = test::read_body(resp).await;
+    let frames = read_apng_frames(&body);
+    assert_eq!(frames.len(), 3);
+    for (rgba, delay_num, delay_den) in &frames {
+        assert_eq!(rgba.len(), 4 * 4 * 4);
+        assert_eq!((*delay_num, *delay_den), (5, 100));
+    }
+}
+
+#[actix_web::test]
+async fn test_convert_apng_to_gif_keeps_transparency() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let red_and_clear: &[u8] = &[255, 0, 0, 255, 0, 0, 0, 0];
+    let clear_and_blue: &[u8] = &[0, 0, 0, 0, 0, 0, 255, 255];
+    let apng = create_apng(2, 1, &[(red_and_clear, 10), (clear_and_blue, 20)]);
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif?format=gif", &apng).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/gif");
+
+    let body = test::read_body(resp).await;
+    assert_eq!(read_gif_delays(&body), vec![10, 20]);
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(body.as_ref()).unwrap();
+    let first = decoder.read_next_frame().unwrap().unwrap().buffer.to_vec();
+    assert_eq!(first[3], 255);
+    assert_eq!(first[7], 0);
+    let second = decoder.read_next_frame().unwrap().unwrap();
+    assert_eq!(second.dispose, gif::DisposalMethod::Background);
+    assert_eq!(second.buffer[3], 0);
+    assert_eq!(second.buffer[7], 255);
+}
+
+/// Decodes a GIF into whole-canvas RGBA frames, applying each frame's disposal
+fn composite_gif_frames(data: &[u8]) -> Vec<Vec<u8>> {
+    decode_gif(data, &DecodeLimits::default())
+        .unwrap()
+        .map(|frame| frame.unwrap().buffer.into_owned())
+        .collect()
+}
+
+#[actix_web::test]
+async fn test_convert_apng_to_gif_does_not_show_previous_frames() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    // APNG frames are read without a disposal of their own
+    let opaque: &[u8] = &[255, 0, 0, 255, 255, 0, 0, 255];
+    let clear_and_blue: &[u8] = &[0, 0, 0, 0, 0, 0, 255, 255];
+    let apng = create_apng(2, 1, &[(opaque, 10), (clear_and_blue, 10)]);
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif?format=gif", &apng).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    let frames = composite_gif_frames(&test::read_body(resp).await);
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[1][3], 0);
+    assert_eq!(&frames[1][4..], &[0, 0, 255, 255]);
+}
+
+#[actix_web::test]
+async fn test_reversed_gif_does_not_show_previous_frames() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    // A frame with a transparent pixel, then an opaque frame that is kept in place
+    let mut gif_data = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut gif_data, 2, 1, &[]).unwrap();
+        let mut clear_and_blue = [0, 0, 0, 0, 0, 0, 255, 255];
+        let mut first = gif::Frame::from_rgba(2, 1, &mut clear_and_blue);
+        first.dispose = gif::DisposalMethod::Background;
+        first.delay = 10;
+        encoder.write_frame(&first).unwrap();
+        let mut second = gif::Frame::from_rgb(2, 1, &[255, 0, 0, 255, 0, 0]);
+        second.dispose = gif::DisposalMethod::Keep;
+        second.delay = 10;
+        encoder.write_frame(&second).unwrap();
+    }
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif?reverse=true", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    let frames = composite_gif_frames(&test::read_body(resp).await);
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0], vec![255, 0, 0, 255, 255, 0, 0, 255]);
+    assert_eq!(frames[1][3], 0);
+    assert_eq!(&frames[1][4..], &[0, 0, 255, 255]);
+}
+
+#[actix_web::test]
+async fn test_mirror_apng_keeps_alpha() {
+    let mirror_processor = processors().mirror.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(mirror_processor)
+            .route("/mirror-gif", web::post().to(mirror_gif)),
+    )
+    .await;
+
+    // A half-transparent red row above an opaque blue row
+    let frame: &[u8] = &[255, 0, 0, 128, 0, 0, 255, 255];
+    let apng = create_apng(1, 2, &[(frame, 4), (frame, 4)]);
+
+    let resp = test::call_service(&app, multipart_request("/mirror-gif", &apng).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/apng");
+
+    let body = test::read_body(resp).await;
+    let frames = read_apng_frames(&body);
+    assert_eq!(frames.len(), 2);
+    for (rgba, delay_num, delay_den) in &frames {
+        assert_eq!(rgba, &[0, 0, 255, 255, 255, 0, 0, 128]);
+        assert_eq!((*delay_num, *delay_den), (4, 100));
+    }
+}
+
+#[actix_web::test]
+async fn test_stills_cannot_be_converted_to_animations() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let png = create_png(2, 2, [0, 255, 0, 255]);
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif?format=gif", &png).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 406);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "not_acceptable");
+}
+
+#[actix_web::test]
+async fn test_frames_as_zip_archive() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let gif_data = create_animated_gif(4, 4, 3);
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif?format=zip", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/zip"
+    );
+    assert_eq!(
+        resp.headers().get("content-disposition").unwrap(),
+        "attachment; filename=\"test.zip\""
+    );
+
+    let body = test::read_body(resp).await;
+    assert_eq!(&body[..4], b"PK\x03\x04");
+    let end = body.len() - 22;
+    assert_eq!(&body[end..end + 4], b"PK\x05\x06");
+    assert_eq!(u16::from_le_bytes([body[end + 10], body[end + 11]]), 3);
+
+    // The first entry is a 4x4 PNG
+    let name_len = u16::from_le_bytes([body[26], body[27]]) as usize;
+    assert_eq!(&body[30..30 + name_len], b"frame-0000.png");
+    let size = u32::from_le_bytes([body[18], body[19], body[20], body[21]]) as usize;
+    let start = 30 + name_len;
+    let frame =
+        image::load_from_memory_with_format(&body[start..start + size], image::ImageFormat::Png)
+            .unwrap();
+    assert_eq!((frame.width(), frame.height()), (4, 4));
+}
+
+#[actix_web::test]
+async fn test_accept_header_negotiation() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let png = create_png(3, 2, [0, 255, 0, 255]);
+    let req = multipart_request("/retime-gif", &png)
+        .insert_header(("accept", "image/webp, image/*;q=0.5"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/webp");
+    assert_eq!(
+        resp.headers().get("content-disposition").unwrap(),
+        "inline; filename=\"test.webp\""
+    );
+
+    let body = test::read_body(resp).await;
+    let image = image::load_from_memory_with_format(&body, image::ImageFormat::WebP).unwrap();
+    assert_eq!((image.width(), image.height()), (3, 2));
+
+    let gif_data = create_animated_gif(2, 2, 2);
+    let req = multipart_request("/retime-gif", &gif_data)
+        .insert_header(("accept", "application/json"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 406);
+}
+
+#[actix_web::test]
+async fn test_format_form_field() {
+    let app = test::init_service(
+        App::new()
+            .route("/retime-gif", web::post().to(retime_gif))
+            .route("/extract-frame", web::post().to(extract_frame)),
+    )
+    .await;
+
+    let boundary = "----boundary----";
+    let png = create_png(2, 2, [255, 0, 0, 255]);
+    let req = test::TestRequest::post()
+        .uri("/retime-gif")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_body(
+            boundary,
+            &[("format", None, b"jpg"), ("file", Some("photo.png"), &png)],
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/jpeg");
+    assert_eq!(
+        resp.headers().get("content-disposition").unwrap(),
+        "inline; filename=\"photo.jpg\""
+    );
+
+    let gif_data = create_animated_gif(4, 4, 2);
+    let resp = test::call_service(
+        &app,
+        multipart_request("/extract-frame?format=bmp", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/bmp");
+    let body = test::read_body(resp).await;
+    assert_eq!(&body[..2], b"BM");
+}
+
+#[actix_web::test]
+async fn test_raw_body_upload() {
+    let mirror_processor = processors().mirror.clone();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(mirror_processor)
+            .route("/mirror-gif", web::post().to(mirror_gif))
+            .route("/retime-gif", web::post().to(retime_gif))
+            .route("/inspect", web::post().to(inspect_gif)),
+    )
+    .await;
+
+    let gif_data = create_animated_gif(4, 4, 4);
+    let req = test::TestRequest::post()
+        .uri("/retime-gif?speed=2")
+        .insert_header(("content-type", "image/gif"))
+        .set_payload(gif_data.clone())
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/gif");
+    assert_eq!(
+        resp.headers().get("content-disposition").unwrap(),
+        "inline; filename=\"image.gif\""
+    );
+    let body = test::read_body(resp).await;
+    assert_eq!(read_gif_delays(&body), vec![3, 3, 3, 3]);
+
+    // The format is sniffed, so a generic binary type works as well
+    let png = create_png(2, 2, [0, 0, 255, 255]);
+    let req = test::TestRequest::post()
+        .uri("/mirror-gif?format=webp")
+        .insert_header(("content-type", "application/octet-stream"))
+        .set_payload(png)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/webp");
+
+    let req = test::TestRequest::post()
+        .uri("/inspect")
+        .insert_header(("content-type", "image/gif"))
+        .set_payload(gif_data)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["frame_count"], 4);
+}
+
+#[actix_web::test]
+async fn test_raw_body_rejections() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/retime-gif")
+        .insert_header(("content-type", "image/gif"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    let req = test::TestRequest::post()
+        .uri("/retime-gif")
+        .insert_header(("content-type", "image/gif"))
+        .set_payload("not an image")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 415);
+
+    let req = test::TestRequest::post()
+        .uri("/retime-gif")
+        .set_payload(create_animated_gif(2, 2, 2))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 415);
+}
+
+#[actix_web::test]
+async fn test_decompression_bomb_is_rejected() {
+    let app = test::init_service(
+        App::new()
+            .route("/retime-gif", web::post().to(retime_gif))
+            .route("/extract-frame", web::post().to(extract_frame)),
+    )
+    .await;
+
+    // A few dozen bytes declaring a 65535x65535 canvas
+    let mut bomb = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut bomb, 65535, 65535, &[]).unwrap();
+        encoder
+            .write_frame(&gif::Frame::from_rgb(1, 1, &[255, 0, 0]))
+            .unwrap();
+    }
+    assert!(bomb.len() < 100);
+
+    for uri in ["/retime-gif", "/extract-frame"] {
+        let resp = test::call_service(&app, multipart_request(uri, &bomb).to_request()).await;
+        assert_eq!(resp.status(), 422);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "limit_exceeded");
+    }
+}
+
+#[actix_web::test]
+async fn test_configured_decode_limits() {
+    let limits = DecodeLimits {
+        max_frames: 3,
+        max_duration_ms: 1000,
+        ..DecodeLimits::default()
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(limits))
+            .route("/retime-gif", web::post().to(retime_gif)),
+    )
+    .await;
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif", &create_animated_gif(4, 4, 3)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif", &create_animated_gif(4, 4, 4)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "limit_exceeded");
+
+    // Three frames of 0.5 s each exceed the duration limit
+    let frame = [0u8; 4 * 4 * 4];
+    let apng = create_apng(4, 4, &[(&frame, 50), (&frame, 50), (&frame, 50)]);
+    let resp = test::call_service(&app, multipart_request("/retime-gif", &apng).to_request()).await;
+    assert_eq!(resp.status(), 422);
+
+    // The frame count of an APNG is known up front
+    let apng = create_apng(4, 4, &[(&frame[..], 1); 4]);
+    let resp = test::call_service(&app, multipart_request("/retime-gif", &apng).to_request()).await;
+    assert_eq!(resp.status(), 422);
+}
+
+#[actix_web::test]
+async fn test_upload_size_limit() {
+    let limits = DecodeLimits {
+        max_upload_bytes: 1024,
+        ..DecodeLimits::default()
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(limits))
+            .route("/retime-gif", web::post().to(retime_gif)),
+    )
+    .await;
+
+    let gif_data = create_animated_gif(32, 32, 8);
+    assert!(gif_data.len() > 1024);
+
+    // A declared length is checked before reading the body
+    let req = test::TestRequest::post()
+        .uri("/retime-gif")
+        .insert_header(("content-type", "image/gif"))
+        .insert_header(("content-length", gif_data.len().to_string()))
+        .set_payload(gif_data.clone())
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 413);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "payload_too_large");
+
+    // Without one, the body is cut off as it streams in
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 413);
+
+    let req = test::TestRequest::post()
+        .uri("/retime-gif")
+        .insert_header(("content-type", "image/gif"))
+        .set_payload(gif_data)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 413);
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif", &create_animated_gif(4, 4, 2)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[actix_web::test]
+async fn test_endpoint_limits_and_deadline() {
+    let small_uploads = DecodeLimits {
+        max_upload_bytes: 1024,
+        ..DecodeLimits::default()
+    };
+    let short_deadline = DecodeLimits {
+        max_processing_ms: 1,
+        ..DecodeLimits::default()
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(DecodeLimits::default()))
+            .service(
+                web::resource("/inspect")
+                    .app_data(web::Data::new(small_uploads))
+                    .route(web::post().to(inspect_gif)),
+            )
+            .service(
+                web::resource("/retime-gif")
+                    .app_data(web::Data::new(short_deadline))
+                    .route(web::post().to(retime_gif)),
+            )
+            .route("/extract-frame", web::post().to(extract_frame)),
+    )
+    .await;
+
+    let gif_data = create_animated_gif(64, 64, 20);
+    assert!(gif_data.len() > 1024);
+
+    let resp =
+        test::call_service(&app, multipart_request("/inspect", &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 413);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "payload_too_large");
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/extract-frame", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    // Twenty frames take longer than a millisecond, so processing stops between two of them
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 504);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "deadline_exceeded");
+}
+
+#[actix_web::test]
+async fn test_gpu_queue_rejects_when_full() {
+    let scheduler = GpuScheduler::new(GpuSchedulerConfig {
+        max_concurrent: 1,
+        queue_depth: 0,
+        retry_after: std::time::Duration::from_secs(3),
+        ..GpuSchedulerConfig::default()
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(processors().blur.clone())
+            .app_data(web::Data::new(scheduler.clone()))
+            .route("/blur-gif", web::post().to(blur_gif))
+            .route("/retime-gif", web::post().to(retime_gif))
+            .route("/gpu-queue", web::get().to(gpu_queue)),
+    )
+    .await;
+    let gif_data = create_animated_gif(4, 4, 2);
+
+    let permit = scheduler.acquire(&Requester::default()).await.unwrap();
+    let resp =
+        test::call_service(&app, multipart_request("/blur-gif", &gif_data).to_request()).await;
+    assert_eq!(resp.status(), 503);
+    assert_eq!(resp.headers().get("retry-after").unwrap(), "3");
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "service_unavailable");
+
+    // Work without GPU operations does not wait for the GPU
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/gpu-queue").to_request(),
+    )
+    .await;
+    let stats: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(
+        stats,
+        serde_json::json!({
+            "running": 1,
+            "waiting": 0,
+            "waiting_by_priority": {"interactive": 0, "default": 0, "bulk": 0},
+            "max_concurrent": 1,
+            "queue_depth": 0,
+            "rejected": 1
+        })
+    );
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif", &gif_data)
+            .insert_header(("x-priority", "urgent"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+
+    drop(permit);
+    let resp = test::call_service(
+        &app,
+        multipart_request("/blur-gif", &gif_data)
+            .insert_header(("x-priority", "bulk"))
+            .insert_header(("x-client-id", "tenant-a"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    test::read_body(resp).await;
+    assert_eq!(scheduler.stats().running, 0);
+}
+
+/// Lists the extensions of a GIF other than graphic control as `(label, sub-blocks)`
+fn read_gif_extensions(data: &[u8]) -> Vec<(u8, Vec<Vec<u8>>)> {
+    gpu_worker::inspect::GifBlocks::new(data)
+        .unwrap()
+        .filter_map(|block| match block.unwrap() {
+            gpu_worker::inspect::Block::Extension { label, sub_blocks }
+                if label != gpu_worker::inspect::GRAPHIC_CONTROL_LABEL =>
+            {
+                Some((label, sub_blocks))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn test_metadata_is_preserved() {
+    let app = test::init_service(App::new().route("/retime-gif", web::post().to(retime_gif))).await;
+
+    let mut gif_data = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut gif_data, 4, 4, &[]).unwrap();
+        encoder.set_repeat(gif::Repeat::Finite(3)).unwrap();
+        encoder
+            .write_raw_extension(gif::AnyExtension(0xFE), &[b"attribution"])
+            .unwrap();
+        encoder
+            .write_raw_extension(gif::AnyExtension(0xFF), &[b"XMP DataXMP", b"<x:xmpmeta/>"])
+            .unwrap();
+        for _ in 0..2 {
+            let mut frame = gif::Frame::from_rgb(4, 4, &[9; 48]);
+            frame.delay = 5;
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif?speed=2", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body = test::read_body(resp).await;
+
+    let extensions = read_gif_extensions(&body);
+    let netscape: Vec<_> = extensions
+        .iter()
+        .filter(|(label, sub_blocks)| *label == 0xFF && sub_blocks[0] == b"NETSCAPE2.0")
+        .collect();
+    assert_eq!(netscape.len(), 1);
+    assert!(extensions.contains(&(0xFE, vec![b"attribution".to_vec()])));
+    assert!(extensions.contains(&(
+        0xFF,
+        vec![b"XMP DataXMP".to_vec(), b"<x:xmpmeta/>".to_vec()]
+    )));
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/retime-gif?strip_metadata=true", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body = test::read_body(resp).await;
+
+    let extensions = read_gif_extensions(&body);
+    assert_eq!(extensions.len(), 1);
+    assert_eq!(extensions[0].1[0], b"NETSCAPE2.0");
+}
+
+#[actix_web::test]
+async fn test_api_keys_are_required_with_scopes() {
+    let keys = ApiKeys::new(vec![
+        ApiKeyEntry {
+            name: "tenant-a".to_string(),
+            sha256: hash_key("secret-a"),
+            scopes: [Scope::Transform].into_iter().collect(),
+            operations: Some(["mirror".to_string()].into_iter().collect()),
+            max_priority: None,
+        },
+        ApiKeyEntry {
+            name: "operator".to_string(),
+            sha256: hash_key("secret-admin"),
+            scopes: [Scope::Admin].into_iter().collect(),
+            operations: None,
+            max_priority: None,
+        },
+    ])
+    .unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(keys))
+            .app_data(processors().mirror.clone())
+            .app_data(processors().blur.clone())
+            .app_data(processors().resize.clone())
+            .app_data(web::Data::new(GpuScheduler::new(
+                GpuSchedulerConfig::default(),
+            )))
+            .route("/health", web::get().to(health_check))
+            .service(
+                web::resource("/mirror-gif")
+                    .wrap(RequireScope(Scope::Transform))
+                    .route(web::post().to(mirror_gif)),
+            )
+            .service(
+                web::resource("/blur-gif")
+                    .wrap(RequireScope(Scope::Transform))
+                    .route(web::post().to(blur_gif)),
+            )
+            .service(
+                web::resource("/assemble-gif")
+                    .wrap(RequireScope(Scope::Transform))
+                    .route(web::post().to(assemble_gif)),
+            )
+            .service(
+                web::resource("/gpu-queue")
+                    .wrap(RequireScope(Scope::Admin))
+                    .route(web::get().to(gpu_queue)),
+            ),
+    )
+    .await;
+    let gif_data = create_animated_gif(4, 4, 2);
+    let png_data = create_png(4, 4, [255, 0, 0, 255]);
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/mirror-gif", &gif_data).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers().get("www-authenticate").unwrap(), "Bearer");
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "unauthorized");
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/mirror-gif", &gif_data)
+            .insert_header(("authorization", "Bearer secret-b"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+
+    let resp = test::call_service(
+        &app,
+        multipart_request("/mirror-gif", &gif_data)
+            .insert_header(("authorization", "Bearer secret-a"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    // The key may only run mirror operations
+    let resp = test::call_service(
+        &app,
+        multipart_request("/blur-gif", &gif_data)
+            .insert_header(("x-api-key", "secret-a"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "forbidden");
+    assert_eq!(
+        body["message"],
+        "Forbidden: API key tenant-a may not run blur"
+    );
+
+    // Assembling resizes every image
+    let resp = test::call_service(
+        &app,
+        multipart_request("/assemble-gif", &png_data)
+            .insert_header(("x-api-key", "secret-a"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(
+        body["message"],
+        "Forbidden: API key tenant-a may not run resize"
+    );
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/gpu-queue")
+            .insert_header(("x-api-key", "secret-a"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 403);
+
+    // Admin keys have every scope
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/gpu-queue")
+            .insert_header(("authorization", "Bearer secret-admin"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let resp = test::call_service(
+        &app,
+        multipart_request("/blur-gif", &gif_data)
+            .insert_header(("authorization", "Bearer secret-admin"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let resp = test::call_service(
+        &app,
+        multipart_request("/assemble-gif", &png_data)
+            .insert_header(("authorization", "Bearer secret-admin"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[actix_web::test]
+async fn test_jobs_are_only_visible_to_their_key() {
+    let key = |name: &str, secret: &str, scope: Scope| ApiKeyEntry {
+        name: name.to_string(),
+        sha256: hash_key(secret),
+        scopes: [scope].into_iter().collect(),
+        operations: None,
+        max_priority: None,
+    };
+    let keys = ApiKeys::new(vec![
+        key("tenant-a", "secret-a", Scope::Jobs),
+        key("tenant-b", "secret-b", Scope::Jobs),
+        key("operator", "secret-admin", Scope::Admin),
+    ])
+    .unwrap();
+    let Processors {
+        mirror,
+        blur,
+        resize,
+    } = processors();
+    let runner = transform_job_runner(
+        ops::Processors {
+            mirror: mirror.clone().into_inner(),
+            blur: blur.clone().into_inner(),
+            resize: resize.clone().into_inner(),
+        },
+        DecodeLimits::default(),
+        QuantizePool::global(),
+        GpuScheduler::new(GpuSchedulerConfig::default()),
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(keys))
+            .app_data(web::Data::new(
+                JobQueue::new(JobQueueConfig::default(), runner).unwrap(),
+            ))
+            .service(
+                web::scope("/jobs")
+                    .wrap(RequireScope(Scope::Jobs))
+                    .route("", web::post().to(submit_job))
+                    .route("/{id}", web::get().to(job_status))
+                    .route("/{id}", web::delete().to(cancel_job))
+                    .route("/{id}/result", web::get().to(job_result))
+                    .route("/{id}/events", web::get().to(job_events)),
+            ),
+    )
+    .await;
+
+    let gif_data = create_animated_gif(4, 4, 2);
+    let uri = format!(
+        "/jobs?{}",
+        serde_urlencoded::to_string([("operations", r#"[{"op":"rotate","degrees":90}]"#)]).unwrap()
+    );
+    let resp = test::call_service(
+        &app,
+        multipart_request(&uri, &gif_data)
+            .insert_header(("x-api-key", "secret-a"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+    let location = resp.headers().get("location").unwrap().to_str().unwrap();
+    let location = location.to_string();
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    loop {
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&location)
+                .insert_header(("x-api-key", "secret-a"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let job: serde_json::Value = test::read_body_json(resp).await;
+        if job["status"] == "succeeded" {
+            break;
+        }
+        assert!(std::time::Instant::now() < deadline, "job did not finish");
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    // Another tenant cannot tell the job exists
+    for request in [
+        test::TestRequest::get().uri(&location),
+        test::TestRequest::get().uri(&format!("{}/result", location)),
+        test::TestRequest::get().uri(&format!("{}/events", location)),
+        test::TestRequest::delete().uri(&location),
+    ] {
+        let resp = test::call_service(
+            &app,
+            request
+                .insert_header(("x-api-key", "secret-b"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 404);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "not_found");
+    }
+
+    for secret in ["secret-a", "secret-admin"] {
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("{}/result", location))
+                .insert_header(("x-api-key", secret))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::delete()
+            .uri(&location)
+            .insert_header(("x-api-key", "secret-a"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 204);
+}
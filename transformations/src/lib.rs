@@ -2,8 +2,10 @@ pub mod blur;
 pub mod error;
 pub mod gpu;
 pub mod mirror;
+pub mod resize;
 
 pub use blur::BlurProcessor;
 pub use error::{Result, TransformationError};
 pub use gpu::GpuProcessor;
 pub use mirror::MirrorProcessor;
+pub use resize::ResizeProcessor;
@@ -0,0 +1,363 @@
+use crate::error::{Result, TransformationError};
+use crate::gpu::{GpuProcessor, Vertex, INDICES};
+use bytemuck::{Pod, Zeroable};
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct ResizeUniforms {
+    source_width: f32,
+    source_height: f32,
+    target_width: f32,
+    target_height: f32,
+}
+
+pub struct ResizeProcessor {
+    gpu: GpuProcessor,
+    pipeline: wgpu::RenderPipeline,
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl ResizeProcessor {
+    pub async fn new() -> Result<Self> {
+        let gpu = GpuProcessor::new().await?;
+
+        let vertex_buffer = gpu.create_vertex_buffer();
+        let index_buffer = gpu.create_index_buffer();
+
+        let bind_group_layout =
+            gpu.device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                    ],
+                    label: Some("resize_bind_group_layout"),
+                });
+
+        let shader = gpu
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Resize Shader"),
+                source: wgpu::ShaderSource::Wgsl(RESIZE_SHADER.into()),
+            });
+
+        let pipeline_layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Resize Pipeline Layout"),
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let pipeline = gpu
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Resize Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[wgpu::VertexBufferLayout {
+                        array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+                        step_mode: wgpu::VertexStepMode::Vertex,
+                        attributes: &[
+                            wgpu::VertexAttribute {
+                                offset: 0,
+                                shader_location: 0,
+                                format: wgpu::VertexFormat::Float32x2,
+                            },
+                            wgpu::VertexAttribute {
+                                offset: std::mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                                shader_location: 1,
+                                format: wgpu::VertexFormat::Float32x2,
+                            },
+                        ],
+                    }],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: Some(wgpu::Face::Back),
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    unclipped_depth: false,
+                    conservative: false,
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            });
+
+        Ok(Self {
+            gpu,
+            pipeline,
+            vertex_buffer,
+            index_buffer,
+            bind_group_layout,
+        })
+    }
+
+    pub async fn resize_image(
+        &self,
+        image_data: &[u8],
+        width: u32,
+        height: u32,
+        target_width: u32,
+        target_height: u32,
+    ) -> Result<Vec<u8>> {
+        if width == 0 || height == 0 || target_width == 0 || target_height == 0 {
+            return Err(TransformationError::InvalidInput(format!(
+                "Cannot resize {}x{} to {}x{}",
+                width, height, target_width, target_height
+            )));
+        }
+
+        let input_size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let output_size = wgpu::Extent3d {
+            width: target_width,
+            height: target_height,
+            depth_or_array_layers: 1,
+        };
+
+        let input_texture = self.gpu.create_texture(
+            width,
+            height,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            "Resize Input Texture",
+        );
+
+        let output_texture = self.gpu.create_texture(
+            target_width,
+            target_height,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            "Resize Output Texture",
+        );
+
+        self.gpu.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &input_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            image_data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * width),
+                rows_per_image: Some(height),
+            },
+            input_size,
+        );
+
+        let uniforms = ResizeUniforms {
+            source_width: width as f32,
+            source_height: height as f32,
+            target_width: target_width as f32,
+            target_height: target_height as f32,
+        };
+
+        let uniform_buffer = self.gpu.create_buffer_init(
+            bytemuck::cast_slice(&[uniforms]),
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            "Resize Uniform Buffer",
+        );
+
+        let input_view = input_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let output_view = output_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let sampler = self.gpu.create_sampler();
+
+        let bind_group = self
+            .gpu
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&input_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: uniform_buffer.as_entire_binding(),
+                    },
+                ],
+                label: Some("resize_bind_group"),
+            });
+
+        let mut encoder = self
+            .gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Resize Encoder"),
+            });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Resize Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &output_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+            render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+            render_pass.draw_indexed(0..INDICES.len() as u32, 0, 0..1);
+        }
+
+        let padded_bytes_per_row = self.gpu.calculate_aligned_bytes_per_row(target_width);
+
+        let output_buffer = self.gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            size: (padded_bytes_per_row * target_height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            label: Some("Resize Output Buffer"),
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &output_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &output_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(target_height),
+                },
+            },
+            output_size,
+        );
+
+        self.gpu.queue.submit(std::iter::once(encoder.finish()));
+
+        let data = self
+            .gpu
+            .read_buffer(
+                &output_buffer,
+                (padded_bytes_per_row * target_height) as u64,
+            )
+            .await?;
+        let result =
+            self.gpu
+                .remove_padding(&data, target_width, target_height, padded_bytes_per_row);
+
+        Ok(result)
+    }
+}
+
+const RESIZE_SHADER: &str = r#"
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+}
+
+struct ResizeUniforms {
+    source_width: f32,
+    source_height: f32,
+    target_width: f32,
+    target_height: f32,
+}
+
+@vertex
+fn vs_main(model: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(model.position, 0.0, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+@group(0) @binding(2)
+var<uniform> uniforms: ResizeUniforms;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Average the source texels covered by this output pixel so downscaling does not alias.
+    // When upscaling the footprint is below one texel and a single bilinear sample is used.
+    let footprint = vec2<f32>(
+        uniforms.source_width / uniforms.target_width,
+        uniforms.source_height / uniforms.target_height
+    );
+    let taps = clamp(ceil(footprint), vec2<f32>(1.0, 1.0), vec2<f32>(8.0, 8.0));
+    let texel_size = vec2<f32>(1.0 / uniforms.source_width, 1.0 / uniforms.source_height);
+
+    var color = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+    for (var y = 0.0; y < taps.y; y += 1.0) {
+        for (var x = 0.0; x < taps.x; x += 1.0) {
+            let offset = ((vec2<f32>(x, y) + 0.5) / taps - 0.5) * footprint * texel_size;
+            color += textureSampleLevel(t_diffuse, s_diffuse, in.tex_coords + offset, 0.0);
+        }
+    }
+
+    return color / (taps.x * taps.y);
+}
+"#;
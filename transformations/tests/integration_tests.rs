@@ -1,5 +1,5 @@
 use image::{ImageBuffer, Rgba};
-use transformations::{BlurProcessor, MirrorProcessor};
+use transformations::{BlurProcessor, MirrorProcessor, ResizeProcessor};
 
 // Helper function to create a test image
 fn create_test_image(width: u32, height: u32) -> Vec<u8> {
@@ -223,3 +223,75 @@ async fn test_single_pixel_image() {
         "Single pixel output should be 4 bytes"
     );
 }
+
+#[tokio::test]
+async fn test_resize_image_processing() {
+    let processor = ResizeProcessor::new()
+        .await
+        .expect("Failed to create ResizeProcessor");
+
+    let width = 64;
+    let height = 32;
+    let test_image = create_test_image(width, height);
+
+    // Downscale and upscale to sizes that are not multiples of the source
+    for (target_width, target_height) in [(20, 9), (100, 50), (64, 32)] {
+        let result = processor
+            .resize_image(&test_image, width, height, target_width, target_height)
+            .await;
+
+        assert!(
+            result.is_ok(),
+            "Resize processing failed: {:?}",
+            result.err()
+        );
+        assert_eq!(
+            result.unwrap().len(),
+            (target_width * target_height * 4) as usize,
+            "Output image has incorrect size"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_resize_image_content() {
+    let processor = ResizeProcessor::new()
+        .await
+        .expect("Failed to create ResizeProcessor");
+
+    // Left half red, right half blue
+    let width = 16;
+    let height = 8;
+    let mut image = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height);
+    for (x, _, pixel) in image.enumerate_pixels_mut() {
+        *pixel = if x < width / 2 {
+            Rgba([255, 0, 0, 255])
+        } else {
+            Rgba([0, 0, 255, 255])
+        };
+    }
+
+    let result = processor
+        .resize_image(&image.into_raw(), width, height, 4, 2)
+        .await
+        .unwrap();
+    let output_image = ImageBuffer::<Rgba<u8>, Vec<u8>>::from_raw(4, 2, result)
+        .expect("Failed to create output image");
+
+    assert_eq!(output_image.get_pixel(0, 0)[0], 255, "Left should stay red");
+    assert_eq!(
+        output_image.get_pixel(3, 1)[2],
+        255,
+        "Right should stay blue"
+    );
+}
+
+#[tokio::test]
+async fn test_resize_rejects_empty_target() {
+    let processor = ResizeProcessor::new()
+        .await
+        .expect("Failed to create ResizeProcessor");
+
+    let result = processor.resize_image(&[0, 0, 0, 255], 1, 1, 0, 1).await;
+    assert!(result.is_err(), "Resizing to zero width should fail");
+}